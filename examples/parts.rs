@@ -0,0 +1,100 @@
+use log::{error, info};
+use std::time::{Duration, Instant};
+use tokio::time;
+
+use playwith as lib;
+
+use lib::bluetooth::HidChannel;
+use lib::protocol::input::Button;
+use lib::protocol::Protocol;
+use lib::{Controller, ControllerType, ErrorKind, Result};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    lib::set_logger(0);
+
+    if let Err(ref e) = run_from_parts().await {
+        error!("{}", e);
+    }
+}
+
+async fn run_from_parts() -> Result<()> {
+    // Controller
+    let adapters = lib::adapters().await?;
+    let adapter = match adapters.first() {
+        Some(adapter) => adapter,
+        None => {
+            error!("Cannot find available adapter");
+
+            return Ok(());
+        }
+    };
+    let mut controller = Controller::new(adapter, ControllerType::ProController).await?;
+
+    // Pair
+    let device = controller.pair().await?;
+    info!("Device {} paired", device.address);
+
+    // Run from parts for 30 seconds, pressing A every second
+    let mut parts = controller.into_parts();
+    if let Some(itr_channel) = &parts.itr_channel {
+        let stop = Instant::now() + Duration::from_secs(30);
+        serve(itr_channel, &mut parts.protocol, stop).await?;
+    }
+
+    let mut controller = Controller::from_parts(parts);
+    controller.disconnect().await
+}
+
+/// Services the device with the protocol until the given time, or until the device closes the
+/// connection.
+async fn serve(itr_channel: &HidChannel, protocol: &mut Protocol, stop: Instant) -> Result<()> {
+    let mut buf = [0u8; 512];
+    let mut next_press = Instant::now() + Duration::from_secs(1);
+    loop {
+        let now = Instant::now();
+        if now >= stop {
+            return Ok(());
+        }
+
+        // Tap A, where the press is released after 100 ms
+        if now >= next_press {
+            protocol.press_for(Button::A, Duration::from_millis(100), now)?;
+            next_press = now + Duration::from_secs(1);
+        }
+
+        // Send input reports
+        while let Some(frame) = protocol.poll_tick(now) {
+            itr_channel.send_report(&frame.data).await?;
+        }
+
+        // Wait for the next output report or the next tick
+        let deadline = protocol
+            .next_tick(now)
+            .into_iter()
+            .chain([next_press, stop])
+            .min()
+            .unwrap_or(stop);
+        tokio::select! {
+            result = itr_channel.recv_report(&mut buf) => {
+                let size = match result {
+                    Ok(size) => size,
+                    Err(e) if matches!(e.kind, ErrorKind::PeerClosed) => {
+                        info!("Device closed the connection");
+
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                };
+                // Replies are sent at once, regardless of the reply delay
+                for frame in protocol.handle_incoming(&buf[..size], Instant::now())? {
+                    itr_channel.send_report(&frame.data).await?;
+                }
+                for event in protocol.take_events() {
+                    info!("Event: {:?}", event);
+                }
+            }
+            _ = time::sleep_until(deadline.into()) => {}
+        }
+    }
+}
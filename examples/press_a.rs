@@ -0,0 +1,63 @@
+use log::{error, info};
+use std::time::Duration;
+use tokio::time;
+
+use playwith as lib;
+
+use lib::protocol::input::Button;
+use lib::{Controller, ControllerType, Result};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    lib::set_logger(0);
+
+    if let Err(ref e) = press_a().await {
+        error!("{}", e);
+    }
+}
+
+async fn press_a() -> Result<()> {
+    // Controller
+    let adapters = lib::adapters().await?;
+    let adapter = match adapters.first() {
+        Some(adapter) => adapter,
+        None => {
+            error!("Cannot find available adapter");
+
+            return Ok(());
+        }
+    };
+    let mut controller = Controller::new(adapter, ControllerType::ProController).await?;
+
+    // Pair
+    let device = controller.pair().await?;
+    info!("Device {} paired", device.address);
+
+    // Wait for player assignment
+    let player = controller
+        .wait_for_player_assignment(Duration::from_secs(30))
+        .await?;
+    info!("Assigned as {}", player);
+
+    // Press A for 100 ms
+    controller.press(Button::A)?;
+    if run_for(&mut controller, Duration::from_millis(100)).await? {
+        return Ok(());
+    }
+    controller.release(Button::A)?;
+    run_for(&mut controller, Duration::from_millis(100)).await?;
+
+    controller.disconnect().await
+}
+
+/// Runs the controller for the given duration and returns if it stopped.
+async fn run_for(controller: &mut Controller, duration: Duration) -> Result<bool> {
+    match time::timeout(duration, controller.run()).await {
+        Ok(reason) => {
+            info!("Stop: {}", reason?);
+
+            Ok(true)
+        }
+        Err(_) => Ok(false),
+    }
+}
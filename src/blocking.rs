@@ -0,0 +1,255 @@
+//! Support for controlling a controller without async.
+//!
+//! A blocking `Controller` owns a current-thread Tokio runtime, which is driven by a thread
+//! spawned for it. Blocking calls run on the calling thread until they return, and the
+//! connection running in background runs on the thread of the runtime. Blocking calls must not
+//! be made from an async context, like inside another Tokio runtime, where they panic.
+//!
+//! The `Controller` can be moved to another thread, but only one thread can call it at once.
+//! Other threads update the input state through `handle`, which is thread-safe.
+
+use crate::bluetooth::Address;
+use crate::protocol::input::{Button, Stick};
+use crate::{
+    CancelToken, ControllerHandle, ControllerType, Error, ErrorKind, PairedDevice, Result,
+    StopReason,
+};
+use std::thread::{self, JoinHandle};
+use tokio::runtime::{Builder, Handle};
+use tokio::sync::oneshot;
+
+/// Represents the run of a controller in background.
+struct Running {
+    // The sender is dropped without sending if the run panics
+    stopped: oneshot::Receiver<(crate::Controller, Result<StopReason>)>,
+    cancel_token: CancelToken,
+}
+
+/// Represents an emulated Nintendo Switch controller with blocking calls.
+pub struct Controller {
+    runtime: Handle,
+    shutdown: Option<(oneshot::Sender<()>, JoinHandle<()>)>,
+    controller: Option<crate::Controller>,
+    handle: ControllerHandle,
+    running: Option<Running>,
+}
+
+impl Controller {
+    /// Creates a `Controller` with the given adapter and controller type.
+    pub fn new(adapter: &str, controller_type: ControllerType) -> Result<Self> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let handle = runtime.handle().clone();
+        let (sender, receiver) = oneshot::channel();
+        let thread = thread::Builder::new()
+            .name("playwith".into())
+            .spawn(move || {
+                let _ = runtime.block_on(receiver);
+            })?;
+
+        let controller = handle.block_on(crate::Controller::new(adapter, controller_type));
+        let controller = match controller {
+            Ok(controller) => controller,
+            Err(e) => {
+                let _ = sender.send(());
+                let _ = thread.join();
+
+                return Err(e);
+            }
+        };
+
+        Ok(Controller {
+            runtime: handle,
+            shutdown: Some((sender, thread)),
+            handle: controller.handle(),
+            controller: Some(controller),
+            running: None,
+        })
+    }
+
+    /// Returns a handle to update the input state of the controller from any thread.
+    pub fn handle(&self) -> ControllerHandle {
+        self.handle.clone()
+    }
+
+    /// Pairs a new device. The controller must not be running in background.
+    pub fn pair(&mut self) -> Result<PairedDevice> {
+        let runtime = self.runtime.clone();
+        let controller = self.idle_controller()?;
+
+        runtime.block_on(controller.pair())
+    }
+
+    /// Connects to a previously paired device. The controller must not be running in
+    /// background.
+    pub fn connect(&mut self, addr: Address) -> Result<()> {
+        let runtime = self.runtime.clone();
+        let controller = self.idle_controller()?;
+
+        runtime.block_on(controller.connect(addr))
+    }
+
+    /// Services the paired device in background until the connection stops. Errors of the
+    /// background run, including panics, are returned by the next blocking call.
+    pub fn run_in_background(&mut self) -> Result<()> {
+        self.idle_controller()?;
+        // The controller is moved into the task, and taken back when the task stops
+        let mut controller = self.controller.take().unwrap();
+        let cancel_token = controller.cancel_token();
+        let (sender, stopped) = oneshot::channel();
+        self.runtime.spawn(async move {
+            let result = controller.run().await;
+            let _ = sender.send((controller, result));
+        });
+        self.running = Some(Running {
+            stopped,
+            cancel_token,
+        });
+
+        Ok(())
+    }
+
+    /// Returns if the controller is running in background.
+    pub fn is_running(&mut self) -> bool {
+        self.check().is_ok() && self.running.is_some()
+    }
+
+    /// Waits until the background run stops, and returns the reason.
+    pub fn wait(&mut self) -> Result<StopReason> {
+        match self.running.take() {
+            Some(running) => self.join(running),
+            None => Err(Error::new(
+                ErrorKind::Other,
+                "controller is not running in background".into(),
+            )),
+        }
+    }
+
+    /// Presses the given button.
+    pub fn press(&mut self, button: Button) -> Result<()> {
+        self.check()?;
+
+        self.handle.press(button)
+    }
+
+    /// Releases the given button.
+    pub fn release(&mut self, button: Button) -> Result<()> {
+        self.check()?;
+
+        self.handle.release(button)
+    }
+
+    /// Taps the given button.
+    pub fn tap(&mut self, button: Button) -> Result<()> {
+        self.check()?;
+
+        self.handle.tap(button)
+    }
+
+    /// Sets the position of the given stick from normalized values in -1 to 1.
+    pub fn set_stick(&mut self, stick: Stick, x: f32, y: f32) -> Result<()> {
+        self.check()?;
+
+        self.handle.set_stick(stick, x, y)
+    }
+
+    /// Disconnects from the paired device, which stops the background run first.
+    pub fn disconnect(&mut self) -> Result<()> {
+        let stopped = match self.running.take() {
+            Some(running) => self.stop(running),
+            None => Ok(()),
+        };
+        let disconnected = match &mut self.controller {
+            Some(controller) => self.runtime.block_on(controller.disconnect()),
+            None => Ok(()),
+        };
+
+        stopped.and(disconnected)
+    }
+
+    /// Returns the controller, which must not be running in background. Errors of the background
+    /// run which stopped are returned first.
+    fn idle_controller(&mut self) -> Result<&mut crate::Controller> {
+        self.check()?;
+        if self.running.is_some() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "controller is running in background".into(),
+            ));
+        }
+
+        match &mut self.controller {
+            Some(controller) => Ok(controller),
+            None => Err(Error::new(
+                ErrorKind::Other,
+                "controller is lost in background".into(),
+            )),
+        }
+    }
+
+    /// Checks the background run, which returns its error if it stopped for an error.
+    fn check(&mut self) -> Result<()> {
+        let mut running = match self.running.take() {
+            Some(running) => running,
+            None => return Ok(()),
+        };
+        let stopped = match running.stopped.try_recv() {
+            Ok(stopped) => Ok(stopped),
+            Err(oneshot::error::TryRecvError::Empty) => {
+                self.running = Some(running);
+
+                return Ok(());
+            }
+            Err(oneshot::error::TryRecvError::Closed) => Err(()),
+        };
+
+        self.stopped(stopped).map(|_| ())
+    }
+
+    /// Waits until the background run stops, which takes the controller back.
+    fn join(&mut self, running: Running) -> Result<StopReason> {
+        let stopped = self.runtime.block_on(running.stopped).map_err(|_| ());
+
+        self.stopped(stopped)
+    }
+
+    fn stopped(
+        &mut self,
+        stopped: std::result::Result<(crate::Controller, Result<StopReason>), ()>,
+    ) -> Result<StopReason> {
+        match stopped {
+            Ok((controller, result)) => {
+                self.controller = Some(controller);
+
+                result
+            }
+            Err(_) => Err(Error::new(
+                ErrorKind::Other,
+                "background run panicked".into(),
+            )),
+        }
+    }
+
+    /// Cancels the background run and waits until it stops.
+    fn stop(&mut self, running: Running) -> Result<()> {
+        running.cancel_token.cancel();
+
+        self.join(running).map(|_| ())
+    }
+}
+
+impl Drop for Controller {
+    fn drop(&mut self) {
+        if let Some(running) = self.running.take() {
+            let _ = self.stop(running);
+        }
+        // The controller is dropped in the runtime
+        if let Some(controller) = self.controller.take() {
+            let _guard = self.runtime.enter();
+            drop(controller);
+        }
+        if let Some((sender, thread)) = self.shutdown.take() {
+            let _ = sender.send(());
+            let _ = thread.join();
+        }
+    }
+}
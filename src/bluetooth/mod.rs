@@ -1,11 +1,253 @@
 //! Support for handling Bluetooth devices.
 
-pub use bluer::l2cap::{SeqPacket, SeqPacketListener, SocketAddr};
+use crate::{CancelToken, ErrorKind};
+pub use bluer::agent::{Agent, AgentHandle};
+use bluer::agent::{AuthorizeService, ReqError, RequestAuthorization, RequestConfirmation};
+use bluer::l2cap::Security;
+pub use bluer::l2cap::{SecurityLevel, SeqPacket, SeqPacketListener, Socket, SocketAddr};
 use bluer::rfcomm::Role;
 pub use bluer::rfcomm::{Profile, ProfileHandle};
-pub use bluer::{Adapter, Address, AddressType, Error, Session, Uuid};
+pub use bluer::{Adapter, Address, AddressType, Session, Uuid};
+use bluer::{
+    AdapterEvent, AdapterProperty, DeviceEvent, DeviceProperty, InternalErrorKind, SessionEvent,
+};
+use futures::stream::{self, BoxStream};
+use futures::{future, FutureExt, StreamExt};
+use log::debug;
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
 use std::io;
+use std::net::Shutdown;
 use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+pub mod sdp;
+
+/// Default L2CAP MTU of HID channels, which real controllers negotiate.
+pub const HID_MTU: u16 = 672;
+
+/// Default L2CAP flush timeout of HID channels in 0.625 ms slots, which is the value advertised
+/// in the service record.
+pub const HID_FLUSH_TIMEOUT: u16 = 0x0c80;
+
+/// Default security level of HID channels, which requests authentication without requiring
+/// encryption, like the input plugin of BlueZ for HID devices. Higher levels may be rejected by
+/// the device, and the kernel default differs between versions.
+pub const HID_SECURITY_LEVEL: SecurityLevel = SecurityLevel::Low;
+
+const EHOSTDOWN: i32 = 112;
+const ENOBUFS: i32 = 105;
+
+/// Number of retries of reports failing to send transiently, and the backoff before the first
+/// retry, which doubles for each retry. Each attempt waits for the socket to be writable in the
+/// send timeout, so sending never holds the run loop for long.
+const SEND_RETRIES: u32 = 2;
+const SEND_BACKOFF: Duration = Duration::from_millis(1);
+const SEND_TIMEOUT: Duration = Duration::from_millis(4);
+
+/// Company identifiers of adapter manufacturers supporting address changes.
+const BROADCOM: u16 = 15;
+const CYPRESS: u16 = 305;
+const INTEL: u16 = 2;
+
+/// OCFs of the scan commands in the controller and baseband group.
+const READ_PAGE_SCAN_ACTIVITY: u16 = 0x001b;
+const WRITE_PAGE_SCAN_ACTIVITY: u16 = 0x001c;
+const READ_INQUIRY_SCAN_ACTIVITY: u16 = 0x001d;
+const WRITE_INQUIRY_SCAN_ACTIVITY: u16 = 0x001e;
+const READ_INQUIRY_SCAN_TYPE: u16 = 0x0042;
+const WRITE_INQUIRY_SCAN_TYPE: u16 = 0x0043;
+const READ_PAGE_SCAN_TYPE: u16 = 0x0046;
+const WRITE_PAGE_SCAN_TYPE: u16 = 0x0047;
+
+/// Names of D-Bus errors where bluetoothd or the D-Bus connection is gone.
+const STACK_LOST_ERRORS: [&str; 4] = [
+    "org.freedesktop.DBus.Error.ServiceUnknown",
+    "org.freedesktop.DBus.Error.NameHasNoOwner",
+    "org.freedesktop.DBus.Error.NoReply",
+    "org.freedesktop.DBus.Error.Disconnected",
+];
+
+/// Interval of polling the Bluetooth stack while it restarts.
+const REOPEN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// UUID of the HID service.
+const HID_SERVICE: &str = "00001124-0000-1000-8000-00805f9b34fb";
+
+/// Represents a Bluetooth error, which keeps the error reported by BlueZ and the failed
+/// operation.
+#[derive(Debug)]
+pub struct Error {
+    /// Represents the failed operation, like `cannot get name of device <ADDRESS>`, or empty if
+    /// unknown.
+    pub message: String,
+    /// Represents the error reported by BlueZ.
+    pub error: Box<bluer::Error>,
+}
+
+impl Error {
+    /// Creates a `Error` from the given error of BlueZ and the failed operation.
+    pub fn new(error: bluer::Error, message: String) -> Self {
+        Error {
+            message,
+            error: Box::new(error),
+        }
+    }
+
+    /// Returns the error kind reported by BlueZ.
+    pub fn kind(&self) -> &bluer::ErrorKind {
+        &self.error.kind
+    }
+
+    /// Returns if bluetoothd or the D-Bus connection to it is gone, like when bluetoothd or
+    /// D-Bus restarts, where the session must be reopened by `reopen`.
+    pub fn is_stack_lost(&self) -> bool {
+        match self.kind() {
+            bluer::ErrorKind::Internal(InternalErrorKind::DBusConnectionLost) => true,
+            bluer::ErrorKind::Internal(InternalErrorKind::DBus(name)) => {
+                STACK_LOST_ERRORS.contains(&name.as_str())
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<bluer::Error> for Error {
+    fn from(error: bluer::Error) -> Self {
+        Error::new(error, String::new())
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.message.is_empty() {
+            write!(f, "{}", self.error)
+        } else {
+            write!(f, "{}: {}", self.message, self.error)
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
+
+/// Returns if the input plugin of bluetoothd is loaded for the given adapter, where the plugin
+/// holds the HID PSMs to serve HID devices. The plugin is detected by the HID service in the
+/// UUIDs of the adapter, so it is only reliable while no controller registers its service
+/// record.
+pub async fn has_input_plugin(adapter: &Adapter) -> crate::Result<bool> {
+    let uuids = adapter.uuids().await.map_err(|e| {
+        crate::Error::from(Error::new(
+            e,
+            format!("cannot get services of adapter {}", adapter.name()),
+        ))
+    })?;
+    let service: Uuid = HID_SERVICE.parse().unwrap();
+
+    Ok(uuids.is_some_and(|uuids| uuids.contains(&service)))
+}
+
+/// Opens a new session to bluetoothd, and finds the adapter of the given address, or the adapter
+/// of the given name if no adapter has the address, like when the address is spoofed. The
+/// session and the adapter are polled until bluetoothd is back in the given timeout, like after
+/// bluetoothd restarts, and the error of the last attempt is returned on timeout.
+pub async fn reopen(
+    name: &str,
+    addr: Address,
+    timeout: Duration,
+) -> crate::Result<(Session, Adapter)> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let e = match find_adapter(name, addr).await {
+            Ok(opened) => return Ok(opened),
+            Err(e) => e,
+        };
+        if Instant::now() + REOPEN_POLL_INTERVAL > deadline {
+            return Err(e);
+        }
+        debug!("{}", e);
+        tokio::time::sleep(REOPEN_POLL_INTERVAL).await;
+    }
+}
+
+async fn find_adapter(name: &str, addr: Address) -> crate::Result<(Session, Adapter)> {
+    let error = |e, message: &str| crate::Error::from(Error::new(e, message.into()));
+
+    let session = Session::new()
+        .await
+        .map_err(|e| error(e, "cannot connect to bluetoothd"))?;
+    let names = session
+        .adapter_names()
+        .await
+        .map_err(|e| error(e, "cannot get adapters"))?;
+    let mut named = None;
+    for candidate in names {
+        let adapter = session
+            .adapter(&candidate)
+            .map_err(|e| error(e, "cannot get adapter"))?;
+        if adapter.address().await.ok() == Some(addr) {
+            return Ok((session, adapter));
+        }
+        if candidate == name {
+            named = Some(adapter);
+        }
+    }
+
+    match named {
+        Some(adapter) => Ok((session, adapter)),
+        None => Err(crate::Error::new(
+            ErrorKind::Unavailable,
+            format!("cannot find adapter {} or {}", addr, name),
+        )),
+    }
+}
+
+/// Interval of polling the power state of adapters.
+const POWER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Power-cycles the given adapter to recover it from wedged states, like when binding or setting
+/// the class keeps failing. The adapter is powered off and then on, where each power state must
+/// settle in the given timeout. Returns the time taken until the adapter is powered on again.
+/// Sockets on the adapter should be closed before, which would be left bound to a reset adapter.
+pub async fn power_cycle(adapter: &Adapter, timeout: Duration) -> crate::Result<Duration> {
+    let start = Instant::now();
+    for powered in [false, true] {
+        adapter.set_powered(powered).await.map_err(|e| {
+            crate::Error::from(Error::new(
+                e,
+                format!("cannot power adapter {} {}", adapter.name(), power(powered)),
+            ))
+        })?;
+        while !adapter.is_powered().await.is_ok_and(|p| p == powered) {
+            if start.elapsed() >= timeout {
+                return Err(crate::Error::new(
+                    ErrorKind::Timeout,
+                    format!(
+                        "adapter {} is not powered {} in {:?}",
+                        adapter.name(),
+                        power(powered),
+                        timeout
+                    ),
+                ));
+            }
+            tokio::time::sleep(POWER_POLL_INTERVAL).await;
+        }
+    }
+
+    Ok(start.elapsed())
+}
+
+fn power(powered: bool) -> &'static str {
+    if powered {
+        "on"
+    } else {
+        "off"
+    }
+}
 
 /// Trait for setting Bluetooth adapter's class.
 pub trait SetClass {
@@ -27,6 +269,487 @@ impl SetClass for Adapter {
     }
 }
 
+/// Trait for setting Bluetooth adapter's address.
+pub trait SetAddress {
+    /// Sets the address through the vendor-specific HCI command of the manufacturer of the
+    /// adapter, which is Broadcom, Cypress or Intel, and resets the adapter to apply it. The
+    /// address is read back to verify it. The address persists until the adapter is reset to
+    /// its original address or power cycled.
+    fn set_address(&self, addr: Address) -> crate::Result<()>;
+}
+
+impl SetAddress for Adapter {
+    fn set_address(&self, addr: Address) -> crate::Result<()> {
+        let name = self.name();
+
+        // Vendor-specific commands of write BD_ADDR in OGF 0x3f
+        let version = hci_tool("hciconfig", &[name, "version"])?;
+        let manufacturer = manufacturer(&version).map(|(_, id)| id);
+        let ocf = match manufacturer {
+            Some(BROADCOM | CYPRESS) => "0x0001",
+            Some(INTEL) => "0x0031",
+            _ => {
+                return Err(crate::Error::new(
+                    ErrorKind::Unavailable,
+                    format!(
+                        "cannot set address of adapter {} by unknown manufacturer {}, which \
+                         supports Broadcom ({}), Cypress ({}) and Intel ({}) only",
+                        name,
+                        manufacturer.map_or("?".into(), |id| id.to_string()),
+                        BROADCOM,
+                        CYPRESS,
+                        INTEL
+                    ),
+                ))
+            }
+        };
+
+        // The address is in little endian
+        let bytes: Vec<_> = addr
+            .0
+            .iter()
+            .rev()
+            .map(|b| format!("0x{:02x}", b))
+            .collect();
+        let mut args = vec!["-i", name, "cmd", "0x3f", ocf];
+        args.extend(bytes.iter().map(|b| b.as_str()));
+        hci_tool("hcitool", &args)?;
+        hci_tool("hciconfig", &[name, "reset"])?;
+
+        // Read back
+        let info = hci_tool("hciconfig", &[name])?;
+        let current = info
+            .split_whitespace()
+            .skip_while(|word| *word != "Address:")
+            .nth(1)
+            .and_then(|addr| addr.parse::<Address>().ok());
+        if current != Some(addr) {
+            return Err(crate::Error::new(
+                ErrorKind::Other,
+                format!(
+                    "cannot set address of adapter {} to {}, which reads back as {}",
+                    name,
+                    addr,
+                    current.map_or("?".into(), |addr| addr.to_string())
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Represents the page scan and inquiry scan parameters of an adapter, where intervals and windows
+/// are in 0.625 ms slots.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct ScanParameters {
+    /// Represents the page scan interval.
+    pub page_interval: u16,
+    /// Represents the page scan window.
+    pub page_window: u16,
+    /// Represents if the page scan is interlaced.
+    pub page_interlaced: bool,
+    /// Represents the inquiry scan interval.
+    pub inquiry_interval: u16,
+    /// Represents the inquiry scan window.
+    pub inquiry_window: u16,
+    /// Represents if the inquiry scan is interlaced.
+    pub inquiry_interlaced: bool,
+}
+
+impl ScanParameters {
+    /// Represents the aggressive parameters for pairing, which scan for 11.25 ms every 40 ms
+    /// interlaced. Adapters scan for 11.25 ms every 1.28 s by default, where a device may wait
+    /// for several intervals until it finds the adapter. The shorter interval bounds the wait
+    /// to a fraction of it at the cost of power, which matters only while pairing.
+    pub const FAST: ScanParameters = ScanParameters {
+        page_interval: 0x0040,
+        page_window: 0x0012,
+        page_interlaced: true,
+        inquiry_interval: 0x0040,
+        inquiry_window: 0x0012,
+        inquiry_interlaced: true,
+    };
+}
+
+impl Display for ScanParameters {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let scan_type = |interlaced| {
+            if interlaced {
+                "interlaced"
+            } else {
+                "standard"
+            }
+        };
+
+        write!(
+            f,
+            "page scan {}/{} ({}), inquiry scan {}/{} ({})",
+            self.page_window,
+            self.page_interval,
+            scan_type(self.page_interlaced),
+            self.inquiry_window,
+            self.inquiry_interval,
+            scan_type(self.inquiry_interlaced)
+        )
+    }
+}
+
+/// Trait for setting Bluetooth adapter's page scan and inquiry scan parameters.
+pub trait SetScanParameters {
+    /// Returns the scan parameters.
+    fn scan_parameters(&self) -> crate::Result<ScanParameters>;
+
+    /// Sets the scan parameters.
+    fn set_scan_parameters(&self, parameters: &ScanParameters) -> crate::Result<()>;
+}
+
+impl SetScanParameters for Adapter {
+    fn scan_parameters(&self) -> crate::Result<ScanParameters> {
+        let page = hci_command(self.name(), READ_PAGE_SCAN_ACTIVITY, &[])?;
+        let page_type = hci_command(self.name(), READ_PAGE_SCAN_TYPE, &[])?;
+        let inquiry = hci_command(self.name(), READ_INQUIRY_SCAN_ACTIVITY, &[])?;
+        let inquiry_type = hci_command(self.name(), READ_INQUIRY_SCAN_TYPE, &[])?;
+        if page.len() < 4 || page_type.is_empty() || inquiry.len() < 4 || inquiry_type.is_empty() {
+            return Err(crate::Error::new(
+                ErrorKind::Other,
+                format!("cannot read scan parameters of adapter {}", self.name()),
+            ));
+        }
+
+        Ok(ScanParameters {
+            page_interval: u16::from_le_bytes([page[0], page[1]]),
+            page_window: u16::from_le_bytes([page[2], page[3]]),
+            page_interlaced: page_type[0] == 1,
+            inquiry_interval: u16::from_le_bytes([inquiry[0], inquiry[1]]),
+            inquiry_window: u16::from_le_bytes([inquiry[2], inquiry[3]]),
+            inquiry_interlaced: inquiry_type[0] == 1,
+        })
+    }
+
+    fn set_scan_parameters(&self, parameters: &ScanParameters) -> crate::Result<()> {
+        let activity = |interval: u16, window: u16| {
+            let mut params = interval.to_le_bytes().to_vec();
+            params.extend(window.to_le_bytes());
+
+            params
+        };
+
+        hci_command(
+            self.name(),
+            WRITE_PAGE_SCAN_ACTIVITY,
+            &activity(parameters.page_interval, parameters.page_window),
+        )?;
+        hci_command(
+            self.name(),
+            WRITE_PAGE_SCAN_TYPE,
+            &[parameters.page_interlaced as u8],
+        )?;
+        hci_command(
+            self.name(),
+            WRITE_INQUIRY_SCAN_ACTIVITY,
+            &activity(parameters.inquiry_interval, parameters.inquiry_window),
+        )?;
+        hci_command(
+            self.name(),
+            WRITE_INQUIRY_SCAN_TYPE,
+            &[parameters.inquiry_interlaced as u8],
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Sends the given HCI command of the controller and baseband group to the given adapter, and
+/// returns the return parameters after the status.
+fn hci_command(adapter: &str, ocf: u16, params: &[u8]) -> crate::Result<Vec<u8>> {
+    let ocf = format!("0x{:04x}", ocf);
+    let params: Vec<_> = params.iter().map(|b| format!("0x{:02x}", b)).collect();
+    let mut args = vec!["-i", adapter, "cmd", "0x03", &ocf];
+    args.extend(params.iter().map(|b| b.as_str()));
+    let output = hci_tool("hcitool", &args)?;
+
+    // The command complete event has the number of packets, the opcode and the status before the
+    // return parameters
+    let event: Vec<_> = output
+        .lines()
+        .skip_while(|line| !line.starts_with("> HCI Event"))
+        .skip(1)
+        .flat_map(|line| line.split_whitespace())
+        .filter_map(|b| u8::from_str_radix(b, 16).ok())
+        .collect();
+    match event.get(3) {
+        Some(0) => Ok(event[4..].to_vec()),
+        status => Err(crate::Error::new(
+            ErrorKind::Other,
+            format!(
+                "HCI command {} of adapter {} failed with status {}",
+                ocf,
+                adapter,
+                status.map_or("?".into(), |status| format!("0x{:02x}", status))
+            ),
+        )),
+    }
+}
+
+/// Runs the given tool of BlueZ with the given arguments, and returns its output.
+fn hci_tool(tool: &str, args: &[&str]) -> crate::Result<String> {
+    let output = Command::new(tool).args(args).output().map_err(|e| {
+        crate::Error::new(
+            ErrorKind::Io(e),
+            format!("cannot run {}, is BlueZ installed?", tool),
+        )
+    })?;
+    if !output.status.success() {
+        return Err(crate::Error::new(
+            ErrorKind::Other,
+            format!(
+                "{} {} failed: {}",
+                tool,
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into())
+}
+
+/// Trait for reading Bluetooth adapter's local name, which is the name advertised to other
+/// devices rather than the alias cached by BlueZ.
+pub trait LocalName {
+    /// Returns the local name.
+    fn local_name(&self) -> crate::Result<String>;
+}
+
+impl LocalName for Adapter {
+    fn local_name(&self) -> crate::Result<String> {
+        let output = hci_tool("hciconfig", &[self.name(), "name"])?;
+
+        output
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("Name:"))
+            .map(|name| name.trim().trim_matches('\'').to_string())
+            .ok_or_else(|| {
+                crate::Error::new(
+                    ErrorKind::Other,
+                    format!("cannot read local name of adapter {}", self.name()),
+                )
+            })
+    }
+}
+
+/// Trait for probing Bluetooth adapter's support of BR/EDR, which is the classic Bluetooth
+/// required by HID over L2CAP.
+pub trait BrEdr {
+    /// Returns if BR/EDR is supported, which is not by LE-only adapters.
+    fn supports_br_edr(&self) -> crate::Result<bool>;
+}
+
+impl BrEdr for Adapter {
+    fn supports_br_edr(&self) -> crate::Result<bool> {
+        let output = hci_tool("hciconfig", &[self.name(), "features"])?;
+        let features: Vec<_> = output
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("Features:"))
+            .map(|features| {
+                features
+                    .split_whitespace()
+                    .filter_map(|b| u8::from_str_radix(b.trim_start_matches("0x"), 16).ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // Bit 5 of byte 4 of LMP features is BR/EDR not supported
+        match features.get(4) {
+            Some(feature) => Ok(feature & 0x20 == 0),
+            None => Err(crate::Error::new(
+                ErrorKind::Other,
+                format!("cannot read features of adapter {}", self.name()),
+            )),
+        }
+    }
+}
+
+/// Represents the hardware information of a Bluetooth adapter, which tells the chipset and
+/// firmware in diagnostics. Unknown fields are `None`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct HardwareInfo {
+    /// Represents the company identifier of the manufacturer.
+    pub manufacturer: Option<u16>,
+    /// Represents the name of the manufacturer.
+    pub manufacturer_name: Option<String>,
+    /// Represents the HCI version, like 6 for Bluetooth 4.0.
+    pub hci_version: Option<u8>,
+    /// Represents the HCI revision.
+    pub hci_revision: Option<u16>,
+    /// Represents the LMP version, like 6 for Bluetooth 4.0.
+    pub lmp_version: Option<u8>,
+    /// Represents the LMP subversion, which is usually the firmware build.
+    pub lmp_subversion: Option<u16>,
+    /// Represents the device ID of the adapter in modalias format, like
+    /// `usb:v1D6Bp0246d0537`.
+    pub modalias: Option<String>,
+}
+
+impl Display for HardwareInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let version = |version: Option<u8>, revision: Option<u16>, label| match version {
+            Some(version) => format!(
+                "{} (0x{:x}) {} {}",
+                core_version(version).unwrap_or("?"),
+                version,
+                label,
+                revision.map_or("?".into(), |revision| format!("0x{:04x}", revision))
+            ),
+            None => "?".into(),
+        };
+        let manufacturer = match (&self.manufacturer_name, self.manufacturer) {
+            (Some(name), Some(id)) => format!("{} ({})", name, id),
+            (Some(name), None) => name.clone(),
+            (None, Some(id)) => id.to_string(),
+            (None, None) => "?".into(),
+        };
+
+        write!(
+            f,
+            "manufacturer {}, HCI {}, LMP {}, modalias {}",
+            manufacturer,
+            version(self.hci_version, self.hci_revision, "revision"),
+            version(self.lmp_version, self.lmp_subversion, "subversion"),
+            self.modalias.as_deref().unwrap_or("?")
+        )
+    }
+}
+
+/// Returns the hardware information of the given adapter. The versions and the manufacturer are
+/// read from the adapter by `hciconfig`, and the modalias from BlueZ. Fields which cannot be read,
+/// like on adapters missing from `hciconfig`, are left unknown rather than failing.
+pub async fn hardware_info(adapter: &Adapter) -> HardwareInfo {
+    let name = adapter.name().to_string();
+    let version = match crate::unblock(move || hci_tool("hciconfig", &[&name, "version"])).await {
+        Ok(version) => version,
+        Err(e) => {
+            debug!("{}", e);
+            String::new()
+        }
+    };
+    let (manufacturer_name, manufacturer) = match manufacturer(&version) {
+        Some((name, id)) => (name, Some(id)),
+        None => (None, None),
+    };
+    let version_of = |key| {
+        hci_field(&version, key)
+            .and_then(|field| field.rsplit_once("(0x"))
+            .and_then(|(_, version)| u8::from_str_radix(version.trim_end_matches(')'), 16).ok())
+    };
+    let hex_of = |key| {
+        hci_field(&version, key)
+            .and_then(|field| u16::from_str_radix(field.trim_start_matches("0x"), 16).ok())
+    };
+    let modalias = match adapter.modalias().await {
+        Ok(modalias) => modalias.map(|modalias| {
+            format!(
+                "{}:v{:04X}p{:04X}d{:04X}",
+                modalias.source, modalias.vendor, modalias.product, modalias.device
+            )
+        }),
+        Err(e) => {
+            debug!("cannot get modalias of adapter {}: {}", adapter.name(), e);
+            None
+        }
+    };
+
+    HardwareInfo {
+        manufacturer,
+        manufacturer_name,
+        hci_version: version_of("HCI Version:"),
+        hci_revision: hex_of("Revision:"),
+        lmp_version: version_of("LMP Version:"),
+        lmp_subversion: hex_of("Subversion:"),
+        modalias,
+    }
+}
+
+/// Returns the name and the company identifier of the manufacturer in the given output of
+/// `hciconfig version`, like `Manufacturer: Broadcom Corporation (15)`.
+fn manufacturer(version: &str) -> Option<(Option<String>, u16)> {
+    let (name, id) = hci_field(version, "Manufacturer:")?
+        .trim_end_matches(')')
+        .rsplit_once('(')?;
+    let name = Some(name.trim().to_string()).filter(|name| !name.is_empty());
+
+    Some((name, id.parse().ok()?))
+}
+
+/// Returns the value of the given key in the given output of `hciconfig`, where fields in a line
+/// are separated by two spaces or tabs.
+fn hci_field<'a>(output: &'a str, key: &str) -> Option<&'a str> {
+    output.lines().find_map(|line| {
+        let (_, value) = line.split_once(key)?;
+        let value = value.trim_start();
+        let end = value.find("  ").or_else(|| value.find('\t'));
+
+        Some(value[..end.unwrap_or(value.len())].trim_end())
+    })
+}
+
+/// Returns the Bluetooth Core Specification version of the given HCI or LMP version.
+fn core_version(version: u8) -> Option<&'static str> {
+    const VERSIONS: [&str; 15] = [
+        "1.0b", "1.1", "1.2", "2.0", "2.1", "3.0", "4.0", "4.1", "4.2", "5.0", "5.1", "5.2", "5.3",
+        "5.4", "6.0",
+    ];
+
+    VERSIONS.get(version as usize).copied()
+}
+
+/// Trait for setting the send MTU of L2CAP sockets. The receive MTU is set by `set_recv_mtu` of
+/// the socket.
+pub trait SetSendMtu {
+    /// Sets the send MTU.
+    fn set_send_mtu(&self, mtu: u16) -> io::Result<()>;
+}
+
+impl<T> SetSendMtu for Socket<T> {
+    fn set_send_mtu(&self, mtu: u16) -> io::Result<()> {
+        let mut opts = self.l2cap_opts()?;
+        opts.omtu = mtu;
+
+        self.set_l2cap_opts(&opts)
+    }
+}
+
+/// Trait for setting the flush timeout of L2CAP sockets, after which unacknowledged packets are
+/// dropped rather than retransmitted.
+pub trait SetFlushTimeout {
+    /// Sets the flush timeout.
+    fn set_flush_timeout(&self, timeout: u16) -> io::Result<()>;
+}
+
+impl<T> SetFlushTimeout for Socket<T> {
+    fn set_flush_timeout(&self, timeout: u16) -> io::Result<()> {
+        let mut opts = self.l2cap_opts()?;
+        opts.flush_to = timeout;
+
+        self.set_l2cap_opts(&opts)
+    }
+}
+
+/// Trait for setting the security level of L2CAP sockets, which is inherited by channels
+/// accepted from listening sockets.
+pub trait SetSecurityLevel {
+    /// Sets the security level.
+    fn set_security_level(&self, level: SecurityLevel) -> io::Result<()>;
+}
+
+impl<T> SetSecurityLevel for Socket<T> {
+    fn set_security_level(&self, level: SecurityLevel) -> io::Result<()> {
+        // The key size is not used by L2CAP sockets
+        self.set_security(Security { level, key_size: 0 })
+    }
+}
+
 /// Trait for Bluetooth service record.
 pub trait ServiceRecord {
     /// Creates a `Profile` which represents a service record.
@@ -52,3 +775,976 @@ impl ServiceRecord for Profile {
         }
     }
 }
+
+/// Trait for the Bluetooth agent answering pairing requests without a user.
+pub trait PairingAgent {
+    /// Creates an `Agent` which accepts pairing requests and the authorization of the given
+    /// service from devices of the given name known by the given adapter, and rejects the others.
+    /// Devices whose name is not resolved yet are accepted.
+    ///
+    /// The agent answers like a NoInputNoOutput agent, where just-works pairing is confirmed
+    /// without a user. The capability registered is derived from the answered requests though.
+    fn new_pairing_agent(adapter: Adapter, service: Uuid, name: &str) -> Agent;
+}
+
+impl PairingAgent for Agent {
+    fn new_pairing_agent(adapter: Adapter, service: Uuid, name: &str) -> Self {
+        // Every request checks the name of the device in the same way
+        let name = name.to_string();
+        let accept = move |device: Address| {
+            let adapter = adapter.clone();
+            let name = name.clone();
+            async move {
+                let device_name = match adapter.device(device) {
+                    Ok(device) => device.name().await.unwrap_or_default(),
+                    Err(_) => None,
+                };
+                match device_name {
+                    Some(device_name) if device_name != name => Err(ReqError::Rejected),
+                    _ => Ok(()),
+                }
+            }
+        };
+        let (confirm, authorize) = (accept.clone(), accept.clone());
+
+        Agent {
+            request_confirmation: Some(Box::new(move |request: RequestConfirmation| {
+                Box::pin(confirm(request.device))
+            })),
+            request_authorization: Some(Box::new(move |request: RequestAuthorization| {
+                Box::pin(authorize(request.device))
+            })),
+            authorize_service: Some(Box::new(move |request: AuthorizeService| {
+                let accepting = accept(request.device);
+                Box::pin(async move {
+                    if request.service != service {
+                        return Err(ReqError::Rejected);
+                    }
+
+                    accepting.await
+                })
+            })),
+            ..Default::default()
+        }
+    }
+}
+
+/// Enumeration of outcomes of receiving a report before a deadline.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Received {
+    /// Represents a report of the length is received.
+    Report(usize),
+    /// Represents no report is ready, which is returned for deadlines already passed.
+    WouldBlock,
+    /// Represents no report is received before the deadline.
+    TimedOut,
+}
+
+/// Enumeration of outcomes of sending a report.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Sent {
+    /// Represents the report is sent as a whole.
+    Report,
+    /// Represents the report is dropped since it kept failing transiently, like on a congested
+    /// link.
+    Dropped,
+}
+
+/// Represents an L2CAP channel of HID reports to a peer device.
+///
+/// The socket is non-blocking, as sockets created and accepted by BlueZ are, so receiving waits
+/// on the runtime rather than on the thread.
+#[derive(Debug)]
+pub struct HidChannel {
+    seq_packet: SeqPacket,
+    peer: Address,
+    // The send MTU is cached once negotiated, where 0 means unknown
+    send_mtu: AtomicUsize,
+}
+
+impl HidChannel {
+    /// Creates a `HidChannel` with the given connected socket and the address of the peer device.
+    pub fn new(seq_packet: SeqPacket, peer: Address) -> Self {
+        HidChannel {
+            seq_packet,
+            peer,
+            send_mtu: AtomicUsize::new(0),
+        }
+    }
+
+    /// Connects to the given PSM of the peer device from the given local adapter address in the
+    /// given timeout. Errors distinguish a page timeout, where the device is asleep or away, with
+    /// `ErrorKind::Timeout`, from a refused connection, where the device does not know the
+    /// adapter, and a permission problem, which keep the IO error of `ErrorKind::Io`.
+    pub async fn connect(
+        local: Address,
+        peer: Address,
+        psm: u16,
+        timeout: Duration,
+    ) -> crate::Result<Self> {
+        let socket = Socket::new_seq_packet()?;
+        socket.bind(SocketAddr::new(local, AddressType::BrEdr, 0))?;
+
+        HidChannel::connect_socket(socket, peer, psm, timeout).await
+    }
+
+    /// Connects the given socket to the given PSM of the peer device in the given timeout. The
+    /// socket must be bound to the local adapter address, and options like the MTU are set
+    /// before connecting. Errors are distinguished like `connect`.
+    pub async fn connect_socket(
+        socket: Socket<SeqPacket>,
+        peer: Address,
+        psm: u16,
+        timeout: Duration,
+    ) -> crate::Result<Self> {
+        let page_timeout = || {
+            crate::Error::new(
+                ErrorKind::Timeout,
+                format!(
+                    "cannot connect to device {} PSM {}: page timeout, is the device nearby and awake?",
+                    peer, psm
+                ),
+            )
+        };
+        let connecting = socket.connect(SocketAddr::new(peer, AddressType::BrEdr, psm));
+        let e = match tokio::time::timeout(timeout, connecting).await {
+            Ok(Ok(seq_packet)) => return Ok(HidChannel::new(seq_packet, peer)),
+            Ok(Err(e)) => e,
+            Err(_) => return Err(page_timeout()),
+        };
+        let reason = match e.kind() {
+            // EHOSTDOWN is returned on page timeout
+            io::ErrorKind::TimedOut => return Err(page_timeout()),
+            _ if e.raw_os_error() == Some(EHOSTDOWN) => return Err(page_timeout()),
+            io::ErrorKind::ConnectionRefused => {
+                "connection refused, does the device know the adapter?"
+            }
+            io::ErrorKind::PermissionDenied => {
+                "permission denied, is the link key valid and the security level accepted?"
+            }
+            _ => "connection failed",
+        };
+        let message = format!("cannot connect to device {} PSM {}: {}", peer, psm, reason);
+
+        Err(crate::Error::new(ErrorKind::Io(e), message))
+    }
+
+    /// Returns the address of the peer device.
+    pub fn peer(&self) -> Address {
+        self.peer
+    }
+
+    /// Returns the negotiated MTU for sending, which may not be available right after the
+    /// channel is established.
+    pub fn send_mtu(&self) -> crate::Result<usize> {
+        let mtu = self.send_mtu.load(Ordering::Relaxed);
+        if mtu != 0 {
+            return Ok(mtu);
+        }
+        let mtu = self
+            .seq_packet
+            .send_mtu()
+            .map_err(|e| self.error(e, "cannot get send MTU of channel to"))?;
+        self.send_mtu.store(mtu, Ordering::Relaxed);
+
+        Ok(mtu)
+    }
+
+    /// Returns the negotiated MTU for receiving.
+    pub fn recv_mtu(&self) -> crate::Result<usize> {
+        self.seq_packet
+            .recv_mtu()
+            .map_err(|e| self.error(e, "cannot get receive MTU of channel to"))
+    }
+
+    /// Returns the flush timeout in 0.625 ms slots.
+    pub fn flush_timeout(&self) -> crate::Result<u16> {
+        self.seq_packet
+            .as_ref()
+            .l2cap_opts()
+            .map(|opts| opts.flush_to)
+            .map_err(|e| self.error(e, "cannot get flush timeout of channel to"))
+    }
+
+    /// Returns the security level in effect.
+    pub fn security_level(&self) -> crate::Result<SecurityLevel> {
+        self.seq_packet
+            .as_ref()
+            .security()
+            .map(|security| security.level)
+            .map_err(|e| self.error(e, "cannot get security level of channel to"))
+    }
+
+    /// Sends the given report, which must be written as a whole. Reports larger than the send
+    /// MTU are rejected rather than truncated. Transient failures, like a full send buffer, are
+    /// retried with a short backoff, and the report is dropped with `Sent::Dropped` once the
+    /// retries are used up, so a congested link delays the following reports only a little.
+    /// Returns `ErrorKind::PeerClosed` if the peer device has closed the channel.
+    pub async fn send_report(&self, frame: &[u8]) -> crate::Result<Sent> {
+        if let Ok(mtu) = self.send_mtu() {
+            if frame.len() > mtu {
+                return Err(crate::Error::new(
+                    ErrorKind::Other,
+                    format!(
+                        "report of {} bytes exceeds send MTU {} of channel to {}",
+                        frame.len(),
+                        mtu,
+                        self.peer
+                    ),
+                ));
+            }
+        }
+        let size = match send_with_retries(|| self.seq_packet.send(frame)).await {
+            Ok(Some(size)) => size,
+            Ok(None) => return Ok(Sent::Dropped),
+            Err(e) => return Err(self.error(e, "cannot send to")),
+        };
+        // A sequential packet socket never splits a report, so a partial send means the stream
+        // is broken
+        if size != frame.len() {
+            return Err(crate::Error::new(
+                ErrorKind::Other,
+                format!("sent {} of {} bytes to {}", size, frame.len(), self.peer),
+            ));
+        }
+
+        Ok(Sent::Report)
+    }
+
+    /// Receives a report into the given buffer, and returns its length. Returns
+    /// `ErrorKind::PeerClosed` if the peer device has closed the channel.
+    ///
+    /// Receiving is cancel safe, so it can be raced in `tokio::select!`. A sequential packet
+    /// socket hands over a whole report in a single read, so a dropped receive loses no part of
+    /// a report, which is received by the next one instead.
+    pub async fn recv_report(&self, buf: &mut [u8]) -> crate::Result<usize> {
+        match self.seq_packet.recv(buf).await {
+            Ok(0) => Err(crate::Error::new(
+                ErrorKind::PeerClosed,
+                format!("{} closed the channel", self.peer),
+            )),
+            Ok(size) => Ok(size),
+            Err(e) => Err(self.error(e, "cannot receive from")),
+        }
+    }
+
+    /// Receives a report into the given buffer until the given deadline. Returns
+    /// `Received::WouldBlock` if the deadline has passed and no report is ready, or
+    /// `Received::TimedOut` if no report is received before the deadline. Timeouts keep the
+    /// channel open, and receiving is cancel safe like `recv_report`.
+    pub async fn recv_with_deadline(
+        &self,
+        buf: &mut [u8],
+        deadline: Instant,
+    ) -> crate::Result<Received> {
+        recv_until(self.recv_report(buf), deadline).await
+    }
+
+    /// Shuts down both directions of the channel.
+    pub fn shutdown(&self) -> crate::Result<()> {
+        self.seq_packet
+            .shutdown(Shutdown::Both)
+            .map_err(|e| self.error(e, "cannot shut down channel to"))
+    }
+
+    /// Returns the socket.
+    pub fn into_inner(self) -> SeqPacket {
+        self.seq_packet
+    }
+
+    fn error(&self, error: io::Error, action: &str) -> crate::Error {
+        // Errors of a closed connection are not distinguished from closing by the peer, and the
+        // link is refused if the security requirements of both ends do not match
+        let (kind, hint) = match error.kind() {
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe => (ErrorKind::PeerClosed, ""),
+            io::ErrorKind::PermissionDenied => (
+                ErrorKind::Io(error),
+                ": permission denied, is the security level accepted by the device?",
+            ),
+            _ => (ErrorKind::Io(error), ""),
+        };
+
+        crate::Error::new(kind, format!("{} {}{}", action, self.peer, hint))
+    }
+}
+
+/// Represents an L2CAP listener of HID channels on a PSM of the local adapter.
+#[derive(Debug)]
+pub struct HidListener {
+    listener: SeqPacketListener,
+    local_addr: SocketAddr,
+}
+
+impl HidListener {
+    /// Creates a `HidListener` with the given listening socket and the local address it is
+    /// bound to.
+    pub fn new(listener: SeqPacketListener, local_addr: SocketAddr) -> Self {
+        HidListener {
+            listener,
+            local_addr,
+        }
+    }
+
+    /// Returns the local address, which is the address of the adapter and the PSM.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Accepts a channel until the given cancel token is cancelled, where
+    /// `ErrorKind::Cancelled` is returned.
+    pub async fn accept(
+        &self,
+        cancel_token: &CancelToken,
+    ) -> crate::Result<(HidChannel, SocketAddr)> {
+        accept_until_cancelled(self.accept_channel(), cancel_token, self.local_addr).await
+    }
+
+    /// Accepts a channel in the given timeout. Returns `None` if no channel is accepted in time.
+    pub async fn accept_with_timeout(
+        &self,
+        timeout: Duration,
+    ) -> crate::Result<Option<(HidChannel, SocketAddr)>> {
+        accept_in_timeout(self.accept_channel(), timeout).await
+    }
+
+    /// Returns the listening socket.
+    pub fn into_inner(self) -> SeqPacketListener {
+        self.listener
+    }
+
+    async fn accept_channel(&self) -> crate::Result<(HidChannel, SocketAddr)> {
+        match self.listener.accept().await {
+            Ok((seq_packet, addr)) => Ok((HidChannel::new(seq_packet, addr.addr), addr)),
+            Err(e) => Err(crate::Error::new(
+                ErrorKind::Io(e),
+                format!(
+                    "cannot accept on PSM {} of adapter {}",
+                    self.local_addr.psm, self.local_addr.addr
+                ),
+            )),
+        }
+    }
+}
+
+/// Sends with the given send, where transient failures and sends which do not finish in time are
+/// retried with a short backoff. Returns the sent size, or `None` if the retries are used up.
+async fn send_with_retries<F, Fut>(mut send: F) -> io::Result<Option<usize>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<usize>>,
+{
+    let transient =
+        |e: &io::Error| e.kind() == io::ErrorKind::WouldBlock || e.raw_os_error() == Some(ENOBUFS);
+    let mut backoff = SEND_BACKOFF;
+    for attempt in 0..=SEND_RETRIES {
+        if attempt > 0 {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        match tokio::time::timeout(SEND_TIMEOUT, send()).await {
+            Ok(Ok(size)) => return Ok(Some(size)),
+            Ok(Err(e)) if transient(&e) => continue,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => continue,
+        }
+    }
+
+    Ok(None)
+}
+
+/// Receives a report with the given future until the given deadline, like
+/// `HidChannel::recv_with_deadline`.
+async fn recv_until(
+    receiving: impl Future<Output = crate::Result<usize>>,
+    deadline: Instant,
+) -> crate::Result<Received> {
+    if deadline <= Instant::now() {
+        return match receiving.now_or_never() {
+            Some(size) => Ok(Received::Report(size?)),
+            None => Ok(Received::WouldBlock),
+        };
+    }
+
+    match tokio::time::timeout_at(deadline.into(), receiving).await {
+        Ok(size) => Ok(Received::Report(size?)),
+        Err(_) => Ok(Received::TimedOut),
+    }
+}
+
+/// Accepts with the given future until the given cancel token is cancelled, where
+/// `ErrorKind::Cancelled` is returned with the given local address.
+async fn accept_until_cancelled<T>(
+    accepting: impl Future<Output = crate::Result<T>>,
+    cancel_token: &CancelToken,
+    local_addr: SocketAddr,
+) -> crate::Result<T> {
+    tokio::select! {
+        accepted = accepting => accepted,
+        _ = cancel_token.cancelled() => Err(crate::Error::new(
+            ErrorKind::Cancelled,
+            format!(
+                "accept on PSM {} of adapter {} cancelled",
+                local_addr.psm, local_addr.addr
+            ),
+        )),
+    }
+}
+
+/// Accepts with the given future in the given timeout. Returns `None` if nothing is accepted in
+/// time.
+async fn accept_in_timeout<T>(
+    accepting: impl Future<Output = crate::Result<T>>,
+    timeout: Duration,
+) -> crate::Result<Option<T>> {
+    match tokio::time::timeout(timeout, accepting).await {
+        Ok(accepted) => accepted.map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Represents a remote Bluetooth device known by an adapter.
+#[derive(Debug, Clone)]
+pub struct Device {
+    adapter: Adapter,
+    device: bluer::Device,
+}
+
+impl Device {
+    /// Creates a `Device` with the given address known by the given adapter.
+    pub fn new(adapter: &Adapter, addr: Address) -> crate::Result<Self> {
+        let device = adapter
+            .device(addr)
+            .map_err(|e| Device::error_of(addr, e, "cannot get"))?;
+
+        Ok(Device {
+            adapter: adapter.clone(),
+            device,
+        })
+    }
+
+    /// Returns all devices known by the given adapter.
+    pub async fn all(adapter: &Adapter) -> crate::Result<Vec<Self>> {
+        let addrs = adapter.device_addresses().await.map_err(|e| {
+            crate::Error::from(Error::new(
+                e,
+                format!("cannot get devices of adapter {}", adapter.name()),
+            ))
+        })?;
+
+        addrs
+            .into_iter()
+            .map(|addr| Device::new(adapter, addr))
+            .collect()
+    }
+
+    /// Returns the address.
+    pub fn address(&self) -> Address {
+        self.device.address()
+    }
+
+    /// Returns the name, or `None` if the name is not known.
+    pub async fn name(&self) -> crate::Result<Option<String>> {
+        self.device
+            .name()
+            .await
+            .map_err(|e| self.error(e, "cannot get name of"))
+    }
+
+    /// Returns the alias, which is the name if the alias is not set.
+    pub async fn alias(&self) -> crate::Result<String> {
+        self.device
+            .alias()
+            .await
+            .map_err(|e| self.error(e, "cannot get alias of"))
+    }
+
+    /// Returns if the device is paired.
+    pub async fn is_paired(&self) -> crate::Result<bool> {
+        self.device
+            .is_paired()
+            .await
+            .map_err(|e| self.error(e, "cannot get pairing of"))
+    }
+
+    /// Returns if the device is trusted.
+    pub async fn is_trusted(&self) -> crate::Result<bool> {
+        self.device
+            .is_trusted()
+            .await
+            .map_err(|e| self.error(e, "cannot get trust of"))
+    }
+
+    /// Returns if the device is connected.
+    pub async fn is_connected(&self) -> crate::Result<bool> {
+        self.device
+            .is_connected()
+            .await
+            .map_err(|e| self.error(e, "cannot get connection of"))
+    }
+
+    /// Returns the RSSI of the connection in dBm, or `None` if the adapter does not support
+    /// reading it. The RSSI is read by the HCI Read RSSI command for the connection, or the
+    /// property of BlueZ where available, which is only known while discovering.
+    pub async fn rssi(&self) -> crate::Result<Option<i16>> {
+        let rssi = self
+            .hci_query("rssi", "RSSI return value:")
+            .await
+            .and_then(|rssi| rssi.parse().ok());
+        if rssi.is_some() {
+            return Ok(rssi);
+        }
+
+        self.device
+            .rssi()
+            .await
+            .map_err(|e| self.error(e, "cannot get RSSI of"))
+    }
+
+    /// Returns the link quality of the connection in 0 to 255, or `None` if the adapter does not
+    /// support reading it. The link quality is read by the HCI Read Link Quality command for the
+    /// connection.
+    pub async fn link_quality(&self) -> Option<u8> {
+        self.hci_query("lq", "Link quality:")
+            .await
+            .and_then(|quality| quality.parse().ok())
+    }
+
+    /// Queries the connection of the device with the given command of hcitool, and returns the
+    /// value after the given prefix. Failures of unsupported adapters return `None`.
+    async fn hci_query(&self, command: &'static str, prefix: &'static str) -> Option<String> {
+        let adapter = self.adapter.name().to_string();
+        let addr = self.address().to_string();
+        let output = crate::unblock(move || hci_tool("hcitool", &["-i", &adapter, command, &addr]))
+            .await
+            .ok()?;
+
+        output
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(prefix))
+            .map(|value| value.trim().to_string())
+    }
+
+    /// Sets if the device is trusted.
+    pub async fn set_trusted(&self, trusted: bool) -> crate::Result<()> {
+        self.device
+            .set_trusted(trusted)
+            .await
+            .map_err(|e| self.error(e, "cannot set trust of"))
+    }
+
+    /// Removes the device from the adapter, which also removes its pairing.
+    pub async fn remove(self) -> crate::Result<()> {
+        let addr = self.address();
+
+        self.adapter
+            .remove_device(addr)
+            .await
+            .map_err(|e| Device::error_of(addr, e, "cannot remove"))
+    }
+
+    fn error(&self, error: bluer::Error, action: &str) -> crate::Error {
+        Device::error_of(self.address(), error, action)
+    }
+
+    fn error_of(addr: Address, error: bluer::Error, action: &str) -> crate::Error {
+        crate::Error::from(Error::new(error, format!("{} device {}", action, addr)))
+    }
+}
+
+/// Names of processes known to run discovery on adapters, as in `/proc/<pid>/comm`.
+const DISCOVERY_CLIENTS: [&str; 5] = [
+    "bluetoothctl",
+    "blueman-manager",
+    "blueman-applet",
+    "gnome-control-c",
+    "bluedevil-wizar",
+];
+
+/// Returns the names of running processes known to run discovery on adapters.
+pub fn discovery_clients() -> Vec<String> {
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    let mut clients: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| std::fs::read_to_string(entry.path().join("comm")).ok())
+        .map(|comm| comm.trim().to_string())
+        .filter(|comm| DISCOVERY_CLIENTS.contains(&comm.as_str()))
+        .collect();
+    clients.sort();
+    clients.dedup();
+
+    clients
+}
+
+/// Stops the discovery of the given adapter through the management interface, which stops
+/// discovery sessions of every client rather than only the ones of this process.
+fn stop_discovery(adapter: &str) -> crate::Result<()> {
+    let index = adapter.trim_start_matches("hci");
+
+    hci_tool("btmgmt", &["--index", index, "stop-find"]).map(|_| ())
+}
+
+/// Represents exclusive use of a Bluetooth adapter, where discovery of other clients is paused.
+///
+/// BlueZ does not allow other clients to be kept from starting discovery, and restarts discovery
+/// of their sessions on its own. The guard stops discovery again whenever it restarts, and
+/// discovery resumes once the guard drops.
+pub struct ExclusiveGuard {
+    adapter: String,
+    discovering: bool,
+    paused: bool,
+    clients: Vec<String>,
+    task: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl ExclusiveGuard {
+    /// Returns if discovery was active when the guard was acquired.
+    pub fn was_discovering(&self) -> bool {
+        self.discovering
+    }
+
+    /// Returns if discovery was active and is paused by the guard.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Returns if discovery is kept paused while the guard is held.
+    pub fn is_exclusive(&self) -> bool {
+        self.task.is_some()
+    }
+
+    /// Returns the names of running processes known to run discovery, which were found when the
+    /// guard was acquired.
+    pub fn clients(&self) -> &[String] {
+        &self.clients
+    }
+}
+
+impl Drop for ExclusiveGuard {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        if self.paused {
+            debug!("Resume discovery of adapter {}", self.adapter);
+        }
+    }
+}
+
+/// Acquires exclusive use of the given adapter, which stops active discovery and stops discovery
+/// again whenever another client restarts it until the returned guard drops. Exclusive use is not
+/// obtained if discovery cannot be stopped, or if the adapter events cannot be subscribed to,
+/// which is reported by `ExclusiveGuard::is_exclusive`.
+pub async fn acquire_exclusive(adapter: &Adapter) -> crate::Result<ExclusiveGuard> {
+    let discovering = adapter.is_discovering().await.map_err(|e| {
+        crate::Error::from(Error::new(
+            e,
+            format!("cannot get discovery of adapter {}", adapter.name()),
+        ))
+    })?;
+    let name = adapter.name().to_string();
+    let mut guard = ExclusiveGuard {
+        adapter: name.clone(),
+        discovering,
+        paused: false,
+        clients: crate::unblock(|| Ok(discovery_clients())).await?,
+        task: None,
+    };
+
+    // Subscribe first, so discovery restarted right after stopping is stopped again
+    let events = match adapter.events().await {
+        Ok(events) => events,
+        Err(e) => {
+            debug!("Cannot subscribe to events of adapter {}: {}", name, e);
+
+            return Ok(guard);
+        }
+    };
+    if discovering {
+        let adapter = name.clone();
+        if let Err(e) = crate::unblock(move || stop_discovery(&adapter)).await {
+            debug!("{}", e);
+
+            return Ok(guard);
+        }
+        guard.paused = true;
+    }
+    guard.task = Some(tokio::spawn(async move {
+        let mut events = Box::pin(events);
+        while let Some(event) = events.next().await {
+            if let AdapterEvent::PropertyChanged(AdapterProperty::Discovering(true)) = event {
+                let adapter = name.clone();
+                if let Err(e) = crate::unblock(move || stop_discovery(&adapter)).await {
+                    debug!("{}", e);
+                }
+            }
+        }
+    }));
+
+    Ok(guard)
+}
+
+/// Enumeration of Bluetooth events of an adapter and its devices.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum BluetoothEvent {
+    /// Represents the adapter is removed, like when the dongle is unplugged.
+    AdapterRemoved,
+    /// Represents the adapter is powered on or off.
+    AdapterPowered(bool),
+    /// Represents the device connected.
+    DeviceConnected(Address),
+    /// Represents the device disconnected, like when the baseband link is lost.
+    DeviceDisconnected(Address),
+    /// Represents the device is removed from the adapter.
+    DeviceRemoved(Address),
+}
+
+impl Display for BluetoothEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BluetoothEvent::AdapterRemoved => write!(f, "adapter removed"),
+            BluetoothEvent::AdapterPowered(true) => write!(f, "adapter powered on"),
+            BluetoothEvent::AdapterPowered(false) => write!(f, "adapter powered off"),
+            BluetoothEvent::DeviceConnected(addr) => write!(f, "device {} connected", addr),
+            BluetoothEvent::DeviceDisconnected(addr) => write!(f, "device {} disconnected", addr),
+            BluetoothEvent::DeviceRemoved(addr) => write!(f, "device {} removed", addr),
+        }
+    }
+}
+
+/// Subscribes to the Bluetooth events of the given adapter, and the connection of the given
+/// device if any. The stream ends after the adapter is removed.
+pub async fn subscribe(
+    session: &Session,
+    adapter: &Adapter,
+    device: Option<Address>,
+) -> crate::Result<BoxStream<'static, BluetoothEvent>> {
+    let error = |e| {
+        crate::Error::from(Error::new(
+            e,
+            format!("cannot subscribe to events of adapter {}", adapter.name()),
+        ))
+    };
+
+    let name = adapter.name().to_string();
+    let session_events = session
+        .events()
+        .await
+        .map_err(error)?
+        .filter_map(move |event| {
+            let event = match event {
+                SessionEvent::AdapterRemoved(removed) if removed == name => {
+                    Some(BluetoothEvent::AdapterRemoved)
+                }
+                _ => None,
+            };
+
+            future::ready(event)
+        });
+    let adapter_events = adapter.events().await.map_err(error)?.filter_map(|event| {
+        let event = match event {
+            AdapterEvent::DeviceRemoved(addr) => Some(BluetoothEvent::DeviceRemoved(addr)),
+            AdapterEvent::PropertyChanged(AdapterProperty::Powered(powered)) => {
+                Some(BluetoothEvent::AdapterPowered(powered))
+            }
+            _ => None,
+        };
+
+        future::ready(event)
+    });
+    let mut streams = vec![session_events.boxed(), adapter_events.boxed()];
+    if let Some(addr) = device {
+        let device_events = adapter
+            .device(addr)
+            .map_err(error)?
+            .events()
+            .await
+            .map_err(error)?
+            .filter_map(move |event| {
+                let event = match event {
+                    DeviceEvent::PropertyChanged(DeviceProperty::Connected(true)) => {
+                        Some(BluetoothEvent::DeviceConnected(addr))
+                    }
+                    DeviceEvent::PropertyChanged(DeviceProperty::Connected(false)) => {
+                        Some(BluetoothEvent::DeviceDisconnected(addr))
+                    }
+                    _ => None,
+                };
+
+                future::ready(event)
+            });
+        streams.push(device_events.boxed());
+    }
+
+    // The stream ends with the adapter, where the session keeps streaming
+    let mut removed = false;
+
+    Ok(stream::select_all(streams)
+        .take_while(move |event| {
+            let taking = !removed;
+            removed = *event == BluetoothEvent::AdapterRemoved;
+
+            future::ready(taking)
+        })
+        .boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Local address of a listener on the CTR PSM.
+    const LOCAL_ADDR: SocketAddr = SocketAddr {
+        addr: Address::new([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+        addr_type: AddressType::BrEdr,
+        psm: 17,
+        cid: 0,
+    };
+
+    /// Returns a send which fails with the given errors in turn, and then sends the given size.
+    /// The attempts are counted in the given counter.
+    fn failing_send(
+        errors: Vec<io::Error>,
+        size: usize,
+        attempts: &AtomicUsize,
+    ) -> impl FnMut() -> future::Ready<io::Result<usize>> + '_ {
+        let mut errors = errors.into_iter();
+        move || {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            future::ready(match errors.next() {
+                Some(e) => Err(e),
+                None => Ok(size),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn send_retries_transient_errors() {
+        let attempts = AtomicUsize::new(0);
+        let errors = vec![
+            io::Error::from(io::ErrorKind::WouldBlock),
+            io::Error::from_raw_os_error(ENOBUFS),
+        ];
+        let sent = send_with_retries(failing_send(errors, 49, &attempts)).await;
+        assert_eq!(sent.unwrap(), Some(49));
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn send_drops_after_retries() {
+        let attempts = AtomicUsize::new(0);
+        let errors = (0..=SEND_RETRIES)
+            .map(|_| io::Error::from_raw_os_error(ENOBUFS))
+            .collect();
+        let sent = send_with_retries(failing_send(errors, 49, &attempts)).await;
+        assert_eq!(sent.unwrap(), None);
+        assert_eq!(attempts.load(Ordering::Relaxed), SEND_RETRIES as usize + 1);
+
+        // Sends which never finish are retried like transient failures
+        let attempts = AtomicUsize::new(0);
+        let sent = send_with_retries(|| {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            future::pending::<io::Result<usize>>()
+        })
+        .await;
+        assert_eq!(sent.unwrap(), None);
+        assert_eq!(attempts.load(Ordering::Relaxed), SEND_RETRIES as usize + 1);
+    }
+
+    #[tokio::test]
+    async fn send_fails_on_other_errors() {
+        let attempts = AtomicUsize::new(0);
+        let errors = vec![io::Error::from(io::ErrorKind::BrokenPipe)];
+        let sent = send_with_retries(failing_send(errors, 49, &attempts)).await;
+        assert_eq!(sent.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn recv_times_out_at_deadline() {
+        let deadline = Instant::now() + Duration::from_millis(10);
+        let received = recv_until(future::pending(), deadline).await;
+        assert!(matches!(received, Ok(Received::TimedOut)));
+        assert!(Instant::now() >= deadline);
+
+        // A report received before the deadline is returned at once
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let received = recv_until(future::ready(Ok(49)), deadline).await;
+        assert!(matches!(received, Ok(Received::Report(49))));
+        assert!(Instant::now() < deadline);
+    }
+
+    #[tokio::test]
+    async fn recv_after_deadline_would_block() {
+        let deadline = Instant::now();
+        let received = recv_until(future::pending(), deadline).await;
+        assert!(matches!(received, Ok(Received::WouldBlock)));
+
+        // A report ready is still taken
+        let received = recv_until(future::ready(Ok(49)), deadline).await;
+        assert!(matches!(received, Ok(Received::Report(49))));
+    }
+
+    #[tokio::test]
+    async fn recv_until_keeps_errors() {
+        let closed = || {
+            future::ready(Err(crate::Error::new(
+                ErrorKind::PeerClosed,
+                "closed".into(),
+            )))
+        };
+        for deadline in [Instant::now(), Instant::now() + Duration::from_secs(10)] {
+            let received = recv_until(closed(), deadline).await;
+            assert!(matches!(
+                received,
+                Err(crate::Error {
+                    kind: ErrorKind::PeerClosed,
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_times_out() {
+        let accepting = future::pending::<crate::Result<()>>();
+        let accepted = accept_in_timeout(accepting, Duration::from_millis(10)).await;
+        assert!(matches!(accepted, Ok(None)));
+
+        let accepting = future::ready(Ok(()));
+        let accepted = accept_in_timeout(accepting, Duration::from_millis(10)).await;
+        assert!(matches!(accepted, Ok(Some(()))));
+    }
+
+    #[tokio::test]
+    async fn accept_is_cancelled() {
+        let cancel_token = CancelToken::new();
+        let accepting = future::pending::<crate::Result<()>>();
+        let cancelling = async {
+            tokio::task::yield_now().await;
+            cancel_token.cancel();
+        };
+        let (accepted, ()) = tokio::join!(
+            accept_until_cancelled(accepting, &cancel_token, LOCAL_ADDR),
+            cancelling
+        );
+        let e = accepted.unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::Cancelled));
+        assert_eq!(
+            e.message,
+            "accept on PSM 17 of adapter 01:02:03:04:05:06 cancelled"
+        );
+
+        // An accepted channel is returned unless cancelled
+        let cancel_token = CancelToken::new();
+        let accepted =
+            accept_until_cancelled(future::ready(Ok(())), &cancel_token, LOCAL_ADDR).await;
+        assert!(accepted.is_ok());
+    }
+}
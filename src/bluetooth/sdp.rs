@@ -0,0 +1,407 @@
+//! Support for building SDP service records.
+//!
+//! A `Record` holds attributes in typed data elements, and renders to the XML which BlueZ
+//! expects in the service record of a `Profile`. Records registered in the local SDP server are
+//! browsed as `Registered`.
+
+use super::hci_tool;
+use std::fmt::{self, Display, Formatter, Write};
+
+/// UUID of the L2CAP protocol.
+pub const L2CAP: u16 = 0x0100;
+/// UUID of the HIDP protocol.
+pub const HIDP: u16 = 0x0011;
+/// UUID of the public browse group.
+pub const PUBLIC_BROWSE_GROUP: u16 = 0x1002;
+/// UUID of the HID service class and profile.
+pub const HID: u16 = 0x1124;
+
+/// Attribute IDs of the universal attributes.
+const SERVICE_CLASS_ID_LIST: u16 = 0x0001;
+const PROTOCOL_DESCRIPTOR_LIST: u16 = 0x0004;
+const BROWSE_GROUP_LIST: u16 = 0x0005;
+const LANGUAGE_BASE_ATTRIBUTE_ID_LIST: u16 = 0x0006;
+const PROFILE_DESCRIPTOR_LIST: u16 = 0x0009;
+const ADDITIONAL_PROTOCOL_DESCRIPTOR_LISTS: u16 = 0x000d;
+
+/// Offsets of the text attributes from the language base.
+const SERVICE_NAME: u16 = 0x0000;
+const SERVICE_DESCRIPTION: u16 = 0x0001;
+const PROVIDER_NAME: u16 = 0x0002;
+
+/// Attribute IDs of the HID attributes.
+const HID_DEVICE_RELEASE_NUMBER: u16 = 0x0200;
+const HID_PARSER_VERSION: u16 = 0x0201;
+const HID_DEVICE_SUBCLASS: u16 = 0x0202;
+const HID_COUNTRY_CODE: u16 = 0x0203;
+const HID_VIRTUAL_CABLE: u16 = 0x0204;
+const HID_RECONNECT_INITIATE: u16 = 0x0205;
+const HID_DESCRIPTOR_LIST: u16 = 0x0206;
+const HID_LANGID_BASE_LIST: u16 = 0x0207;
+const HID_PROFILE_VERSION: u16 = 0x020b;
+const HID_SUPERVISION_TIMEOUT: u16 = 0x020c;
+const HID_NORMALLY_CONNECTABLE: u16 = 0x020d;
+const HID_BOOT_DEVICE: u16 = 0x020e;
+const HID_SSR_HOST_MAX_LATENCY: u16 = 0x020f;
+const HID_SSR_HOST_MIN_TIMEOUT: u16 = 0x0210;
+
+/// Descriptor type of HID report descriptors.
+const REPORT_DESCRIPTOR: u8 = 0x22;
+
+/// Represents a data element of an SDP attribute.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum DataElement {
+    /// Represents a 16-bit UUID.
+    Uuid(u16),
+    /// Represents an 8-bit unsigned integer.
+    Uint8(u8),
+    /// Represents a 16-bit unsigned integer.
+    Uint16(u16),
+    /// Represents a boolean.
+    Boolean(bool),
+    /// Represents a text string.
+    Text(String),
+    /// Represents a text string of raw bytes, which is rendered in hex.
+    Bytes(Vec<u8>),
+    /// Represents a sequence of data elements.
+    Sequence(Vec<DataElement>),
+}
+
+impl DataElement {
+    /// Renders the data element in XML at the given depth of indentation.
+    fn render(&self, buf: &mut String, depth: usize) {
+        let indent = "    ".repeat(depth);
+        let _ = match self {
+            DataElement::Uuid(uuid) => writeln!(buf, r#"{}<uuid value="0x{:04x}"/>"#, indent, uuid),
+            DataElement::Uint8(value) => {
+                writeln!(buf, r#"{}<uint8 value="0x{:02x}"/>"#, indent, value)
+            }
+            DataElement::Uint16(value) => {
+                writeln!(buf, r#"{}<uint16 value="0x{:04x}"/>"#, indent, value)
+            }
+            DataElement::Boolean(value) => {
+                writeln!(buf, r#"{}<boolean value="{}"/>"#, indent, value)
+            }
+            DataElement::Text(text) => {
+                writeln!(buf, r#"{}<text value="{}"/>"#, indent, escape(text))
+            }
+            DataElement::Bytes(bytes) => {
+                let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+                writeln!(buf, r#"{}<text encoding="hex" value="{}"/>"#, indent, hex)
+            }
+            DataElement::Sequence(elements) => {
+                let _ = writeln!(buf, "{}<sequence>", indent);
+                for element in elements.iter() {
+                    element.render(buf, depth + 1);
+                }
+
+                writeln!(buf, "{}</sequence>", indent)
+            }
+        };
+    }
+}
+
+/// Escapes the given text for XML attribute values.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Returns the protocol descriptor list of HIDP over L2CAP on the given PSM.
+fn hidp_over_l2cap(psm: u16) -> DataElement {
+    DataElement::Sequence(vec![
+        DataElement::Sequence(vec![DataElement::Uuid(L2CAP), DataElement::Uint16(psm)]),
+        DataElement::Sequence(vec![DataElement::Uuid(HIDP)]),
+    ])
+}
+
+/// Represents the HID attributes of a service record.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct HidAttributes {
+    /// Represents the device release number.
+    pub device_release: u16,
+    /// Represents the version of the HID parser.
+    pub parser_version: u16,
+    /// Represents the device subclass, which is the minor device class.
+    pub subclass: u8,
+    /// Represents the country code, or 0 if not localized.
+    pub country_code: u8,
+    /// Represents if the device is a virtual cable.
+    pub virtual_cable: bool,
+    /// Represents if the device initiates reconnections.
+    pub reconnect_initiate: bool,
+    /// Represents the HID report descriptor.
+    pub report_descriptor: Vec<u8>,
+    /// Represents the language ID of the HID strings.
+    pub language: u16,
+    /// Represents the version of the HID profile.
+    pub profile_version: u16,
+    /// Represents the supervision timeout in 0.625 ms slots.
+    pub supervision_timeout: u16,
+    /// Represents if the device is normally connectable.
+    pub normally_connectable: bool,
+    /// Represents if the device supports the boot protocol.
+    pub boot_device: bool,
+    /// Represents the max latency of sniff subrating of the host in 0.625 ms slots.
+    pub ssr_host_max_latency: u16,
+    /// Represents the min timeout of sniff subrating of the host in 0.625 ms slots.
+    pub ssr_host_min_timeout: u16,
+}
+
+/// Represents an SDP service record.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct Record {
+    attributes: Vec<(u16, DataElement)>,
+    language_base: u16,
+}
+
+impl Record {
+    /// Creates an empty `Record`.
+    pub fn new() -> Self {
+        Record::default()
+    }
+
+    /// Sets the attribute of the given ID, which replaces the existing one. Attributes are
+    /// rendered in the ascending order of their IDs.
+    pub fn attribute(mut self, id: u16, value: DataElement) -> Self {
+        match self.attributes.binary_search_by_key(&id, |(id, _)| *id) {
+            Ok(i) => self.attributes[i].1 = value,
+            Err(i) => self.attributes.insert(i, (id, value)),
+        }
+
+        self
+    }
+
+    /// Sets the service classes.
+    pub fn service_classes(self, classes: &[u16]) -> Self {
+        let classes = classes.iter().map(|class| DataElement::Uuid(*class));
+
+        self.attribute(
+            SERVICE_CLASS_ID_LIST,
+            DataElement::Sequence(classes.collect()),
+        )
+    }
+
+    /// Sets the protocol descriptor list to HIDP over L2CAP on the given control PSM.
+    pub fn protocol_descriptors(self, psm: u16) -> Self {
+        self.attribute(PROTOCOL_DESCRIPTOR_LIST, hidp_over_l2cap(psm))
+    }
+
+    /// Sets the additional protocol descriptor lists to HIDP over L2CAP on the given interrupt
+    /// PSM.
+    pub fn additional_protocol_descriptors(self, psm: u16) -> Self {
+        self.attribute(
+            ADDITIONAL_PROTOCOL_DESCRIPTOR_LISTS,
+            DataElement::Sequence(vec![hidp_over_l2cap(psm)]),
+        )
+    }
+
+    /// Sets the browse groups.
+    pub fn browse_groups(self, groups: &[u16]) -> Self {
+        let groups = groups.iter().map(|group| DataElement::Uuid(*group));
+
+        self.attribute(BROWSE_GROUP_LIST, DataElement::Sequence(groups.collect()))
+    }
+
+    /// Sets the language base, which is the language code in ISO 639, the character encoding in
+    /// IANA MIBenum and the base of the text attributes. The name, description and provider
+    /// are placed at the base, so the base is set before them.
+    pub fn language_base(mut self, language: u16, encoding: u16, base: u16) -> Self {
+        self.language_base = base;
+
+        self.attribute(
+            LANGUAGE_BASE_ATTRIBUTE_ID_LIST,
+            DataElement::Sequence(vec![
+                DataElement::Uint16(language),
+                DataElement::Uint16(encoding),
+                DataElement::Uint16(base),
+            ]),
+        )
+    }
+
+    /// Sets the profile descriptor of the given profile and version.
+    pub fn profile_descriptor(self, profile: u16, version: u16) -> Self {
+        self.attribute(
+            PROFILE_DESCRIPTOR_LIST,
+            DataElement::Sequence(vec![DataElement::Sequence(vec![
+                DataElement::Uuid(profile),
+                DataElement::Uint16(version),
+            ])]),
+        )
+    }
+
+    /// Sets the service name.
+    pub fn name(self, name: &str) -> Self {
+        let id = self.language_base + SERVICE_NAME;
+
+        self.attribute(id, DataElement::Text(name.into()))
+    }
+
+    /// Sets the service description.
+    pub fn description(self, description: &str) -> Self {
+        let id = self.language_base + SERVICE_DESCRIPTION;
+
+        self.attribute(id, DataElement::Text(description.into()))
+    }
+
+    /// Sets the provider name.
+    pub fn provider(self, provider: &str) -> Self {
+        let id = self.language_base + PROVIDER_NAME;
+
+        self.attribute(id, DataElement::Text(provider.into()))
+    }
+
+    /// Sets the HID attributes.
+    pub fn hid(self, hid: &HidAttributes) -> Self {
+        let language_base = self.language_base;
+
+        self.attribute(
+            HID_DEVICE_RELEASE_NUMBER,
+            DataElement::Uint16(hid.device_release),
+        )
+        .attribute(HID_PARSER_VERSION, DataElement::Uint16(hid.parser_version))
+        .attribute(HID_DEVICE_SUBCLASS, DataElement::Uint8(hid.subclass))
+        .attribute(HID_COUNTRY_CODE, DataElement::Uint8(hid.country_code))
+        .attribute(HID_VIRTUAL_CABLE, DataElement::Boolean(hid.virtual_cable))
+        .attribute(
+            HID_RECONNECT_INITIATE,
+            DataElement::Boolean(hid.reconnect_initiate),
+        )
+        .attribute(
+            HID_DESCRIPTOR_LIST,
+            DataElement::Sequence(vec![DataElement::Sequence(vec![
+                DataElement::Uint8(REPORT_DESCRIPTOR),
+                DataElement::Bytes(hid.report_descriptor.clone()),
+            ])]),
+        )
+        .attribute(
+            HID_LANGID_BASE_LIST,
+            DataElement::Sequence(vec![DataElement::Sequence(vec![
+                DataElement::Uint16(hid.language),
+                DataElement::Uint16(language_base),
+            ])]),
+        )
+        .attribute(
+            HID_PROFILE_VERSION,
+            DataElement::Uint16(hid.profile_version),
+        )
+        .attribute(
+            HID_SUPERVISION_TIMEOUT,
+            DataElement::Uint16(hid.supervision_timeout),
+        )
+        .attribute(
+            HID_NORMALLY_CONNECTABLE,
+            DataElement::Boolean(hid.normally_connectable),
+        )
+        .attribute(HID_BOOT_DEVICE, DataElement::Boolean(hid.boot_device))
+        .attribute(
+            HID_SSR_HOST_MAX_LATENCY,
+            DataElement::Uint16(hid.ssr_host_max_latency),
+        )
+        .attribute(
+            HID_SSR_HOST_MIN_TIMEOUT,
+            DataElement::Uint16(hid.ssr_host_min_timeout),
+        )
+    }
+
+    /// Renders the record in the XML which BlueZ expects.
+    pub fn render(&self) -> String {
+        let mut buf = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" ?>\n<record>\n");
+        for (id, value) in self.attributes.iter() {
+            let _ = writeln!(buf, r#"    <attribute id="0x{:04x}">"#, id);
+            value.render(&mut buf, 2);
+            buf.push_str("    </attribute>\n");
+        }
+        buf.push_str("</record>\n");
+
+        buf
+    }
+}
+
+/// Represents a service record registered in the local SDP server, as browsed by `sdptool`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Registered {
+    /// Represents the handle of the record.
+    pub handle: u32,
+    /// Represents the 16-bit UUIDs of the service classes.
+    pub service_classes: Vec<u16>,
+    /// Represents the service name.
+    pub name: Option<String>,
+    /// Represents the provider name.
+    pub provider: Option<String>,
+}
+
+impl Registered {
+    /// Returns if the record is of the given service class, name and provider.
+    pub fn matches(&self, service_class: u16, name: &str, provider: &str) -> bool {
+        self.service_classes.contains(&service_class)
+            && self.name.as_deref() == Some(name)
+            && self.provider.as_deref() == Some(provider)
+    }
+}
+
+impl Display for Registered {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "0x{:x} ({} by {})",
+            self.handle,
+            self.name.as_deref().unwrap_or("?"),
+            self.provider.as_deref().unwrap_or("?")
+        )
+    }
+}
+
+/// Returns the service records registered in the local SDP server by all processes. Browsing the
+/// local SDP server requires the compatibility interface of bluetoothd, which is enabled by
+/// `bluetoothd --compat`.
+pub fn registered_records() -> crate::Result<Vec<Registered>> {
+    let output = hci_tool("sdptool", &["browse", "local"])?;
+
+    // Records are separated by empty lines, where the class ID list is indented under its title
+    let mut records = vec![];
+    for block in output.split("\n\n") {
+        let mut record = Registered::default();
+        let mut handle = None;
+        let mut class_list = false;
+        for line in block.lines() {
+            if let Some(name) = line.strip_prefix("Service Name:") {
+                record.name = Some(name.trim().to_string());
+            } else if let Some(provider) = line.strip_prefix("Service Provider:") {
+                record.provider = Some(provider.trim().to_string());
+            } else if let Some(value) = line.strip_prefix("Service RecordHandle:") {
+                handle = u32::from_str_radix(value.trim().trim_start_matches("0x"), 16).ok();
+            } else if line.starts_with("Service Class ID List:") {
+                class_list = true;
+                continue;
+            }
+            class_list = class_list && line.starts_with(' ');
+            if class_list {
+                // Like `  "Human Interface Device" (0x1124)`
+                let uuid = line
+                    .trim_end()
+                    .strip_suffix(')')
+                    .and_then(|class| class.rsplit_once("(0x"))
+                    .and_then(|(_, uuid)| u16::from_str_radix(uuid, 16).ok());
+                record.service_classes.extend(uuid);
+            }
+        }
+        if let Some(handle) = handle {
+            record.handle = handle;
+            records.push(record);
+        }
+    }
+
+    Ok(records)
+}
+
+/// Removes the service record of the given handle from the local SDP server, which requires the
+/// compatibility interface of bluetoothd like `registered_records`.
+pub fn remove_record(handle: u32) -> crate::Result<()> {
+    hci_tool("sdptool", &["del", &format!("0x{:x}", handle)])?;
+
+    Ok(())
+}
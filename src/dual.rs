@@ -0,0 +1,496 @@
+//! Support for emulating a pair of Joy-Cons.
+
+use crate::bluetooth::Address;
+use crate::protocol::event::SwitchEvent;
+use crate::protocol::imu::MotionSample;
+use crate::protocol::input::{Button, ButtonState, ControllerInput, Stick};
+use crate::{
+    adapters, CancelToken, Controller, ControllerType, Error, ErrorKind, Events, PairedDevice,
+    Result, StopReason,
+};
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::time::Duration;
+
+/// Enumeration for sides of a pair of Joy-Cons.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum JoyConSide {
+    /// Represents the Joy-Con (L).
+    Left,
+    /// Represents the Joy-Con (R).
+    Right,
+}
+
+impl JoyConSide {
+    /// Returns the controller type of the side.
+    pub fn controller_type(&self) -> ControllerType {
+        match self {
+            JoyConSide::Left => ControllerType::JoyConL,
+            JoyConSide::Right => ControllerType::JoyConR,
+        }
+    }
+
+    /// Returns the side which has the given button in a pair of Joy-Cons. The SL and SR buttons
+    /// are on both sides, where `None` is returned.
+    pub fn of_button(button: Button) -> Option<JoyConSide> {
+        match button {
+            Button::Sl | Button::Sr => None,
+            _ if ControllerType::JoyConL.has_button(button) => Some(JoyConSide::Left),
+            _ if ControllerType::JoyConR.has_button(button) => Some(JoyConSide::Right),
+            _ => None,
+        }
+    }
+
+    /// Returns the side which has the given stick.
+    pub fn of_stick(stick: Stick) -> JoyConSide {
+        match stick {
+            Stick::Left => JoyConSide::Left,
+            Stick::Right => JoyConSide::Right,
+        }
+    }
+}
+
+impl Display for JoyConSide {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.controller_type().name())
+    }
+}
+
+/// Represents a receiver of events from the device of both sides of a `DualJoyCon`.
+#[derive(Debug)]
+pub struct DualEvents {
+    left: Events,
+    right: Events,
+}
+
+impl DualEvents {
+    /// Receives the next event with the side which emitted it, or `None` if the `DualJoyCon` is
+    /// dropped. The oldest events are dropped if events are not received in time.
+    pub async fn recv(&mut self) -> Option<(JoyConSide, SwitchEvent)> {
+        tokio::select! {
+            event = self.left.recv() => event.map(|event| (JoyConSide::Left, event)),
+            event = self.right.recv() => event.map(|event| (JoyConSide::Right, event)),
+        }
+    }
+
+    /// Returns the number of dropped events of both sides.
+    pub fn dropped(&self) -> u64 {
+        self.left.dropped() + self.right.dropped()
+    }
+}
+
+/// Represents a pair of emulated Joy-Cons which are presented as one gamepad.
+///
+/// A device knows a controller by the address of its adapter, so the Joy-Con (L) and the
+/// Joy-Con (R) must be emulated on different adapters. Both sides are paired one after another
+/// with the same device, which joins them when both are connected, e.g., by pressing L and R in
+/// the Change Grip/Order menu.
+///
+/// Inputs are routed to the side which has them: the A, B, X, Y, R, ZR, plus and HOME buttons and
+/// the right stick go to the Joy-Con (R), the directional, L, ZL, minus and capture buttons and
+/// the left stick go to the Joy-Con (L), and motion goes to both. The SL and SR buttons are on
+/// both sides, and can only be set through `side`.
+pub struct DualJoyCon {
+    left: Controller,
+    right: Controller,
+}
+
+impl DualJoyCon {
+    /// Creates a `DualJoyCon` with the given adapters of the Joy-Con (L) and the Joy-Con (R),
+    /// which must be different existing adapters.
+    pub async fn new(left_adapter: &str, right_adapter: &str) -> Result<Self> {
+        if left_adapter == right_adapter {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "{} and {} require different adapters, but both use {}",
+                    JoyConSide::Left,
+                    JoyConSide::Right,
+                    left_adapter
+                ),
+            ));
+        }
+        let names = adapters().await?;
+        for (side, adapter) in [
+            (JoyConSide::Left, left_adapter),
+            (JoyConSide::Right, right_adapter),
+        ] {
+            if !names.iter().any(|name| name == adapter) {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("adapter {} of {} does not exist", adapter, side),
+                ));
+            }
+        }
+
+        let left = Controller::new(left_adapter, ControllerType::JoyConL).await?;
+        let right = Controller::new(right_adapter, ControllerType::JoyConR).await?;
+
+        DualJoyCon::from_controllers(left, right)
+    }
+
+    /// Creates a `DualJoyCon` with the given controllers of the Joy-Con (L) and the Joy-Con (R),
+    /// which must be on different adapters.
+    pub fn from_controllers(left: Controller, right: Controller) -> Result<Self> {
+        for (side, controller) in [(JoyConSide::Left, &left), (JoyConSide::Right, &right)] {
+            let controller_type = controller.handle().lock().controller_type();
+            if controller_type != side.controller_type() {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("expected {}, found {}", side, controller_type),
+                ));
+            }
+        }
+        if left.adapter_name() == right.adapter_name() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "{} and {} require different adapters, but both use {}",
+                    JoyConSide::Left,
+                    JoyConSide::Right,
+                    left.adapter_name()
+                ),
+            ));
+        }
+
+        Ok(DualJoyCon { left, right })
+    }
+
+    /// Returns the controller of the given side.
+    pub fn side(&self, side: JoyConSide) -> &Controller {
+        match side {
+            JoyConSide::Left => &self.left,
+            JoyConSide::Right => &self.right,
+        }
+    }
+
+    /// Returns the controller of the given side mutably.
+    pub fn side_mut(&mut self, side: JoyConSide) -> &mut Controller {
+        match side {
+            JoyConSide::Left => &mut self.left,
+            JoyConSide::Right => &mut self.right,
+        }
+    }
+
+    /// Disconnects both sides.
+    pub async fn disconnect(&mut self) -> Result<()> {
+        let left = self.left.disconnect().await;
+        let right = self.right.disconnect().await;
+
+        left.and(right)
+    }
+
+    /// Pairs both sides with a new device, the Joy-Con (L) first. The Joy-Con (L) is serviced
+    /// while the Joy-Con (R) pairs, or the device drops it. Both sides must be paired with the
+    /// same device.
+    pub async fn pair(&mut self) -> Result<(PairedDevice, PairedDevice)> {
+        let left = self.left.pair().await?;
+        let (left_token, right_token) = (self.left.cancel_token(), self.right.cancel_token());
+        let right = pair_serving(
+            self.right.pair(),
+            self.left.run(),
+            &left_token,
+            &right_token,
+        )
+        .await?;
+        if left.address != right.address {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "{} paired with {} but {} paired with {}",
+                    JoyConSide::Left,
+                    left.address,
+                    JoyConSide::Right,
+                    right.address
+                ),
+            ));
+        }
+
+        Ok((left, right))
+    }
+
+    /// Connects both sides to the paired device.
+    pub async fn connect(&mut self, addr: Address) -> Result<()> {
+        self.left.connect(addr).await?;
+        self.right.connect(addr).await?;
+
+        Ok(())
+    }
+
+    /// Services the device with both sides until the connection of either side stops, which
+    /// also stops the other side. Returns the reason of the side which stopped first.
+    pub async fn run(&mut self) -> Result<(JoyConSide, StopReason)> {
+        let left_token = self.left.cancel_token();
+        let right_token = self.right.cancel_token();
+        let left = self.left.run();
+        let right = self.right.run();
+        tokio::pin!(left, right);
+
+        tokio::select! {
+            result = &mut left => {
+                right_token.cancel();
+                let _ = right.await;
+
+                result.map(|reason| (JoyConSide::Left, reason))
+            }
+            result = &mut right => {
+                left_token.cancel();
+                let _ = left.await;
+
+                result.map(|reason| (JoyConSide::Right, reason))
+            }
+        }
+    }
+
+    /// Returns a receiver of events from the device of both sides, e.g., the player lights and
+    /// the rumble of each side.
+    pub fn events(&self) -> DualEvents {
+        DualEvents {
+            left: self.left.events(),
+            right: self.right.events(),
+        }
+    }
+
+    /// Returns the player lights of the given side.
+    pub fn player_lights(&self, side: JoyConSide) -> u8 {
+        self.side(side).player_lights()
+    }
+
+    /// Returns the state of buttons of both sides.
+    pub fn buttons(&self) -> ButtonState {
+        let mut buttons = ButtonState::new();
+        for button in self
+            .left
+            .buttons()
+            .pressed()
+            .into_iter()
+            .chain(self.right.buttons().pressed())
+        {
+            buttons.press(button);
+        }
+
+        buttons
+    }
+
+    /// Presses the given button on the side which has it.
+    pub fn press(&self, button: Button) -> Result<()> {
+        self.button_side(button)?.press(button)
+    }
+
+    /// Releases the given button on the side which has it.
+    pub fn release(&self, button: Button) -> Result<()> {
+        self.button_side(button)?.release(button)
+    }
+
+    /// Presses the given button on the side which has it for the duration.
+    pub fn press_for(&self, button: Button, duration: Duration) -> Result<()> {
+        self.button_side(button)?.press_for(button, duration)
+    }
+
+    /// Taps the given button on the side which has it.
+    pub fn tap(&self, button: Button) -> Result<()> {
+        self.button_side(button)?.tap(button)
+    }
+
+    /// Sets the position of the given stick on the side which has it from normalized values in
+    /// -1 to 1.
+    pub fn set_stick(&self, stick: Stick, x: f32, y: f32) -> Result<()> {
+        self.side(JoyConSide::of_stick(stick))
+            .set_stick(stick, x, y)
+    }
+
+    /// Sets the motion sample of both sides.
+    pub fn set_motion(&self, motion: MotionSample) {
+        self.left.set_motion(motion);
+        self.right.set_motion(motion);
+    }
+
+    /// Sets the whole input state of both sides at once. Inputs are split to the sides which
+    /// have them, and each side reflects its part in its next input report.
+    pub fn set_input(&self, input: &ControllerInput) -> Result<()> {
+        let mut left = ControllerInput {
+            left_stick: input.left_stick,
+            motion: input.motion,
+            ..ControllerInput::new()
+        };
+        let mut right = ControllerInput {
+            right_stick: input.right_stick,
+            motion: input.motion,
+            ..ControllerInput::new()
+        };
+        for button in input.buttons.pressed() {
+            match JoyConSide::of_button(button) {
+                Some(JoyConSide::Left) => left.buttons.press(button),
+                Some(JoyConSide::Right) => right.buttons.press(button),
+                None => return Err(unavailable(button)),
+            }
+        }
+
+        self.left.set_input(&left)?;
+        self.right.set_input(&right)
+    }
+
+    fn button_side(&self, button: Button) -> Result<&Controller> {
+        match JoyConSide::of_button(button) {
+            Some(side) => Ok(self.side(side)),
+            None => Err(unavailable(button)),
+        }
+    }
+}
+
+fn unavailable(button: Button) -> Error {
+    match button {
+        Button::Sl | Button::Sr => Error::new(
+            ErrorKind::Unavailable,
+            format!(
+                "{} is on both sides of Joy-Cons, set it on a side instead",
+                button
+            ),
+        ),
+        _ => Error::new(
+            ErrorKind::Unavailable,
+            format!("Joy-Cons do not have {}", button),
+        ),
+    }
+}
+
+/// Waits for the pairing of the Joy-Con (R) while servicing the Joy-Con (L) with the given run,
+/// which is cancelled with the given token once paired. If the run stops first, the pairing is
+/// cancelled with the given token, and an error is returned.
+async fn pair_serving(
+    pairing: impl Future<Output = Result<PairedDevice>>,
+    serving: impl Future<Output = Result<StopReason>>,
+    serving_token: &CancelToken,
+    pairing_token: &CancelToken,
+) -> Result<PairedDevice> {
+    tokio::pin!(pairing, serving);
+
+    tokio::select! {
+        paired = &mut pairing => {
+            serving_token.cancel();
+            let stopped = serving.await;
+            let paired = paired?;
+            stopped?;
+
+            Ok(paired)
+        }
+        stopped = &mut serving => {
+            pairing_token.cancel();
+            let _ = pairing.await;
+            let kind = match stopped? {
+                StopReason::Cancelled => ErrorKind::Cancelled,
+                _ => ErrorKind::Disconnected,
+            };
+
+            Err(Error::new(
+                kind,
+                format!(
+                    "{} stopped while pairing {}",
+                    JoyConSide::Left,
+                    JoyConSide::Right
+                ),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Direction, InputType, Protocol, Subcommand, Type};
+    use std::time::{Instant, SystemTime};
+    use tokio::sync::mpsc;
+
+    /// Returns an output report requesting the device info.
+    fn request_device_info() -> Vec<u8> {
+        let mut frame = vec![Direction::Output as u8, Type::Subcommand as u8, 0x00];
+        frame.extend_from_slice(&[0x00, 0x01, 0x40, 0x40, 0x00, 0x01, 0x40, 0x40]);
+        frame.push(Subcommand::RequestDeviceInfo as u8);
+        frame.resize(49, 0x00);
+
+        frame
+    }
+
+    /// Returns a device paired with.
+    fn device() -> PairedDevice {
+        PairedDevice {
+            address: Address::new([0x98, 0xB6, 0xE9, 0x00, 0x00, 0x01]),
+            name: Some("Nintendo Switch".into()),
+            alias: None,
+            adapter: "hci1".into(),
+            paired_at: SystemTime::UNIX_EPOCH,
+            removed_previous: false,
+        }
+    }
+
+    /// Services the given protocol with the output reports of the device until cancelled, and
+    /// sends the replies back.
+    async fn serve(
+        protocol: &mut Protocol,
+        outputs: &mut mpsc::Receiver<Vec<u8>>,
+        replies: &mpsc::Sender<Vec<u8>>,
+        cancel_token: &CancelToken,
+    ) -> Result<StopReason> {
+        loop {
+            tokio::select! {
+                _ = cancel_token.cancelled() => return Ok(StopReason::Cancelled),
+                output = outputs.recv() => match output {
+                    Some(output) => {
+                        if let Some(reply) = protocol.handle_output(&output, Instant::now())? {
+                            let _ = replies.send(reply).await;
+                        }
+                    }
+                    None => return Ok(StopReason::Closed),
+                },
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn left_is_serviced_while_right_pairs() {
+        let mut left = Protocol::new(ControllerType::JoyConL);
+        let (output_sender, mut outputs) = mpsc::channel(1);
+        let (replies, mut reply_receiver) = mpsc::channel::<Vec<u8>>(1);
+        let (left_token, right_token) = (CancelToken::new(), CancelToken::new());
+
+        // The device polls the Joy-Con (L) before the Joy-Con (R) completes pairing
+        let pairing = async {
+            output_sender.send(request_device_info()).await.unwrap();
+            let reply = reply_receiver.recv().await.unwrap();
+            assert_eq!(reply[1], InputType::SubcommandReply as u8);
+            assert_eq!(reply[15], Subcommand::RequestDeviceInfo as u8);
+
+            Ok(device())
+        };
+        let serving = serve(&mut left, &mut outputs, &replies, &left_token);
+        let paired = pair_serving(pairing, serving, &left_token, &right_token)
+            .await
+            .unwrap();
+
+        assert_eq!(paired.address, device().address);
+        assert!(left_token.is_cancelled());
+        assert!(!right_token.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn stopped_left_fails_pairing() {
+        let mut left = Protocol::new(ControllerType::JoyConL);
+        let (output_sender, mut outputs) = mpsc::channel::<Vec<u8>>(1);
+        let (replies, _reply_receiver) = mpsc::channel(1);
+        let (left_token, right_token) = (CancelToken::new(), CancelToken::new());
+        drop(output_sender);
+
+        let pairing = async {
+            right_token.cancelled().await;
+
+            Err(Error::new(ErrorKind::Cancelled, "pairing cancelled".into()))
+        };
+        let serving = serve(&mut left, &mut outputs, &replies, &left_token);
+        let e = pair_serving(pairing, serving, &left_token, &right_token)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(e.kind, ErrorKind::Disconnected));
+        assert!(right_token.is_cancelled());
+    }
+}
@@ -1,20 +1,57 @@
 //! Emulate Nintendo Switch controllers over Bluetooth.
 
+use bluer::{AdapterEvent, AdapterProperty};
+use futures::{future, FutureExt, Stream, StreamExt};
 use log::{debug, info, warn, LevelFilter};
+use std::collections::BTreeMap;
 use std::fmt::{self, Display, Formatter};
+use std::future::Future;
 use std::io;
-use std::net::Shutdown;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, watch, Notify};
 
+#[cfg(feature = "blocking")]
+pub mod blocking;
 pub mod bluetooth;
+pub mod dual;
 mod logger;
+pub mod manager;
 pub mod protocol;
+#[cfg(feature = "store")]
+pub mod store;
 
 use bluetooth::{
-    Adapter, Address, AddressType, Profile, ProfileHandle, SeqPacket, SeqPacketListener,
-    ServiceRecord, Session, SetClass, SocketAddr,
+    sdp, Adapter, Address, AddressType, Agent, BluetoothEvent, BrEdr, Device, ExclusiveGuard,
+    HardwareInfo, HidChannel, HidListener, LocalName, PairingAgent, Profile, ProfileHandle,
+    Received, ScanParameters, SecurityLevel, Sent, ServiceRecord, Session, SetAddress, SetClass,
+    SetFlushTimeout, SetScanParameters, SetSecurityLevel, SetSendMtu, Socket, SocketAddr,
+    HID_FLUSH_TIMEOUT, HID_MTU, HID_SECURITY_LEVEL,
 };
 use logger::Logger;
+use protocol::battery::BatteryLevel;
+use protocol::event::{HciState, PairingEvent, PlayerLights, PlayerNumber, SwitchEvent};
+use protocol::imu::MotionSample;
+use protocol::input::{
+    Button, ButtonState, ControllerInput, Orientation, Stick, StickConfig, StickPosition,
+};
+use protocol::macros::InputMacro;
+use protocol::mcu::{Amiibo, McuVersion};
+use protocol::recording::InputRecording;
+use protocol::rumble::DEFAULT_RUMBLE_EPSILON;
+use protocol::spi::{Colors, ReloadMode, SpiFlash};
+use protocol::state::ControllerState;
+use protocol::tas::TasScript;
+use protocol::{
+    Direction, FirmwareProfile, OutgoingFrame, Output, Protocol, ProtocolStateSnapshot, ReportMode,
+    ReportRate, ReportTick, ScheduleAt, Type, DEFAULT_REPLY_DELAY,
+};
+#[cfg(feature = "store")]
+use store::{PairingRecord, PairingStore};
 
 /// Enumeration of error kinds.
 #[derive(Debug)]
@@ -25,92 +62,4892 @@ pub enum ErrorKind {
     Io(io::Error),
     /// Represents the protocol error.
     Protocol,
+    /// Represents the error of inputs unavailable in the controller.
+    Unavailable,
+    /// Represents the timeout error.
+    Timeout,
+    /// Represents the error of cancelled operations.
+    Cancelled,
+    /// Represents the error of connections lost.
+    Disconnected,
+    /// Represents the error of channels closed by the peer device.
+    PeerClosed,
+    /// Represents the error of missing privileges, like root or capabilities.
+    PermissionDenied,
     /// Represents the other error.
     Other,
 }
 
-impl Display for ErrorKind {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        match self {
-            ErrorKind::Bluetooth(error) => write!(f, "{}", error),
-            ErrorKind::Io(error) => write!(f, "{}", error),
-            ErrorKind::Protocol => write!(f, "protocol"),
-            ErrorKind::Other => write!(f, "other"),
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::Bluetooth(error) => write!(f, "{}", error),
+            ErrorKind::Io(error) => write!(f, "{}", error),
+            ErrorKind::Protocol => write!(f, "protocol"),
+            ErrorKind::Unavailable => write!(f, "unavailable"),
+            ErrorKind::Timeout => write!(f, "timeout"),
+            ErrorKind::Cancelled => write!(f, "cancelled"),
+            ErrorKind::Disconnected => write!(f, "disconnected"),
+            ErrorKind::PeerClosed => write!(f, "peer closed"),
+            ErrorKind::PermissionDenied => write!(f, "permission denied"),
+            ErrorKind::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// Maximum number of bytes kept in a `Dump`.
+const DUMP_LEN: usize = 64;
+
+/// Represents a dump of the frame which caused an error.
+#[derive(Debug, Clone)]
+pub struct Dump {
+    /// Represents the leading bytes of the frame.
+    pub data: Vec<u8>,
+    /// Represents the length of the whole frame.
+    pub len: usize,
+    /// Represents the offset of the byte which failed validation.
+    pub offset: usize,
+}
+
+impl Dump {
+    /// Creates a `Dump` of the given frame.
+    pub fn new(frame: &[u8], offset: usize) -> Self {
+        Dump {
+            data: frame.iter().take(DUMP_LEN).copied().collect(),
+            len: frame.len(),
+            offset,
+        }
+    }
+}
+
+impl Display for Dump {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (i, b) in self.data.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:02x}", b)?;
+        }
+        if self.len > self.data.len() {
+            write!(f, " ...")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Represents an error.
+#[derive(Debug)]
+pub struct Error {
+    /// Represents the error kind.
+    pub kind: ErrorKind,
+    /// Represents the detailed message.
+    pub message: String,
+    /// Represents the dump of the offending frame.
+    pub dump: Option<Dump>,
+}
+
+impl Error {
+    /// Creates a `Error`.
+    pub fn new(kind: ErrorKind, message: String) -> Self {
+        Error {
+            kind,
+            message,
+            dump: None,
+        }
+    }
+
+    /// Creates a `Error` with a dump of the offending frame.
+    pub fn with_dump(kind: ErrorKind, message: String, frame: &[u8], offset: usize) -> Self {
+        Error {
+            kind,
+            message,
+            dump: Some(Dump::new(frame, offset)),
+        }
+    }
+
+    /// Returns if the Bluetooth stack is gone, like when bluetoothd or D-Bus restarts, where the
+    /// session must be reconnected by `Controller::reconnect_session`.
+    pub fn is_stack_lost(&self) -> bool {
+        matches!(&self.kind, ErrorKind::Bluetooth(e) if e.is_stack_lost())
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error::new(kind, String::new())
+    }
+}
+
+impl From<bluetooth::Error> for Error {
+    fn from(error: bluetooth::Error) -> Self {
+        Error::new(ErrorKind::Bluetooth(error), String::new())
+    }
+}
+
+impl From<bluer::Error> for Error {
+    fn from(error: bluer::Error) -> Self {
+        Error::from(bluetooth::Error::from(error))
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::new(ErrorKind::Io(error), String::new())
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.message.is_empty() {
+            write!(f, "{}", &self.kind)?;
+        } else {
+            match self.kind {
+                ErrorKind::Other => write!(f, "{}", &self.message)?,
+                _ => write!(f, "{}: {}", &self.kind, &self.message)?,
+            }
+        }
+
+        // Raw bytes are only shown in debug level to keep logs readable
+        if let Some(dump) = &self.dump {
+            write!(f, " (offset {} of {} bytes)", dump.offset, dump.len)?;
+            if log::max_level() >= LevelFilter::Debug {
+                write!(f, ": {}", dump)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Bluetooth(error) => Some(error),
+            ErrorKind::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Represents an result.
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Initializes the logger and set its level.
+pub fn set_logger(verbose: usize) {
+    let level = match verbose {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    Logger::init(level);
+}
+
+/// Gets the list of Bluetooth adapters.
+pub async fn adapters() -> Result<Vec<String>> {
+    Ok(Session::new().await?.adapter_names().await?)
+}
+
+/// Gets the list of Nintendo Switches paired with the given Bluetooth adapter, which can be
+/// removed with `Controller::unpair`.
+pub async fn switches(adapter: &str) -> Result<Vec<Address>> {
+    let session = Session::new().await?;
+    let devices = switch_devices(&session.adapter(adapter)?).await?;
+
+    Ok(devices.iter().map(|device| device.address()).collect())
+}
+
+const NINTENDO_SWITCH_NAME: &str = "Nintendo Switch";
+const GAMEPAD_JOYSITCK_COD: u32 = 0x002508;
+const CTR_PSM: u16 = 17;
+const ITR_PSM: u16 = 19;
+const SERVICE: &str = "00001124-0000-1000-8000-00805f9b34fb";
+const SERVICE_NAME: &str = "Wireless Gamepad";
+const SERVICE_PROVIDER: &str = "Nintendo";
+const HID_DEVICE_RELEASE: u16 = 0x0100;
+const HID_PROFILE_VERSION: u16 = 0x0100;
+
+/// HID report descriptor of the Pro Controller.
+#[rustfmt::skip]
+const PRO_CONTROLLER_HID_DESCRIPTOR: [u8; 203] = [
+    0x05, 0x01, 0x15, 0x00, 0x09, 0x04, 0xa1, 0x01, 0x85, 0x30, 0x05, 0x01,
+    0x05, 0x09, 0x19, 0x01, 0x29, 0x0a, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01,
+    0x95, 0x0a, 0x55, 0x00, 0x65, 0x00, 0x81, 0x02, 0x05, 0x09, 0x19, 0x0b,
+    0x29, 0x0e, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x04, 0x81, 0x02,
+    0x75, 0x01, 0x95, 0x02, 0x81, 0x03, 0x0b, 0x01, 0x00, 0x01, 0x00, 0xa1,
+    0x00, 0x0b, 0x30, 0x00, 0x01, 0x00, 0x0b, 0x31, 0x00, 0x01, 0x00, 0x0b,
+    0x32, 0x00, 0x01, 0x00, 0x0b, 0x35, 0x00, 0x01, 0x00, 0x15, 0x00, 0x27,
+    0xff, 0xff, 0x00, 0x00, 0x75, 0x10, 0x95, 0x04, 0x81, 0x02, 0xc0, 0x0b,
+    0x39, 0x00, 0x01, 0x00, 0x15, 0x00, 0x25, 0x07, 0x35, 0x00, 0x46, 0x3b,
+    0x01, 0x65, 0x14, 0x75, 0x04, 0x95, 0x01, 0x81, 0x02, 0x05, 0x09, 0x19,
+    0x0f, 0x29, 0x12, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x04, 0x81,
+    0x02, 0x75, 0x08, 0x95, 0x34, 0x81, 0x03, 0x06, 0x00, 0xff, 0x85, 0x21,
+    0x09, 0x01, 0x75, 0x08, 0x95, 0x3f, 0x81, 0x03, 0x85, 0x81, 0x09, 0x02,
+    0x75, 0x08, 0x95, 0x3f, 0x81, 0x03, 0x85, 0x01, 0x09, 0x03, 0x75, 0x08,
+    0x95, 0x3f, 0x91, 0x83, 0x85, 0x10, 0x09, 0x04, 0x75, 0x08, 0x95, 0x3f,
+    0x91, 0x83, 0x85, 0x80, 0x09, 0x05, 0x75, 0x08, 0x95, 0x3f, 0x91, 0x83,
+    0x85, 0x82, 0x09, 0x06, 0x75, 0x08, 0x95, 0x3f, 0x91, 0x83, 0xc0,
+];
+
+/// HID report descriptor of the Joy-Con (L), which declares the vendor reports and the simple HID
+/// input report in a single collection.
+#[rustfmt::skip]
+const JOY_CON_L_HID_DESCRIPTOR: [u8; 170] = [
+    0x05, 0x01, 0x09, 0x05, 0xa1, 0x01, 0x06, 0x01, 0xff, 0x85, 0x21, 0x09,
+    0x21, 0x75, 0x08, 0x95, 0x30, 0x81, 0x02, 0x85, 0x30, 0x09, 0x30, 0x75,
+    0x08, 0x95, 0x30, 0x81, 0x02, 0x85, 0x31, 0x09, 0x31, 0x75, 0x08, 0x96,
+    0x69, 0x01, 0x81, 0x02, 0x85, 0x32, 0x09, 0x32, 0x75, 0x08, 0x96, 0x69,
+    0x01, 0x81, 0x02, 0x85, 0x33, 0x09, 0x33, 0x75, 0x08, 0x96, 0x69, 0x01,
+    0x81, 0x02, 0x85, 0x3f, 0x05, 0x09, 0x19, 0x01, 0x29, 0x10, 0x15, 0x00,
+    0x25, 0x01, 0x75, 0x01, 0x95, 0x10, 0x81, 0x02, 0x05, 0x01, 0x09, 0x39,
+    0x15, 0x00, 0x25, 0x07, 0x75, 0x04, 0x95, 0x01, 0x81, 0x42, 0x05, 0x09,
+    0x75, 0x04, 0x95, 0x01, 0x81, 0x01, 0x05, 0x01, 0x09, 0x30, 0x09, 0x31,
+    0x09, 0x33, 0x09, 0x34, 0x16, 0x00, 0x00, 0x27, 0xff, 0xff, 0x00, 0x00,
+    0x75, 0x10, 0x95, 0x04, 0x81, 0x02, 0x06, 0x01, 0xff, 0x85, 0x01, 0x09,
+    0x01, 0x75, 0x08, 0x95, 0x30, 0x91, 0x02, 0x85, 0x10, 0x09, 0x10, 0x75,
+    0x08, 0x95, 0x30, 0x91, 0x02, 0x85, 0x11, 0x09, 0x11, 0x75, 0x08, 0x95,
+    0x30, 0x91, 0x02, 0x85, 0x12, 0x09, 0x12, 0x75, 0x08, 0x95, 0x30, 0x91,
+    0x02, 0xc0,
+];
+
+/// Returns the service record of the given controller type.
+fn service_record(controller_type: ControllerType) -> String {
+    let hid = sdp::HidAttributes {
+        device_release: controller_type.hid_device_release(),
+        parser_version: 0x0111,
+        subclass: 0x08,
+        country_code: 0x00,
+        virtual_cable: true,
+        reconnect_initiate: true,
+        report_descriptor: controller_type.hid_descriptor().to_vec(),
+        language: 0x0409,
+        profile_version: controller_type.hid_profile_version(),
+        supervision_timeout: HID_FLUSH_TIMEOUT,
+        normally_connectable: false,
+        boot_device: true,
+        ssr_host_max_latency: 0x0640,
+        ssr_host_min_timeout: 0x0320,
+    };
+
+    sdp::Record::new()
+        .service_classes(&[sdp::HID])
+        .protocol_descriptors(CTR_PSM)
+        .browse_groups(&[sdp::PUBLIC_BROWSE_GROUP])
+        .language_base(0x656e, 0x006a, 0x0100)
+        .profile_descriptor(sdp::HID, 0x0100)
+        .additional_protocol_descriptors(ITR_PSM)
+        .name(SERVICE_NAME)
+        .description("Gamepad")
+        .provider(SERVICE_PROVIDER)
+        .hid(&hid)
+        .render()
+}
+
+const RECV_MTU: usize = 50;
+const EVENTS_CAPACITY: usize = 64;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const EPERM: i32 = 1;
+const ALIAS_RETRIES: u32 = 3;
+const ALIAS_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+const POWER_CYCLE_TIMEOUT: Duration = Duration::from_secs(10);
+const STACK_RESTART_TIMEOUT: Duration = Duration::from_secs(30);
+const CAP_NET_ADMIN: u32 = 12;
+/// Time in which report hooks are expected to return.
+pub const HOOK_TIME_BUDGET: Duration = Duration::from_millis(1);
+/// Default time in which a frame is expected from the device while connected.
+pub const DEFAULT_RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Default number of times the class of the adapter is reapplied in a row while pairing.
+pub const DEFAULT_CLASS_RETRIES: u32 = 5;
+/// Default interval between checks of the class of the adapter while pairing.
+pub const DEFAULT_CLASS_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Enumeration for controller types.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "SCREAMING_SNAKE_CASE")
+)]
+pub enum ControllerType {
+    /// Represents the Joy-Con (L).
+    JoyConL,
+    /// Represents the Joy-Con (R).
+    JoyConR,
+    /// Represents the Nintendo Switch Pro Controller.
+    ProController,
+    /// Represents the Nintendo Entertainment System controller for Nintendo Switch Online.
+    NesController,
+    /// Represents the Super Nintendo Entertainment System controller for Nintendo Switch Online.
+    SnesController,
+    /// Represents the Nintendo 64 controller for Nintendo Switch Online.
+    N64Controller,
+}
+
+impl ControllerType {
+    /// Returns the Bluetooth controller name.
+    pub fn name(&self) -> &str {
+        match self {
+            ControllerType::JoyConL => "Joy-Con (L)",
+            ControllerType::JoyConR => "Joy-Con (R)",
+            ControllerType::ProController => "Pro Controller",
+            ControllerType::NesController => "NES Controller",
+            ControllerType::SnesController => "SNES Controller",
+            ControllerType::N64Controller => "N64 Controller",
+        }
+    }
+
+    /// Returns the HID report descriptor advertised in the service record. Descriptors of the
+    /// Joy-Con (R) and the Nintendo Switch Online controllers are not captured yet, so the
+    /// Joy-Con (R) advertises the one of the Joy-Con (L), which declares the same reports, and
+    /// the others advertise the one of the Pro Controller.
+    pub fn hid_descriptor(&self) -> &'static [u8] {
+        match self {
+            ControllerType::JoyConL | ControllerType::JoyConR => &JOY_CON_L_HID_DESCRIPTOR,
+            ControllerType::ProController
+            | ControllerType::NesController
+            | ControllerType::SnesController
+            | ControllerType::N64Controller => &PRO_CONTROLLER_HID_DESCRIPTOR,
+        }
+    }
+
+    /// Returns the HID device release number advertised in the service record, which is 1.0
+    /// for all captured controllers.
+    pub fn hid_device_release(&self) -> u16 {
+        HID_DEVICE_RELEASE
+    }
+
+    /// Returns the HID profile version advertised in the service record, which is 1.0 for all
+    /// captured controllers.
+    pub fn hid_profile_version(&self) -> u16 {
+        HID_PROFILE_VERSION
+    }
+
+    /// Returns the SDP service record registered in pairing.
+    pub fn service_record(&self) -> String {
+        service_record(*self)
+    }
+
+    /// Returns the controller type byte in device info.
+    pub fn id(&self) -> u8 {
+        match self {
+            ControllerType::JoyConL => 0x01,
+            ControllerType::JoyConR => 0x02,
+            ControllerType::ProController => 0x03,
+            ControllerType::NesController => 0x09,
+            ControllerType::SnesController => 0x0B,
+            ControllerType::N64Controller => 0x0C,
+        }
+    }
+
+    /// Returns if the controller has the given stick.
+    pub fn has_stick(&self, stick: Stick) -> bool {
+        match self {
+            ControllerType::JoyConL => stick == Stick::Left,
+            ControllerType::JoyConR => stick == Stick::Right,
+            ControllerType::ProController => true,
+            ControllerType::N64Controller => stick == Stick::Left,
+            ControllerType::NesController | ControllerType::SnesController => false,
+        }
+    }
+
+    /// Returns if the controller has the given button.
+    pub fn has_button(&self, button: Button) -> bool {
+        use Button::*;
+
+        match self {
+            ControllerType::JoyConL => matches!(
+                button,
+                Down | Up | Right | Left | L | Zl | Minus | LeftStick | Capture | Sr | Sl
+            ),
+            ControllerType::JoyConR => matches!(
+                button,
+                Y | X | B | A | R | Zr | Plus | RightStick | Home | Sr | Sl
+            ),
+            ControllerType::ProController => {
+                !matches!(button, Sr | Sl | CUp | CDown | CLeft | CRight)
+            }
+            ControllerType::NesController => {
+                matches!(
+                    button,
+                    B | A | L | R | Minus | Plus | Down | Up | Right | Left
+                )
+            }
+            ControllerType::SnesController => matches!(
+                button,
+                Y | X | B | A | L | R | Zl | Zr | Minus | Plus | Down | Up | Right | Left
+            ),
+            ControllerType::N64Controller => matches!(
+                button,
+                B | A
+                    | L
+                    | R
+                    | Zr
+                    | Plus
+                    | Home
+                    | Capture
+                    | Down
+                    | Up
+                    | Right
+                    | Left
+                    | CUp
+                    | CDown
+                    | CLeft
+                    | CRight
+            ),
+        }
+    }
+
+    /// Returns if the controller has the IMU.
+    pub fn has_imu(&self) -> bool {
+        !matches!(
+            self,
+            ControllerType::NesController
+                | ControllerType::SnesController
+                | ControllerType::N64Controller
+        )
+    }
+
+    /// Returns if the controller is a Joy-Con.
+    pub fn is_joy_con(&self) -> bool {
+        matches!(self, ControllerType::JoyConL | ControllerType::JoyConR)
+    }
+}
+
+impl Display for ControllerType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for ControllerType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "JOY_CON_L" => Ok(ControllerType::JoyConL),
+            "JOY_CON_R" => Ok(ControllerType::JoyConR),
+            "PRO_CONTROLLER" => Ok(ControllerType::ProController),
+            "NES_CONTROLLER" => Ok(ControllerType::NesController),
+            "SNES_CONTROLLER" => Ok(ControllerType::SnesController),
+            "N64_CONTROLLER" => Ok(ControllerType::N64Controller),
+            _ => Err(Error::new(
+                ErrorKind::Protocol,
+                "unknown controller type".into(),
+            )),
+        }
+    }
+}
+
+/// Enumeration for reasons why a `Controller` stops running.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum StopReason {
+    /// Represents the device closed the connection.
+    Closed,
+    /// Represents no frame is received from the device in the receive timeout, like when the
+    /// device runs out of battery or goes out of range without closing the connection.
+    Timeout,
+    /// Represents the device requested the HCI state, which disconnects the controller.
+    HciState(HciState),
+    /// Represents the connection failed on an IO or protocol error. The error is returned
+    /// instead where a result is expected.
+    Failed,
+    /// Represents the run is cancelled.
+    Cancelled,
+    /// Represents the device reset the pairing info, so it does not expect the controller to
+    /// reconnect.
+    PairingReset,
+    /// Represents the controller disconnected since no input changed in the idle timeout.
+    Idle,
+    /// Represents the connection is dropped on purpose by `simulate_disconnect`.
+    Simulated,
+    /// Represents the baseband link to the device is lost, which is noticed before the channels
+    /// time out.
+    LinkLost,
+    /// Represents the adapter is removed, like when the dongle is unplugged.
+    AdapterRemoved,
+}
+
+impl Display for StopReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Closed => write!(f, "connection closed"),
+            StopReason::Timeout => write!(f, "receive timeout"),
+            StopReason::HciState(state) => write!(f, "HCI state {}", state),
+            StopReason::Failed => write!(f, "connection failed"),
+            StopReason::Cancelled => write!(f, "cancelled"),
+            StopReason::PairingReset => write!(f, "pairing reset"),
+            StopReason::Idle => write!(f, "idle"),
+            StopReason::Simulated => write!(f, "simulated disconnection"),
+            StopReason::LinkLost => write!(f, "link lost"),
+            StopReason::AdapterRemoved => write!(f, "adapter removed"),
+        }
+    }
+}
+
+/// Represents the policy of `Controller::run_forever` to reconnect to the device.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f32,
+    jitter: f32,
+    max_attempts: Option<u32>,
+    pair_fallback: bool,
+    reset_after: Option<u32>,
+    ping_interval: Duration,
+    ping_timeout: Duration,
+}
+
+impl ReconnectPolicy {
+    /// Creates a `ReconnectPolicy` which retries forever from a backoff of 1 second up to 1
+    /// minute, and never falls back to pairing.
+    pub fn new() -> Self {
+        ReconnectPolicy::default()
+    }
+
+    /// Sets the backoff before the first reconnection attempt.
+    pub fn initial_backoff(mut self, backoff: Duration) -> Self {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    /// Sets the maximum backoff between reconnection attempts.
+    pub fn max_backoff(mut self, backoff: Duration) -> Self {
+        self.max_backoff = backoff;
+        self
+    }
+
+    /// Sets the multiplier of the backoff after each failed attempt, which is at least 1.
+    pub fn multiplier(mut self, multiplier: f32) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// Sets the jitter of backoffs as a fraction in 0 to 1, where a backoff varies by the
+    /// fraction of itself in both ways.
+    pub fn jitter(mut self, jitter: f32) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets the number of failed reconnection attempts before giving up, or `None` to retry
+    /// forever.
+    pub fn max_attempts(mut self, attempts: Option<u32>) -> Self {
+        self.max_attempts = attempts;
+        self
+    }
+
+    /// Sets if the controller falls back to pairing after the reconnection attempts are used up.
+    pub fn pair_fallback(mut self, fallback: bool) -> Self {
+        self.pair_fallback = fallback;
+        self
+    }
+
+    /// Sets the number of reconnection attempts failing in a row on adapter errors, after which
+    /// the adapter is power-cycled, or `None` to never power-cycle. Attempts timing out, like
+    /// when the device is asleep, are not counted.
+    pub fn reset_after(mut self, attempts: Option<u32>) -> Self {
+        self.reset_after = attempts;
+        self
+    }
+
+    /// Sets the interval of checking the connection.
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    /// Sets the timeout of checking the connection.
+    pub fn ping_timeout(mut self, timeout: Duration) -> Self {
+        self.ping_timeout = timeout;
+        self
+    }
+
+    fn next_backoff(&self, backoff: Duration) -> Duration {
+        backoff.mul_f32(self.multiplier).min(self.max_backoff)
+    }
+
+    fn jittered(&self, backoff: Duration) -> Duration {
+        // Jitter only decorrelates controllers, where the clock is random enough
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let random = nanos as f32 / 1_000_000_000.0;
+
+        backoff.mul_f32(1.0 + self.jitter * (random * 2.0 - 1.0))
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+            pair_fallback: false,
+            reset_after: None,
+            ping_interval: Duration::from_secs(5),
+            ping_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Enumeration for reasons why a `Controller` is disconnected.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum DisconnectReason {
+    /// Represents the device closed the connection.
+    Closed,
+    /// Represents the connection is disconnected on request.
+    Requested,
+    /// Represents the connection failed.
+    Failed,
+    /// Represents no frame is received from the device in the receive timeout.
+    Timeout,
+    /// Represents the controller disconnected since no input changed in the idle timeout.
+    Idle,
+    /// Represents the connection is dropped on purpose by `simulate_disconnect`.
+    Simulated,
+    /// Represents the baseband link to the device is lost.
+    LinkLost,
+    /// Represents the adapter is removed.
+    AdapterRemoved,
+}
+
+impl Display for DisconnectReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::Closed => write!(f, "connection closed"),
+            DisconnectReason::Requested => write!(f, "requested"),
+            DisconnectReason::Failed => write!(f, "connection failed"),
+            DisconnectReason::Timeout => write!(f, "receive timeout"),
+            DisconnectReason::Idle => write!(f, "idle"),
+            DisconnectReason::Simulated => write!(f, "simulated"),
+            DisconnectReason::LinkLost => write!(f, "link lost"),
+            DisconnectReason::AdapterRemoved => write!(f, "adapter removed"),
+        }
+    }
+}
+
+/// Enumeration for connection statuses of a `Controller`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ConnectionStatus {
+    /// Represents the controller is neither pairing nor connected.
+    Idle,
+    /// Represents the controller is waiting for a device to pair.
+    Pairing,
+    /// Represents the controller is connected to the device since the instant.
+    Connected {
+        switch_addr: Address,
+        since: Instant,
+    },
+    /// Represents the controller is connecting to a previously paired device, or waiting for it
+    /// to reconnect.
+    Reconnecting,
+    /// Represents the controller is disconnected for the reason.
+    Disconnected { reason: DisconnectReason },
+}
+
+impl Display for ConnectionStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionStatus::Idle => write!(f, "idle"),
+            ConnectionStatus::Pairing => write!(f, "pairing"),
+            ConnectionStatus::Connected { switch_addr, .. } => {
+                write!(f, "connected to {}", switch_addr)
+            }
+            ConnectionStatus::Reconnecting => write!(f, "reconnecting"),
+            ConnectionStatus::Disconnected { reason } => write!(f, "disconnected ({})", reason),
+        }
+    }
+}
+
+/// Represents the parts of a `Controller` taken by `Controller::into_parts`, which service the
+/// device in a run loop of the caller.
+///
+/// The caller owns the channels and the protocol: output reports received on the ITR channel are
+/// fed to `Protocol::handle_incoming`, and the returned frames and the input reports from
+/// `Protocol::poll_tick` are sent on the ITR channel, at `Protocol::next_tick` at the latest.
+///
+/// The adapter settings changed by pairing are restored when pairing returns, so no restoring is
+/// left to the parts. The rest of the controller, like the service record, the listeners of
+/// reconnections and the settings, is kept in the parts until `Controller::from_parts`. Dropping
+/// the parts closes the channels and unregisters the service record, where the device stays
+/// paired with the adapter, and dropping the channels alone closes the connection.
+pub struct ControllerParts {
+    /// Represents the protocol.
+    pub protocol: Protocol,
+    /// Represents the CTR channel, or `None` if not connected.
+    pub ctr_channel: Option<HidChannel>,
+    /// Represents the ITR channel, or `None` if not connected.
+    pub itr_channel: Option<HidChannel>,
+    /// Represents the address of the last connected device if any.
+    pub switch_addr: Option<Address>,
+    controller: Controller,
+}
+
+/// Represents a device paired by a `Controller`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PairedDevice {
+    /// Represents the address.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_address"))]
+    pub address: Address,
+    /// Represents the name, or `None` if unknown.
+    pub name: Option<String>,
+    /// Represents the alias, or `None` if unknown.
+    pub alias: Option<String>,
+    /// Represents the name of the adapter paired with.
+    pub adapter: String,
+    /// Represents the time when the device is paired.
+    pub paired_at: SystemTime,
+    /// Represents if previously paired devices are removed from the adapter.
+    pub removed_previous: bool,
+}
+
+impl Display for PairedDevice {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} ({})", name, self.address)?,
+            None => write!(f, "{}", self.address)?,
+        }
+        write!(f, " on adapter {}", self.adapter)
+    }
+}
+
+#[cfg(feature = "serde")]
+fn serialize_address<S: serde::Serializer>(
+    address: &Address,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(address)
+}
+
+/// Enumeration for actions of a report hook on a frame.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum HookAction {
+    /// Represents passing the frame.
+    Continue,
+    /// Represents dropping the frame.
+    Drop,
+    /// Represents replacing the frame with the data.
+    Replace(Vec<u8>),
+}
+
+type ReportHook = Box<dyn Fn(&[u8]) -> HookAction + Send + Sync>;
+
+/// Represents the statistics of a `Controller`.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SessionStats {
+    /// Represents the number of sent input reports, including subcommand replies.
+    pub reports_sent: u64,
+    /// Represents the number of received output reports.
+    pub output_reports_received: u64,
+    /// Represents the number of answered subcommands by subcommand.
+    pub subcommands_answered: BTreeMap<u8, u64>,
+    /// Represents the number of dropped malformed output reports.
+    pub malformed_frames: u64,
+    /// Represents the number of input reports dropped since they kept failing to send.
+    pub dropped_reports: u64,
+    /// Represents the number of received rumble frames.
+    pub rumble_frames: u64,
+    /// Represents the number of connections rejected from devices other than the connected one.
+    pub rejected_peers: u64,
+    /// Represents the number of rumble frames which are not recorded as events.
+    pub suppressed_rumble_frames: u64,
+    /// Represents the rate of periodic input reports in Hz, or `None` if not streaming.
+    pub report_rate: Option<f32>,
+    /// Represents the time since connected, or `None` if not connected.
+    pub uptime: Option<Duration>,
+    /// Represents if the kernel refused the flush timeout of a HID channel, which retransmits
+    /// stale input reports then.
+    pub flush_timeout_refused: bool,
+    /// Represents the RSSI of the connection in dBm at the last refresh, or `None` if unknown.
+    pub rssi: Option<i16>,
+    /// Represents the link quality of the connection in 0 to 255 at the last refresh, or `None`
+    /// if unknown.
+    pub link_quality: Option<u8>,
+}
+
+/// Represents the signal quality of the connection refreshed in the background.
+#[derive(Debug, Clone, Copy, Default)]
+struct Signal {
+    rssi: Option<i16>,
+    link_quality: Option<u8>,
+}
+
+/// Refreshes the signal quality of the connected device of the given adapter at the interval
+/// until the signal is dropped with the controller. The refresh runs apart from the run, so it
+/// never delays input reports.
+async fn refresh_signal(
+    adapter: Adapter,
+    status: watch::Receiver<ConnectionStatus>,
+    signal: Weak<Mutex<Signal>>,
+    interval: Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let switch_addr = match *status.borrow() {
+            ConnectionStatus::Connected { switch_addr, .. } => Some(switch_addr),
+            _ => None,
+        };
+        let refreshed = match switch_addr.map(|addr| Device::new(&adapter, addr)) {
+            Some(Ok(device)) => Signal {
+                rssi: device.rssi().await.unwrap_or_default(),
+                link_quality: device.link_quality().await,
+            },
+            _ => Signal::default(),
+        };
+        match signal.upgrade() {
+            Some(signal) => *signal.lock().unwrap_or_else(|e| e.into_inner()) = refreshed,
+            None => return,
+        }
+    }
+}
+
+/// Represents the counters of `SessionStats` updated by the controller.
+struct StatsCounters {
+    reports_sent: AtomicU64,
+    output_reports_received: AtomicU64,
+    subcommands_answered: Vec<AtomicU64>,
+    malformed_frames: AtomicU64,
+    dropped_reports: AtomicU64,
+    rejected_peers: AtomicU64,
+    // Kept by reset, which is a fact of the sockets rather than a counter
+    flush_timeout_refused: AtomicBool,
+}
+
+impl StatsCounters {
+    fn new() -> Self {
+        StatsCounters {
+            reports_sent: AtomicU64::new(0),
+            output_reports_received: AtomicU64::new(0),
+            subcommands_answered: (0..=u8::MAX).map(|_| AtomicU64::new(0)).collect(),
+            malformed_frames: AtomicU64::new(0),
+            dropped_reports: AtomicU64::new(0),
+            rejected_peers: AtomicU64::new(0),
+            flush_timeout_refused: AtomicBool::new(false),
+        }
+    }
+
+    fn record_sent(&self, sent: Sent) {
+        match sent {
+            Sent::Report => self.reports_sent.fetch_add(1, Ordering::Relaxed),
+            Sent::Dropped => self.dropped_reports.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    fn record_incoming<T>(&self, frame: &[u8], result: &Result<T>) {
+        self.output_reports_received.fetch_add(1, Ordering::Relaxed);
+        match result {
+            Ok(_) => {
+                if let Ok(Output {
+                    t: Type::Subcommand,
+                    subcommand: Some(id),
+                    ..
+                }) = Output::try_from(frame)
+                {
+                    self.subcommands_answered[id as usize].fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Err(_) => {
+                self.malformed_frames.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn reset(&self) {
+        for counter in [
+            &self.reports_sent,
+            &self.output_reports_received,
+            &self.malformed_frames,
+            &self.dropped_reports,
+            &self.rejected_peers,
+        ]
+        .into_iter()
+        .chain(self.subcommands_answered.iter())
+        {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Represents a receiver of events from the device.
+#[derive(Debug)]
+pub struct Events {
+    receiver: broadcast::Receiver<SwitchEvent>,
+    dropped: u64,
+}
+
+impl Events {
+    /// Receives the next event, or `None` if the controller is dropped. The oldest events are
+    /// dropped if events are not received in time.
+    pub async fn recv(&mut self) -> Option<SwitchEvent> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(n)) => self.dropped += n,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Returns the number of dropped events.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[derive(Debug, Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+/// Represents a token which cancels the running operation of a `Controller`.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    state: Arc<CancelState>,
+}
+
+impl CancelToken {
+    /// Creates a `CancelToken`.
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// Cancels the running operation.
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::SeqCst);
+        self.state.notify.notify_waiters();
+    }
+
+    /// Returns if the token is cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the token is cancelled.
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.state.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Represents the adapter settings changed by pairing.
+#[derive(Debug, Clone, PartialEq)]
+struct AdapterSettings {
+    alias: String,
+    class: u32,
+    discoverable: bool,
+    discoverable_timeout: u32,
+    pairable: bool,
+    scan: Option<ScanParameters>,
+}
+
+/// Represents an adapter whose settings are changed by pairing.
+trait SettingsAdapter: Clone + Send + Sync + 'static {
+    /// Returns the name of the adapter.
+    fn name(&self) -> &str;
+
+    /// Saves the settings of the adapter, including the scan parameters if required. The scan
+    /// parameters are saved in best effort, where `None` is kept if they cannot be read.
+    fn save_settings(&self, scan: bool) -> impl Future<Output = Result<AdapterSettings>> + Send;
+
+    /// Restores the given settings to the adapter.
+    fn restore_settings(
+        &self,
+        settings: &AdapterSettings,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+impl SettingsAdapter for Adapter {
+    fn name(&self) -> &str {
+        Adapter::name(self)
+    }
+
+    async fn save_settings(&self, scan: bool) -> Result<AdapterSettings> {
+        let scan = if scan {
+            let adapter = self.clone();
+            match unblock(move || adapter.scan_parameters()).await {
+                Ok(scan) => Some(scan),
+                Err(e) => {
+                    warn!("{}", e);
+
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        Ok(AdapterSettings {
+            alias: self.alias().await?,
+            class: self.class().await?,
+            discoverable: self.is_discoverable().await?,
+            discoverable_timeout: self.discoverable_timeout().await?,
+            pairable: self.is_pairable().await?,
+            scan,
+        })
+    }
+
+    async fn restore_settings(&self, settings: &AdapterSettings) -> Result<()> {
+        self.set_discoverable_timeout(settings.discoverable_timeout)
+            .await?;
+        self.set_discoverable(settings.discoverable).await?;
+        self.set_pairable(settings.pairable).await?;
+        self.set_alias(settings.alias.clone()).await?;
+        let (class, scan) = (settings.class, settings.scan);
+        let adapter = self.clone();
+        unblock(move || {
+            adapter.set_class(class)?;
+            if let Some(scan) = &scan {
+                debug!("restore scan parameters: {}", scan);
+                adapter.set_scan_parameters(scan)?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// Represents a guard of the adapter settings changed by pairing. The settings are restored by
+/// `restore`, or restored in the background on drop.
+struct AdapterGuard<A: SettingsAdapter = Adapter> {
+    adapter: A,
+    settings: Option<AdapterSettings>,
+}
+
+impl<A: SettingsAdapter> AdapterGuard<A> {
+    /// Saves the settings of the given adapter, including the scan parameters if required.
+    async fn save(adapter: &A, scan: bool) -> Result<Self> {
+        Ok(AdapterGuard {
+            adapter: adapter.clone(),
+            settings: Some(adapter.save_settings(scan).await?),
+        })
+    }
+
+    /// Returns if the scan parameters are saved.
+    fn has_scan(&self) -> bool {
+        self.settings
+            .as_ref()
+            .is_some_and(|settings| settings.scan.is_some())
+    }
+
+    /// Restores the settings.
+    async fn restore(mut self) -> Result<()> {
+        match self.settings.take() {
+            Some(settings) => self.adapter.restore_settings(&settings).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl<A: SettingsAdapter> Drop for AdapterGuard<A> {
+    fn drop(&mut self) {
+        if let Some(settings) = self.settings.take() {
+            // Restoring is async, which is left to the runtime if any
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    let adapter = self.adapter.clone();
+                    runtime.spawn(async move {
+                        if let Err(e) = adapter.restore_settings(&settings).await {
+                            warn!("{}", e);
+                        }
+                    });
+                }
+                Err(_) => warn!("Cannot restore settings of adapter {}", self.adapter.name()),
+            }
+        }
+    }
+}
+
+/// Represents a handle to update the input state of a `Controller` from other tasks or threads.
+///
+/// Updates only change the shared state, and input reports always carry the newest state. Dropping
+/// handles does not affect the controller.
+#[derive(Clone)]
+pub struct ControllerHandle {
+    protocol: Arc<Mutex<Protocol>>,
+    changed: Arc<Notify>,
+}
+
+impl ControllerHandle {
+    fn new(protocol: Protocol) -> Self {
+        ControllerHandle {
+            protocol: Arc::new(Mutex::new(protocol)),
+            changed: Arc::new(Notify::new()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Protocol> {
+        // The protocol is never left inconsistent by a panic
+        self.protocol.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn update<T>(&self, f: impl FnOnce(&mut Protocol) -> Result<T>) -> Result<T> {
+        let result = f(&mut self.lock());
+        self.changed.notify_one();
+
+        result
+    }
+
+    /// Returns the state of buttons.
+    pub fn buttons(&self) -> ButtonState {
+        let protocol = self.lock();
+
+        protocol
+            .orientation()
+            .logical_buttons(protocol.controller_type(), protocol.buttons())
+    }
+
+    /// Presses the given button.
+    pub fn press(&self, button: Button) -> Result<()> {
+        self.update(|protocol| protocol.set_button(physical_button(protocol, button)?, true))
+    }
+
+    /// Releases the given button.
+    pub fn release(&self, button: Button) -> Result<()> {
+        self.update(|protocol| protocol.set_button(physical_button(protocol, button)?, false))
+    }
+
+    /// Sets if the given buttons are pressed at once.
+    pub fn set_buttons(&self, buttons: &[(Button, bool)]) -> Result<()> {
+        self.update(|protocol| {
+            let buttons = buttons
+                .iter()
+                .map(|(button, pressed)| Ok((physical_button(protocol, *button)?, *pressed)))
+                .collect::<Result<Vec<_>>>()?;
+
+            protocol.set_buttons(&buttons)
+        })
+    }
+
+    /// Presses the given button for the duration.
+    pub fn press_for(&self, button: Button, duration: Duration) -> Result<()> {
+        self.update(|protocol| {
+            protocol.press_for(physical_button(protocol, button)?, duration, Instant::now())
+        })
+    }
+
+    /// Presses the given button for one input report.
+    pub fn tap(&self, button: Button) -> Result<()> {
+        self.update(|protocol| protocol.tap(physical_button(protocol, button)?, Instant::now()))
+    }
+
+    /// Sets the turbo rate of the given button in Hz, or disables the turbo if `None`.
+    pub fn set_turbo(&self, button: Button, rate: Option<f32>) -> Result<()> {
+        self.update(|protocol| protocol.set_turbo(physical_button(protocol, button)?, rate))
+    }
+
+    /// Sets the rate of periodic input reports.
+    pub fn set_report_rate(&self, rate: ReportRate) {
+        let _ = self.update(|protocol| {
+            protocol.set_report_rate(rate, Instant::now());
+
+            Ok(())
+        });
+    }
+
+    /// Returns the position of the given stick.
+    pub fn stick(&self, stick: Stick) -> StickPosition {
+        let protocol = self.lock();
+        let (orientation, controller_type) = (protocol.orientation(), protocol.controller_type());
+
+        match orientation.physical_stick(controller_type, stick) {
+            Some(physical) => {
+                orientation.logical_position(controller_type, protocol.stick(physical))
+            }
+            None => StickPosition::default(),
+        }
+    }
+
+    /// Sets the position of the given stick from normalized values in -1 to 1.
+    pub fn set_stick(&self, stick: Stick, x: f32, y: f32) -> Result<()> {
+        self.update(|protocol| {
+            let (orientation, controller_type) =
+                (protocol.orientation(), protocol.controller_type());
+            let physical = orientation
+                .physical_stick(controller_type, stick)
+                .ok_or_else(|| orientation.unavailable(controller_type, stick))?;
+            let (x, y) = orientation.physical_position(controller_type, (x, y));
+
+            protocol.set_stick_normalized(physical, x, y)
+        })
+    }
+
+    /// Sets the positions of both sticks from normalized values in -1 to 1 at once.
+    pub fn set_sticks(&self, left: (f32, f32), right: (f32, f32)) -> Result<()> {
+        self.update(|protocol| {
+            let (orientation, controller_type) =
+                (protocol.orientation(), protocol.controller_type());
+            let config = protocol.stick_config();
+            let mut sticks = vec![];
+            for (stick, position) in [(Stick::Left, left), (Stick::Right, right)] {
+                match orientation.physical_stick(controller_type, stick) {
+                    Some(physical) => {
+                        let (x, y) = orientation.physical_position(controller_type, position);
+                        sticks.push((physical, config.position(x, y)));
+                    }
+                    None if position == (0.0, 0.0) => {}
+                    None => return Err(orientation.unavailable(controller_type, stick)),
+                }
+            }
+
+            protocol.set_sticks(&sticks)
+        })
+    }
+
+    /// Sets the whole input state at once.
+    pub fn set_input(&self, input: &ControllerInput) -> Result<()> {
+        self.update(|protocol| protocol.set_input(&physical_input(protocol, input)?))
+    }
+
+    /// Schedules the input state to be set at the given tick or instant.
+    pub fn schedule(&self, at: impl Into<ScheduleAt>, input: ControllerInput) -> Result<()> {
+        self.update(|protocol| {
+            let input = physical_input(protocol, &input)?;
+
+            protocol.schedule(at.into(), input, Instant::now())
+        })
+    }
+
+    /// Removes all scheduled inputs.
+    pub fn clear_schedule(&self) {
+        let _ = self.update(|protocol| {
+            protocol.clear_schedule();
+
+            Ok(())
+        });
+    }
+
+    /// Returns the tick of the next periodic input report.
+    pub fn current_tick(&self) -> ReportTick {
+        self.lock().current_tick()
+    }
+
+    /// Returns the estimated time of the periodic input report of the given tick.
+    pub fn tick_time(&self, tick: ReportTick) -> Option<Instant> {
+        self.lock().tick_time(tick, Instant::now())
+    }
+
+    /// Returns the estimated tick of the first periodic input report at or after the given time.
+    pub fn time_tick(&self, time: Instant) -> Option<ReportTick> {
+        self.lock().time_tick(time, Instant::now())
+    }
+
+    /// Sets the motion sample in physical units.
+    pub fn set_motion(&self, motion: MotionSample) {
+        let _ = self.update(|protocol| {
+            protocol.set_motion(motion);
+
+            Ok(())
+        });
+    }
+
+    /// Returns the player lights set by the device.
+    pub fn player_lights(&self) -> u8 {
+        self.lock().player_lights()
+    }
+
+    /// Returns a snapshot of the protocol state.
+    pub fn query_state(&self) -> ProtocolStateSnapshot {
+        self.lock().snapshot()
+    }
+
+    /// Sets the whole input state from the given declarative state at once.
+    pub fn apply_state(&self, state: &ControllerState) -> Result<()> {
+        self.update(|protocol| {
+            let input = state.to_input(protocol.controller_type(), protocol.orientation())?;
+
+            protocol.set_input(&physical_input(protocol, &input)?)
+        })
+    }
+
+    /// Replaces the SPI flash, and returns if the device must reconnect to see the changes.
+    pub fn reload_spi(&self, spi_flash: SpiFlash, mode: ReloadMode) -> bool {
+        self.lock().reload_spi_flash(spi_flash, mode)
+    }
+
+    /// Replaces the SPI flash with the image in the given path, and returns if the device must
+    /// reconnect to see the changes. The image is read off the runtime, so a slow read does not
+    /// stall the run loop.
+    pub async fn reload_spi_image<P: AsRef<Path>>(
+        &self,
+        path: P,
+        mode: ReloadMode,
+    ) -> Result<bool> {
+        let path = path.as_ref().to_path_buf();
+        let spi_flash = unblock(move || SpiFlash::from_image(std::fs::read(path)?)).await?;
+
+        Ok(self.reload_spi(spi_flash, mode))
+    }
+
+    /// Watches the SPI image in the given path, and reloads it whenever its modification time
+    /// changes, which is polled at the interval. Images which cannot be loaded are logged and
+    /// skipped. The watch never returns, so it should be spawned and aborted when no longer
+    /// needed.
+    pub async fn watch_spi_image<P: AsRef<Path>>(
+        &self,
+        path: P,
+        mode: ReloadMode,
+        interval: Duration,
+    ) {
+        let path = path.as_ref();
+        let modified = |path: &Path| {
+            let path = path.to_path_buf();
+            unblock(move || Ok(std::fs::metadata(path)?.modified()?))
+        };
+        let mut last = modified(path).await.ok();
+        loop {
+            tokio::time::sleep(interval).await;
+            let current = modified(path).await.ok();
+            if current.is_none() || current == last {
+                continue;
+            }
+            last = current;
+
+            match self.reload_spi_image(path, mode).await {
+                Ok(true) => info!(
+                    "Reload SPI image {}, reconnect to apply cached changes",
+                    path.display()
+                ),
+                Ok(false) => info!("Reload SPI image {}", path.display()),
+                Err(e) => warn!("Skip SPI image {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Sets the battery level and if the battery is charging.
+    pub fn set_battery(&self, level: BatteryLevel, charging: bool) {
+        let _ = self.update(|protocol| {
+            protocol.set_battery(level, charging);
+
+            Ok(())
+        });
+    }
+
+    /// Returns the threshold of changes in rumble frames.
+    pub fn rumble_epsilon(&self) -> f32 {
+        self.lock().rumble_epsilon()
+    }
+
+    /// Sets the threshold of changes in rumble frames.
+    pub fn set_rumble_epsilon(&self, epsilon: f32) {
+        self.lock().set_rumble_epsilon(epsilon)
+    }
+
+    /// Puts the given amiibo on the NFC point.
+    pub fn set_amiibo(&self, amiibo: Amiibo) {
+        let _ = self.update(|protocol| {
+            protocol.set_amiibo(amiibo);
+
+            Ok(())
+        });
+    }
+
+    /// Lifts the amiibo off the NFC point.
+    pub fn remove_amiibo(&self) {
+        let _ = self.update(|protocol| {
+            protocol.remove_amiibo();
+
+            Ok(())
+        });
+    }
+
+    /// Sends a raw input report ahead of the next periodic input report.
+    pub fn send_raw_report(&self, bytes: &[u8]) -> Result<()> {
+        self.update(|protocol| protocol.push_raw_report(bytes))
+    }
+
+    /// Drops the connection of the running controller as if the battery is pulled, and
+    /// reconnects to the device after the given duration if any, where the run resumes. The
+    /// request is ignored if the connection stops before it is served.
+    pub fn simulate_disconnect(&self, reconnect_after: Option<Duration>) {
+        let _ = self.update(|protocol| {
+            protocol.request_disconnect(reconnect_after);
+
+            Ok(())
+        });
+    }
+}
+
+/// Returns the physical button of the given logical button in the orientation of the protocol.
+fn physical_button(protocol: &Protocol, button: Button) -> Result<Button> {
+    let (orientation, controller_type) = (protocol.orientation(), protocol.controller_type());
+
+    orientation
+        .physical_button(controller_type, button)
+        .ok_or_else(|| orientation.unavailable(controller_type, button))
+}
+
+/// Returns the physical input of the given logical input in the orientation of the protocol.
+fn physical_input(protocol: &Protocol, input: &ControllerInput) -> Result<ControllerInput> {
+    protocol
+        .orientation()
+        .physical_input(protocol.controller_type(), input)
+}
+
+/// Represents a builder of `Controller`.
+///
+/// Options overlapping in the SPI flash take precedence over the SPI image: the image is the
+/// base, where the colors and the stick configuration are written over if set. Device info overrides
+/// do not touch the SPI flash, so the device info may disagree with it on purpose.
+#[derive(Debug, Clone)]
+pub struct ControllerBuilder {
+    adapter: String,
+    controller_type: ControllerType,
+    firmware_profile: FirmwareProfile,
+    mcu_version: Option<McuVersion>,
+    reply_delay: Duration,
+    report_rate: ReportRate,
+    mtu: u16,
+    flush_timeout: u16,
+    security_level: SecurityLevel,
+    accept_reconnects: bool,
+    idle_timeout: Option<Duration>,
+    receive_timeout: Option<Duration>,
+    class_retry: (u32, Duration),
+    preflight: bool,
+    pairing_agent: bool,
+    fast_scan: bool,
+    clean_stale_records: bool,
+    signal_interval: Option<Duration>,
+    spoof_address: Option<Address>,
+    restore_address: bool,
+    grip: bool,
+    orientation: Orientation,
+    initial_buttons: Vec<Button>,
+    initial_buttons_release: Option<Duration>,
+    battery: (BatteryLevel, bool),
+    rumble_epsilon: f32,
+    stick_config: Option<StickConfig>,
+    colors: Option<Colors>,
+    spi_image: Option<PathBuf>,
+    device_type_id: Option<u8>,
+    spi_colors: Option<u8>,
+    device_info_unknown: Option<[u8; 2]>,
+    #[cfg(feature = "store")]
+    pairing_store: Option<PathBuf>,
+}
+
+impl ControllerBuilder {
+    /// Creates a `ControllerBuilder` with the given adapter and controller type.
+    pub fn new(adapter: &str, controller_type: ControllerType) -> Self {
+        ControllerBuilder {
+            adapter: adapter.into(),
+            controller_type,
+            firmware_profile: FirmwareProfile::default(),
+            mcu_version: None,
+            reply_delay: DEFAULT_REPLY_DELAY,
+            report_rate: ReportRate::default(),
+            mtu: HID_MTU,
+            flush_timeout: HID_FLUSH_TIMEOUT,
+            security_level: HID_SECURITY_LEVEL,
+            accept_reconnects: false,
+            idle_timeout: None,
+            receive_timeout: Some(DEFAULT_RECEIVE_TIMEOUT),
+            class_retry: (DEFAULT_CLASS_RETRIES, DEFAULT_CLASS_RETRY_INTERVAL),
+            preflight: true,
+            pairing_agent: true,
+            fast_scan: true,
+            clean_stale_records: false,
+            signal_interval: None,
+            spoof_address: None,
+            restore_address: false,
+            grip: false,
+            orientation: Orientation::default(),
+            initial_buttons: vec![],
+            initial_buttons_release: None,
+            battery: (BatteryLevel::default(), false),
+            rumble_epsilon: DEFAULT_RUMBLE_EPSILON,
+            stick_config: None,
+            colors: None,
+            spi_image: None,
+            device_type_id: None,
+            spi_colors: None,
+            device_info_unknown: None,
+            #[cfg(feature = "store")]
+            pairing_store: None,
+        }
+    }
+
+    /// Sets the firmware profile.
+    pub fn firmware_profile(mut self, profile: FirmwareProfile) -> Self {
+        self.firmware_profile = profile;
+        self
+    }
+
+    /// Overrides the NFC/IR MCU firmware version of the firmware profile.
+    pub fn mcu_version(mut self, version: McuVersion) -> Self {
+        self.mcu_version = Some(version);
+        self
+    }
+
+    /// Sets the delay before replying to subcommands.
+    pub fn reply_delay(mut self, delay: Duration) -> Self {
+        self.reply_delay = delay;
+        self
+    }
+
+    /// Sets the rate of periodic input reports.
+    pub fn report_rate(mut self, rate: ReportRate) -> Self {
+        self.report_rate = rate;
+        self
+    }
+
+    /// Sets the L2CAP MTU for sending and receiving on the HID channels, which is 672 bytes in
+    /// real controllers. The negotiated MTU must fit the input reports of the report mode set by
+    /// the device, or the connection fails.
+    pub fn mtu(mut self, mtu: u16) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// Sets the L2CAP flush timeout of the HID channels in 0.625 ms slots, after which stale
+    /// input reports are dropped rather than retransmitted. The flush timeout is
+    /// `HID_FLUSH_TIMEOUT` by default, which is advertised in the service record. The controller
+    /// continues without it if the kernel refuses the option.
+    pub fn flush_timeout(mut self, timeout: u16) -> Self {
+        self.flush_timeout = timeout;
+        self
+    }
+
+    /// Sets the L2CAP security level of the HID channels, which is `HID_SECURITY_LEVEL` by
+    /// default. The device may drop connections right after they are established if the level
+    /// does not match what it expects, so other levels are for experiments only.
+    pub fn security_level(mut self, level: SecurityLevel) -> Self {
+        self.security_level = level;
+        self
+    }
+
+    /// Sets if the listeners are kept for the lifetime of the controller to accept reconnections
+    /// initiated by the device, like when the Nintendo Switch wakes from sleep. `run` accepts
+    /// reconnections from the last connected device only. While serving, a reconnection of the
+    /// device replaces the stale channels, and connections from other devices are shut down at
+    /// once, which are counted in `SessionStats::rejected_peers`.
+    pub fn accept_reconnects(mut self, accept: bool) -> Self {
+        self.accept_reconnects = accept;
+        self
+    }
+
+    /// Sets the idle timeout, after which the controller disconnects if no input changed, like a
+    /// real controller saving its battery. Input reports repeating the same input do not reset
+    /// the timeout. `run` returns `StopReason::Idle`, and `run_forever` reconnects once the input
+    /// changes again. Controllers never disconnect for idleness by default.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Sets the receive timeout, after which the controller disconnects if no frame is received
+    /// from the device, which streams output reports constantly while connected. `run` returns
+    /// `StopReason::Timeout`. The receive timeout is `DEFAULT_RECEIVE_TIMEOUT` by default, and
+    /// `None` disables it.
+    pub fn receive_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.receive_timeout = timeout;
+        self
+    }
+
+    /// Sets the number of times the gamepad class of the adapter is reapplied in a row, and the
+    /// interval between checks of the class while pairing. bluetoothd may rewrite the class, like
+    /// when another profile registers, and the device does not pair with other classes.
+    pub fn class_retry(mut self, retries: u32, interval: Duration) -> Self {
+        self.class_retry = (retries, interval);
+        self
+    }
+
+    /// Sets if the privileges are checked before any socket is bound, which are checked by
+    /// default. Missing privileges fail at once with `ErrorKind::PermissionDenied` rather than
+    /// deep inside pairing. The check can be skipped for containers with unusual capability
+    /// setups, where the capabilities of the process are not what they seem.
+    pub fn preflight(mut self, preflight: bool) -> Self {
+        self.preflight = preflight;
+        self
+    }
+
+    /// Sets if a Bluetooth agent is registered while pairing, which is registered by default. The
+    /// agent accepts pairing requests and the authorization of the HID service from the Nintendo
+    /// Switch, which are left unanswered on systems without an agent. Users running their own
+    /// agent may opt out.
+    pub fn pairing_agent(mut self, pairing_agent: bool) -> Self {
+        self.pairing_agent = pairing_agent;
+        self
+    }
+
+    /// Sets if the adapter scans with `ScanParameters::FAST` while pairing, which scans by
+    /// default. The device finds the adapter sooner, and the previous parameters are restored
+    /// when pairing returns. Connecting to a device does not scan, so it is not affected.
+    pub fn fast_scan(mut self, fast_scan: bool) -> Self {
+        self.fast_scan = fast_scan;
+        self
+    }
+
+    /// Sets if stale service records of controllers, like those left by a previous run which
+    /// crashed, are removed before registering the service record in pairing. They are detected
+    /// and warned regardless, and removed only if set, since they may belong to another running
+    /// controller. See `Controller::stale_service_records`.
+    pub fn clean_stale_records(mut self, clean: bool) -> Self {
+        self.clean_stale_records = clean;
+        self
+    }
+
+    /// Sets the interval at which the RSSI and the link quality of the connection are refreshed
+    /// for `Controller::stats`. They are refreshed in a separate task, and not refreshed by
+    /// default.
+    pub fn signal_interval(mut self, interval: Option<Duration>) -> Self {
+        self.signal_interval = interval;
+        self
+    }
+
+    /// Sets the address of the adapter to the given address when the controller is built, like
+    /// to impersonate a real controller paired with the device. The address is set through the
+    /// vendor-specific HCI commands of Broadcom, Cypress and Intel adapters, and persists after
+    /// the controller is dropped unless it is restored.
+    pub fn spoof_address(mut self, addr: Address) -> Self {
+        self.spoof_address = Some(addr);
+        self
+    }
+
+    /// Sets if the original address of the adapter is restored on `disconnect` after it is
+    /// spoofed by `spoof_address`.
+    pub fn restore_address(mut self, restore: bool) -> Self {
+        self.restore_address = restore;
+        self
+    }
+
+    /// Sets if the Joy-Con is connected to the charging grip.
+    pub fn grip(mut self, grip: bool) -> Self {
+        self.grip = grip;
+        self
+    }
+
+    /// Sets the orientation in which the Joy-Con is held. Buttons and sticks set through the
+    /// controller and its handle are logical inputs of the orientation, which are mapped to
+    /// physical inputs, so input reports are exactly what a real Joy-Con held so sends. Macros,
+    /// scripts and recordings are in physical inputs.
+    pub fn orientation(mut self, orientation: Orientation) -> Self {
+        self.orientation = orientation;
+        self
+    }
+
+    /// Sets the buttons held during the pairing handshake, e.g., L and R to confirm the order in
+    /// the Change Grip/Order menu. The buttons are pressed from the first input report, and
+    /// released once the device assigns a player.
+    pub fn initial_buttons(mut self, buttons: &[Button]) -> Self {
+        self.initial_buttons = buttons.to_vec();
+        self
+    }
+
+    /// Sets the duration after which the initial buttons are released if the device has not
+    /// assigned a player yet.
+    pub fn release_initial_buttons_after(mut self, duration: Duration) -> Self {
+        self.initial_buttons_release = Some(duration);
+        self
+    }
+
+    /// Sets the battery level and if the battery is charging.
+    pub fn battery(mut self, level: BatteryLevel, charging: bool) -> Self {
+        self.battery = (level, charging);
+        self
+    }
+
+    /// Sets the threshold of changes in rumble frames, which is kept across connections. Rumble
+    /// frames are only emitted as events if they differ from the last emitted one beyond the
+    /// threshold.
+    pub fn rumble_epsilon(mut self, epsilon: f32) -> Self {
+        self.rumble_epsilon = epsilon;
+        self
+    }
+
+    /// Sets the stick configuration, which overrides the stick calibration in the SPI image.
+    pub fn stick_config(mut self, config: StickConfig) -> Self {
+        self.stick_config = Some(config);
+        self
+    }
+
+    /// Sets the colors, which override the colors in the SPI image.
+    pub fn colors(mut self, colors: Colors) -> Self {
+        self.colors = Some(colors);
+        self
+    }
+
+    /// Sets the path of an image of the whole SPI flash, which replaces the default factory
+    /// configuration.
+    pub fn spi_image<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.spi_image = Some(path.as_ref().into());
+        self
+    }
+
+    /// Overrides the controller type byte in device info.
+    pub fn device_type_id(mut self, id: u8) -> Self {
+        self.device_type_id = Some(id);
+        self
+    }
+
+    /// Overrides the byte indicating if colors in SPI flash are used in device info.
+    pub fn spi_colors(mut self, spi_colors: u8) -> Self {
+        self.spi_colors = Some(spi_colors);
+        self
+    }
+
+    /// Overrides the unknown bytes in device info.
+    pub fn device_info_unknown(mut self, unknown: [u8; 2]) -> Self {
+        self.device_info_unknown = Some(unknown);
+        self
+    }
+
+    /// Sets the path of the pairing store, where pairings are recorded and `connect_last` looks
+    /// up the device. The store is locked while the controller lives.
+    #[cfg(feature = "store")]
+    pub fn pairing_store<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.pairing_store = Some(path.as_ref().into());
+        self
+    }
+
+    /// Builds the `Controller`.
+    pub async fn build(self) -> Result<Controller> {
+        let session = Session::new().await?;
+        let adapter = session.adapter(&self.adapter)?;
+        check_br_edr(&session, &adapter).await?;
+        debug!(
+            "adapter {}: {}",
+            adapter.name(),
+            bluetooth::hardware_info(&adapter).await
+        );
+        if self.preflight {
+            let addr = adapter.address().await?;
+            unblock(move || preflight(addr, true)).await?;
+        }
+        let original_address = match self.spoof_address {
+            Some(addr) => {
+                let original_address = adapter.address().await?;
+                info!("Spoof address of adapter {} as {}", adapter.name(), addr);
+                let spoofed = adapter.clone();
+                unblock(move || spoofed.set_address(addr)).await?;
+
+                Some(original_address).filter(|_| self.restore_address)
+            }
+            None => None,
+        };
+        let adapter_address = adapter.address().await?;
+
+        // SPI flash
+        let mut spi_flash = match &self.spi_image {
+            Some(path) => {
+                info!("Use SPI image {}", path.display());
+                let path = path.clone();
+                SpiFlash::from_image(unblock(move || Ok(std::fs::read(path)?)).await?)?
+            }
+            None => SpiFlash::new(self.controller_type),
+        };
+        if let Some(colors) = self.colors {
+            spi_flash.set_colors(colors);
+        }
+
+        let mut protocol = Protocol::with_spi_flash(self.controller_type, spi_flash);
+        protocol.set_grip(self.grip)?;
+        protocol.set_orientation(self.orientation)?;
+        protocol.set_initial_buttons(&self.initial_buttons, self.initial_buttons_release)?;
+        protocol.set_battery(self.battery.0, self.battery.1);
+        protocol.set_rumble_epsilon(self.rumble_epsilon);
+        if let Some(config) = self.stick_config {
+            protocol.set_stick_config(config);
+        }
+        protocol.set_firmware_profile(self.firmware_profile);
+        info!("Use firmware profile {}", self.firmware_profile);
+        if let Some(version) = self.mcu_version {
+            protocol.set_mcu_version(version);
+        }
+        protocol.set_reply_delay(self.reply_delay);
+        protocol.set_report_rate(self.report_rate, Instant::now());
+        let mut device_info = protocol.device_info();
+        if let Some(id) = self.device_type_id {
+            device_info.controller_type = id;
+        }
+        if let Some(spi_colors) = self.spi_colors {
+            device_info.spi_colors = spi_colors;
+        }
+        if let Some(unknown) = self.device_info_unknown {
+            device_info.unknown = unknown;
+        }
+        protocol.set_device_info(device_info);
+
+        #[cfg(feature = "store")]
+        let store = match &self.pairing_store {
+            Some(path) => {
+                let path = path.clone();
+                let store = unblock(move || PairingStore::open(path)).await?;
+                Some(Arc::new(Mutex::new(store)))
+            }
+            None => None,
+        };
+
+        let status = watch::channel(ConnectionStatus::Idle).0;
+        let signal = Arc::new(Mutex::new(Signal::default()));
+        if let Some(interval) = self.signal_interval {
+            tokio::spawn(refresh_signal(
+                adapter.clone(),
+                status.subscribe(),
+                Arc::downgrade(&signal),
+                interval,
+            ));
+        }
+
+        Ok(Controller {
+            session,
+            adapter,
+            controller_type: self.controller_type,
+            handle: ControllerHandle::new(protocol),
+            profile_handle: None,
+            mtu: self.mtu,
+            flush_timeout: self.flush_timeout,
+            security_level: self.security_level,
+            accept_reconnects: self.accept_reconnects,
+            idle_timeout: self.idle_timeout,
+            receive_timeout: self.receive_timeout,
+            class_retry: self.class_retry,
+            preflight: self.preflight,
+            pairing_agent: self.pairing_agent,
+            fast_scan: self.fast_scan,
+            clean_stale_records: self.clean_stale_records,
+            service_record_handle: None,
+            adapter_address,
+            original_address,
+            listeners: None,
+            switch_addr: None,
+            ctr_channel: None,
+            itr_channel: None,
+            scheduled: vec![],
+            simulated_reconnect: None,
+            events: broadcast::channel(EVENTS_CAPACITY).0,
+            status,
+            stats: StatsCounters::new(),
+            signal,
+            input_hook: None,
+            output_hook: None,
+            #[cfg(feature = "store")]
+            store,
+            cancel_token: CancelToken::new(),
+        })
+    }
+}
+
+/// Represents an emulated Nintendo Switch controller.
+pub struct Controller {
+    session: Session,
+    adapter: Adapter,
+    controller_type: ControllerType,
+    handle: ControllerHandle,
+    profile_handle: Option<ProfileHandle>,
+    mtu: u16,
+    flush_timeout: u16,
+    security_level: SecurityLevel,
+    accept_reconnects: bool,
+    idle_timeout: Option<Duration>,
+    receive_timeout: Option<Duration>,
+    class_retry: (u32, Duration),
+    preflight: bool,
+    pairing_agent: bool,
+    fast_scan: bool,
+    clean_stale_records: bool,
+    // The handle of the service record registered in the local SDP server, if browsable
+    service_record_handle: Option<u32>,
+    // The address of the adapter to find it again after the Bluetooth stack restarts
+    adapter_address: Address,
+    // The original address of the adapter to restore on disconnect
+    original_address: Option<Address>,
+    listeners: Option<(HidListener, HidListener)>,
+    switch_addr: Option<Address>,
+    ctr_channel: Option<HidChannel>,
+    itr_channel: Option<HidChannel>,
+    scheduled: Vec<OutgoingFrame>,
+    simulated_reconnect: Option<Duration>,
+    events: broadcast::Sender<SwitchEvent>,
+    status: watch::Sender<ConnectionStatus>,
+    stats: StatsCounters,
+    signal: Arc<Mutex<Signal>>,
+    input_hook: Option<ReportHook>,
+    output_hook: Option<ReportHook>,
+    // The pairing store, which is shared with the blocking threads updating it
+    #[cfg(feature = "store")]
+    store: Option<Arc<Mutex<PairingStore>>>,
+    cancel_token: CancelToken,
+}
+
+impl Controller {
+    /// Creates a `Controller` with the given adapter and controller type, and the defaults of
+    /// `ControllerBuilder`.
+    pub async fn new(adapter: &str, controller_type: ControllerType) -> Result<Self> {
+        ControllerBuilder::new(adapter, controller_type)
+            .build()
+            .await
+    }
+
+    /// Creates a `ControllerBuilder` with the given adapter and controller type.
+    pub fn builder(adapter: &str, controller_type: ControllerType) -> ControllerBuilder {
+        ControllerBuilder::new(adapter, controller_type)
+    }
+
+    /// Returns the SDP service record registered in pairing.
+    pub fn service_record(&self) -> String {
+        self.controller_type.service_record()
+    }
+
+    /// Disconnects the paired device and unregisters the service record. The original address of
+    /// the adapter is restored if requested by `ControllerBuilder::restore_address`.
+    pub async fn disconnect(&mut self) -> Result<()> {
+        let mut result = Ok(());
+
+        // Close connection
+        for channel in [self.itr_channel.take(), self.ctr_channel.take()]
+            .into_iter()
+            .flatten()
+        {
+            if let Err(e) = channel.shutdown() {
+                if !matches!(e.kind, ErrorKind::PeerClosed) && result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        self.scheduled.clear();
+
+        // Unregister service record
+        self.profile_handle.take();
+
+        // Restore address
+        if let Some(addr) = self.original_address.take() {
+            info!(
+                "Restore address of adapter {} as {}",
+                self.adapter.name(),
+                addr
+            );
+            let adapter = self.adapter.clone();
+            if let Err(e) = unblock(move || adapter.set_address(addr)).await {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        if let ConnectionStatus::Connected { .. } = self.status() {
+            self.set_status(ConnectionStatus::Disconnected {
+                reason: DisconnectReason::Requested,
+            });
+        }
+
+        result
+    }
+
+    /// Closes the connection and unregisters the service record in best effort. The adapter
+    /// settings can only be restored by `disconnect`.
+    fn close(&mut self) {
+        for channel in [self.itr_channel.take(), self.ctr_channel.take()]
+            .into_iter()
+            .flatten()
+        {
+            if let Err(e) = channel.shutdown() {
+                warn!("{}", e);
+            }
+        }
+        self.scheduled.clear();
+        self.profile_handle.take();
+    }
+
+    /// Closes the connection and the listeners, and power-cycles the adapter to recover it from
+    /// wedged states. Returns the time taken until the adapter is powered on again. The service
+    /// record is registered again in the next pairing, and the listeners are opened again in the
+    /// next connection if reconnections are accepted.
+    pub async fn reset_adapter(&mut self) -> Result<Duration> {
+        // Bound PSMs are released first, which would be left behind by the reset adapter
+        let connected = self.itr_channel.is_some();
+        self.close();
+        self.listeners.take();
+        if connected {
+            self.set_status(ConnectionStatus::Disconnected {
+                reason: DisconnectReason::Requested,
+            });
+        }
+
+        bluetooth::power_cycle(&self.adapter, POWER_CYCLE_TIMEOUT).await
+    }
+
+    /// Reconnects the session to bluetoothd after the Bluetooth stack restarted, like when
+    /// bluetoothd or D-Bus restarts, where calls to the previous session keep failing with
+    /// `Error::is_stack_lost`. The connection and the listeners are closed, and the adapter is
+    /// found again by its address, waiting for bluetoothd to be back up to 30 seconds. The service
+    /// record is registered again if it was, and `SwitchEvent::BluetoothStackRestarted` is
+    /// emitted.
+    pub async fn reconnect_session(&mut self) -> Result<()> {
+        let registered = self.profile_handle.is_some();
+        let connected = self.itr_channel.is_some();
+        self.close();
+        self.listeners.take();
+        if connected {
+            self.set_status(ConnectionStatus::Disconnected {
+                reason: DisconnectReason::Failed,
+            });
+        }
+
+        let (session, adapter) = bluetooth::reopen(
+            self.adapter.name(),
+            self.adapter_address,
+            STACK_RESTART_TIMEOUT,
+        )
+        .await?;
+        info!("Reconnect session to adapter {}", adapter.name());
+        self.session = session;
+        self.adapter = adapter;
+        self.adapter_address = self.adapter.address().await?;
+        let _ = self.events.send(SwitchEvent::BluetoothStackRestarted);
+
+        if registered {
+            let stale = self.check_stale_records().await;
+            self.register_service_record(&stale).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Pairs a new device. Pairing can be cancelled with the cancel token, where
+    /// `ErrorKind::Cancelled` is returned. The alias, class, discoverability and pairability of
+    /// the adapter are restored when pairing returns, whether it succeeds or not. The paired
+    /// device is marked trusted, so it reconnects without an agent.
+    ///
+    /// The progress is emitted as `SwitchEvent::Pairing` events. Pairing returns once the device
+    /// connects, and the handshake continues in the next run until the device assigns a player,
+    /// which emits `PairingEvent::PairingComplete`, or the connection stops, which emits
+    /// `PairingEvent::PairingFailed`.
+    pub async fn pair(&mut self) -> Result<PairedDevice> {
+        self.pair_until(None).await
+    }
+
+    /// Pairs a new device, which must connect in the given timeout. On timeout, the adapter stops
+    /// pairing and `ErrorKind::Timeout` is returned.
+    pub async fn pair_with_timeout(&mut self, timeout: Duration) -> Result<PairedDevice> {
+        self.pair_until(Some(timeout)).await
+    }
+
+    async fn pair_until(&mut self, timeout: Option<Duration>) -> Result<PairedDevice> {
+        self.set_status(ConnectionStatus::Pairing);
+
+        // Discovery of other clients breaks incoming connections, which is paused while pairing
+        let _exclusive = self.acquire_exclusive().await;
+
+        // Adapter settings are restored in every exit path, or on drop if the pairing is dropped
+        let result = match AdapterGuard::save(&self.adapter, self.fast_scan).await {
+            Ok(guard) => {
+                let result = self.accept_pairing(timeout, guard.has_scan()).await;
+                if let Err(e) = guard.restore().await {
+                    warn!("{}", e);
+                }
+
+                result
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(ref device) => {
+                if let Err(e) = self
+                    .handle
+                    .lock()
+                    .start_handshake(device.clone(), Instant::now())
+                {
+                    warn!("{}", e);
+                }
+                self.switch_addr = Some(device.address);
+                #[cfg(feature = "store")]
+                self.record_pairing(device.address).await;
+                self.set_status(ConnectionStatus::Connected {
+                    switch_addr: device.address,
+                    since: Instant::now(),
+                })
+            }
+            Err(ref e) => {
+                self.emit_pairing(PairingEvent::PairingFailed(e.to_string()));
+                match e.kind {
+                    ErrorKind::Timeout | ErrorKind::Cancelled => {
+                        self.set_status(ConnectionStatus::Idle)
+                    }
+                    _ => self.set_status(ConnectionStatus::Disconnected {
+                        reason: DisconnectReason::Failed,
+                    }),
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Acquires exclusive use of the adapter in best effort, and warns about clients which may
+    /// interfere with pairing if not obtained.
+    async fn acquire_exclusive(&self) -> Option<ExclusiveGuard> {
+        let guard = match bluetooth::acquire_exclusive(&self.adapter).await {
+            Ok(guard) => guard,
+            Err(e) => {
+                warn!("{}", e);
+
+                return None;
+            }
+        };
+        let clients = if guard.clients().is_empty() {
+            "another client".to_string()
+        } else {
+            guard.clients().join(", ")
+        };
+        if guard.is_paused() {
+            info!(
+                "Paused discovery of adapter {} by {}",
+                self.adapter.name(),
+                clients
+            );
+        }
+        if !guard.is_exclusive() && (guard.was_discovering() || !guard.clients().is_empty()) {
+            warn!(
+                "Cannot use adapter {} exclusively, where discovery by {} may break pairing",
+                self.adapter.name(),
+                clients
+            );
+        }
+
+        Some(guard)
+    }
+
+    /// Returns the service records of controllers registered in bluetoothd other than the one of
+    /// this controller, like those left by a previous run which crashed, where the device may
+    /// read a stale record. Records are told by the HID service class, and the name and the
+    /// provider of the service record. Browsing the records requires the compatibility interface
+    /// of bluetoothd, which is enabled by `bluetoothd --compat`.
+    pub async fn stale_service_records(&self) -> Result<Vec<sdp::Registered>> {
+        let own = self.service_record_handle;
+        let records = unblock(sdp::registered_records).await?;
+
+        Ok(records
+            .into_iter()
+            .filter(|r| r.matches(sdp::HID, SERVICE_NAME, SERVICE_PROVIDER))
+            .filter(|r| Some(r.handle) != own)
+            .collect())
+    }
+
+    /// Detects stale service records, and removes them if cleaning is set. Returns the stale
+    /// records left. The detection is in best effort, where no records are returned if the
+    /// records are not browsable.
+    async fn check_stale_records(&self) -> Vec<sdp::Registered> {
+        let stale = match self.stale_service_records().await {
+            Ok(stale) => stale,
+            Err(e) => {
+                debug!("cannot detect stale service records: {}", e);
+
+                return vec![];
+            }
+        };
+
+        let mut left = vec![];
+        for record in stale {
+            if !self.clean_stale_records {
+                warn!(
+                    "Stale service record {} is active, which may be left by a previous run",
+                    record
+                );
+                left.push(record);
+                continue;
+            }
+            let handle = record.handle;
+            match unblock(move || sdp::remove_record(handle)).await {
+                Ok(()) => info!("Remove stale service record {}", record),
+                Err(e) => {
+                    warn!("Cannot remove stale service record {}: {}", record, e);
+                    left.push(record);
+                }
+            }
+        }
+
+        left
+    }
+
+    /// Registers the service record, where the given stale records are included in the error if
+    /// the registration fails.
+    async fn register_service_record(&mut self, stale: &[sdp::Registered]) -> Result<()> {
+        self.profile_handle = Some(
+            self.session
+                .register_profile(Profile::new_service_record(
+                    SERVICE.parse().unwrap(),
+                    self.controller_type.service_record(),
+                ))
+                .await
+                .map_err(|e| service_record_error(e, stale))?,
+        );
+        // The registered record is the one not seen before, if the records are browsable
+        if let Ok(records) = self.stale_service_records().await {
+            self.service_record_handle = records
+                .iter()
+                .map(|r| r.handle)
+                .find(|handle| stale.iter().all(|r| r.handle != *handle));
+        }
+
+        Ok(())
+    }
+
+    /// Emits the given progress of pairing.
+    fn emit_pairing(&self, event: PairingEvent) {
+        // Events are dropped if nobody is listening
+        let _ = self.events.send(SwitchEvent::Pairing(event));
+    }
+
+    async fn accept_pairing(
+        &mut self,
+        timeout: Option<Duration>,
+        fast_scan: bool,
+    ) -> Result<PairedDevice> {
+        self.close();
+        self.handle.lock().reset();
+
+        // Check privileges, where kept listeners are bound already
+        if self.preflight {
+            let (addr, psm) = (self.adapter.address().await?, self.listeners.is_none());
+            unblock(move || preflight(addr, psm)).await?;
+        }
+
+        // Check active service records, where the service record of the controller is
+        // unregistered on close
+        if let Some(uuids) = self.adapter.uuids().await? {
+            if uuids.len() > 3 {
+                warn!("Too many service records active");
+            }
+        }
+        let stale = self.check_stale_records().await;
+
+        // Unpair paired Nintendo Switches
+        let removed = switch_devices(&self.adapter).await?;
+        for device in removed.iter() {
+            warn!("Unpair previous device {}", device.address());
+            device.clone().remove().await?;
+        }
+
+        // Listeners
+        let addr = self.adapter.address().await?;
+        self.handle.lock().set_address(addr);
+        let (ctr_listener, itr_listener) = match self.listeners.take() {
+            Some(listeners) => listeners,
+            None => {
+                listen(
+                    &self.adapter,
+                    addr,
+                    self.mtu,
+                    self.flush_timeout,
+                    self.security_level,
+                    &self.stats.flush_timeout_refused,
+                )
+                .await?
+            }
+        };
+        self.emit_pairing(PairingEvent::Listening);
+
+        self.adapter.set_powered(true).await?;
+        self.adapter.set_pairable(true).await?;
+        set_alias(
+            &self.adapter,
+            self.controller_type,
+            self.controller_type.name(),
+        )
+        .await?;
+
+        // Register service record, where pairing is stopped on any failure after, so the
+        // service record never outlives a failed pairing
+        self.register_service_record(&stale).await?;
+        self.emit_pairing(PairingEvent::ServiceRecordRegistered);
+        let addr = match self
+            .accept_registered(ctr_listener, itr_listener, timeout, fast_scan)
+            .await
+        {
+            Ok(addr) => addr,
+            Err(e) => {
+                self.stop_pairing().await;
+
+                return Err(e);
+            }
+        };
+
+        // The device is trusted so BlueZ accepts its reconnections without an agent, which is
+        // in best effort like the device info
+        let trusted = match Device::new(&self.adapter, addr) {
+            Ok(device) => device.set_trusted(true).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = trusted {
+            warn!("{}", e);
+        }
+
+        // Device info is looked up in best effort
+        let (name, alias) = match self.device_names(addr).await {
+            Ok(names) => names,
+            Err(e) => {
+                warn!("{}", e);
+
+                (None, None)
+            }
+        };
+        let device = PairedDevice {
+            address: addr,
+            name,
+            alias,
+            adapter: self.adapter.name().into(),
+            paired_at: SystemTime::now(),
+            removed_previous: !removed.is_empty(),
+        };
+        info!("Paired with {}", device);
+
+        Ok(device)
+    }
+
+    /// Makes the adapter discoverable with the service record registered, and accepts the
+    /// connections of the device in the given timeout. Returns the address of the device, which
+    /// is connected with the adapter undiscoverable.
+    async fn accept_registered(
+        &mut self,
+        ctr_listener: HidListener,
+        itr_listener: HidListener,
+        timeout: Option<Duration>,
+        fast_scan: bool,
+    ) -> Result<Address> {
+        // Register agent, which is unregistered on drop when pairing returns. Many setups pair
+        // without it
+        let agent = self.pairing_agent.then(|| {
+            Agent::new_pairing_agent(
+                self.adapter.clone(),
+                SERVICE.parse().unwrap(),
+                NINTENDO_SWITCH_NAME,
+            )
+        });
+        let _agent = match agent {
+            Some(agent) => match self.session.register_agent(agent).await {
+                Ok(agent) => Some(agent),
+                Err(e) => {
+                    warn!("Cannot register pairing agent: {}", e);
+
+                    None
+                }
+            },
+            None => None,
+        };
+
+        // The discoverable timeout is disabled so the pairing window does not expire, which is
+        // restored with the other settings
+        self.adapter.set_discoverable_timeout(0).await?;
+        self.adapter.set_discoverable(true).await?;
+        if fast_scan {
+            // The saved scan parameters are restored with the other settings
+            debug!("scan parameters: {}", ScanParameters::FAST);
+            let adapter = self.adapter.clone();
+            if let Err(e) =
+                unblock(move || adapter.set_scan_parameters(&ScanParameters::FAST)).await
+            {
+                warn!("{}", e);
+            }
+        }
+        let (retries, interval) = self.class_retry;
+        settle_class(&self.adapter, retries, interval).await?;
+
+        // Accept, where a socket accepted before a failure is closed on drop. The class and the
+        // discoverable are held while accepting
+        info!("Wait for device to connect");
+        let cancel_token = self.cancel_token.clone();
+        let holding = async {
+            tokio::select! {
+                e = hold_class(&self.adapter, retries, interval) => e,
+                e = hold_discoverable(&self.adapter, |event| self.emit_pairing(event)) => e,
+            }
+        };
+        let accepting = async {
+            let accepting = accept(&ctr_listener, &itr_listener, &cancel_token, |event| {
+                let _ = self.events.send(SwitchEvent::Pairing(event));
+            });
+            match timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, accepting).await {
+                    Ok(accepted) => accepted,
+                    Err(_) => Err(Error::new(
+                        ErrorKind::Timeout,
+                        format!("no device connected in {:?}", timeout),
+                    )),
+                },
+                None => accepting.await,
+            }
+        };
+        let accepted = tokio::select! {
+            accepted = accepting => accepted,
+            e = holding => Err(e),
+        };
+        let (ctr_channel, itr_channel, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                let e = match e.kind {
+                    ErrorKind::Cancelled => {
+                        self.cancel_token = CancelToken::new();
+
+                        Error::new(ErrorKind::Cancelled, "pairing cancelled".into())
+                    }
+                    _ => e,
+                };
+
+                return Err(e);
+            }
+        };
+        self.ctr_channel = Some(ctr_channel);
+        self.itr_channel = Some(itr_channel);
+        if self.accept_reconnects {
+            self.listeners = Some((ctr_listener, itr_listener));
+        }
+
+        self.adapter.set_discoverable(false).await?;
+        self.adapter.set_pairable(false).await?;
+
+        Ok(addr)
+    }
+
+    /// Returns the name and the alias of the given device.
+    async fn device_names(&self, addr: Address) -> Result<(Option<String>, Option<String>)> {
+        let device = Device::new(&self.adapter, addr)?;
+
+        Ok((device.name().await?, Some(device.alias().await?)))
+    }
+
+    /// Warns if the given device is known by the adapter but not trusted, where BlueZ may deny
+    /// its connections silently without an agent.
+    async fn check_trusted(&self, addr: Address) {
+        let device = match Device::all(&self.adapter).await {
+            Ok(devices) => devices.into_iter().find(|device| device.address() == addr),
+            Err(e) => {
+                warn!("{}", e);
+
+                return;
+            }
+        };
+        if let Some(device) = device {
+            match device.is_trusted().await {
+                Ok(true) => {}
+                Ok(false) => warn!(
+                    "Device {} is not trusted, reconnections may be denied silently",
+                    addr
+                ),
+                Err(e) => warn!("{}", e),
+            }
+        }
+    }
+
+    /// Stops pairing by making the adapter undiscoverable and unregistering the service record.
+    /// Errors are logged since this is the cleanup of another failure.
+    async fn stop_pairing(&mut self) {
+        if let Err(e) = self.adapter.set_discoverable(false).await {
+            warn!("{}", e);
+        }
+        if let Err(e) = self.adapter.set_pairable(false).await {
+            warn!("{}", e);
+        }
+        self.profile_handle.take();
+    }
+
+    /// Connects to a previously paired device. The device must have paired with the adapter
+    /// before, so it knows the controller. Returns `ErrorKind::Timeout` if the device does not
+    /// answer the page, like when it is asleep or away.
+    pub async fn connect(&mut self, addr: Address) -> Result<()> {
+        self.set_status(ConnectionStatus::Reconnecting);
+        let result = self.connect_to(addr).await;
+        match result {
+            Ok(_) => {
+                self.switch_addr = Some(addr);
+                #[cfg(feature = "store")]
+                self.record_connection(addr).await;
+                self.set_status(ConnectionStatus::Connected {
+                    switch_addr: addr,
+                    since: Instant::now(),
+                })
+            }
+            Err(_) => self.set_status(ConnectionStatus::Disconnected {
+                reason: DisconnectReason::Failed,
+            }),
+        }
+
+        result
+    }
+
+    /// Connects to the device which the adapter last connected to in the pairing store, and
+    /// returns its address.
+    #[cfg(feature = "store")]
+    pub async fn connect_last(&mut self) -> Result<Address> {
+        let adapter_addr = self.adapter.address().await?;
+        let addr = match &self.store {
+            Some(store) => lock_store(store)?
+                .latest_of(adapter_addr)
+                .map(|record| record.switch_addr),
+            None => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    "no pairing store is set".into(),
+                ))
+            }
+        };
+        let addr = match addr {
+            Some(addr) => addr,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("adapter {} has not paired with any device", adapter_addr),
+                ))
+            }
+        };
+        self.connect(addr).await?;
+
+        Ok(addr)
+    }
+
+    /// Records the pairing with the given device in the pairing store in best effort.
+    #[cfg(feature = "store")]
+    async fn record_pairing(&mut self, addr: Address) {
+        if self.store.is_none() {
+            return;
+        }
+        let adapter_addr = match self.adapter.address().await {
+            Ok(adapter_addr) => adapter_addr,
+            Err(e) => {
+                warn!("{}", e);
+
+                return;
+            }
+        };
+        let now = SystemTime::now();
+        let record = PairingRecord {
+            switch_addr: addr,
+            adapter_addr,
+            controller_type: self.controller_type,
+            spi_pairing: self.handle.lock().spi_flash().pairing_info().to_vec(),
+            paired_at: now,
+            connected_at: now,
+        };
+        if let Err(e) = self.update_store(|store| store.insert(record)).await {
+            warn!("{}", e);
+        }
+    }
+
+    /// Records the connection with the given device in the pairing store in best effort.
+    #[cfg(feature = "store")]
+    async fn record_connection(&mut self, addr: Address) {
+        if self.store.is_none() {
+            return;
+        }
+        let adapter_addr = match self.adapter.address().await {
+            Ok(adapter_addr) => adapter_addr,
+            Err(e) => {
+                warn!("{}", e);
+
+                return;
+            }
+        };
+        if let Err(e) = self
+            .update_store(move |store| store.touch(addr, adapter_addr))
+            .await
+        {
+            warn!("{}", e);
+        }
+    }
+
+    /// Updates the pairing store with the given call on the blocking threads. The store is
+    /// shared with the call, so it is kept if the returned future is dropped. Later updates fail
+    /// if the call panics.
+    #[cfg(feature = "store")]
+    async fn update_store<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut PairingStore) -> Result<T> + Send + 'static,
+    {
+        let store = match &self.store {
+            Some(store) => store.clone(),
+            None => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    "no pairing store is set".into(),
+                ))
+            }
+        };
+
+        unblock(move || f(&mut *lock_store(&store)?)).await
+    }
+
+    async fn connect_to(&mut self, addr: Address) -> Result<()> {
+        self.close();
+        self.handle.lock().reset();
+
+        let local_addr = self.adapter.address().await?;
+        self.handle.lock().set_address(local_addr);
+        self.adapter.set_powered(true).await?;
+        if self.accept_reconnects && self.listeners.is_none() {
+            self.listeners = Some(
+                listen(
+                    &self.adapter,
+                    local_addr,
+                    self.mtu,
+                    self.flush_timeout,
+                    self.security_level,
+                    &self.stats.flush_timeout_refused,
+                )
+                .await?,
+            );
+        }
+
+        // Connect
+        self.check_trusted(addr).await;
+        info!("Connect to device {}", addr);
+        let ctr_channel = connect(
+            local_addr,
+            addr,
+            CTR_PSM,
+            self.mtu,
+            self.flush_timeout,
+            self.security_level,
+            &self.stats.flush_timeout_refused,
+        )
+        .await?;
+        debug!("connect {}, PSM = {} (CTR)", addr, CTR_PSM);
+        let itr_channel = connect(
+            local_addr,
+            addr,
+            ITR_PSM,
+            self.mtu,
+            self.flush_timeout,
+            self.security_level,
+            &self.stats.flush_timeout_refused,
+        )
+        .await?;
+        debug!("connect {}, PSM = {} (ITR)", addr, ITR_PSM);
+        log_options(&itr_channel);
+        self.ctr_channel = Some(ctr_channel);
+        self.itr_channel = Some(itr_channel);
+
+        Ok(())
+    }
+
+    /// Removes the pairing and the trust of the given device from the adapter, and clears the
+    /// device from the pairing info in the SPI flash. The connection is closed if it is with the
+    /// device.
+    pub async fn unpair(&mut self, addr: Address) -> Result<()> {
+        if let ConnectionStatus::Connected { switch_addr, .. } = self.status() {
+            if switch_addr == addr {
+                self.close();
+                self.set_status(ConnectionStatus::Disconnected {
+                    reason: DisconnectReason::Requested,
+                });
+            }
+        }
+
+        let device = Device::all(&self.adapter)
+            .await?
+            .into_iter()
+            .find(|device| device.address() == addr);
+        let known = device.is_some();
+        if let Some(device) = device {
+            info!("Unpair device {}", addr);
+            device.set_trusted(false).await?;
+            device.remove().await?;
+        }
+        let cleared = self.handle.lock().clear_pairing(addr);
+        if cleared {
+            debug!("clear pairing info of {}", addr);
+        }
+        #[cfg(feature = "store")]
+        if self.store.is_some() {
+            if let Err(e) = self.update_store(move |store| store.remove(addr)).await {
+                warn!("{}", e);
+            }
+        }
+        if !known && !cleared {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("device {} is not paired", addr),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Sets if the Joy-Con is connected to the charging grip.
+    pub fn set_grip(&mut self, grip: bool) -> Result<()> {
+        self.handle.update(|protocol| protocol.set_grip(grip))
+    }
+
+    /// Takes the controller apart to service the device in a run loop of the caller. Scheduled
+    /// frames which are not sent yet are dropped.
+    ///
+    /// Handles of the controller keep updating a detached protocol until `from_parts`, which
+    /// attaches them to the protocol of the parts again, so inputs should be set on the protocol
+    /// of the parts in the meantime.
+    pub fn into_parts(mut self) -> ControllerParts {
+        let protocol = std::mem::replace(
+            &mut *self.handle.lock(),
+            Protocol::new(self.controller_type),
+        );
+        self.scheduled.clear();
+
+        ControllerParts {
+            protocol,
+            ctr_channel: self.ctr_channel.take(),
+            itr_channel: self.itr_channel.take(),
+            switch_addr: self.switch_addr,
+            controller: self,
+        }
+    }
+
+    /// Reassembles a controller from the given parts taken by `into_parts`.
+    pub fn from_parts(parts: ControllerParts) -> Self {
+        let mut controller = parts.controller;
+        *controller.handle.lock() = parts.protocol;
+        controller.handle.changed.notify_one();
+        controller.ctr_channel = parts.ctr_channel;
+        controller.itr_channel = parts.itr_channel;
+        controller.switch_addr = parts.switch_addr;
+        if controller.itr_channel.is_none()
+            && matches!(controller.status(), ConnectionStatus::Connected { .. })
+        {
+            controller.set_status(ConnectionStatus::Disconnected {
+                reason: DisconnectReason::Closed,
+            });
+        }
+
+        controller
+    }
+
+    /// Returns a handle to update the input state while the controller is running.
+    pub fn handle(&self) -> ControllerHandle {
+        self.handle.clone()
+    }
+
+    /// Returns the state of buttons.
+    pub fn buttons(&self) -> ButtonState {
+        self.handle.buttons()
+    }
+
+    /// Presses the given button. The change is reflected in the next input report.
+    pub fn press(&self, button: Button) -> Result<()> {
+        self.handle.press(button)
+    }
+
+    /// Releases the given button. The change is reflected in the next input report.
+    pub fn release(&self, button: Button) -> Result<()> {
+        self.handle.release(button)
+    }
+
+    /// Sets if the given buttons are pressed at once. The changes are reflected in the next input
+    /// report.
+    pub fn set_buttons(&self, buttons: &[(Button, bool)]) -> Result<()> {
+        self.handle.set_buttons(buttons)
+    }
+
+    /// Presses the given button for the duration, where the release is scheduled by `run`. The
+    /// press is carried by at least one input report, and pressing the button again extends the
+    /// hold instead of truncating it.
+    pub fn press_for(&self, button: Button, duration: Duration) -> Result<()> {
+        self.handle.press_for(button, duration)
+    }
+
+    /// Presses the given button for one input report.
+    pub fn tap(&self, button: Button) -> Result<()> {
+        self.handle.tap(button)
+    }
+
+    /// Sets the turbo rate of the given button in Hz, or disables the turbo if `None`. `run`
+    /// toggles the button on report boundaries, so the rate is bounded by half the input report
+    /// rate. The turbo keeps running while other inputs are updated.
+    pub fn set_turbo(&self, button: Button, rate: Option<f32>) -> Result<()> {
+        self.handle.set_turbo(button, rate)
+    }
+
+    /// Sets the rate of periodic input reports, which `run` follows from the next input report.
+    pub fn set_report_rate(&self, rate: ReportRate) {
+        self.handle.set_report_rate(rate)
+    }
+
+    /// Returns the position of the given stick.
+    pub fn stick(&self, stick: Stick) -> StickPosition {
+        self.handle.stick(stick)
+    }
+
+    /// Sets the position of the given stick from normalized values in -1 to 1. Values are
+    /// converted with the stick configuration and clamped at its extremes.
+    pub fn set_stick(&self, stick: Stick, x: f32, y: f32) -> Result<()> {
+        self.handle.set_stick(stick, x, y)
+    }
+
+    /// Sets the positions of both sticks from normalized values in -1 to 1 at once, so a
+    /// movement is not split across input reports.
+    pub fn set_sticks(&self, left: (f32, f32), right: (f32, f32)) -> Result<()> {
+        self.handle.set_sticks(left, right)
+    }
+
+    /// Sets the motion sample in physical units. Samples can be set at any rate, where they are
+    /// downsampled or repeated to fit input reports.
+    pub fn set_motion(&self, motion: MotionSample) {
+        self.handle.set_motion(motion)
+    }
+
+    /// Sets the whole input state at once, so it is reflected in the same input report. Buttons
+    /// and sticks which the controller does not have must be released and centered.
+    pub fn set_input(&self, input: &ControllerInput) -> Result<()> {
+        self.handle.set_input(input)
+    }
+
+    /// Sets the whole input state from the given declarative state at once, so it is reflected
+    /// in the same input report. The state is validated against the controller type first,
+    /// where the errors of every invalid field are returned together and nothing is set.
+    pub fn apply_state(&self, state: &ControllerState) -> Result<()> {
+        self.handle.apply_state(state)
+    }
+
+    /// Schedules the input state to be set at the given tick or instant. An input scheduled at a
+    /// tick is carried by exactly the periodic input report of the tick, and an input scheduled
+    /// at an instant is carried by the first input report at or after the instant. Ticks count
+    /// periodic input reports from 0 in each connection, and only advance while `run` pushes
+    /// them. Targets in the past are rejected.
+    pub fn schedule(&self, at: impl Into<ScheduleAt>, input: ControllerInput) -> Result<()> {
+        self.handle.schedule(at, input)
+    }
+
+    /// Removes all scheduled inputs.
+    pub fn clear_schedule(&self) {
+        self.handle.clear_schedule()
+    }
+
+    /// Returns the tick of the next periodic input report.
+    pub fn current_tick(&self) -> ReportTick {
+        self.handle.current_tick()
+    }
+
+    /// Returns the estimated time of the periodic input report of the given tick, or `None` if
+    /// input reports are not pushed periodically or the tick is in the past. The estimation
+    /// assumes the input report mode and the report rate are kept.
+    pub fn tick_time(&self, tick: ReportTick) -> Option<Instant> {
+        self.handle.tick_time(tick)
+    }
+
+    /// Returns the estimated tick of the first periodic input report at or after the given time,
+    /// or `None` if input reports are not pushed periodically.
+    pub fn time_tick(&self, time: Instant) -> Option<ReportTick> {
+        self.handle.time_tick(time)
+    }
+
+    /// Returns the player lights set by the device.
+    pub fn player_lights(&self) -> u8 {
+        self.handle.player_lights()
+    }
+
+    /// Returns a snapshot of the protocol state as the controller currently believes, like the
+    /// negotiated input report mode and the NFC/IR MCU state. The protocol is only locked to take
+    /// the snapshot, so the run is not paused.
+    pub fn query_state(&self) -> ProtocolStateSnapshot {
+        self.handle.query_state()
+    }
+
+    /// Replaces the SPI flash while connected, where later SPI flash reads by the device are
+    /// served from the given one. Writes by the device, e.g., the pairing info, are kept over the
+    /// given SPI flash with `ReloadMode::Merge`, or discarded with `ReloadMode::Discard`.
+    ///
+    /// Returns if the device must reconnect to see the changes, since the device caches
+    /// regions like the colors and calibration on connection.
+    pub fn reload_spi(&self, spi_flash: SpiFlash, mode: ReloadMode) -> bool {
+        self.handle.reload_spi(spi_flash, mode)
+    }
+
+    /// Replaces the SPI flash with the image in the given path like `reload_spi`. The image is
+    /// read off the runtime.
+    pub async fn reload_spi_image<P: AsRef<Path>>(
+        &self,
+        path: P,
+        mode: ReloadMode,
+    ) -> Result<bool> {
+        self.handle.reload_spi_image(path, mode).await
+    }
+
+    /// Sets the battery level and if the battery is charging, which take effect on the next
+    /// input report. The regulated voltage replied to the device follows the battery level.
+    pub fn set_battery(&self, level: BatteryLevel, charging: bool) {
+        self.handle.set_battery(level, charging)
+    }
+
+    /// Returns the threshold of changes in rumble frames.
+    pub fn rumble_epsilon(&self) -> f32 {
+        self.handle.rumble_epsilon()
+    }
+
+    /// Sets the threshold of changes in rumble frames, which is kept across connections. Rumble
+    /// frames are only emitted as events if they differ from the last emitted one beyond the
+    /// threshold.
+    pub fn set_rumble_epsilon(&self, epsilon: f32) {
+        self.handle.set_rumble_epsilon(epsilon)
+    }
+
+    /// Puts the given amiibo on the NFC point, which replaces the previous one. A read in
+    /// progress finishes with the previous amiibo, and `SwitchEvent::AmiiboRead` is emitted when
+    /// the device finishes reading an amiibo.
+    pub fn set_amiibo(&self, amiibo: Amiibo) {
+        self.handle.set_amiibo(amiibo)
+    }
+
+    /// Lifts the amiibo off the NFC point.
+    pub fn remove_amiibo(&self) {
+        self.handle.remove_amiibo()
+    }
+
+    /// Sends a raw input report, which is for protocol research only. The report is sent by
+    /// `run` between other input reports, and only its direction byte and length are validated.
+    /// Reports the protocol would never generate may confuse or disconnect the device.
+    pub fn send_raw_report(&self, bytes: &[u8]) -> Result<()> {
+        self.handle.send_raw_report(bytes)
+    }
+
+    /// Sets the hook of frames in the given direction, which replaces the previous one. The hook
+    /// sees every frame in the direction while running, and decides to pass, drop or replace the
+    /// frame. Hooks run synchronously in the run loop and are expected to return in
+    /// `HOOK_TIME_BUDGET`, where slower hooks delay the reports and are logged. A panic in the
+    /// hook is caught, where the frame is passed through.
+    pub fn set_report_hook(
+        &mut self,
+        direction: Direction,
+        hook: impl Fn(&[u8]) -> HookAction + Send + Sync + 'static,
+    ) {
+        let hook: ReportHook = Box::new(hook);
+        match direction {
+            Direction::Input => self.input_hook = Some(hook),
+            Direction::Output => self.output_hook = Some(hook),
+        }
+    }
+
+    /// Removes the hook of frames in the given direction.
+    pub fn remove_report_hook(&mut self, direction: Direction) {
+        match direction {
+            Direction::Input => self.input_hook = None,
+            Direction::Output => self.output_hook = None,
+        }
+    }
+
+    /// Returns a receiver of events from the device. Events are emitted by `run`.
+    pub fn events(&self) -> Events {
+        Events {
+            receiver: self.events.subscribe(),
+            dropped: 0,
+        }
+    }
+
+    /// Returns the name of the adapter.
+    pub fn adapter_name(&self) -> &str {
+        self.adapter.name()
+    }
+
+    /// Returns the hardware information of the adapter, like the manufacturer and the firmware
+    /// version, which is included in diagnostics.
+    pub async fn hardware_info(&self) -> HardwareInfo {
+        bluetooth::hardware_info(&self.adapter).await
+    }
+
+    /// Returns the configured L2CAP flush timeout of the HID channels in 0.625 ms slots. The
+    /// kernel may refuse it, which is recorded in `SessionStats::flush_timeout_refused`, and the
+    /// flush timeout in effect is returned by `HidChannel::flush_timeout`.
+    pub fn flush_timeout(&self) -> u16 {
+        self.flush_timeout
+    }
+
+    /// Returns the configured L2CAP security level of the HID channels. The level in effect is
+    /// returned by `HidChannel::security_level`.
+    pub fn security_level(&self) -> SecurityLevel {
+        self.security_level
+    }
+
+    /// Returns the connection status.
+    pub fn status(&self) -> ConnectionStatus {
+        *self.status.borrow()
+    }
+
+    /// Returns a receiver of connection status changes, which always holds the latest status.
+    pub fn subscribe_status(&self) -> watch::Receiver<ConnectionStatus> {
+        self.status.subscribe()
+    }
+
+    fn set_status(&self, status: ConnectionStatus) {
+        debug!("status: {}", status);
+        // The status is kept even if nobody is subscribing
+        self.status.send_replace(status);
+    }
+
+    /// Returns a snapshot of the statistics. The counters of the protocol, which are the rumble
+    /// frames, reset on every pairing or connection, while the others are kept until
+    /// `reset_stats`.
+    pub fn stats(&self) -> SessionStats {
+        let (protocol_stats, interval) = {
+            let protocol = self.handle.lock();
+            (protocol.stats(), protocol.report_interval())
+        };
+        let uptime = match self.status() {
+            ConnectionStatus::Connected { since, .. } => Some(since.elapsed()),
+            _ => None,
+        };
+        let signal = *self.signal.lock().unwrap_or_else(|e| e.into_inner());
+
+        SessionStats {
+            reports_sent: self.stats.reports_sent.load(Ordering::Relaxed),
+            output_reports_received: self.stats.output_reports_received.load(Ordering::Relaxed),
+            subcommands_answered: self
+                .stats
+                .subcommands_answered
+                .iter()
+                .enumerate()
+                .map(|(id, count)| (id as u8, count.load(Ordering::Relaxed)))
+                .filter(|(_, count)| *count > 0)
+                .collect(),
+            malformed_frames: self.stats.malformed_frames.load(Ordering::Relaxed),
+            dropped_reports: self.stats.dropped_reports.load(Ordering::Relaxed),
+            rumble_frames: protocol_stats.rumble_frames,
+            rejected_peers: self.stats.rejected_peers.load(Ordering::Relaxed),
+            suppressed_rumble_frames: protocol_stats.suppressed_rumble_frames,
+            report_rate: interval
+                .filter(|interval| !interval.is_zero())
+                .map(|interval| 1.0 / interval.as_secs_f32()),
+            uptime,
+            flush_timeout_refused: self.stats.flush_timeout_refused.load(Ordering::Relaxed),
+            rssi: signal.rssi,
+            link_quality: signal.link_quality,
+        }
+    }
+
+    /// Resets the statistics.
+    pub fn reset_stats(&self) {
+        self.stats.reset();
+        let _ = self.handle.update(|protocol| {
+            protocol.reset_stats();
+
+            Ok(())
+        });
+    }
+
+    /// Returns the token which cancels `pair` and `run`. A cancelled token is replaced when the
+    /// cancelled operation returns, so the token should be fetched again for later operations.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel_token.clone()
+    }
+
+    /// Services the paired device until the connection is closed or the run is cancelled.
+    /// Output reports are handled and replied, and input reports are pushed at the interval of
+    /// the input report mode. Malformed output reports are logged and ignored. On IO errors,
+    /// the connection is dropped and the status turns disconnected before the error is returned.
+    /// A lost link or a removed adapter reported by BlueZ stops the run at once with
+    /// `StopReason::LinkLost` or `StopReason::AdapterRemoved`.
+    ///
+    /// If the controller accepts reconnections, a connection closed by the device or a lost link
+    /// does not stop the run. Instead, the run waits for the device to reconnect and resumes servicing it.
+    pub async fn run(&mut self) -> Result<StopReason> {
+        loop {
+            match self.run_until(|_| false).await? {
+                Some(StopReason::Closed | StopReason::Timeout | StopReason::LinkLost)
+                    if self.listeners.is_some() =>
+                {
+                    if let Some(reason) = self.accept_reconnect().await? {
+                        return Ok(reason);
+                    }
+                }
+                Some(reason) => return Ok(reason),
+                None => {}
+            }
+        }
+    }
+
+    /// Accepts a reconnection from the last connected device, where connections from other
+    /// devices are rejected. Returns `StopReason::Cancelled` if cancelled, or `None` once
+    /// reconnected.
+    async fn accept_reconnect(&mut self) -> Result<Option<StopReason>> {
+        let (ctr_listener, itr_listener, switch_addr) = match (&self.listeners, self.switch_addr) {
+            (Some((ctr_listener, itr_listener)), Some(switch_addr)) => {
+                (ctr_listener, itr_listener, switch_addr)
+            }
+            _ => return Ok(Some(StopReason::Closed)),
+        };
+
+        self.set_status(ConnectionStatus::Reconnecting);
+        self.check_trusted(switch_addr).await;
+        info!("Wait for device {} to reconnect", switch_addr);
+        let cancel_token = self.cancel_token.clone();
+        let accepted = accept_from(
+            ctr_listener,
+            itr_listener,
+            switch_addr,
+            &cancel_token,
+            &self.stats.rejected_peers,
+        )
+        .await;
+        let (ctr_channel, itr_channel) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) if matches!(e.kind, ErrorKind::Cancelled) => {
+                self.cancel_token = CancelToken::new();
+                self.set_status(ConnectionStatus::Disconnected {
+                    reason: DisconnectReason::Closed,
+                });
+
+                return Ok(Some(StopReason::Cancelled));
+            }
+            Err(e) => {
+                self.set_status(ConnectionStatus::Disconnected {
+                    reason: DisconnectReason::Failed,
+                });
+
+                return Err(e);
+            }
+        };
+
+        // The device starts over as a reconnected controller
+        self.handle.lock().reset();
+        self.ctr_channel = Some(ctr_channel);
+        self.itr_channel = Some(itr_channel);
+        info!("Device {} reconnected", switch_addr);
+        self.set_status(ConnectionStatus::Connected {
+            switch_addr,
+            since: Instant::now(),
+        });
+
+        Ok(None)
+    }
+
+    /// Services the paired device until the device assigns a player with stable player lights,
+    /// and returns the player number. The current player lights are checked first, so an
+    /// assignment which already happened is returned immediately. `ErrorKind::Timeout` is
+    /// returned if no player is assigned in the timeout.
+    pub async fn wait_for_player_assignment(&mut self, timeout: Duration) -> Result<PlayerNumber> {
+        let player = |protocol: &Protocol| PlayerLights::new(protocol.player_lights()).player();
+        if let Some(player) = player(&self.handle.lock()) {
+            return Ok(player);
+        }
+
+        let running = self.run_until(|protocol| player(protocol).is_some());
+        let result = match tokio::time::timeout(timeout, running).await {
+            Ok(result) => result,
+            Err(_) => {
+                return Err(Error::new(
+                    ErrorKind::Timeout,
+                    format!("no player assigned in {:?}", timeout),
+                ))
+            }
+        };
+        match result? {
+            None => player(&self.handle.lock())
+                .ok_or_else(|| Error::new(ErrorKind::Protocol, "player unassigned".into())),
+            Some(StopReason::Cancelled) => Err(Error::new(
+                ErrorKind::Cancelled,
+                "waiting for player cancelled".into(),
+            )),
+            Some(_) => Err(Error::from(ErrorKind::Io(io::Error::from(
+                io::ErrorKind::NotConnected,
+            )))),
+        }
+    }
+
+    /// Plays the given macro while servicing the paired device, and returns when the macro
+    /// finishes. Each step spans whole input report intervals, and its progress is emitted as
+    /// events. Playback can be cancelled with the cancel token, where the inputs held by the
+    /// current step are released and `ErrorKind::Cancelled` is returned.
+    pub async fn play_macro(&mut self, macro_: InputMacro) -> Result<()> {
+        self.handle.update(|protocol| protocol.play_macro(macro_))?;
+
+        let result = self
+            .run_until(|protocol| !protocol.is_playing_macro())
+            .await;
+        let error = match result {
+            Ok(None) => return Ok(()),
+            Ok(Some(StopReason::Cancelled)) => {
+                Error::new(ErrorKind::Cancelled, "macro cancelled".into())
+            }
+            Ok(Some(_)) => Error::from(ErrorKind::Io(io::Error::from(io::ErrorKind::NotConnected))),
+            Err(e) => e,
+        };
+        self.handle.update(|protocol| {
+            protocol.stop_macro();
+
+            Ok(())
+        })?;
+        self.emit_events();
+
+        Err(error)
+    }
+
+    /// Plays the given nx-TAS script at the given frames per second while servicing the paired
+    /// device, and returns when the script finishes. Frames are scheduled at report ticks, where
+    /// each frame spans the ticks of its duration, so a script of a lower rate than input
+    /// reports repeats frames deterministically. Input reports must be pushed periodically.
+    /// Playback can be cancelled with the cancel token, where all inputs are released and
+    /// `ErrorKind::Cancelled` is returned.
+    pub async fn play_tas(&mut self, script: &TasScript, fps: f32) -> Result<()> {
+        if !(fps > 0.0 && fps.is_finite()) {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("invalid frame rate {}", fps),
+            ));
+        }
+        let mut inputs = vec![];
+        let mut previous = None;
+        for frame in 0..script.len() {
+            let input = script.input(frame);
+            if previous != Some(input) {
+                inputs.push((frame, input));
+            }
+            previous = Some(input);
+        }
+        inputs.push((script.len(), ControllerInput::new()));
+
+        let frame_interval = Duration::from_secs_f64(1.0 / fps as f64);
+        self.play_inputs(inputs, frame_interval, "TAS playback")
+            .await
+    }
+
+    /// Starts recording every change of the input state carried by periodic input reports,
+    /// which replaces the ongoing recording if any.
+    pub fn start_recording(&self) {
+        let _ = self.handle.update(|protocol| {
+            protocol.start_recording();
+
+            Ok(())
+        });
+    }
+
+    /// Stops recording and returns the recording, or `None` if not recording.
+    pub fn stop_recording(&self) -> Option<InputRecording> {
+        self.handle.lock().stop_recording()
+    }
+
+    /// Plays the given recording while servicing the paired device, and returns when the
+    /// recording finishes. Inputs are scheduled at the report ticks corresponding to their
+    /// recorded ticks, so a recording of a lower report rate repeats inputs deterministically.
+    /// Input reports must be pushed periodically. Playback can be cancelled with the cancel
+    /// token, where all inputs are released and `ErrorKind::Cancelled` is returned.
+    pub async fn play_recording(&mut self, recording: &InputRecording) -> Result<()> {
+        let mut inputs: Vec<_> = recording
+            .inputs
+            .iter()
+            .map(|recorded| (recorded.tick, recorded.input))
+            .collect();
+        inputs.push((recording.ticks, ControllerInput::new()));
+
+        self.play_inputs(inputs, recording.interval, "recording playback")
+            .await
+    }
+
+    /// Schedules the given inputs at their frames of the given interval from the tick after the
+    /// next, and services the paired device until all inputs are set.
+    async fn play_inputs(
+        &mut self,
+        inputs: Vec<(u64, ControllerInput)>,
+        frame_interval: Duration,
+        name: &str,
+    ) -> Result<()> {
+        self.handle.update(|protocol| {
+            let interval = match protocol.report_interval() {
+                Some(interval) => interval,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        "input reports are not pushed periodically".into(),
+                    ))
+                }
+            };
+
+            // Start from the tick after the next to leave time to schedule
+            let start = protocol.current_tick().0 + 1;
+            let ticks_per_frame = frame_interval.as_secs_f64() / interval.as_secs_f64();
+            for (frame, input) in inputs {
+                let tick = ReportTick(start + (frame as f64 * ticks_per_frame).round() as u64);
+                if let Err(e) = protocol.schedule(tick.into(), input, Instant::now()) {
+                    protocol.clear_schedule();
+
+                    return Err(e);
+                }
+            }
+
+            Ok(())
+        })?;
+
+        let result = self.run_until(|protocol| !protocol.has_scheduled()).await;
+        let error = match result {
+            Ok(None) => return Ok(()),
+            Ok(Some(StopReason::Cancelled)) => {
+                Error::new(ErrorKind::Cancelled, format!("{} cancelled", name))
+            }
+            Ok(Some(_)) => Error::from(ErrorKind::Io(io::Error::from(io::ErrorKind::NotConnected))),
+            Err(e) => e,
+        };
+        self.handle.update(|protocol| {
+            protocol.clear_schedule();
+            protocol.set_input(&ControllerInput::new())
+        })?;
+
+        Err(error)
+    }
+
+    /// Services the paired device while setting the input state from the given stream, and
+    /// returns when the stream ends or the connection is closed. Only the latest input ready in
+    /// the stream is set, and older ones are skipped instead of queued. Inputs which cannot be
+    /// set are logged and ignored. All inputs are released when the stream ends. The run can be
+    /// cancelled with the cancel token, where `ErrorKind::Cancelled` is returned.
+    pub async fn run_with_input<S: Stream<Item = ControllerInput>>(
+        &mut self,
+        input: S,
+    ) -> Result<()> {
+        let handle = self.handle.clone();
+        let feeding = async move {
+            futures::pin_mut!(input);
+            while let Some(mut latest) = input.next().await {
+                let mut ended = false;
+                loop {
+                    match input.next().now_or_never() {
+                        Some(Some(next)) => latest = next,
+                        Some(None) => {
+                            ended = true;
+                            break;
+                        }
+                        None => break,
+                    }
+                }
+                if let Err(e) = handle.set_input(&latest) {
+                    warn!("{}", e);
+                }
+                if ended {
+                    break;
+                }
+            }
+        };
+
+        let reason = tokio::select! {
+            result = self.run() => result?,
+            _ = feeding => {
+                // Release all inputs
+                self.handle.set_input(&ControllerInput::new())?;
+                if let Some(itr_channel) = &self.itr_channel {
+                    let frame = self.handle.lock().push_current_report();
+                    self.send_input(itr_channel, &frame.data).await?;
+                }
+
+                return Ok(());
+            }
+        };
+
+        match reason {
+            StopReason::Cancelled => Err(Error::new(ErrorKind::Cancelled, "run cancelled".into())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Services the paired device until the given condition of the protocol holds, where
+    /// `None` is returned.
+    async fn run_until(&mut self, done: impl Fn(&Protocol) -> bool) -> Result<Option<StopReason>> {
+        let result = self.serve(done).await;
+        if result.is_err() && self.itr_channel.is_some() {
+            // The connection cannot be trusted after a failure
+            self.drop_connection(DisconnectReason::Failed);
+            let _ = self
+                .events
+                .send(SwitchEvent::Disconnected(StopReason::Failed));
+        }
+
+        result
+    }
+
+    /// Drops the connection for the reason.
+    fn drop_connection(&mut self, reason: DisconnectReason) {
+        self.itr_channel.take();
+        self.ctr_channel.take();
+        self.scheduled.clear();
+        self.set_status(ConnectionStatus::Disconnected { reason });
+    }
+
+    /// Keeps the controller available to the last connected device until the device resets the
+    /// pairing info or the run is cancelled.
+    ///
+    /// The device is serviced while connected, where the connection is checked with `ping` at
+    /// the ping interval. On IO errors, ping timeouts, lost links or a disconnecting HCI
+    /// state requested by the device, the connection is dropped and the controller reconnects to
+    /// the device with an exponential backoff. If the policy allows, the controller falls back to
+    /// pairing after the reconnection attempts are used up. After disconnecting for idleness,
+    /// the controller reconnects once the input changes. Transitions are observable through
+    /// the status and events, and `StopReason::PairingReset` is returned when the device resets
+    /// the pairing info.
+    ///
+    /// If the Bluetooth stack restarts, like when bluetoothd or D-Bus restarts, the session is
+    /// reconnected by `reconnect_session` and the controller reconnects to the device. A removed
+    /// adapter is waited for in the same way, and `StopReason::AdapterRemoved` is returned if it
+    /// is not back.
+    pub async fn run_forever(&mut self, policy: ReconnectPolicy) -> Result<StopReason> {
+        loop {
+            // Service the device while connected
+            if self.itr_channel.is_some() {
+                match self.serve_watched(&policy).await {
+                    Some(StopReason::Idle) if !self.wait_input().await => {
+                        return Ok(StopReason::Cancelled)
+                    }
+                    Some(StopReason::Idle) => {}
+                    // The adapter is removed when bluetoothd stops, and added again when it
+                    // restarts
+                    Some(StopReason::AdapterRemoved) => {
+                        if let Err(e) = self.reconnect_session().await {
+                            warn!("{}", e);
+
+                            return Ok(StopReason::AdapterRemoved);
+                        }
+                    }
+                    Some(reason) => return Ok(reason),
+                    None => {}
+                }
+            }
+
+            // Reconnect
+            let mut attempts = 0;
+            let mut failures = 0;
+            let mut backoff = policy.initial_backoff;
+            while self.itr_channel.is_none() {
+                let addr = match self.switch_addr {
+                    Some(addr) if policy.max_attempts.is_none_or(|n| attempts < n) => addr,
+                    _ if policy.pair_fallback => {
+                        info!("Fall back to pairing");
+                        match self.pair().await {
+                            Ok(_) => break,
+                            Err(e) if matches!(e.kind, ErrorKind::Cancelled) => {
+                                return Ok(StopReason::Cancelled)
+                            }
+                            Err(e) => {
+                                warn!("{}", e);
+                                attempts = 0;
+                                continue;
+                            }
+                        }
+                    }
+                    Some(addr) => {
+                        return Err(Error::new(
+                            ErrorKind::Disconnected,
+                            format!(
+                                "cannot reconnect to device {} in {} attempts",
+                                addr, attempts
+                            ),
+                        ))
+                    }
+                    None => {
+                        return Err(Error::new(
+                            ErrorKind::Disconnected,
+                            "no device to reconnect".into(),
+                        ))
+                    }
+                };
+
+                let delay = policy.jittered(backoff);
+                info!("Reconnect to device {} in {:?}", addr, delay);
+                let cancel_token = self.cancel_token.clone();
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = cancel_token.cancelled() => {
+                        self.cancel_token = CancelToken::new();
+
+                        return Ok(StopReason::Cancelled);
+                    }
+                }
+                let result = tokio::select! {
+                    result = self.connect(addr) => result,
+                    _ = cancel_token.cancelled() => {
+                        self.cancel_token = CancelToken::new();
+                        self.drop_connection(DisconnectReason::Requested);
+
+                        return Ok(StopReason::Cancelled);
+                    }
+                };
+                if let Err(e) = result {
+                    warn!("{}", e);
+                    attempts += 1;
+                    backoff = policy.next_backoff(backoff);
+
+                    // The session is broken for good after the Bluetooth stack restarts
+                    if e.is_stack_lost() {
+                        failures = 0;
+                        warn!("Bluetooth stack is lost, reconnect session");
+                        if let Err(e) = self.reconnect_session().await {
+                            warn!("{}", e);
+                        }
+                        continue;
+                    }
+
+                    // Adapter errors in a row may be a wedged adapter, which a power cycle
+                    // recovers
+                    if matches!(e.kind, ErrorKind::Timeout) {
+                        failures = 0;
+                    } else {
+                        failures += 1;
+                    }
+                    if policy.reset_after.is_some_and(|n| failures >= n) {
+                        failures = 0;
+                        warn!("Power-cycle adapter {}", self.adapter.name());
+                        match self.reset_adapter().await {
+                            Ok(elapsed) => info!(
+                                "Adapter {} is power-cycled in {:?}",
+                                self.adapter.name(),
+                                elapsed
+                            ),
+                            Err(e) => warn!("{}", e),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Waits until the input changes. Returns `false` if cancelled.
+    async fn wait_input(&mut self) -> bool {
+        info!("Wait for input to reconnect");
+        let changed = self.handle.changed.clone();
+        let cancel_token = self.cancel_token.clone();
+        while !self.handle.lock().input_changed() {
+            tokio::select! {
+                _ = changed.notified() => {}
+                _ = cancel_token.cancelled() => {
+                    self.cancel_token = CancelToken::new();
+
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Services the device until the connection drops, and returns the reason if the run should
+    /// stop.
+    async fn serve_watched(&mut self, policy: &ReconnectPolicy) -> Option<StopReason> {
+        loop {
+            let running = self.run_until(|protocol| {
+                protocol.pairing_reset() || protocol.hci_state() == Some(HciState::Disconnect)
+            });
+            let result = match tokio::time::timeout(policy.ping_interval, running).await {
+                Ok(result) => result,
+                Err(_) => {
+                    // The run is dropped without frames lost
+                    match self.ping(policy.ping_timeout).await {
+                        Ok(_) => continue,
+                        Err(e) => {
+                            warn!("{}", e);
+                            if self.itr_channel.is_some() {
+                                self.drop_connection(DisconnectReason::Failed);
+                            }
+
+                            return None;
+                        }
+                    }
+                }
+            };
+
+            match result {
+                Ok(Some(StopReason::Closed | StopReason::Timeout | StopReason::LinkLost)) => {
+                    return None
+                }
+                Ok(Some(reason)) => return Some(reason),
+                Ok(None) => {
+                    let (pairing_reset, hci_state) = {
+                        let protocol = self.handle.lock();
+
+                        (protocol.pairing_reset(), protocol.hci_state())
+                    };
+                    if pairing_reset {
+                        info!("Device reset the pairing info");
+                        self.close();
+                        self.set_status(ConnectionStatus::Disconnected {
+                            reason: DisconnectReason::Requested,
+                        });
+                        let _ = self
+                            .events
+                            .send(SwitchEvent::Disconnected(StopReason::PairingReset));
+
+                        return Some(StopReason::PairingReset);
+                    }
+                    let state = hci_state.unwrap_or(HciState::Disconnect);
+                    info!("Device requested HCI state {}", state);
+                    self.close();
+                    self.set_status(ConnectionStatus::Disconnected {
+                        reason: DisconnectReason::Requested,
+                    });
+                    let _ = self
+                        .events
+                        .send(SwitchEvent::Disconnected(StopReason::HciState(state)));
+
+                    return None;
+                }
+                Err(e) => {
+                    warn!("{}", e);
+
+                    return None;
+                }
+            }
+        }
+    }
+
+    async fn serve(&mut self, done: impl Fn(&Protocol) -> bool) -> Result<Option<StopReason>> {
+        loop {
+            let result = self.serve_connection(&done).await;
+
+            // A connection stopped before the device assigns a player fails the pairing
+            let reason = match &result {
+                Ok(Some(reason)) => Some(reason.to_string()),
+                Ok(None) => None,
+                Err(e) => Some(e.to_string()),
+            };
+            if let Some(reason) = reason {
+                if self.handle.lock().fail_handshake(reason) {
+                    self.emit_events();
+                }
+            }
+
+            // The run resumes after a simulated disconnection reconnects
+            if let Ok(Some(StopReason::Simulated)) = result {
+                if let Some(delay) = self.simulated_reconnect.take() {
+                    match self.reconnect_after(delay).await? {
+                        Some(reason) => return Ok(Some(reason)),
+                        None => continue,
+                    }
+                }
+            }
+
+            return result;
+        }
+    }
+
+    /// Drops the connection as if the battery is pulled, and reconnects to the device after the
+    /// given duration if any. Nothing happens if the controller is not connected. To drop the
+    /// connection of a running controller, use `ControllerHandle::simulate_disconnect` instead.
+    pub async fn simulate_disconnect(&mut self, reconnect_after: Option<Duration>) -> Result<()> {
+        if self.itr_channel.is_none() {
+            return Ok(());
+        }
+        self.simulated();
+        if let Some(delay) = reconnect_after {
+            if let Some(reason) = self.reconnect_after(delay).await? {
+                return Err(Error::new(
+                    ErrorKind::Cancelled,
+                    format!("reconnection stopped: {}", reason),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reconnects to the last connected device after the given duration. Returns
+    /// `StopReason::Cancelled` if cancelled, or `None` once reconnected.
+    async fn reconnect_after(&mut self, delay: Duration) -> Result<Option<StopReason>> {
+        let switch_addr = match self.switch_addr {
+            Some(switch_addr) => switch_addr,
+            None => return Ok(Some(StopReason::Simulated)),
+        };
+
+        info!("Reconnect to device {} in {:?}", switch_addr, delay);
+        let cancel_token = self.cancel_token.clone();
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = cancel_token.cancelled() => {
+                self.cancel_token = CancelToken::new();
+
+                return Ok(Some(StopReason::Cancelled));
+            }
+        }
+        self.connect(switch_addr).await?;
+
+        Ok(None)
+    }
+
+    async fn serve_connection(
+        &mut self,
+        done: impl Fn(&Protocol) -> bool,
+    ) -> Result<Option<StopReason>> {
+        loop {
+            let itr_channel = match &self.itr_channel {
+                Some(itr_channel) => itr_channel,
+                None => {
+                    return Err(Error::from(ErrorKind::Io(io::Error::from(
+                        io::ErrorKind::NotConnected,
+                    ))))
+                }
+            };
+            let cancel_token = self.cancel_token.clone();
+            let switch_addr = itr_channel.peer();
+            let changed = self.handle.changed.clone();
+            let mut buf = [0u8; RECV_MTU];
+            let mut last_received = Instant::now();
+            let mut report_mode = self.handle.lock().report_mode();
+            // Bluetooth events notice a lost link or a removed adapter before the channels fail
+            let mut bluetooth_events =
+                match bluetooth::subscribe(&self.session, &self.adapter, Some(itr_channel.peer()))
+                    .await
+                {
+                    Ok(events) => events,
+                    Err(e) => {
+                        warn!("{}", e);
+
+                        futures::stream::pending().boxed()
+                    }
+                };
+            // Connections on the kept listeners while serving are from the device reconnecting over
+            // stale channels, or from other devices which are rejected
+            let never = CancelToken::new();
+            let mut reconnecting = Box::pin(async {
+                match &self.listeners {
+                    Some((ctr_listener, itr_listener)) => {
+                        accept_from(
+                            ctr_listener,
+                            itr_listener,
+                            switch_addr,
+                            &never,
+                            &self.stats.rejected_peers,
+                        )
+                        .await
+                    }
+                    None => future::pending().await,
+                }
+            });
+            let mut reconnected = None;
+
+            let reason = loop {
+                // Send due frames
+                let now = Instant::now();
+                // Scheduled frames are kept in the controller in case the run is dropped
+                let (due, pending): (Vec<_>, Vec<_>) = self
+                    .scheduled
+                    .drain(..)
+                    .partition(|frame| frame.is_due(now));
+                self.scheduled = pending;
+                for frame in due {
+                    self.send_input(itr_channel, &frame.data).await?;
+                }
+                loop {
+                    // The protocol is never locked across sends
+                    let frame = self.handle.lock().poll_tick(now);
+                    match frame {
+                        Some(frame) => self.send_input(itr_channel, &frame.data).await?,
+                        None => break,
+                    }
+                }
+                self.emit_events();
+                let (done, last_input) = {
+                    let protocol = self.handle.lock();
+
+                    (done(&protocol), protocol.last_input())
+                };
+                if done {
+                    return Ok(None);
+                }
+                let idle_deadline = self
+                    .idle_timeout
+                    .zip(last_input)
+                    .map(|(timeout, last_input)| last_input + timeout);
+                if idle_deadline.is_some_and(|deadline| deadline <= now) {
+                    break StopReason::Idle;
+                }
+                let receive_deadline = self.receive_timeout.map(|timeout| last_received + timeout);
+                if receive_deadline.is_some_and(|deadline| deadline <= now) {
+                    break StopReason::Timeout;
+                }
+
+                if let Some(reconnect_after) = self.handle.lock().take_disconnect_request() {
+                    self.simulated_reconnect = reconnect_after;
+                    break StopReason::Simulated;
+                }
+
+                // Wait for the next output report or the next frame to send
+                let deadline = self
+                    .scheduled
+                    .iter()
+                    .filter_map(|frame| frame.send_after)
+                    .chain(self.handle.lock().next_tick(now))
+                    .chain(idle_deadline)
+                    .chain(receive_deadline)
+                    .min();
+                let sleep = async {
+                    match deadline {
+                        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    _ = cancel_token.cancelled() => break StopReason::Cancelled,
+                    result = itr_channel.recv_report(&mut buf) => {
+                        let size = match result {
+                            Ok(size) => size,
+                            Err(e) if matches!(e.kind, ErrorKind::PeerClosed) => {
+                                break StopReason::Closed
+                            }
+                            Err(e) => return Err(e),
+                        };
+                        last_received = Instant::now();
+                        let frames = self.handle_output(&buf[..size]);
+                        self.scheduled.extend(frames);
+
+                        // The input reports of a new report mode must fit in the MTU
+                        let mode = self.handle.lock().report_mode();
+                        if mode != report_mode {
+                            report_mode = mode;
+                            check_mtu(itr_channel, mode)?;
+                        }
+                    }
+                    event = bluetooth_events.next() => match event {
+                        Some(BluetoothEvent::AdapterRemoved) => break StopReason::AdapterRemoved,
+                        Some(BluetoothEvent::AdapterPowered(false)) => break StopReason::LinkLost,
+                        Some(
+                            BluetoothEvent::DeviceDisconnected(addr)
+                            | BluetoothEvent::DeviceRemoved(addr),
+                        ) if addr == itr_channel.peer() => break StopReason::LinkLost,
+                        Some(event) => debug!("{}", event),
+                        None => bluetooth_events = futures::stream::pending().boxed(),
+                    },
+                    accepted = &mut reconnecting => {
+                        reconnected = Some(accepted?);
+                        break StopReason::Closed;
+                    }
+                    _ = changed.notified() => {}
+                    _ = sleep => {}
+                }
+            };
+            drop(reconnecting);
+            if let Some((ctr_channel, itr_channel)) = reconnected {
+                self.replace_connection(ctr_channel, itr_channel);
+                continue;
+            }
+
+            match reason {
+                StopReason::Closed => self.closed(),
+                StopReason::Cancelled => self.cancel_token = CancelToken::new(),
+                StopReason::PairingReset => {}
+                StopReason::Timeout => self.timed_out(),
+                StopReason::Idle => self.idle(),
+                StopReason::Simulated => self.simulated(),
+                StopReason::LinkLost => self.link_lost(),
+                StopReason::AdapterRemoved => self.adapter_removed(),
+                StopReason::HciState(_) | StopReason::Failed => {}
+            }
+
+            return Ok(Some(reason));
+        }
+    }
+
+    /// Replaces the channels with the ones of the device reconnecting, where the device starts
+    /// over as a reconnected controller.
+    fn replace_connection(&mut self, ctr_channel: HidChannel, itr_channel: HidChannel) {
+        for channel in [self.itr_channel.take(), self.ctr_channel.take()]
+            .into_iter()
+            .flatten()
+        {
+            if let Err(e) = channel.shutdown() {
+                debug!("{}", e);
+            }
+        }
+        self.scheduled.clear();
+        self.handle.lock().reset();
+        let switch_addr = itr_channel.peer();
+        self.ctr_channel = Some(ctr_channel);
+        self.itr_channel = Some(itr_channel);
+        info!("Device {} reconnected over stale channels", switch_addr);
+        self.set_status(ConnectionStatus::Connected {
+            switch_addr,
+            since: Instant::now(),
+        });
+    }
+
+    /// Checks if the connection is alive by sending an input report and waiting for any frame
+    /// from the device, which streams output reports constantly while connected. Returns the
+    /// time until the frame is received, or `ErrorKind::Disconnected` if no frame is received in
+    /// the timeout. The connection is kept on timeout, so the caller decides whether to drop it.
+    pub async fn ping(&mut self, timeout: Duration) -> Result<Duration> {
+        let itr_channel = match &self.itr_channel {
+            Some(itr_channel) => itr_channel,
+            None => {
+                return Err(Error::from(ErrorKind::Io(io::Error::from(
+                    io::ErrorKind::NotConnected,
+                ))))
+            }
+        };
+
+        let frame = self.handle.lock().push_current_report();
+        let start = Instant::now();
+        self.send_input(itr_channel, &frame.data).await?;
+        let mut buf = [0u8; RECV_MTU];
+        let size = match itr_channel
+            .recv_with_deadline(&mut buf, start + timeout)
+            .await
+        {
+            Ok(Received::Report(size)) => Some(size),
+            Err(e) if matches!(e.kind, ErrorKind::PeerClosed) => None,
+            Err(e) => return Err(e),
+            Ok(Received::WouldBlock | Received::TimedOut) => {
+                return Err(Error::new(
+                    ErrorKind::Disconnected,
+                    format!("no frame from device in {:?}", timeout),
+                ))
+            }
+        };
+        let size = match size {
+            Some(size) => size,
+            None => {
+                self.closed();
+
+                return Err(Error::new(
+                    ErrorKind::Disconnected,
+                    "connection closed".into(),
+                ));
+            }
+        };
+        let elapsed = start.elapsed();
+
+        // The frame is handled as in `run`
+        let frames = self.handle_output(&buf[..size]);
+        self.scheduled.extend(frames);
+        self.emit_events();
+
+        Ok(elapsed)
+    }
+
+    /// Sends an input report through the hook of the input direction.
+    async fn send_input(&self, channel: &HidChannel, data: &[u8]) -> Result<()> {
+        let sent = match self.apply_hook(Direction::Input, data) {
+            HookAction::Continue => channel.send_report(data).await?,
+            HookAction::Drop => return Ok(()),
+            HookAction::Replace(data) => channel.send_report(&data).await?,
+        };
+        if sent == Sent::Dropped {
+            debug!(
+                "Drop report to {}, which keeps failing to send",
+                channel.peer()
+            );
+        }
+        self.stats.record_sent(sent);
+
+        Ok(())
+    }
+
+    /// Handles an output report through the hook of the output direction, and returns the frames
+    /// to reply. Malformed output reports are logged and ignored.
+    fn handle_output(&self, data: &[u8]) -> Vec<OutgoingFrame> {
+        let replaced;
+        let data = match self.apply_hook(Direction::Output, data) {
+            HookAction::Continue => data,
+            HookAction::Drop => return vec![],
+            HookAction::Replace(data) => {
+                replaced = data;
+                &replaced
+            }
+        };
+
+        let result = self.handle.lock().handle_incoming(data, Instant::now());
+        self.stats.record_incoming(data, &result);
+        match result {
+            Ok(frames) => frames,
+            Err(e) => {
+                warn!("{}", e);
+
+                vec![]
+            }
+        }
+    }
+
+    /// Returns the action of the report hook of the given direction on the frame. A panicking
+    /// hook is logged and the frame is passed through.
+    fn apply_hook(&self, direction: Direction, data: &[u8]) -> HookAction {
+        let hook = match direction {
+            Direction::Input => &self.input_hook,
+            Direction::Output => &self.output_hook,
+        };
+        let hook = match hook {
+            Some(hook) => hook,
+            None => return HookAction::Continue,
+        };
+
+        let start = Instant::now();
+        let action = match panic::catch_unwind(AssertUnwindSafe(|| hook(data))) {
+            Ok(action) => action,
+            Err(_) => {
+                warn!("{} report hook panicked, pass the frame through", direction);
+
+                HookAction::Continue
+            }
+        };
+        let elapsed = start.elapsed();
+        if elapsed > HOOK_TIME_BUDGET {
+            warn!(
+                "{} report hook took {:?}, exceeding the budget of {:?}",
+                direction, elapsed, HOOK_TIME_BUDGET
+            );
+        }
+
+        action
+    }
+
+    /// Drops the connection closed by the device.
+    fn closed(&mut self) {
+        info!("Device closed the connection");
+        self.itr_channel.take();
+        self.ctr_channel.take();
+        self.scheduled.clear();
+        self.set_status(ConnectionStatus::Disconnected {
+            reason: DisconnectReason::Closed,
+        });
+        let _ = self
+            .events
+            .send(SwitchEvent::Disconnected(StopReason::Closed));
+    }
+
+    /// Shuts down the channels like `disconnect` and drops the connection for the reason, where
+    /// the service record is kept for reconnections.
+    fn shutdown(&mut self, reason: DisconnectReason) {
+        for channel in [self.itr_channel.iter(), self.ctr_channel.iter()]
+            .into_iter()
+            .flatten()
+        {
+            if let Err(e) = channel.shutdown() {
+                warn!("{}", e);
+            }
+        }
+        self.drop_connection(reason);
+    }
+
+    fn idle(&mut self) {
+        info!("Disconnect for idleness");
+        self.shutdown(DisconnectReason::Idle);
+        let _ = self.events.send(SwitchEvent::IdleDisconnect);
+    }
+
+    fn simulated(&mut self) {
+        info!("Simulate disconnection");
+        self.shutdown(DisconnectReason::Simulated);
+        let _ = self
+            .events
+            .send(SwitchEvent::Disconnected(StopReason::Simulated));
+    }
+
+    fn link_lost(&mut self) {
+        warn!("Link to device lost");
+        self.shutdown(DisconnectReason::LinkLost);
+        let _ = self
+            .events
+            .send(SwitchEvent::Disconnected(StopReason::LinkLost));
+    }
+
+    fn adapter_removed(&mut self) {
+        warn!("Adapter {} removed", self.adapter.name());
+        // The channels are gone with the adapter, so they are not shut down
+        self.drop_connection(DisconnectReason::AdapterRemoved);
+        let _ = self
+            .events
+            .send(SwitchEvent::Disconnected(StopReason::AdapterRemoved));
+    }
+
+    fn timed_out(&mut self) {
+        warn!(
+            "No frame from device in {:?}",
+            self.receive_timeout.unwrap_or_default()
+        );
+        self.shutdown(DisconnectReason::Timeout);
+        let _ = self
+            .events
+            .send(SwitchEvent::Disconnected(StopReason::Timeout));
+    }
+
+    /// Broadcasts the events taken from the protocol.
+    fn emit_events(&self) {
+        // Events are dropped if nobody is listening
+        let events = self.handle.lock().take_events();
+        for event in events {
+            let _ = self.events.send(event);
+        }
+    }
+
+    /// Receives raw data from the paired device. Returns `ErrorKind::PeerClosed` if the device
+    /// has closed the connection.
+    pub async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        match &self.itr_channel {
+            Some(itr_channel) => itr_channel.recv_report(buf).await,
+            None => Err(Error::from(ErrorKind::Io(io::Error::from(
+                io::ErrorKind::NotConnected,
+            )))),
+        }
+    }
+
+    /// Receives an output report from the paired device. Returns `ErrorKind::PeerClosed` if the
+    /// device has closed the connection.
+    pub async fn recv_output(&self) -> Result<Output> {
+        let mut buf = [0u8; RECV_MTU];
+        let size = self.recv(&mut buf).await?;
+
+        Output::try_from(&buf[..size])
+    }
+}
+
+/// Listens on the CTR and ITR PSMs of the given local address of the adapter with the given MTU,
+/// flush timeout and security level, which are inherited by accepted channels.
+async fn listen(
+    adapter: &Adapter,
+    addr: Address,
+    mtu: u16,
+    flush_timeout: u16,
+    security_level: SecurityLevel,
+    refused: &AtomicBool,
+) -> Result<(HidListener, HidListener)> {
+    let bind = |psm| -> Result<HidListener> {
+        let local_addr = SocketAddr::new(addr, AddressType::BrEdr, psm);
+        let socket = Socket::new_seq_packet()?;
+        socket.bind(local_addr)?;
+        socket.set_recv_mtu(mtu)?;
+        socket.set_send_mtu(mtu)?;
+        set_flush_timeout(&socket, flush_timeout, psm, refused);
+        set_security_level(&socket, security_level, psm)?;
+
+        Ok(HidListener::new(socket.listen(1)?, local_addr))
+    };
+
+    // The bind fails if another process, like the input plugin of bluetoothd, holds the PSM
+    let in_use =
+        |e: &Error| matches!(&e.kind, ErrorKind::Io(e) if e.kind() == io::ErrorKind::AddrInUse);
+    let mut listeners = vec![];
+    for psm in [CTR_PSM, ITR_PSM] {
+        match bind(psm) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) if in_use(&e) => return Err(psm_in_use(adapter, psm).await),
+            Err(e) => return Err(e),
+        }
+    }
+    let itr_listener = listeners.pop().unwrap();
+    let ctr_listener = listeners.pop().unwrap();
+
+    Ok((ctr_listener, itr_listener))
+}
+
+/// Returns the error of the given PSM in use, which explains if the input plugin of bluetoothd
+/// holds it.
+async fn psm_in_use(adapter: &Adapter, psm: u16) -> Error {
+    let message = match bluetooth::has_input_plugin(adapter).await {
+        Ok(true) => format!(
+            "PSM {} of adapter {} is held by the input plugin of bluetoothd, restart bluetoothd \
+             without it by `bluetoothd -P input`, or with a compatibility override of its service",
+            psm,
+            adapter.name()
+        ),
+        Ok(false) => format!(
+            "PSM {} of adapter {} is in use by another process, is another controller or the \
+             input plugin of bluetoothd running?",
+            psm,
+            adapter.name()
+        ),
+        Err(e) => {
+            debug!("{}", e);
+
+            format!(
+                "PSM {} of adapter {} is in use, is the input plugin of bluetoothd running? \
+                 Restart bluetoothd by `bluetoothd -P input` to disable it",
+                psm,
+                adapter.name()
+            )
+        }
+    };
+
+    let error = bluer::Error {
+        kind: bluer::ErrorKind::AlreadyExists,
+        message: io::Error::from(io::ErrorKind::AddrInUse).to_string(),
+    };
+
+    Error::from(bluetooth::Error::new(error, message))
+}
+
+/// Runs the given blocking call, like running a process or accessing files, on the blocking
+/// threads, so the runtime keeps handling connections and sending reports meanwhile.
+pub(crate) async fn unblock<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) => Err(Error::new(
+            ErrorKind::Other,
+            format!("blocking call failed: {}", e),
+        )),
+    }
+}
+
+/// Locks the given pairing store, which fails if an update of the store panicked.
+#[cfg(feature = "store")]
+fn lock_store(store: &Mutex<PairingStore>) -> Result<MutexGuard<'_, PairingStore>> {
+    store.lock().map_err(|_| {
+        Error::new(
+            ErrorKind::Other,
+            "pairing store is poisoned by a failed update".into(),
+        )
+    })
+}
+
+/// Checks if the given adapter supports BR/EDR, and names other adapters supporting it if not.
+/// The probe is in best effort, where adapters pass if their features are unknown.
+async fn check_br_edr(session: &Session, adapter: &Adapter) -> Result<()> {
+    let supports_br_edr = |adapter: Adapter| unblock(move || adapter.supports_br_edr());
+    match supports_br_edr(adapter.clone()).await {
+        Ok(true) => return Ok(()),
+        Ok(false) => {}
+        Err(e) => {
+            debug!("{}", e);
+
+            return Ok(());
+        }
+    }
+
+    let mut others = vec![];
+    for name in session.adapter_names().await? {
+        if name == adapter.name() {
+            continue;
+        }
+        if let Ok(true) = supports_br_edr(session.adapter(&name)?).await {
+            others.push(name);
+        }
+    }
+    let hint = if others.is_empty() {
+        "no other adapter supports it".to_string()
+    } else {
+        format!("try adapter {}", others.join(", "))
+    };
+
+    Err(Error::new(
+        ErrorKind::Unavailable,
+        format!(
+            "adapter {} supports LE only, where BR/EDR is required, {}",
+            adapter.name(),
+            hint
+        ),
+    ))
+}
+
+/// Checks if the process can set the class of the adapter, and create L2CAP sockets and bind the
+/// HID PSMs of the given local address if the PSMs are checked. The socket is closed after the
+/// check.
+fn preflight(addr: Address, psm: bool) -> Result<()> {
+    let denied = |e: &io::Error| {
+        e.kind() == io::ErrorKind::PermissionDenied || e.raw_os_error() == Some(EPERM)
+    };
+
+    let socket =
+        match Socket::new_seq_packet() {
+            Ok(socket) => socket,
+            Err(e) if denied(&e) => return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "cannot create L2CAP sockets for HID channels, which requires root or CAP_NET_RAW"
+                    .into(),
+            )),
+            Err(e) => return Err(e.into()),
+        };
+    if psm {
+        // Other bind errors, like the PSM in use, are explained when listening
+        match socket.bind(SocketAddr::new(addr, AddressType::BrEdr, CTR_PSM)) {
+            Err(e) if denied(&e) => {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!(
+                        "cannot bind HID PSM {} for listening, which requires root or \
+                         CAP_NET_BIND_SERVICE",
+                        CTR_PSM
+                    ),
+                ))
+            }
+            _ => {}
+        }
+    }
+    drop(socket);
+
+    // The class is set by hciconfig through a raw HCI socket. Capabilities are checked in best
+    // effort, where unknown capabilities pass
+    if let Some(false) = has_capability(CAP_NET_ADMIN) {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "cannot set class of adapter through a raw HCI socket, which requires root or \
+             CAP_NET_ADMIN"
+                .into(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns if the process has the given effective capability, or `None` if unknown.
+fn has_capability(capability: u32) -> Option<bool> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let caps = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())?;
+
+    Some(caps & (1 << capability) != 0)
+}
+
+/// Sets the flush timeout of the given socket. The socket works without the flush timeout, so a
+/// refusal of the kernel is warned and recorded rather than failing.
+fn set_flush_timeout<T>(socket: &Socket<T>, timeout: u16, psm: u16, refused: &AtomicBool) {
+    if let Err(e) = socket.set_flush_timeout(timeout) {
+        warn!(
+            "Cannot set flush timeout {} of PSM {}, stale input reports may be retransmitted: {}",
+            timeout, psm, e
+        );
+        refused.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Sets the security level of the given socket. Unlike the flush timeout, a refused security
+/// level fails, since the device may not accept the level the kernel falls back to.
+fn set_security_level<T>(socket: &Socket<T>, level: SecurityLevel, psm: u16) -> Result<()> {
+    socket.set_security_level(level).map_err(|e| {
+        Error::new(
+            ErrorKind::Io(e),
+            format!("cannot set security level {:?} of PSM {}", level, psm),
+        )
+    })
+}
+
+/// Checks if the input reports of the given report mode fit in the send MTU of the given
+/// channel. The check is skipped if the MTU is not negotiated yet.
+fn check_mtu(channel: &HidChannel, mode: ReportMode) -> Result<()> {
+    let mtu = match channel.send_mtu() {
+        Ok(mtu) => mtu,
+        Err(_) => return Ok(()),
+    };
+    if mode.report_len() > mtu {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!(
+                "send MTU {} of channel to {} is too small for {} input reports of {} bytes",
+                mtu,
+                channel.peer(),
+                mode,
+                mode.report_len()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Logs the negotiated MTU and the security level in effect of the given channel.
+/// Returns the error of registering the service record, which includes the given stale records.
+fn service_record_error(error: bluer::Error, stale: &[sdp::Registered]) -> bluetooth::Error {
+    let mut message = String::from("cannot register service record");
+    if !stale.is_empty() {
+        let records: Vec<_> = stale.iter().map(|r| r.to_string()).collect();
+        message += &format!(
+            ", where stale service records {} are active",
+            records.join(", ")
+        );
+    }
+
+    bluetooth::Error::new(error, message)
+}
+
+fn log_options(channel: &HidChannel) {
+    match (channel.send_mtu(), channel.recv_mtu()) {
+        (Ok(send_mtu), Ok(recv_mtu)) => {
+            debug!("MTU = {} (send), {} (receive)", send_mtu, recv_mtu)
+        }
+        (Err(e), _) | (_, Err(e)) => debug!("{}", e),
+    }
+    match channel.security_level() {
+        Ok(level) => debug!("security level = {:?}", level),
+        Err(e) => debug!("{}", e),
+    }
+}
+
+/// Sets the alias of the given adapter to the given name of the controller type, and checks the
+/// name advertised to the device, which may be cached by BlueZ. The alias is reapplied for a
+/// bounded number of times if the name differs.
+async fn set_alias(adapter: &Adapter, controller_type: ControllerType, alias: &str) -> Result<()> {
+    // The device does not pair with other names
+    if alias != controller_type.name() {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!(
+                "alias {} is not accepted for {}, which is {}",
+                alias,
+                controller_type,
+                controller_type.name()
+            ),
+        ));
+    }
+
+    let mut advertised = String::new();
+    for attempt in 0..=ALIAS_RETRIES {
+        if attempt > 0 {
+            debug!("reapply alias {}, which is {}", alias, advertised);
+            tokio::time::sleep(ALIAS_RETRY_INTERVAL).await;
+        }
+        adapter.set_alias(alias.into()).await?;
+        advertised = adapter.alias().await?;
+        // The local name is checked in best effort, where the alias is trusted if unavailable
+        if advertised == alias {
+            let named = adapter.clone();
+            match unblock(move || named.local_name()).await {
+                Ok(name) => advertised = name,
+                Err(e) => debug!("{}", e),
+            }
+        }
+        if advertised == alias {
+            return Ok(());
+        }
+    }
+
+    Err(Error::new(
+        ErrorKind::Other,
+        format!(
+            "cannot set alias of adapter {} to {}, which advertises {}",
+            adapter.name(),
+            alias,
+            advertised
+        ),
+    ))
+}
+
+/// Sets the gamepad class of the given adapter, which runs `hciconfig` on the blocking threads.
+async fn set_gamepad_class(adapter: &Adapter) -> Result<()> {
+    let adapter = adapter.clone();
+
+    unblock(move || Ok(adapter.set_class(GAMEPAD_JOYSITCK_COD)?)).await
+}
+
+/// Sets the gamepad class of the given adapter, and checks it for the given number of times at
+/// the interval, where the class is reapplied if it changes. Returns an error if the class is
+/// not held at the last check.
+async fn settle_class(adapter: &Adapter, retries: u32, interval: Duration) -> Result<()> {
+    set_gamepad_class(adapter).await?;
+    let mut class = adapter.class().await?;
+    for _ in 0..retries {
+        tokio::time::sleep(interval).await;
+        class = adapter.class().await?;
+        if class != GAMEPAD_JOYSITCK_COD {
+            warn!(
+                "Reapply class of adapter {}, which changed to 0x{:06x}",
+                adapter.name(),
+                class
+            );
+            set_gamepad_class(adapter).await?;
+        }
+    }
+    if class != GAMEPAD_JOYSITCK_COD {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("cannot set class for adapter {}", adapter.name()),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Holds the gamepad class of the given adapter by checking it at the interval, where the class
+/// is reapplied if it changes. Returns an error only if the class is not held after the given
+/// number of reapplies in a row.
+async fn hold_class(adapter: &Adapter, retries: u32, interval: Duration) -> Error {
+    let mut reapplied = 0;
+    loop {
+        tokio::time::sleep(interval).await;
+        let class = match adapter.class().await {
+            Ok(class) => class,
+            Err(e) => return e.into(),
+        };
+        if class == GAMEPAD_JOYSITCK_COD {
+            reapplied = 0;
+            continue;
+        }
+        if reapplied >= retries {
+            return Error::new(
+                ErrorKind::Other,
+                format!(
+                    "cannot hold class for adapter {}, which changed to 0x{:06x}",
+                    adapter.name(),
+                    class
+                ),
+            );
+        }
+        warn!(
+            "Reapply class of adapter {}, which changed to 0x{:06x}",
+            adapter.name(),
+            class
+        );
+        if let Err(e) = set_gamepad_class(adapter).await {
+            return e;
+        }
+        reapplied += 1;
+    }
+}
+
+/// Holds the given adapter discoverable by watching its discoverable, where it is made
+/// discoverable again if something else turns it off. Every restore is reported through the
+/// given callback. Returns an error only if the adapter cannot be made discoverable again, and
+/// never returns if the adapter events cannot be subscribed to.
+async fn hold_discoverable(adapter: &Adapter, emit: impl Fn(PairingEvent)) -> Error {
+    let events = match adapter.events().await {
+        Ok(events) => events,
+        Err(e) => {
+            warn!(
+                "Cannot watch discoverable of adapter {}: {}",
+                adapter.name(),
+                e
+            );
+
+            return future::pending().await;
+        }
+    };
+    let mut events = Box::pin(events);
+    while let Some(event) = events.next().await {
+        if let AdapterEvent::PropertyChanged(AdapterProperty::Discoverable(false)) = event {
+            warn!(
+                "Make adapter {} discoverable again, which was turned off while pairing",
+                adapter.name()
+            );
+            if let Err(e) = adapter.set_discoverable(true).await {
+                return e.into();
+            }
+            emit(PairingEvent::PairingWindowRestored);
         }
     }
+
+    future::pending().await
 }
 
-/// Represents an error.
-#[derive(Debug)]
-pub struct Error {
-    /// Represents the error kind.
-    pub kind: ErrorKind,
-    /// Represents the detailed message.
-    pub message: String,
+/// Represents a channel accepted from a listener.
+trait AcceptedChannel {
+    /// Returns the address of the peer device.
+    fn peer(&self) -> Address;
+
+    /// Shuts down both directions of the channel.
+    fn shutdown(&self) -> Result<()>;
+
+    /// Logs the negotiated options of the channel.
+    fn log_options(&self);
 }
 
-impl Error {
-    /// Creates a `Error`.
-    pub fn new(kind: ErrorKind, message: String) -> Self {
-        Error { kind, message }
+impl AcceptedChannel for HidChannel {
+    fn peer(&self) -> Address {
+        HidChannel::peer(self)
     }
-}
 
-impl From<ErrorKind> for Error {
-    fn from(kind: ErrorKind) -> Self {
-        Error::new(kind, String::new())
+    fn shutdown(&self) -> Result<()> {
+        HidChannel::shutdown(self)
+    }
+
+    fn log_options(&self) {
+        log_options(self)
     }
 }
 
-impl From<bluetooth::Error> for Error {
-    fn from(error: bluetooth::Error) -> Self {
-        Error::new(ErrorKind::Bluetooth(error), String::new())
+/// Represents a listener of channels on a PSM.
+trait ChannelListener {
+    /// The accepted channel.
+    type Channel: AcceptedChannel + Send;
+
+    /// Accepts a channel until the given cancel token is cancelled, where `ErrorKind::Cancelled`
+    /// is returned.
+    fn accept(
+        &self,
+        cancel_token: &CancelToken,
+    ) -> impl Future<Output = Result<(Self::Channel, SocketAddr)>> + Send;
+}
+
+impl ChannelListener for HidListener {
+    type Channel = HidChannel;
+
+    async fn accept(&self, cancel_token: &CancelToken) -> Result<(HidChannel, SocketAddr)> {
+        HidListener::accept(self, cancel_token).await
     }
 }
 
-impl From<io::Error> for Error {
-    fn from(error: io::Error) -> Self {
-        Error::new(ErrorKind::Io(error), String::new())
+/// Accepts the CTR and ITR connections from the same device until the given cancel token is
+/// cancelled. Every accepted channel is reported through the given callback.
+async fn accept<L: ChannelListener>(
+    ctr_listener: &L,
+    itr_listener: &L,
+    cancel_token: &CancelToken,
+    emit: impl Fn(PairingEvent),
+) -> Result<(L::Channel, L::Channel, Address)> {
+    let (mut ctr_channel, ctr_addr) = ctr_listener.accept(cancel_token).await?;
+    debug!("accept {}, PSM = {} (CTR)", ctr_addr.addr, ctr_addr.psm);
+    emit(PairingEvent::CtrAccepted(ctr_addr.addr));
+    let (mut itr_channel, itr_addr) = itr_listener.accept(cancel_token).await?;
+    debug!("accept {}, PSM = {} (ITR)", itr_addr.addr, itr_addr.psm);
+    emit(PairingEvent::ItrAccepted(itr_addr.addr));
+
+    // Another device may probe the HID service while the Nintendo Switch is connecting. The
+    // latest connection on a listener replaces the previous one until both channels come from
+    // the same device
+    while ctr_channel.peer() != itr_channel.peer() {
+        tokio::select! {
+            accepted = ctr_listener.accept(cancel_token) => {
+                let (channel, addr) = accepted?;
+                debug!("accept {}, PSM = {} (CTR)", addr.addr, addr.psm);
+                warn!("Reject device {} on CTR", ctr_channel.peer());
+                emit(PairingEvent::CtrAccepted(addr.addr));
+                ctr_channel = channel;
+            }
+            accepted = itr_listener.accept(cancel_token) => {
+                let (channel, addr) = accepted?;
+                debug!("accept {}, PSM = {} (ITR)", addr.addr, addr.psm);
+                warn!("Reject device {} on ITR", itr_channel.peer());
+                emit(PairingEvent::ItrAccepted(addr.addr));
+                itr_channel = channel;
+            }
+        }
     }
+
+    itr_channel.log_options();
+    let addr = itr_channel.peer();
+
+    Ok((ctr_channel, itr_channel, addr))
 }
 
-impl Display for Error {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        if self.message.is_empty() {
-            write!(f, "{}", &self.kind)
-        } else {
-            match self.kind {
-                ErrorKind::Other => write!(f, "{}", &self.message),
-                _ => write!(f, "{}: {}", &self.kind, &self.message),
+/// Accepts the CTR and ITR connections from the given device until the given cancel token is
+/// cancelled. Connections from other devices are shut down at once and counted as rejected, so
+/// they never replace a connection of the device.
+async fn accept_from<L: ChannelListener>(
+    ctr_listener: &L,
+    itr_listener: &L,
+    addr: Address,
+    cancel_token: &CancelToken,
+    rejected: &AtomicU64,
+) -> Result<(L::Channel, L::Channel)> {
+    let mut ctr_channel = None;
+    loop {
+        let (channel, peer) = tokio::select! {
+            accepted = ctr_listener.accept(cancel_token) => accepted?,
+            accepted = itr_listener.accept(cancel_token) => accepted?,
+        };
+        debug!("accept {}, PSM = {}", peer.addr, peer.psm);
+        if peer.addr != addr {
+            warn!(
+                "Reject device {} on PSM {}, which is not the device {}",
+                peer.addr, peer.psm, addr
+            );
+            rejected.fetch_add(1, Ordering::Relaxed);
+            if let Err(e) = channel.shutdown() {
+                debug!("{}", e);
             }
+            continue;
+        }
+
+        // The ITR channel follows the CTR channel, so an ITR channel alone is stale
+        if peer.psm == CTR_PSM {
+            ctr_channel = Some(channel);
+        } else if let Some(ctr_channel) = ctr_channel.take() {
+            channel.log_options();
+
+            return Ok((ctr_channel, channel));
+        } else {
+            debug!("drop ITR without CTR from {}", peer.addr);
         }
     }
 }
 
-/// Represents an result.
-pub type Result<T> = std::result::Result<T, Error>;
+/// Connects to the given PSM of the device with the given MTU, flush timeout and security level.
+async fn connect(
+    local_addr: Address,
+    addr: Address,
+    psm: u16,
+    mtu: u16,
+    flush_timeout: u16,
+    security_level: SecurityLevel,
+    refused: &AtomicBool,
+) -> Result<HidChannel> {
+    let socket = Socket::new_seq_packet()?;
+    socket.bind(SocketAddr::new(local_addr, AddressType::BrEdr, 0))?;
+    socket.set_recv_mtu(mtu)?;
+    socket.set_send_mtu(mtu)?;
+    set_flush_timeout(&socket, flush_timeout, psm, refused);
+    set_security_level(&socket, security_level, psm)?;
 
-// Initializes the logger and set its level.
-pub fn set_logger(verbose: usize) {
-    let level = match verbose {
-        0 => LevelFilter::Info,
-        1 => LevelFilter::Debug,
-        _ => LevelFilter::Trace,
-    };
-    Logger::init(level);
+    HidChannel::connect_socket(socket, addr, psm, CONNECT_TIMEOUT).await
 }
 
-/// Gets the list of Bluetooth adapters.
-pub async fn adapters() -> Result<Vec<String>> {
-    Ok(Session::new().await?.adapter_names().await?)
+/// Returns the Nintendo Switches known by the adapter.
+async fn switch_devices(adapter: &Adapter) -> Result<Vec<Device>> {
+    let mut devices = vec![];
+    for device in Device::all(adapter).await? {
+        if device.name().await?.as_deref() == Some(NINTENDO_SWITCH_NAME) {
+            devices.push(device);
+        }
+    }
+
+    Ok(devices)
 }
 
-const NINTENDO_SWITCH_NAME: &str = "Nintendo Switch";
-const GAMEPAD_JOYSITCK_COD: u32 = 0x002508;
-const CTR_PSM: u16 = 17;
-const ITR_PSM: u16 = 19;
-const SERVICE: &str = "00001124-0000-1000-8000-00805f9b34fb";
-const SERVICE_RECORD: &str = r#"<?xml version="1.0" encoding="UTF-8" ?>
+impl Drop for Controller {
+    fn drop(&mut self) {
+        // Disconnect is expected to be called before drop
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Represents an adapter which keeps its settings in memory.
+    #[derive(Clone)]
+    struct FakeAdapter {
+        settings: Arc<Mutex<AdapterSettings>>,
+        restores: Arc<AtomicU64>,
+    }
+
+    impl FakeAdapter {
+        fn new() -> Self {
+            FakeAdapter {
+                settings: Arc::new(Mutex::new(AdapterSettings {
+                    alias: "raspberrypi".into(),
+                    class: 0x6C0000,
+                    discoverable: false,
+                    discoverable_timeout: 180,
+                    pairable: false,
+                    scan: Some(ScanParameters {
+                        page_interval: 0x0800,
+                        page_window: 0x0012,
+                        page_interlaced: false,
+                        inquiry_interval: 0x1000,
+                        inquiry_window: 0x0012,
+                        inquiry_interlaced: false,
+                    }),
+                })),
+                restores: Arc::new(AtomicU64::new(0)),
+            }
+        }
+
+        fn settings(&self) -> AdapterSettings {
+            self.settings.lock().unwrap().clone()
+        }
+
+        /// Changes the settings like pairing.
+        fn change(&self) {
+            let mut settings = self.settings.lock().unwrap();
+            settings.alias = "Pro Controller".into();
+            settings.class = 0x002508;
+            settings.discoverable = true;
+            settings.discoverable_timeout = 0;
+            settings.pairable = true;
+            if let Some(scan) = &mut settings.scan {
+                scan.page_window = scan.page_interval;
+            }
+        }
+
+        fn restores(&self) -> u64 {
+            self.restores.load(Ordering::Relaxed)
+        }
+    }
+
+    impl SettingsAdapter for FakeAdapter {
+        fn name(&self) -> &str {
+            "hci0"
+        }
+
+        async fn save_settings(&self, scan: bool) -> Result<AdapterSettings> {
+            let mut settings = self.settings();
+            if !scan {
+                settings.scan = None;
+            }
+
+            Ok(settings)
+        }
+
+        async fn restore_settings(&self, settings: &AdapterSettings) -> Result<()> {
+            let mut current = self.settings.lock().unwrap();
+            *current = AdapterSettings {
+                scan: settings.scan.or(current.scan),
+                ..settings.clone()
+            };
+            self.restores.fetch_add(1, Ordering::Relaxed);
+
+            Ok(())
+        }
+    }
+
+    /// Lets tasks spawned on drop run.
+    async fn settle() {
+        for _ in 0..8 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn adapter_guard_restores() {
+        let adapter = FakeAdapter::new();
+        let saved = adapter.settings();
+        let guard = AdapterGuard::save(&adapter, true).await.unwrap();
+        assert!(guard.has_scan());
+        adapter.change();
+        assert_ne!(adapter.settings(), saved);
+
+        guard.restore().await.unwrap();
+        assert_eq!(adapter.settings(), saved);
+        settle().await;
+        assert_eq!(adapter.restores(), 1);
+    }
+
+    #[tokio::test]
+    async fn adapter_guard_restores_on_drop() {
+        let adapter = FakeAdapter::new();
+        let saved = adapter.settings();
+        let guard = AdapterGuard::save(&adapter, false).await.unwrap();
+        assert!(!guard.has_scan());
+        adapter.change();
+
+        drop(guard);
+        settle().await;
+        assert_eq!(adapter.restores(), 1);
+        // The scan parameters are only restored if saved
+        let mut expected = saved;
+        expected.scan = adapter.settings().scan;
+        assert_eq!(adapter.settings(), expected);
+    }
+
+    #[tokio::test]
+    async fn adapter_guard_restores_on_early_return() {
+        async fn pair(adapter: &FakeAdapter) -> Result<()> {
+            let guard = AdapterGuard::save(adapter, true).await?;
+            adapter.change();
+            Err(Error::new(ErrorKind::Timeout, "pairing timed out".into()))?;
+
+            guard.restore().await
+        }
+
+        let adapter = FakeAdapter::new();
+        let saved = adapter.settings();
+        assert!(matches!(
+            pair(&adapter).await,
+            Err(Error {
+                kind: ErrorKind::Timeout,
+                ..
+            })
+        ));
+        settle().await;
+        assert_eq!(adapter.restores(), 1);
+        assert_eq!(adapter.settings(), saved);
+    }
+
+    #[test]
+    fn adapter_guard_without_runtime() {
+        let adapter = FakeAdapter::new();
+        let guard = futures::executor::block_on(AdapterGuard::save(&adapter, true)).unwrap();
+        adapter.change();
+
+        // Nothing restores the settings without a runtime
+        drop(guard);
+        assert_eq!(adapter.restores(), 0);
+    }
+
+    /// Service record of the Pro Controller, which was written by hand before the record was
+    /// built from attributes.
+    const PRO_CONTROLLER_SERVICE_RECORD: &str = r#"<?xml version="1.0" encoding="UTF-8" ?>
 <record>
     <attribute id="0x0001">
         <sequence>
@@ -225,178 +5062,349 @@ const SERVICE_RECORD: &str = r#"<?xml version="1.0" encoding="UTF-8" ?>
 </record>
 "#;
 
-const RECV_MTU: usize = 50;
+    #[test]
+    fn pro_controller_service_record_is_unchanged() {
+        assert_eq!(
+            service_record(ControllerType::ProController),
+            PRO_CONTROLLER_SERVICE_RECORD
+        );
+    }
 
-/// Enumeration for controller types.
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
-pub enum ControllerType {
-    /// Represents the Joy-Con (L).
-    JoyConL,
-    /// Represents the Joy-Con (R).
-    JoyConR,
-    /// Represents the Nintendo Switch Pro Controller.
-    ProController,
-}
+    /// Returns the data element of the given HID report descriptor in a service record.
+    fn descriptor_element(descriptor: &[u8]) -> String {
+        let hex: String = descriptor.iter().map(|b| format!("{:02x}", b)).collect();
 
-impl ControllerType {
-    /// Returns the Bluetooth controller name.
-    pub fn name(&self) -> &str {
-        match self {
-            ControllerType::JoyConL => "Joy-Con (L)",
-            ControllerType::JoyConR => "Joy-Con (R)",
-            ControllerType::ProController => "Pro Controller",
-        }
+        format!("<text encoding=\"hex\" value=\"{}\"/>", hex)
     }
-}
 
-impl Display for ControllerType {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.name())
-    }
-}
+    /// Returns the report IDs declared in the given HID report descriptor, whose items must
+    /// all be short items.
+    fn report_ids(descriptor: &[u8]) -> Vec<u8> {
+        let mut ids = vec![];
+        let mut i = 0;
+        while i < descriptor.len() {
+            let prefix = descriptor[i];
+            let size = [0, 1, 2, 4][(prefix & 0x03) as usize];
+            assert!(i + size < descriptor.len(), "item at {} is truncated", i);
+            if prefix == 0x85 {
+                ids.push(descriptor[i + 1]);
+            }
+            i += 1 + size;
+        }
 
-impl FromStr for ControllerType {
-    type Err = Error;
+        ids
+    }
 
-    fn from_str(s: &str) -> Result<Self> {
-        match s {
-            "JOY_CON_L" => Ok(ControllerType::JoyConL),
-            "JOY_CON_R" => Ok(ControllerType::JoyConR),
-            "PRO_CONTROLLER" => Ok(ControllerType::ProController),
-            _ => Err(Error::new(
-                ErrorKind::Protocol,
-                "unknown controller type".into(),
-            )),
+    #[test]
+    fn service_record_of_controller_types() {
+        for controller_type in [
+            ControllerType::JoyConL,
+            ControllerType::JoyConR,
+            ControllerType::ProController,
+            ControllerType::NesController,
+            ControllerType::SnesController,
+            ControllerType::N64Controller,
+        ] {
+            let record = service_record(controller_type);
+            assert!(
+                record.contains(&descriptor_element(controller_type.hid_descriptor())),
+                "{}",
+                controller_type
+            );
+            assert!(record.contains(&format!(
+                "<attribute id=\"0x0200\">\n        <uint16 value=\"0x{:04x}\"/>",
+                controller_type.hid_device_release()
+            )));
+            assert!(record.contains(&format!(
+                "<attribute id=\"0x020b\">\n        <uint16 value=\"0x{:04x}\"/>",
+                controller_type.hid_profile_version()
+            )));
         }
     }
-}
 
-/// Represents an emulated Nintendo Switch controller.
-pub struct Controller {
-    session: Session,
-    adapter: Adapter,
-    controller_type: ControllerType,
-    handle: Option<ProfileHandle>,
-    ctr_seq_packet: Option<SeqPacket>,
-    itr_seq_packet: Option<SeqPacket>,
-}
+    #[test]
+    fn joy_con_hid_descriptors() {
+        assert_eq!(ControllerType::JoyConL.hid_descriptor().len(), 170);
+        assert_eq!(
+            ControllerType::JoyConL.hid_descriptor(),
+            ControllerType::JoyConR.hid_descriptor()
+        );
+        assert_ne!(
+            ControllerType::JoyConL.hid_descriptor(),
+            ControllerType::ProController.hid_descriptor()
+        );
+        // Vendor input reports, the simple HID input report, and vendor output reports
+        assert_eq!(
+            report_ids(ControllerType::JoyConL.hid_descriptor()),
+            [0x21, 0x30, 0x31, 0x32, 0x33, 0x3F, 0x01, 0x10, 0x11, 0x12]
+        );
+        assert_eq!(
+            report_ids(ControllerType::ProController.hid_descriptor()),
+            [0x30, 0x21, 0x81, 0x01, 0x10, 0x80, 0x82]
+        );
+    }
 
-impl Controller {
-    /// Creates a `Controller` with the given adapter and controller type.
-    pub async fn new(adapter: &str, controller_type: ControllerType) -> Result<Self> {
-        let session = Session::new().await?;
-        let adapter = session.adapter(adapter)?;
+    /// Returns the error of registering the service record where BlueZ fails with the given
+    /// error kind.
+    fn register_error(kind: bluer::ErrorKind) -> Error {
+        let error = bluer::Error {
+            kind,
+            message: "simulated".into(),
+        };
 
-        Ok(Controller {
-            session,
-            adapter,
-            controller_type,
-            handle: None,
-            ctr_seq_packet: None,
-            itr_seq_packet: None,
-        })
+        service_record_error(error, &[]).into()
     }
 
-    /// Disconnects the paired device.
-    pub fn disconnect(&mut self) {
-        // Close connection
-        if let Some(itr_seq_packet) = &self.itr_seq_packet {
-            if let Err(e) = itr_seq_packet.shutdown(Shutdown::Both) {
-                warn!("{}", e);
+    #[test]
+    fn register_errors_are_distinguished() {
+        let already_exists = register_error(bluer::ErrorKind::AlreadyExists);
+        let not_ready = register_error(bluer::ErrorKind::NotReady);
+        for (error, expected) in [
+            (&already_exists, bluer::ErrorKind::AlreadyExists),
+            (&not_ready, bluer::ErrorKind::NotReady),
+        ] {
+            match &error.kind {
+                ErrorKind::Bluetooth(e) => {
+                    assert_eq!(*e.kind(), expected);
+                    assert_eq!(e.message, "cannot register service record");
+                    assert!(!e.is_stack_lost());
+                }
+                kind => panic!("unexpected error kind {}", kind),
             }
-            self.itr_seq_packet.take();
         }
-        if let Some(ctr_seq_packet) = &self.ctr_seq_packet {
-            if let Err(e) = ctr_seq_packet.shutdown(Shutdown::Both) {
-                warn!("{}", e);
+
+        // The error of BlueZ is kept as the source
+        let source = std::error::Error::source(&already_exists)
+            .and_then(|e| e.source())
+            .and_then(|e| e.downcast_ref::<bluer::Error>())
+            .unwrap();
+        assert_eq!(source.kind, bluer::ErrorKind::AlreadyExists);
+        assert_ne!(already_exists.to_string(), not_ready.to_string());
+    }
+
+    #[tokio::test]
+    async fn reload_spi_image_keeps_runtime_responsive() {
+        let dir = std::env::temp_dir().join(format!("playwith-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("spi.fifo");
+        let _ = std::fs::remove_file(&path);
+        let status = std::process::Command::new("mkfifo")
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        // Reading the FIFO blocks until it is written, while other tasks keep running
+        let handle = ControllerHandle::new(Protocol::new(ControllerType::ProController));
+        let mut reloading = Box::pin(handle.reload_spi_image(&path, ReloadMode::Discard));
+        for _ in 0..3 {
+            tokio::select! {
+                _ = &mut reloading => panic!("image is read before it is written"),
+                _ = tokio::time::sleep(Duration::from_millis(10)) => {}
             }
-            self.ctr_seq_packet.take();
+            handle.set_battery(BatteryLevel::Low, true);
         }
 
-        // Unregister service record
-        self.handle.take();
+        let writing = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                std::fs::write(path, vec![0xFF; protocol::spi::SPI_FLASH_SIZE])
+            })
+        };
+        assert!(reloading.await.is_ok());
+        writing.join().unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
-    /// Pairs a new device.
-    pub async fn pair(&mut self) -> Result<Address> {
-        // Check active service records
-        if let Some(uuids) = self.adapter.uuids().await? {
-            if uuids.len() > 3 {
-                warn!("Too many service records active");
-            }
+    #[test]
+    fn dropped_reports_are_counted() {
+        let stats = StatsCounters::new();
+        stats.record_sent(Sent::Report);
+        stats.record_sent(Sent::Dropped);
+        stats.record_sent(Sent::Report);
+        assert_eq!(stats.reports_sent.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.dropped_reports.load(Ordering::Relaxed), 1);
+
+        stats.reset();
+        assert_eq!(stats.dropped_reports.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn rumble_epsilon_is_kept_across_connections() {
+        let handle = ControllerHandle::new(Protocol::new(ControllerType::ProController));
+        assert_eq!(handle.rumble_epsilon(), DEFAULT_RUMBLE_EPSILON);
+        handle.set_rumble_epsilon(0.25);
+
+        // Connections reset the protocol
+        handle.lock().reset();
+        assert_eq!(handle.rumble_epsilon(), 0.25);
+    }
+
+    const SWITCH: Address = Address::new([0x98, 0xB6, 0xE9, 0x01, 0x02, 0x03]);
+    const STRANGER: Address = Address::new([0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+
+    /// Represents a channel which records whether it is shut down.
+    #[derive(Debug)]
+    struct MockChannel {
+        peer: Address,
+        shut_down: Arc<AtomicBool>,
+    }
+
+    impl AcceptedChannel for MockChannel {
+        fn peer(&self) -> Address {
+            self.peer
         }
 
-        // Unpair paired Nintendo Switches
-        for device_addr in self.adapter.device_addresses().await?.into_iter() {
-            if let Some(name) = self.adapter.device(device_addr)?.name().await? {
-                if name == NINTENDO_SWITCH_NAME {
-                    warn!("Unpair previous device {}", device_addr);
-                    self.adapter.remove_device(device_addr).await?;
-                }
-            };
+        fn shutdown(&self) -> Result<()> {
+            self.shut_down.store(true, Ordering::Relaxed);
+
+            Ok(())
         }
 
-        // Listeners
-        let addr = self.adapter.address().await?;
-        let ctr_listener =
-            SeqPacketListener::bind(SocketAddr::new(addr, AddressType::BrEdr, CTR_PSM)).await?;
-        let itr_listener =
-            SeqPacketListener::bind(SocketAddr::new(addr, AddressType::BrEdr, ITR_PSM)).await?;
+        fn log_options(&self) {}
+    }
 
-        self.adapter.set_powered(true).await?;
-        self.adapter.set_pairable(true).await?;
-        self.adapter
-            .set_alias(self.controller_type.name().into())
-            .await?;
+    /// Represents a listener on a PSM which accepts the connections sent to it.
+    struct MockListener {
+        psm: u16,
+        connections: tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<MockChannel>>,
+    }
 
-        // Register service record
-        self.handle = Some(
-            self.session
-                .register_profile(Profile::new_service_record(
-                    SERVICE.parse().unwrap(),
-                    SERVICE_RECORD.into(),
-                ))
-                .await?,
-        );
+    impl ChannelListener for MockListener {
+        type Channel = MockChannel;
 
-        self.adapter.set_discoverable(true).await?;
-        self.adapter.set_class(GAMEPAD_JOYSITCK_COD)?;
-        if self.adapter.class().await? != GAMEPAD_JOYSITCK_COD {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("cannot set class for adapter {}", self.adapter.name()).into(),
-            ));
+        async fn accept(&self, cancel_token: &CancelToken) -> Result<(MockChannel, SocketAddr)> {
+            let mut connections = self.connections.lock().await;
+            tokio::select! {
+                Some(channel) = connections.recv() => {
+                    let addr = SocketAddr::new(channel.peer, AddressType::BrEdr, self.psm);
+
+                    Ok((channel, addr))
+                }
+                _ = cancel_token.cancelled() => {
+                    Err(Error::new(ErrorKind::Cancelled, "accept cancelled".into()))
+                }
+            }
         }
+    }
 
-        // Accept
-        info!("Wait for device to connect");
-        let (ctr_seq_packet, ctr_addr) = ctr_listener.accept().await?;
-        debug!("accept {}, PSM = {} (CTR)", ctr_addr.addr, ctr_addr.psm);
-        let (itr_seq_packet, itr_addr) = itr_listener.accept().await?;
-        debug!("accept {}, PSM = {} (ITR)", itr_addr.addr, itr_addr.psm);
-        assert!(ctr_addr.addr == itr_addr.addr);
-        self.ctr_seq_packet = Some(ctr_seq_packet);
-        self.itr_seq_packet = Some(itr_seq_packet);
+    /// Represents the remote end of a mock listener, where devices connect.
+    struct MockConnector(tokio::sync::mpsc::UnboundedSender<MockChannel>);
 
-        self.adapter.set_discoverable(false).await?;
-        self.adapter.set_pairable(false).await?;
+    impl MockConnector {
+        /// Connects from the given device, and returns the flag of whether the channel is shut
+        /// down.
+        fn connect(&self, peer: Address) -> Arc<AtomicBool> {
+            let shut_down = Arc::new(AtomicBool::new(false));
+            let channel = MockChannel {
+                peer,
+                shut_down: shut_down.clone(),
+            };
+            self.0.send(channel).unwrap();
 
-        Ok(itr_addr.addr)
+            shut_down
+        }
     }
 
-    /// Receives raw data from the paired device.
-    pub async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
-        match &self.itr_seq_packet {
-            Some(itr_seq_packet) => Ok(itr_seq_packet.recv(buf).await?),
-            None => Err(Error::from(ErrorKind::Io(io::Error::from(
-                io::ErrorKind::NotConnected,
-            )))),
-        }
+    fn mock_listener(psm: u16) -> (MockListener, MockConnector) {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let listener = MockListener {
+            psm,
+            connections: tokio::sync::Mutex::new(receiver),
+        };
+
+        (listener, MockConnector(sender))
     }
-}
 
-impl Drop for Controller {
-    fn drop(&mut self) {
-        self.disconnect();
+    #[tokio::test]
+    async fn accept_after_stranger_on_ctr() {
+        let (ctr_listener, ctr) = mock_listener(CTR_PSM);
+        let (itr_listener, itr) = mock_listener(ITR_PSM);
+        let events = Mutex::new(vec![]);
+        let cancel_token = CancelToken::new();
+        let accepting = accept(&ctr_listener, &itr_listener, &cancel_token, |event| {
+            events.lock().unwrap().push(event)
+        });
+        let connecting = async {
+            // The stranger probes the HID service before the Nintendo Switch connects
+            ctr.connect(STRANGER);
+            settle().await;
+            ctr.connect(SWITCH);
+            settle().await;
+            itr.connect(SWITCH);
+        };
+
+        let (accepted, ()) = tokio::join!(accepting, connecting);
+        let (ctr_channel, itr_channel, addr) = accepted.unwrap();
+        assert_eq!(addr, SWITCH);
+        assert_eq!(ctr_channel.peer(), SWITCH);
+        assert_eq!(itr_channel.peer(), SWITCH);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                PairingEvent::CtrAccepted(STRANGER),
+                PairingEvent::ItrAccepted(SWITCH),
+                PairingEvent::CtrAccepted(SWITCH),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn accept_from_rejects_stranger_between_ctr_and_itr() {
+        let (ctr_listener, ctr) = mock_listener(CTR_PSM);
+        let (itr_listener, itr) = mock_listener(ITR_PSM);
+        let rejected = AtomicU64::new(0);
+        let cancel_token = CancelToken::new();
+        let accepting = accept_from(
+            &ctr_listener,
+            &itr_listener,
+            SWITCH,
+            &cancel_token,
+            &rejected,
+        );
+        let connecting = async {
+            ctr.connect(SWITCH);
+            settle().await;
+            // The stranger connects while the Nintendo Switch is connecting, on both PSMs
+            let stranger_ctr = ctr.connect(STRANGER);
+            settle().await;
+            let stranger_itr = itr.connect(STRANGER);
+            settle().await;
+            itr.connect(SWITCH);
+
+            (stranger_ctr, stranger_itr)
+        };
+
+        let (accepted, (stranger_ctr, stranger_itr)) = tokio::join!(accepting, connecting);
+        let (ctr_channel, itr_channel) = accepted.unwrap();
+        assert_eq!(ctr_channel.peer(), SWITCH);
+        assert_eq!(itr_channel.peer(), SWITCH);
+        assert!(!ctr_channel.shut_down.load(Ordering::Relaxed));
+        assert!(!itr_channel.shut_down.load(Ordering::Relaxed));
+        assert!(stranger_ctr.load(Ordering::Relaxed));
+        assert!(stranger_itr.load(Ordering::Relaxed));
+        assert_eq!(rejected.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn accept_cancelled() {
+        let (ctr_listener, ctr) = mock_listener(CTR_PSM);
+        let (itr_listener, _itr) = mock_listener(ITR_PSM);
+        let cancel_token = CancelToken::new();
+        ctr.connect(STRANGER);
+        let accepting = accept(&ctr_listener, &itr_listener, &cancel_token, |_| {});
+        let cancelling = async {
+            settle().await;
+            cancel_token.cancel();
+        };
+
+        let (accepted, ()) = tokio::join!(accepting, cancelling);
+        assert!(matches!(
+            accepted,
+            Err(Error {
+                kind: ErrorKind::Cancelled,
+                ..
+            })
+        ));
     }
 }
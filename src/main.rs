@@ -1,9 +1,16 @@
-use log::{error, info};
+use log::{error, info, warn};
+use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
 
 use playwith as lib;
 
-use lib::{Controller, ControllerType};
+use lib::bluetooth::Address;
+use lib::protocol::event::{PairingEvent, SwitchEvent};
+use lib::protocol::input::Button;
+use lib::protocol::mcu::Amiibo;
+use lib::protocol::{FirmwareProfile, ReportRate};
+use lib::{Controller, ControllerType, ReconnectPolicy};
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
@@ -47,7 +54,33 @@ async fn main() {
 
     // Controller
     info!("Use adapter {} for {} emulation", adapter, flags.controller);
-    let mut controller = match Controller::new(&adapter, flags.controller).await {
+    let mut builder = Controller::builder(&adapter, flags.controller)
+        .firmware_profile(flags.firmware)
+        .report_rate(flags.report_rate)
+        .accept_reconnects(flags.accept_reconnects)
+        .idle_timeout(flags.idle_timeout.map(Duration::from_secs))
+        .grip(flags.grip)
+        .initial_buttons(&flags.hold)
+        .preflight(!flags.skip_preflight)
+        .pairing_agent(!flags.no_agent)
+        .fast_scan(!flags.no_fast_scan)
+        .clean_stale_records(flags.clean_stale_records);
+    if let Some(seconds) = flags.hold_for {
+        builder = builder.release_initial_buttons_after(Duration::from_secs(seconds));
+    }
+    if let Some(path) = flags.spi_image {
+        builder = builder.spi_image(path);
+    }
+    if let Some(id) = flags.device_type_id {
+        builder = builder.device_type_id(id);
+    }
+    if let Some(spi_colors) = flags.spi_colors {
+        builder = builder.spi_colors(spi_colors);
+    }
+    if let Some(unknown) = flags.device_info_unknown {
+        builder = builder.device_info_unknown([unknown[0], unknown[1]]);
+    }
+    let mut controller = match builder.build().await {
         Ok(controller) => controller,
         Err(ref e) => {
             error!("{}", e);
@@ -56,15 +89,72 @@ async fn main() {
         }
     };
 
-    // Pair
-    match controller.pair().await {
-        Ok(addr) => info!("Device {} paired", addr),
-        Err(ref e) => {
-            error!("{}", e);
+    // Amiibo
+    if let Some(path) = flags.amiibo {
+        match Amiibo::load(&path) {
+            Ok(amiibo) => controller.set_amiibo(amiibo),
+            Err(ref e) => {
+                error!("{}", e);
 
-            return;
+                return;
+            }
+        }
+    }
+
+    // Stop on Ctrl+C
+    let cancel_token = controller.cancel_token();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            cancel_token.cancel();
         }
+    });
+
+    // Pairing progress and Bluetooth stack restarts
+    let mut events = controller.events();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            match event {
+                SwitchEvent::Pairing(event @ PairingEvent::PairingFailed(_)) => {
+                    warn!("Pairing: {}", event)
+                }
+                SwitchEvent::Pairing(event) => info!("Pairing: {}", event),
+                SwitchEvent::BluetoothStackRestarted => warn!("Bluetooth stack restarted"),
+                _ => {}
+            }
+        }
+    });
+
+    // Pair or connect
+    let result = match (flags.connect, flags.timeout) {
+        (Some(addr), _) => controller.connect(addr).await.map(|_| addr),
+        (None, Some(timeout)) => controller
+            .pair_with_timeout(Duration::from_secs(timeout))
+            .await
+            .map(|device| device.address),
+        (None, None) => controller.pair().await.map(|device| device.address),
     };
+    match result {
+        Ok(addr) => {
+            info!("Device {} connected", addr);
+
+            // Run
+            let policy = ReconnectPolicy::new().reset_after(flags.reset_after);
+            let result = match flags.reconnect {
+                true => controller.run_forever(policy).await,
+                false => controller.run().await,
+            };
+            match result {
+                Ok(reason) => info!("Stop: {}", reason),
+                Err(ref e) => error!("{}", e),
+            };
+        }
+        Err(ref e) => error!("{}", e),
+    };
+
+    // Disconnect
+    if let Err(ref e) = controller.disconnect().await {
+        error!("{}", e);
+    }
 }
 
 #[derive(StructOpt, Clone, Debug, Eq, Hash, PartialEq)]
@@ -76,12 +166,146 @@ struct Flags {
     #[structopt(
         long,
         short,
-        help = "Controller (JOY_CON_L, JOY_CON_R or PRO_CONTROLLER)",
+        help = "Controller (JOY_CON_L, JOY_CON_R, PRO_CONTROLLER, NES_CONTROLLER, SNES_CONTROLLER or N64_CONTROLLER)",
         value_name = "CONTROLLER",
         default_value = "PRO_CONTROLLER"
     )]
     pub controller: ControllerType,
 
+    #[structopt(
+        long,
+        help = "Firmware profile (FW_3_72, FW_3_139, FW_4_XX or LATEST)",
+        value_name = "FIRMWARE",
+        default_value = "FW_3_139"
+    )]
+    pub firmware: FirmwareProfile,
+
+    #[structopt(
+        long,
+        help = "Report rate (AUTO, 60 or 120)",
+        value_name = "RATE",
+        default_value = "AUTO"
+    )]
+    pub report_rate: ReportRate,
+
+    #[structopt(
+        long,
+        help = "Connects to a previously paired device instead of pairing",
+        value_name = "ADDRESS"
+    )]
+    pub connect: Option<Address>,
+
+    #[structopt(
+        long,
+        help = "Stops pairing if no device connects in the timeout",
+        value_name = "SECONDS"
+    )]
+    pub timeout: Option<u64>,
+
+    #[structopt(
+        long,
+        help = "Loads the SPI flash from an image of the whole SPI flash",
+        value_name = "PATH"
+    )]
+    pub spi_image: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Puts the amiibo of an NTAG215 dump on the NFC point",
+        value_name = "PATH"
+    )]
+    pub amiibo: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Accepts reconnections from the device, like when the Nintendo Switch wakes from sleep"
+    )]
+    pub accept_reconnects: bool,
+
+    #[structopt(
+        long,
+        help = "Reconnects to the device on disconnection until the device resets the pairing"
+    )]
+    pub reconnect: bool,
+
+    #[structopt(
+        long,
+        help = "Power-cycles the adapter after reconnections fail on adapter errors in a row",
+        value_name = "ATTEMPTS"
+    )]
+    pub reset_after: Option<u32>,
+
+    #[structopt(
+        long,
+        help = "Disconnects if no input changes in the idle timeout",
+        value_name = "SECONDS"
+    )]
+    pub idle_timeout: Option<u64>,
+
+    #[structopt(
+        long,
+        help = "Skips the privilege checks, like in containers with unusual capability setups"
+    )]
+    pub skip_preflight: bool,
+
+    #[structopt(
+        long,
+        help = "Does not register a pairing agent, like when running another agent"
+    )]
+    pub no_agent: bool,
+
+    #[structopt(long, help = "Keeps the scan parameters of the adapter while pairing")]
+    pub no_fast_scan: bool,
+
+    #[structopt(
+        long,
+        help = "Removes stale service records of controllers left by previous runs before pairing"
+    )]
+    pub clean_stale_records: bool,
+
+    #[structopt(long, help = "Connects Joy-Con to the charging grip")]
+    pub grip: bool,
+
+    #[structopt(
+        long,
+        help = "Holds the buttons while pairing until a player is assigned, like L,R in the Change Grip/Order menu",
+        value_name = "BUTTONS",
+        use_delimiter = true
+    )]
+    pub hold: Vec<Button>,
+
+    #[structopt(
+        long,
+        help = "Releases the held buttons after the duration if no player is assigned",
+        value_name = "SECONDS"
+    )]
+    pub hold_for: Option<u64>,
+
+    #[structopt(
+        long,
+        help = "Overrides the controller type byte in device info",
+        value_name = "ID",
+        parse(try_from_str = parse_u8)
+    )]
+    pub device_type_id: Option<u8>,
+
+    #[structopt(
+        long,
+        help = "Overrides the SPI colors byte in device info",
+        value_name = "VALUE",
+        parse(try_from_str = parse_u8)
+    )]
+    pub spi_colors: Option<u8>,
+
+    #[structopt(
+        long,
+        help = "Overrides the unknown bytes in device info",
+        value_name = "VALUE",
+        number_of_values = 2,
+        parse(try_from_str = parse_u8)
+    )]
+    pub device_info_unknown: Option<Vec<u8>>,
+
     #[structopt(
         long,
         short,
@@ -90,3 +314,10 @@ struct Flags {
     )]
     pub verbose: usize,
 }
+
+fn parse_u8(s: &str) -> Result<u8, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(s) => u8::from_str_radix(s, 16),
+        None => s.parse(),
+    }
+}
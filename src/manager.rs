@@ -0,0 +1,307 @@
+//! Support for managing multiple controllers.
+
+use crate::protocol::event::SwitchEvent;
+use crate::{
+    CancelToken, ConnectionStatus, Controller, ControllerHandle, Error, ErrorKind, PairedDevice,
+    Result, StopReason, EVENTS_CAPACITY,
+};
+use std::fmt::{self, Display, Formatter};
+use tokio::sync::broadcast;
+use tokio::task::{JoinError, JoinHandle};
+
+/// Represents the identifier of a controller in a `ControllerManager`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ControllerId(pub usize);
+
+impl Display for ControllerId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "controller {}", self.0)
+    }
+}
+
+/// Represents a receiver of events from the devices of all controllers in a `ControllerManager`.
+#[derive(Debug)]
+pub struct ManagerEvents {
+    receiver: broadcast::Receiver<(ControllerId, SwitchEvent)>,
+    dropped: u64,
+}
+
+impl ManagerEvents {
+    /// Receives the next event with the identifier of its controller, or `None` if the manager
+    /// is dropped. The oldest events are dropped if events are not received in time.
+    pub async fn recv(&mut self) -> Option<(ControllerId, SwitchEvent)> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(n)) => self.dropped += n,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Returns the number of dropped events.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/// Represents a manager of multiple emulated controllers in one process.
+///
+/// Each controller owns an adapter, since a device knows a controller by the address of its
+/// adapter, and the CTR and ITR PSMs can only be bound once per adapter. The manager keeps the
+/// listeners of each controller for the lifetime of the controller, so the PSMs of an adapter are
+/// bound once, and a device reconnecting to an adapter is routed to the controller owning it.
+///
+/// Each started controller runs in its own task, so a controller can be paired while the others
+/// are running.
+pub struct ControllerManager {
+    controllers: Vec<Slot>,
+    next_id: usize,
+    events: broadcast::Sender<(ControllerId, SwitchEvent)>,
+}
+
+/// Represents a controller in a `ControllerManager`, which is either idle or running in a task.
+struct Slot {
+    id: ControllerId,
+    adapter_name: String,
+    handle: ControllerHandle,
+    cancel_token: CancelToken,
+    controller: Option<Controller>,
+    task: Option<JoinHandle<(Controller, Result<StopReason>)>>,
+}
+
+impl ControllerManager {
+    /// Creates an empty `ControllerManager`.
+    pub fn new() -> Self {
+        ControllerManager {
+            controllers: vec![],
+            next_id: 0,
+            events: broadcast::channel(EVENTS_CAPACITY).0,
+        }
+    }
+
+    /// Adds the controller, and returns its identifier. A controller whose adapter is used by
+    /// another controller is rejected. The controller keeps its listeners to accept
+    /// reconnections. Events of the controller are forwarded by a task spawned on the current
+    /// Tokio runtime.
+    pub fn add(&mut self, mut controller: Controller) -> Result<ControllerId> {
+        if let Some(slot) = self
+            .controllers
+            .iter()
+            .find(|slot| slot.adapter_name == controller.adapter_name())
+        {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "adapter {} is already used by {}",
+                    controller.adapter_name(),
+                    slot.id
+                ),
+            ));
+        }
+
+        let id = ControllerId(self.next_id);
+        self.next_id += 1;
+
+        // The task ends when the controller is dropped
+        let mut events = controller.events();
+        let sender = self.events.clone();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                let _ = sender.send((id, event));
+            }
+        });
+        controller.accept_reconnects = true;
+        self.controllers.push(Slot {
+            id,
+            adapter_name: controller.adapter_name().to_string(),
+            handle: controller.handle(),
+            cancel_token: controller.cancel_token(),
+            controller: Some(controller),
+            task: None,
+        });
+
+        Ok(id)
+    }
+
+    /// Removes the controller with the given identifier and returns it. A running controller is
+    /// cancelled and waited for first.
+    pub async fn remove(&mut self, id: ControllerId) -> Option<Controller> {
+        if self.is_running(id) {
+            self.slot(id)?.cancel_token.cancel();
+            let _ = self.join(id).await;
+        }
+        let index = self.controllers.iter().position(|slot| slot.id == id)?;
+
+        self.controllers.remove(index).controller
+    }
+
+    /// Returns the identifiers of the controllers.
+    pub fn ids(&self) -> Vec<ControllerId> {
+        self.controllers.iter().map(|slot| slot.id).collect()
+    }
+
+    /// Returns the controller with the given identifier, or `None` if it is running.
+    pub fn controller(&self, id: ControllerId) -> Option<&Controller> {
+        self.slot(id)?.controller.as_ref()
+    }
+
+    /// Returns the controller with the given identifier mutably, or `None` if it is running.
+    pub fn controller_mut(&mut self, id: ControllerId) -> Option<&mut Controller> {
+        self.slot_mut(id)?.controller.as_mut()
+    }
+
+    /// Returns a handle to update the input state of the controller with the given identifier,
+    /// which also works while the controller is running.
+    pub fn handle(&self, id: ControllerId) -> Option<ControllerHandle> {
+        self.slot(id).map(|slot| slot.handle.clone())
+    }
+
+    /// Returns if the controller with the given identifier is running.
+    pub fn is_running(&self, id: ControllerId) -> bool {
+        self.slot(id).is_some_and(|slot| slot.task.is_some())
+    }
+
+    /// Returns a receiver of events from the devices of all controllers, which are tagged with
+    /// the identifiers of the controllers.
+    pub fn events(&self) -> ManagerEvents {
+        ManagerEvents {
+            receiver: self.events.subscribe(),
+            dropped: 0,
+        }
+    }
+
+    /// Pairs a new device with the controller with the given identifier. Running controllers
+    /// keep servicing their devices while pairing, but the controller itself must not be running.
+    pub async fn pair(&mut self, id: ControllerId) -> Result<PairedDevice> {
+        match self.idle_mut(id) {
+            Ok(controller) => controller.pair().await,
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Starts running the controller with the given identifier in a task spawned on the current
+    /// Tokio runtime, which services its device until it stops. The controller is returned to the
+    /// manager by `join`.
+    pub fn start(&mut self, id: ControllerId) -> Result<()> {
+        self.idle_mut(id)?;
+        let slot = self.slot_mut(id).unwrap();
+        let mut controller = slot.controller.take().unwrap();
+        slot.cancel_token = controller.cancel_token();
+        slot.task = Some(tokio::spawn(async move {
+            let result = controller.run().await;
+
+            (controller, result)
+        }));
+
+        Ok(())
+    }
+
+    /// Waits for the running controller with the given identifier to stop, and returns the
+    /// result of its run. Returns `None` if the controller is not running.
+    pub async fn join(&mut self, id: ControllerId) -> Option<Result<StopReason>> {
+        let task = self.slot_mut(id)?.task.take()?;
+        let joined = task.await;
+
+        Some(self.finish(id, joined))
+    }
+
+    /// Starts all connected controllers which are not running, waits for all running
+    /// controllers to stop, and returns the results with the identifiers of the controllers.
+    pub async fn run(&mut self) -> Vec<(ControllerId, Result<StopReason>)> {
+        let connected: Vec<_> = self
+            .controllers
+            .iter()
+            .filter(|slot| {
+                slot.controller.as_ref().is_some_and(|controller| {
+                    matches!(controller.status(), ConnectionStatus::Connected { .. })
+                })
+            })
+            .map(|slot| slot.id)
+            .collect();
+        for id in connected {
+            let _ = self.start(id);
+        }
+
+        let tasks = self.controllers.iter_mut().filter_map(|slot| {
+            let id = slot.id;
+            let task = slot.task.take()?;
+
+            Some(async move { (id, task.await) })
+        });
+        let joined = futures::future::join_all(tasks).await;
+
+        joined
+            .into_iter()
+            .map(|(id, joined)| (id, self.finish(id, joined)))
+            .collect()
+    }
+
+    /// Cancels the running operations of all controllers.
+    pub fn cancel_all(&self) {
+        for slot in self.controllers.iter() {
+            match &slot.controller {
+                Some(controller) => controller.cancel_token().cancel(),
+                None => slot.cancel_token.cancel(),
+            }
+        }
+    }
+
+    fn slot(&self, id: ControllerId) -> Option<&Slot> {
+        self.controllers.iter().find(|slot| slot.id == id)
+    }
+
+    fn slot_mut(&mut self, id: ControllerId) -> Option<&mut Slot> {
+        self.controllers.iter_mut().find(|slot| slot.id == id)
+    }
+
+    /// Returns the controller with the given identifier if it is not running.
+    fn idle_mut(&mut self, id: ControllerId) -> Result<&mut Controller> {
+        match self.slot_mut(id) {
+            Some(slot) => match slot.controller.as_mut() {
+                Some(controller) => Ok(controller),
+                None => Err(Error::new(ErrorKind::Other, format!("{} is running", id))),
+            },
+            None => Err(Error::new(ErrorKind::Other, format!("unknown {}", id))),
+        }
+    }
+
+    /// Returns the controller of a joined task to the manager, and returns the result of its
+    /// run. A controller whose task panicked is lost, and is removed from the manager.
+    fn finish(
+        &mut self,
+        id: ControllerId,
+        joined: std::result::Result<(Controller, Result<StopReason>), JoinError>,
+    ) -> Result<StopReason> {
+        match joined {
+            Ok((controller, result)) => {
+                if let Some(slot) = self.slot_mut(id) {
+                    slot.controller = Some(controller);
+                }
+
+                result
+            }
+            Err(e) => {
+                self.controllers.retain(|slot| slot.id != id);
+
+                Err(Error::new(
+                    ErrorKind::Other,
+                    format!("{} failed: {}", id, e),
+                ))
+            }
+        }
+    }
+}
+
+impl Default for ControllerManager {
+    fn default() -> Self {
+        ControllerManager::new()
+    }
+}
+
+impl Drop for ControllerManager {
+    fn drop(&mut self) {
+        // Running controllers stop and are dropped in their tasks
+        self.cancel_all();
+    }
+}
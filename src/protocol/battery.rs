@@ -0,0 +1,52 @@
+//! Support for the battery.
+
+use std::fmt::{self, Display, Formatter};
+
+/// Enumeration for battery levels.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum BatteryLevel {
+    /// Represents the empty battery.
+    Empty = 0x00,
+    /// Represents the critical battery.
+    Critical = 0x02,
+    /// Represents the low battery.
+    Low = 0x04,
+    /// Represents the medium battery.
+    Medium = 0x06,
+    /// Represents the full battery.
+    #[default]
+    Full = 0x08,
+}
+
+impl BatteryLevel {
+    /// Returns the regulated voltage of subcommand 0x50, which is in 2.5 mV of the battery
+    /// voltage and rises while charging.
+    pub fn regulated_voltage(&self, charging: bool) -> u16 {
+        let voltage = match self {
+            BatteryLevel::Empty => 0x0528,
+            BatteryLevel::Critical => 0x0570,
+            BatteryLevel::Low => 0x05C0,
+            BatteryLevel::Medium => 0x05F8,
+            BatteryLevel::Full => 0x0650,
+        };
+
+        match charging {
+            true => (voltage + 0x20).min(0x0690),
+            false => voltage,
+        }
+    }
+}
+
+impl Display for BatteryLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BatteryLevel::Empty => write!(f, "empty"),
+            BatteryLevel::Critical => write!(f, "critical"),
+            BatteryLevel::Low => write!(f, "low"),
+            BatteryLevel::Medium => write!(f, "medium"),
+            BatteryLevel::Full => write!(f, "full"),
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! Support for events from the device.
+
+use super::macros::MacroEvent;
+use super::rumble::RumbleEvent;
+use crate::bluetooth::Address;
+use crate::{Error, ErrorKind, PairedDevice, Result, StopReason};
+use std::fmt::{self, Display, Formatter};
+
+/// Represents the player lights.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct PlayerLights {
+    /// Represents the raw value of subcommand 0x30.
+    pub value: u8,
+}
+
+impl PlayerLights {
+    /// Creates a `PlayerLights` with the raw value of subcommand 0x30.
+    pub fn new(value: u8) -> Self {
+        PlayerLights { value }
+    }
+
+    /// Returns if the light of the given player in 1 to 4 keeps on.
+    pub fn is_on(&self, player: u8) -> bool {
+        (1..=4).contains(&player) && self.value & (1 << (player - 1)) != 0
+    }
+
+    /// Returns if the light of the given player in 1 to 4 is flashing.
+    pub fn is_flashing(&self, player: u8) -> bool {
+        (1..=4).contains(&player) && self.value & (1 << (player + 3)) != 0
+    }
+
+    /// Returns if any of the lights is on or flashing.
+    pub fn is_any(&self) -> bool {
+        self.value != 0
+    }
+
+    /// Returns the player number assigned by the lights, or `None` if no stable pattern is set.
+    /// Players 1 to 4 light up to their numbers of lights, or only their own light, and players
+    /// 5 to 8 light the patterns of the Nintendo Switch. Any flashing light means the player is
+    /// not assigned yet.
+    pub fn player(&self) -> Option<PlayerNumber> {
+        if self.value & 0xF0 != 0 {
+            return None;
+        }
+
+        let player = match self.value {
+            0x1 => 1,
+            0x3 | 0x2 => 2,
+            0x7 | 0x4 => 3,
+            0xF | 0x8 => 4,
+            0x9 => 5,
+            0xA => 6,
+            0xB => 7,
+            0x6 => 8,
+            _ => return None,
+        };
+
+        Some(PlayerNumber(player))
+    }
+}
+
+/// Represents the number of a player in 1 to 8.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct PlayerNumber(pub u8);
+
+impl Display for PlayerNumber {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "player {}", self.0)
+    }
+}
+
+impl Display for PlayerLights {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for player in 1..=4 {
+            if self.is_on(player) {
+                write!(f, "*")?;
+            } else if self.is_flashing(player) {
+                write!(f, "~")?;
+            } else {
+                write!(f, "-")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Represents the pattern of the HOME light.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct HomeLightPattern {
+    /// Represents the raw data of subcommand 0x38.
+    pub data: Vec<u8>,
+}
+
+impl HomeLightPattern {
+    /// Creates a `HomeLightPattern` with the raw data of subcommand 0x38.
+    pub fn new(data: Vec<u8>) -> Self {
+        HomeLightPattern { data }
+    }
+
+    /// Returns the number of mini cycles.
+    pub fn mini_cycles(&self) -> u8 {
+        self.data.first().map_or(0, |b| b >> 4)
+    }
+
+    /// Returns the base duration of mini cycles in 8 ms.
+    pub fn mini_cycle_duration(&self) -> u8 {
+        self.data.first().map_or(0, |b| b & 0x0F)
+    }
+
+    /// Returns the starting intensity in 0 to 15.
+    pub fn start_intensity(&self) -> u8 {
+        self.data.get(1).map_or(0, |b| b >> 4)
+    }
+
+    /// Returns the number of full cycles, which repeats forever if 0.
+    pub fn full_cycles(&self) -> u8 {
+        self.data.get(1).map_or(0, |b| b & 0x0F)
+    }
+
+    /// Returns if the HOME light is off.
+    pub fn is_off(&self) -> bool {
+        self.mini_cycles() == 0 && self.start_intensity() == 0
+    }
+}
+
+/// Enumeration for HCI states requested by subcommand 0x06.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum HciState {
+    /// Represents disconnecting, where the controller goes to sleep.
+    Disconnect = 0x00,
+    /// Represents rebooting and reconnecting to the device.
+    RebootReconnect = 0x01,
+    /// Represents rebooting and entering pairing mode.
+    RebootPair = 0x02,
+    /// Represents rebooting and reconnecting to the device in HOME mode.
+    RebootReconnectHome = 0x04,
+}
+
+impl Display for HciState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HciState::Disconnect => write!(f, "disconnect"),
+            HciState::RebootReconnect => write!(f, "reboot and reconnect"),
+            HciState::RebootPair => write!(f, "reboot and pair"),
+            HciState::RebootReconnectHome => write!(f, "reboot and reconnect in HOME mode"),
+        }
+    }
+}
+
+impl TryFrom<u8> for HciState {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0x00 => Ok(HciState::Disconnect),
+            0x01 => Ok(HciState::RebootReconnect),
+            0x02 => Ok(HciState::RebootPair),
+            0x04 => Ok(HciState::RebootReconnectHome),
+            _ => Err(Error::new(
+                ErrorKind::Protocol,
+                "unsupported HCI state".into(),
+            )),
+        }
+    }
+}
+
+/// Enumeration for progress of pairing.
+///
+/// A pairing starts listening, registers the service record, accepts the CTR and ITR channels
+/// from the device, and then goes through the handshake in which the device requests the device
+/// info and assigns a player. It ends in either `PairingComplete` or `PairingFailed`.
+#[non_exhaustive]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum PairingEvent {
+    /// Represents the controller is listening on the CTR and ITR PSMs.
+    Listening,
+    /// Represents the service record is registered.
+    ServiceRecordRegistered,
+    /// Represents the adapter is made discoverable again after something else turned it off
+    /// while waiting for the device.
+    PairingWindowRestored,
+    /// Represents the CTR channel is accepted from the device.
+    CtrAccepted(Address),
+    /// Represents the ITR channel is accepted from the device.
+    ItrAccepted(Address),
+    /// Represents the device started the handshake.
+    HandshakeStarted,
+    /// Represents the device info is sent to the device.
+    DeviceInfoSent,
+    /// Represents the device assigned the player.
+    PlayerAssigned(PlayerNumber),
+    /// Represents the pairing failed for the reason.
+    PairingFailed(String),
+    /// Represents the pairing completed with the device.
+    PairingComplete(PairedDevice),
+}
+
+impl PairingEvent {
+    /// Returns if the event ends the pairing.
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            PairingEvent::PairingFailed(_) | PairingEvent::PairingComplete(_)
+        )
+    }
+}
+
+impl Display for PairingEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PairingEvent::Listening => write!(f, "listening"),
+            PairingEvent::ServiceRecordRegistered => write!(f, "service record registered"),
+            PairingEvent::PairingWindowRestored => write!(f, "pairing window restored"),
+            PairingEvent::CtrAccepted(addr) => write!(f, "CTR accepted from {}", addr),
+            PairingEvent::ItrAccepted(addr) => write!(f, "ITR accepted from {}", addr),
+            PairingEvent::HandshakeStarted => write!(f, "handshake started"),
+            PairingEvent::DeviceInfoSent => write!(f, "device info sent"),
+            PairingEvent::PlayerAssigned(player) => write!(f, "assigned {}", player),
+            PairingEvent::PairingFailed(reason) => write!(f, "pairing failed: {}", reason),
+            PairingEvent::PairingComplete(device) => write!(f, "paired with {}", device),
+        }
+    }
+}
+
+/// Enumeration for events from the device.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq)]
+pub enum SwitchEvent {
+    /// Represents the rumble changed or stopped.
+    Rumble(RumbleEvent),
+    /// Represents the player lights changed.
+    PlayerLights(PlayerLights),
+    /// Represents the HOME light pattern changed.
+    HomeLight(HomeLightPattern),
+    /// Represents the vibration is enabled or disabled.
+    VibrationToggled(bool),
+    /// Represents the connection stopped for the reason.
+    Disconnected(StopReason),
+    /// Represents the progress of macro playback.
+    Macro(MacroEvent),
+    /// Represents the device finished reading the amiibo.
+    AmiiboRead,
+    /// Represents the device requested the HCI state.
+    HciState(HciState),
+    /// Represents the device reset the pairing info.
+    PairingReset,
+    /// Represents the controller disconnected after no input changed in the idle timeout.
+    IdleDisconnect,
+    /// Represents the progress of pairing.
+    Pairing(PairingEvent),
+    /// Represents the session is reconnected after the Bluetooth stack restarted, like when
+    /// bluetoothd or D-Bus restarts.
+    BluetoothStackRestarted,
+}
@@ -0,0 +1,385 @@
+//! Support for the IMU.
+
+use crate::{Error, ErrorKind, Result};
+use std::fmt::{self, Display, Formatter};
+
+/// Length of the IMU data of a sample in an input report.
+pub const IMU_SAMPLE_LEN: usize = 12;
+/// Number of IMU samples in an input report.
+pub const IMU_SAMPLES: usize = 3;
+/// Length of the IMU calibration.
+pub const IMU_CALIBRATION_LEN: usize = 24;
+/// Default accelerometer sensitivity in the IMU calibration.
+const DEFAULT_ACCEL_SENSITIVITY: i16 = 0x4000;
+/// Default gyroscope sensitivity in the IMU calibration.
+const DEFAULT_GYRO_SENSITIVITY: i16 = 0x343B;
+/// Address of the accelerometer control register.
+const CTRL1_XL: u8 = 0x10;
+/// Address of the gyroscope control register.
+const CTRL2_G: u8 = 0x11;
+
+/// Enumeration for gyroscope sensitivities.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub enum GyroSensitivity {
+    /// Represents the full scale of ±250 dps.
+    Dps250,
+    /// Represents the full scale of ±500 dps.
+    Dps500,
+    /// Represents the full scale of ±1000 dps.
+    Dps1000,
+    /// Represents the full scale of ±2000 dps.
+    #[default]
+    Dps2000,
+}
+
+impl GyroSensitivity {
+    /// Returns the angular velocity of a LSB in dps.
+    pub fn scale(&self) -> f32 {
+        match self {
+            GyroSensitivity::Dps250 => 0.00875,
+            GyroSensitivity::Dps500 => 0.0175,
+            GyroSensitivity::Dps1000 => 0.035,
+            GyroSensitivity::Dps2000 => 0.07,
+        }
+    }
+
+    /// Returns the sensitivity from subcommand 0x41.
+    fn from_setting(value: u8) -> Option<Self> {
+        match value {
+            0x00 => Some(GyroSensitivity::Dps250),
+            0x01 => Some(GyroSensitivity::Dps500),
+            0x02 => Some(GyroSensitivity::Dps1000),
+            0x03 => Some(GyroSensitivity::Dps2000),
+            _ => None,
+        }
+    }
+
+    /// Returns the full scale bits in the gyroscope control register.
+    fn to_register(self) -> u8 {
+        match self {
+            GyroSensitivity::Dps250 => 0x00,
+            GyroSensitivity::Dps500 => 0x04,
+            GyroSensitivity::Dps1000 => 0x08,
+            GyroSensitivity::Dps2000 => 0x0C,
+        }
+    }
+
+    fn from_register(value: u8) -> Self {
+        match value & 0x0C {
+            0x00 => GyroSensitivity::Dps250,
+            0x04 => GyroSensitivity::Dps500,
+            0x08 => GyroSensitivity::Dps1000,
+            _ => GyroSensitivity::Dps2000,
+        }
+    }
+}
+
+impl Display for GyroSensitivity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            GyroSensitivity::Dps250 => write!(f, "±250 dps"),
+            GyroSensitivity::Dps500 => write!(f, "±500 dps"),
+            GyroSensitivity::Dps1000 => write!(f, "±1000 dps"),
+            GyroSensitivity::Dps2000 => write!(f, "±2000 dps"),
+        }
+    }
+}
+
+/// Enumeration for accelerometer sensitivities.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub enum AccelSensitivity {
+    /// Represents the full scale of ±2 G.
+    G2,
+    /// Represents the full scale of ±4 G.
+    G4,
+    /// Represents the full scale of ±8 G.
+    #[default]
+    G8,
+    /// Represents the full scale of ±16 G.
+    G16,
+}
+
+impl AccelSensitivity {
+    /// Returns the acceleration of a LSB in G.
+    pub fn scale(&self) -> f32 {
+        match self {
+            AccelSensitivity::G2 => 0.000061,
+            AccelSensitivity::G4 => 0.000122,
+            AccelSensitivity::G8 => 0.000244,
+            AccelSensitivity::G16 => 0.000488,
+        }
+    }
+
+    /// Returns the sensitivity from subcommand 0x41.
+    fn from_setting(value: u8) -> Option<Self> {
+        match value {
+            0x00 => Some(AccelSensitivity::G8),
+            0x01 => Some(AccelSensitivity::G4),
+            0x02 => Some(AccelSensitivity::G2),
+            0x03 => Some(AccelSensitivity::G16),
+            _ => None,
+        }
+    }
+
+    /// Returns the full scale bits in the accelerometer control register.
+    fn to_register(self) -> u8 {
+        match self {
+            AccelSensitivity::G2 => 0x00,
+            AccelSensitivity::G16 => 0x04,
+            AccelSensitivity::G4 => 0x08,
+            AccelSensitivity::G8 => 0x0C,
+        }
+    }
+
+    fn from_register(value: u8) -> Self {
+        match value & 0x0C {
+            0x00 => AccelSensitivity::G2,
+            0x04 => AccelSensitivity::G16,
+            0x08 => AccelSensitivity::G4,
+            _ => AccelSensitivity::G8,
+        }
+    }
+}
+
+impl Display for AccelSensitivity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AccelSensitivity::G2 => write!(f, "±2 G"),
+            AccelSensitivity::G4 => write!(f, "±4 G"),
+            AccelSensitivity::G8 => write!(f, "±8 G"),
+            AccelSensitivity::G16 => write!(f, "±16 G"),
+        }
+    }
+}
+
+/// Represents the sensitivity of the IMU.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct ImuSensitivity {
+    /// Represents the gyroscope sensitivity.
+    pub gyro: GyroSensitivity,
+    /// Represents the accelerometer sensitivity.
+    pub accel: AccelSensitivity,
+}
+
+impl ImuSensitivity {
+    /// Creates an `ImuSensitivity` from the data of subcommand 0x41.
+    pub fn from_setting(data: &[u8]) -> Result<Self> {
+        match *data {
+            [gyro, accel, ..] => match (
+                GyroSensitivity::from_setting(gyro),
+                AccelSensitivity::from_setting(accel),
+            ) {
+                (Some(gyro), Some(accel)) => Ok(ImuSensitivity { gyro, accel }),
+                _ => Err(Error::new(
+                    ErrorKind::Protocol,
+                    "invalid IMU sensitivity".into(),
+                )),
+            },
+            _ => Err(Error::new(
+                ErrorKind::Protocol,
+                "missing IMU sensitivity".into(),
+            )),
+        }
+    }
+
+    /// Writes the IMU register with the given address. Only the full scale bits of the control
+    /// registers are honored.
+    pub fn write_register(&mut self, addr: u8, value: u8) {
+        match addr {
+            CTRL1_XL => self.accel = AccelSensitivity::from_register(value),
+            CTRL2_G => self.gyro = GyroSensitivity::from_register(value),
+            _ => {}
+        }
+    }
+
+    /// Reads the IMU register with the given address.
+    pub fn read_register(&self, addr: u8) -> u8 {
+        match addr {
+            CTRL1_XL => self.accel.to_register(),
+            CTRL2_G => self.gyro.to_register(),
+            _ => 0x00,
+        }
+    }
+}
+
+impl Display for ImuSensitivity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "gyroscope {}, accelerometer {}", self.gyro, self.accel)
+    }
+}
+
+/// Represents the IMU calibration in the SPI flash.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct ImuCalibration {
+    /// Represents the accelerometer origins of the X, Y and Z axes.
+    pub accel_origin: [i16; 3],
+    /// Represents the accelerometer sensitivities of the X, Y and Z axes.
+    pub accel_sensitivity: [i16; 3],
+    /// Represents the gyroscope origins of the X, Y and Z axes.
+    pub gyro_origin: [i16; 3],
+    /// Represents the gyroscope sensitivities of the X, Y and Z axes.
+    pub gyro_sensitivity: [i16; 3],
+}
+
+impl ImuCalibration {
+    /// Creates an `ImuCalibration` from the IMU calibration in the SPI flash.
+    pub fn from_bytes(data: &[u8; IMU_CALIBRATION_LEN]) -> Self {
+        let values = |offset: usize| {
+            let mut values = [0i16; 3];
+            for (i, value) in values.iter_mut().enumerate() {
+                let offset = offset + i * 2;
+                *value = i16::from_le_bytes([data[offset], data[offset + 1]]);
+            }
+            values
+        };
+
+        ImuCalibration {
+            accel_origin: values(0),
+            accel_sensitivity: values(6),
+            gyro_origin: values(12),
+            gyro_sensitivity: values(18),
+        }
+    }
+}
+
+impl Default for ImuCalibration {
+    fn default() -> Self {
+        ImuCalibration {
+            accel_origin: [0; 3],
+            accel_sensitivity: [DEFAULT_ACCEL_SENSITIVITY; 3],
+            gyro_origin: [0; 3],
+            gyro_sensitivity: [DEFAULT_GYRO_SENSITIVITY; 3],
+        }
+    }
+}
+
+/// Represents a motion sample in physical units. The axes are of the controller lying flat with
+/// its face up, where the Z axis points up.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MotionSample {
+    /// Represents the acceleration of the X, Y and Z axes in G.
+    pub accel: [f32; 3],
+    /// Represents the angular velocity of the X, Y and Z axes in dps.
+    pub gyro: [f32; 3],
+}
+
+impl MotionSample {
+    /// Creates a `MotionSample` with the given acceleration in G and angular velocity in dps.
+    pub fn new(accel: [f32; 3], gyro: [f32; 3]) -> Self {
+        MotionSample { accel, gyro }
+    }
+
+    /// Creates a still `MotionSample` with the gravity of the given orientation in degrees. The
+    /// pitch rotates around the X axis, and the roll around the Y axis. The yaw does not change
+    /// the gravity, so it is accepted only for symmetry.
+    pub fn from_orientation(pitch: f32, _yaw: f32, roll: f32) -> Self {
+        let (pitch, roll) = (pitch.to_radians(), roll.to_radians());
+
+        MotionSample {
+            accel: [
+                -roll.sin() * pitch.cos(),
+                pitch.sin(),
+                roll.cos() * pitch.cos(),
+            ],
+            gyro: [0.0; 3],
+        }
+    }
+
+    /// Sets the angular velocity from the given rates of pitch, yaw and roll in dps, which
+    /// rotate around the X, Z and Y axes respectively.
+    pub fn rotating(mut self, pitch: f32, yaw: f32, roll: f32) -> Self {
+        self.gyro = [pitch, roll, yaw];
+        self
+    }
+
+    /// Returns the sample in raw values of the given sensitivity and calibration, which are
+    /// clamped to the full scale.
+    pub fn encode(
+        &self,
+        sensitivity: ImuSensitivity,
+        calibration: &ImuCalibration,
+    ) -> [u8; IMU_SAMPLE_LEN] {
+        // The calibration scales the sensitivity around the origin
+        let raw = |value: f32, scale: f32, origin: i16, cal: i16, default: i16| {
+            let gain = (cal as f32 - origin as f32) / default as f32;
+            let value = (value / scale * gain).round() + origin as f32;
+            let value = if value.is_nan() { 0.0 } else { value };
+
+            (value.clamp(i16::MIN as f32, i16::MAX as f32) as i16).to_le_bytes()
+        };
+
+        let mut buf = [0u8; IMU_SAMPLE_LEN];
+        for i in 0..3 {
+            buf[i * 2..i * 2 + 2].copy_from_slice(&raw(
+                self.accel[i],
+                sensitivity.accel.scale(),
+                calibration.accel_origin[i],
+                calibration.accel_sensitivity[i],
+                DEFAULT_ACCEL_SENSITIVITY,
+            ));
+            buf[6 + i * 2..8 + i * 2].copy_from_slice(&raw(
+                self.gyro[i],
+                sensitivity.gyro.scale(),
+                calibration.gyro_origin[i],
+                calibration.gyro_sensitivity[i],
+                DEFAULT_GYRO_SENSITIVITY,
+            ));
+        }
+
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the raw angular velocity of the X axis of the sample.
+    fn raw_gyro_x(sample: &MotionSample, sensitivity: ImuSensitivity) -> i16 {
+        let buf = sample.encode(sensitivity, &ImuCalibration::default());
+
+        i16::from_le_bytes([buf[6], buf[7]])
+    }
+
+    #[test]
+    fn gyro_scales_with_sensitivity() {
+        let sample = MotionSample::default().rotating(100.0, 0.0, 0.0);
+        let fine = ImuSensitivity::from_setting(&[0x00, 0x00]).unwrap();
+        let coarse = ImuSensitivity::from_setting(&[0x03, 0x00]).unwrap();
+        assert_eq!(fine.gyro, GyroSensitivity::Dps250);
+        assert_eq!(coarse.gyro, GyroSensitivity::Dps2000);
+
+        let (fine, coarse) = (raw_gyro_x(&sample, fine), raw_gyro_x(&sample, coarse));
+        assert_eq!(fine, (100.0f32 / 0.00875).round() as i16);
+        assert_eq!(coarse, (100.0f32 / 0.07).round() as i16);
+        // ±2000 dps spans 8 times the range of ±250 dps
+        let ratio = fine as f32 / coarse as f32;
+        assert!((ratio - 8.0).abs() < 0.01, "{}", ratio);
+    }
+
+    #[test]
+    fn register_writes_set_sensitivity() {
+        let sample = MotionSample::default().rotating(100.0, 0.0, 0.0);
+        let mut sensitivity = ImuSensitivity::default();
+        sensitivity.write_register(CTRL2_G, GyroSensitivity::Dps500.to_register());
+        assert_eq!(sensitivity.gyro, GyroSensitivity::Dps500);
+        assert_eq!(
+            sensitivity.read_register(CTRL2_G),
+            GyroSensitivity::Dps500.to_register()
+        );
+        let dps500 = raw_gyro_x(&sample, sensitivity);
+
+        sensitivity.write_register(CTRL2_G, GyroSensitivity::Dps1000.to_register());
+        let dps1000 = raw_gyro_x(&sample, sensitivity);
+        assert_eq!(dps500, (100.0f32 / 0.0175).round() as i16);
+        assert_eq!(dps1000, (100.0f32 / 0.035).round() as i16);
+    }
+
+    #[test]
+    fn raw_values_are_clamped() {
+        let sample = MotionSample::default().rotating(1000.0, 0.0, 0.0);
+        let fine = ImuSensitivity::from_setting(&[0x00, 0x00]).unwrap();
+        assert_eq!(raw_gyro_x(&sample, fine), i16::MAX);
+    }
+}
@@ -0,0 +1,863 @@
+//! Support for controller inputs.
+
+use super::imu::MotionSample;
+use crate::{ControllerType, Error, ErrorKind, Result};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// Center of the 12-bit stick values.
+pub const STICK_CENTER: u16 = 0x800;
+/// Maximum of the 12-bit stick values.
+pub const STICK_MAX: u16 = 0xFFF;
+/// Default travel of the stick from the center to the extremes in 12-bit values.
+pub const DEFAULT_STICK_RANGE: u16 = 0x600;
+/// Default deadzone of the stick in 12-bit values.
+pub const DEFAULT_STICK_DEADZONE: u16 = 0x96;
+/// Bytes of the stick which a single Joy-Con does not have.
+pub const ABSENT_STICK: [u8; 3] = [0x00, 0x00, 0x00];
+/// Neutral hat in simple HID input reports.
+pub const HAT_NEUTRAL: u8 = 0x08;
+
+/// All buttons.
+pub const BUTTONS: [Button; 24] = [
+    Button::Y,
+    Button::X,
+    Button::B,
+    Button::A,
+    Button::R,
+    Button::Zr,
+    Button::Minus,
+    Button::Plus,
+    Button::RightStick,
+    Button::LeftStick,
+    Button::Home,
+    Button::Capture,
+    Button::Down,
+    Button::Up,
+    Button::Right,
+    Button::Left,
+    Button::L,
+    Button::Zl,
+    Button::Sr,
+    Button::Sl,
+    Button::CUp,
+    Button::CDown,
+    Button::CLeft,
+    Button::CRight,
+];
+
+/// Enumeration for sticks.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Stick {
+    /// Represents the left stick.
+    Left,
+    /// Represents the right stick.
+    Right,
+}
+
+impl Display for Stick {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Stick::Left => write!(f, "left stick"),
+            Stick::Right => write!(f, "right stick"),
+        }
+    }
+}
+
+/// Enumeration for buttons.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Button {
+    /// Represents the Y button.
+    Y,
+    /// Represents the X button.
+    X,
+    /// Represents the B button.
+    B,
+    /// Represents the A button.
+    A,
+    /// Represents the R button.
+    R,
+    /// Represents the ZR button.
+    Zr,
+    /// Represents the minus button.
+    Minus,
+    /// Represents the plus button.
+    Plus,
+    /// Represents the right stick button.
+    RightStick,
+    /// Represents the left stick button.
+    LeftStick,
+    /// Represents the HOME button.
+    Home,
+    /// Represents the capture button.
+    Capture,
+    /// Represents the down button.
+    Down,
+    /// Represents the up button.
+    Up,
+    /// Represents the right button.
+    Right,
+    /// Represents the left button.
+    Left,
+    /// Represents the L button.
+    L,
+    /// Represents the ZL button.
+    Zl,
+    /// Represents the SR button of a Joy-Con.
+    Sr,
+    /// Represents the SL button of a Joy-Con.
+    Sl,
+    /// Represents the C-up button of the Nintendo 64 controller.
+    CUp,
+    /// Represents the C-down button of the Nintendo 64 controller.
+    CDown,
+    /// Represents the C-left button of the Nintendo 64 controller.
+    CLeft,
+    /// Represents the C-right button of the Nintendo 64 controller.
+    CRight,
+}
+
+impl Button {
+    /// Returns the byte offset in the 3 button bytes and the mask of the button in standard input
+    /// reports. C buttons are reported as the right stick instead.
+    pub fn standard_bits(&self, controller_type: ControllerType) -> Option<(usize, u8)> {
+        match self {
+            Button::Y => Some((0, 0x01)),
+            Button::X => Some((0, 0x02)),
+            Button::B => Some((0, 0x04)),
+            Button::A => Some((0, 0x08)),
+            Button::R => Some((0, 0x40)),
+            Button::Zr => Some((0, 0x80)),
+            Button::Minus => Some((1, 0x01)),
+            Button::Plus => Some((1, 0x02)),
+            Button::RightStick => Some((1, 0x04)),
+            Button::LeftStick => Some((1, 0x08)),
+            Button::Home => Some((1, 0x10)),
+            Button::Capture => Some((1, 0x20)),
+            Button::Down => Some((2, 0x01)),
+            Button::Up => Some((2, 0x02)),
+            Button::Right => Some((2, 0x04)),
+            Button::Left => Some((2, 0x08)),
+            Button::L => Some((2, 0x40)),
+            Button::Zl => Some((2, 0x80)),
+            Button::Sr | Button::Sl => {
+                let offset = match controller_type {
+                    ControllerType::JoyConR => 0,
+                    _ => 2,
+                };
+                let mask = match self {
+                    Button::Sr => 0x10,
+                    _ => 0x20,
+                };
+
+                Some((offset, mask))
+            }
+            Button::CUp | Button::CDown | Button::CLeft | Button::CRight => None,
+        }
+    }
+
+    /// Returns the byte offset in the 2 button bytes and the mask of the button in simple HID
+    /// input reports. Directional buttons of controllers other than single Joy-Cons are reported
+    /// as the hat, and C buttons are reported as the right stick instead.
+    pub fn simple_hid_bits(&self, controller_type: ControllerType) -> Option<(usize, u8)> {
+        match controller_type {
+            // Single Joy-Cons are held sideways
+            ControllerType::JoyConL => match self {
+                Button::Left => Some((0, 0x01)),
+                Button::Down => Some((0, 0x02)),
+                Button::Up => Some((0, 0x04)),
+                Button::Right => Some((0, 0x08)),
+                Button::Sl => Some((0, 0x10)),
+                Button::Sr => Some((0, 0x20)),
+                Button::Minus => Some((1, 0x01)),
+                Button::LeftStick => Some((1, 0x04)),
+                Button::Capture => Some((1, 0x20)),
+                Button::L => Some((1, 0x40)),
+                Button::Zl => Some((1, 0x80)),
+                _ => None,
+            },
+            ControllerType::JoyConR => match self {
+                Button::A => Some((0, 0x01)),
+                Button::X => Some((0, 0x02)),
+                Button::B => Some((0, 0x04)),
+                Button::Y => Some((0, 0x08)),
+                Button::Sl => Some((0, 0x10)),
+                Button::Sr => Some((0, 0x20)),
+                Button::Plus => Some((1, 0x02)),
+                Button::RightStick => Some((1, 0x08)),
+                Button::Home => Some((1, 0x10)),
+                Button::R => Some((1, 0x40)),
+                Button::Zr => Some((1, 0x80)),
+                _ => None,
+            },
+            _ => match self {
+                Button::B => Some((0, 0x01)),
+                Button::A => Some((0, 0x02)),
+                Button::Y => Some((0, 0x04)),
+                Button::X => Some((0, 0x08)),
+                Button::L => Some((0, 0x10)),
+                Button::R => Some((0, 0x20)),
+                Button::Zl => Some((0, 0x40)),
+                Button::Zr => Some((0, 0x80)),
+                Button::Minus => Some((1, 0x01)),
+                Button::Plus => Some((1, 0x02)),
+                Button::LeftStick => Some((1, 0x04)),
+                Button::RightStick => Some((1, 0x08)),
+                Button::Home => Some((1, 0x10)),
+                Button::Capture => Some((1, 0x20)),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl Display for Button {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Button::Y => write!(f, "Y button"),
+            Button::X => write!(f, "X button"),
+            Button::B => write!(f, "B button"),
+            Button::A => write!(f, "A button"),
+            Button::R => write!(f, "R button"),
+            Button::Zr => write!(f, "ZR button"),
+            Button::Minus => write!(f, "minus button"),
+            Button::Plus => write!(f, "plus button"),
+            Button::RightStick => write!(f, "right stick button"),
+            Button::LeftStick => write!(f, "left stick button"),
+            Button::Home => write!(f, "HOME button"),
+            Button::Capture => write!(f, "capture button"),
+            Button::Down => write!(f, "down button"),
+            Button::Up => write!(f, "up button"),
+            Button::Right => write!(f, "right button"),
+            Button::Left => write!(f, "left button"),
+            Button::L => write!(f, "L button"),
+            Button::Zl => write!(f, "ZL button"),
+            Button::Sr => write!(f, "SR button"),
+            Button::Sl => write!(f, "SL button"),
+            Button::CUp => write!(f, "C-up button"),
+            Button::CDown => write!(f, "C-down button"),
+            Button::CLeft => write!(f, "C-left button"),
+            Button::CRight => write!(f, "C-right button"),
+        }
+    }
+}
+
+impl Button {
+    /// Returns the name of the button, which is parsed back by `from_str`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Button::Y => "y",
+            Button::X => "x",
+            Button::B => "b",
+            Button::A => "a",
+            Button::R => "r",
+            Button::Zr => "zr",
+            Button::Minus => "minus",
+            Button::Plus => "plus",
+            Button::RightStick => "right_stick",
+            Button::LeftStick => "left_stick",
+            Button::Home => "home",
+            Button::Capture => "capture",
+            Button::Down => "down",
+            Button::Up => "up",
+            Button::Right => "right",
+            Button::Left => "left",
+            Button::L => "l",
+            Button::Zl => "zl",
+            Button::Sr => "sr",
+            Button::Sl => "sl",
+            Button::CUp => "c_up",
+            Button::CDown => "c_down",
+            Button::CLeft => "c_left",
+            Button::CRight => "c_right",
+        }
+    }
+}
+
+impl FromStr for Button {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        BUTTONS
+            .iter()
+            .copied()
+            .find(|button| button.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| Error::new(ErrorKind::Other, format!("unknown button {}", s)))
+    }
+}
+
+/// Represents the state of buttons.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ButtonState {
+    bits: u32,
+}
+
+impl ButtonState {
+    /// Creates a `ButtonState` with all buttons released.
+    pub fn new() -> Self {
+        ButtonState::default()
+    }
+
+    /// Returns if the given button is pressed.
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.bits & (1 << button as u8) != 0
+    }
+
+    /// Sets if the given button is pressed.
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        match pressed {
+            true => self.bits |= 1 << button as u8,
+            false => self.bits &= !(1 << button as u8),
+        }
+    }
+
+    /// Presses the given button.
+    pub fn press(&mut self, button: Button) {
+        self.set(button, true);
+    }
+
+    /// Releases the given button.
+    pub fn release(&mut self, button: Button) {
+        self.set(button, false);
+    }
+
+    /// Returns the pressed buttons.
+    pub fn pressed(&self) -> Vec<Button> {
+        BUTTONS
+            .iter()
+            .copied()
+            .filter(|button| self.is_pressed(*button))
+            .collect()
+    }
+
+    /// Returns the 3 button bytes in standard input reports.
+    pub fn encode(&self, controller_type: ControllerType) -> [u8; 3] {
+        let mut buf = [0u8; 3];
+        for button in self.pressed() {
+            if let Some((offset, mask)) = button.standard_bits(controller_type) {
+                buf[offset] |= mask;
+            }
+        }
+
+        buf
+    }
+
+    /// Returns the 2 button bytes in simple HID input reports.
+    pub fn to_simple_hid(&self, controller_type: ControllerType) -> [u8; 2] {
+        let mut buf = [0u8; 2];
+        for button in self.pressed() {
+            if let Some((offset, mask)) = button.simple_hid_bits(controller_type) {
+                buf[offset] |= mask;
+            }
+        }
+
+        buf
+    }
+
+    /// Returns the directional buttons as a stick position.
+    pub fn dpad(&self) -> StickPosition {
+        self.directions([Button::Up, Button::Down, Button::Left, Button::Right])
+    }
+
+    /// Returns the C buttons as a stick position.
+    pub fn c_buttons(&self) -> StickPosition {
+        self.directions([Button::CUp, Button::CDown, Button::CLeft, Button::CRight])
+    }
+
+    fn directions(&self, [up, down, left, right]: [Button; 4]) -> StickPosition {
+        let axis = |negative: Button, positive: Button| match (
+            self.is_pressed(negative),
+            self.is_pressed(positive),
+        ) {
+            (true, false) => 0,
+            (false, true) => STICK_MAX,
+            _ => STICK_CENTER,
+        };
+
+        StickPosition::new(axis(left, right), axis(down, up))
+    }
+}
+
+/// Represents the position of a stick in 12-bit values.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct StickPosition {
+    /// Represents the horizontal position.
+    pub x: u16,
+    /// Represents the vertical position.
+    pub y: u16,
+}
+
+impl StickPosition {
+    /// Creates a `StickPosition` with the given position, which is clamped to 12-bit values.
+    pub fn new(x: u16, y: u16) -> Self {
+        StickPosition {
+            x: x.min(STICK_MAX),
+            y: y.min(STICK_MAX),
+        }
+    }
+
+    /// Returns the stick position in standard input reports.
+    pub fn encode(&self) -> [u8; 3] {
+        [
+            (self.x & 0xFF) as u8,
+            ((self.x >> 8) & 0x0F) as u8 | ((self.y & 0x0F) << 4) as u8,
+            (self.y >> 4) as u8,
+        ]
+    }
+
+    /// Returns the stick position in 16-bit values used by simple HID input reports.
+    pub fn to_simple_hid(&self) -> [u16; 2] {
+        // Simple HID input reports grow downwards
+        [self.x << 4, mirror(self.y) << 4]
+    }
+
+    /// Returns the hat direction of the stick used by simple HID input reports.
+    pub fn to_hat(&self) -> u8 {
+        let threshold = STICK_CENTER / 2;
+        let x = self.x as i32 - STICK_CENTER as i32;
+        let y = self.y as i32 - STICK_CENTER as i32;
+        let (right, left) = (x > threshold as i32, x < -(threshold as i32));
+        let (up, down) = (y > threshold as i32, y < -(threshold as i32));
+
+        match (up, right, down, left) {
+            (true, false, _, false) => 0,
+            (true, true, _, _) => 1,
+            (false, true, false, _) => 2,
+            (_, true, true, _) => 3,
+            (_, false, true, false) => 4,
+            (_, _, true, true) => 5,
+            (false, _, false, true) => 6,
+            (true, _, _, true) => 7,
+            _ => HAT_NEUTRAL,
+        }
+    }
+
+    /// Returns the stick position rotated by 90 degrees counterclockwise.
+    pub fn rotate_counterclockwise(&self) -> Self {
+        StickPosition::new(mirror(self.y), self.x)
+    }
+
+    /// Returns the stick position rotated by 90 degrees clockwise.
+    pub fn rotate_clockwise(&self) -> Self {
+        StickPosition::new(self.y, mirror(self.x))
+    }
+}
+
+impl Default for StickPosition {
+    fn default() -> Self {
+        StickPosition::new(STICK_CENTER, STICK_CENTER)
+    }
+}
+
+/// Represents the configuration of sticks, which is also reported as the factory calibration in
+/// the SPI flash.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct StickConfig {
+    /// Represents the deadzone in 12-bit values.
+    pub deadzone: u16,
+    /// Represents the travel from the center to the extremes in 12-bit values.
+    pub range: u16,
+    /// Represents the center in 12-bit values.
+    pub center: u16,
+}
+
+impl StickConfig {
+    /// Creates a `StickConfig` with the given deadzone, range and center. The center is clamped to
+    /// 12-bit values, the range is clamped so that the extremes stay in 12-bit values, and the
+    /// deadzone is clamped to the range.
+    pub fn new(deadzone: u16, range: u16, center: u16) -> Self {
+        let center = center.min(STICK_MAX);
+        let range = range.min(center).min(STICK_MAX - center);
+
+        StickConfig {
+            deadzone: deadzone.min(range),
+            range,
+            center,
+        }
+    }
+
+    /// Returns the 12-bit value of the given normalized value in -1 to 1. Values in the deadzone
+    /// are reported as the center, and values beyond the extremes are clamped.
+    pub fn to_raw(&self, value: f32) -> u16 {
+        let value = if value.is_nan() {
+            0.0
+        } else {
+            value.clamp(-1.0, 1.0)
+        };
+        let offset = (value * self.range as f32).round() as i32;
+        if offset.unsigned_abs() <= self.deadzone as u32 {
+            return self.center;
+        }
+
+        (self.center as i32 + offset) as u16
+    }
+
+    /// Returns the stick position of the given normalized values in -1 to 1.
+    pub fn position(&self, x: f32, y: f32) -> StickPosition {
+        StickPosition::new(self.to_raw(x), self.to_raw(y))
+    }
+
+    /// Returns the normalized values in -1 to 1 of the given stick position, which converts back
+    /// to the position unless it is in the deadzone.
+    pub fn normalize(&self, position: StickPosition) -> (f32, f32) {
+        let value = |raw: u16| {
+            let value = (raw as f32 - self.center as f32) / self.range.max(1) as f32;
+
+            value.clamp(-1.0, 1.0)
+        };
+
+        (value(position.x), value(position.y))
+    }
+}
+
+impl Default for StickConfig {
+    fn default() -> Self {
+        StickConfig::new(DEFAULT_STICK_DEADZONE, DEFAULT_STICK_RANGE, STICK_CENTER)
+    }
+}
+
+/// Represents a whole input state of a controller.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ControllerInput {
+    /// Represents the state of buttons.
+    pub buttons: ButtonState,
+    /// Represents the left stick position in normalized values in -1 to 1.
+    pub left_stick: (f32, f32),
+    /// Represents the right stick position in normalized values in -1 to 1.
+    pub right_stick: (f32, f32),
+    /// Represents the motion sample, or `None` to keep the current one.
+    pub motion: Option<MotionSample>,
+}
+
+impl ControllerInput {
+    /// Creates a `ControllerInput` with all buttons released and all sticks centered.
+    pub fn new() -> Self {
+        ControllerInput::default()
+    }
+}
+
+/// Logical and physical buttons of the Joy-Con (L) held sideways.
+const SIDEWAYS_JOY_CON_L: [(Button, Button); 11] = [
+    (Button::A, Button::Down),
+    (Button::B, Button::Left),
+    (Button::X, Button::Right),
+    (Button::Y, Button::Up),
+    (Button::L, Button::Sl),
+    (Button::R, Button::Sr),
+    (Button::Zl, Button::L),
+    (Button::Zr, Button::Zl),
+    (Button::Plus, Button::Minus),
+    (Button::LeftStick, Button::LeftStick),
+    (Button::Home, Button::Capture),
+];
+/// Logical and physical buttons of the Joy-Con (R) held sideways.
+const SIDEWAYS_JOY_CON_R: [(Button, Button); 11] = [
+    (Button::A, Button::X),
+    (Button::B, Button::A),
+    (Button::X, Button::Y),
+    (Button::Y, Button::B),
+    (Button::L, Button::Sl),
+    (Button::R, Button::Sr),
+    (Button::Zl, Button::R),
+    (Button::Zr, Button::Zr),
+    (Button::Plus, Button::Plus),
+    (Button::LeftStick, Button::RightStick),
+    (Button::Home, Button::Home),
+];
+
+/// Enumeration for orientations in which a controller is held.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Orientation {
+    /// Represents the controller is held upright, where logical inputs are physical inputs.
+    #[default]
+    Upright,
+    /// Represents a single Joy-Con is held sideways with its stick on the left. The face buttons
+    /// are the buttons under the thumb, SL and SR are the L and R buttons, the rail-side L/R and
+    /// ZL/ZR are the ZL and ZR buttons, and the stick is the left stick rotated by 90 degrees.
+    Sideways,
+}
+
+impl Orientation {
+    /// Returns if the orientation remaps inputs of the given controller type.
+    pub fn is_remapped(&self, controller_type: ControllerType) -> bool {
+        *self == Orientation::Sideways && controller_type.is_joy_con()
+    }
+
+    /// Returns the physical button of the given logical button, or `None` if the logical button
+    /// cannot be reached in the orientation.
+    pub fn physical_button(
+        &self,
+        controller_type: ControllerType,
+        button: Button,
+    ) -> Option<Button> {
+        match self.sideways_buttons(controller_type) {
+            Some(buttons) => buttons
+                .iter()
+                .find(|(logical, _)| *logical == button)
+                .map(|(_, physical)| *physical),
+            None => Some(button),
+        }
+    }
+
+    /// Returns the logical button of the given physical button, or `None` if the physical
+    /// button is not reached in the orientation.
+    pub fn logical_button(
+        &self,
+        controller_type: ControllerType,
+        button: Button,
+    ) -> Option<Button> {
+        match self.sideways_buttons(controller_type) {
+            Some(buttons) => buttons
+                .iter()
+                .find(|(_, physical)| *physical == button)
+                .map(|(logical, _)| *logical),
+            None => Some(button),
+        }
+    }
+
+    /// Returns the logical state of the given physical state of buttons.
+    pub fn logical_buttons(
+        &self,
+        controller_type: ControllerType,
+        buttons: ButtonState,
+    ) -> ButtonState {
+        let mut logical = ButtonState::new();
+        for button in buttons.pressed() {
+            if let Some(button) = self.logical_button(controller_type, button) {
+                logical.press(button);
+            }
+        }
+
+        logical
+    }
+
+    /// Returns the physical stick of the given logical stick, or `None` if the logical stick
+    /// cannot be reached in the orientation.
+    pub fn physical_stick(&self, controller_type: ControllerType, stick: Stick) -> Option<Stick> {
+        if !self.is_remapped(controller_type) {
+            return Some(stick);
+        }
+
+        match (controller_type, stick) {
+            (ControllerType::JoyConL, Stick::Left) => Some(Stick::Left),
+            (ControllerType::JoyConR, Stick::Left) => Some(Stick::Right),
+            _ => None,
+        }
+    }
+
+    /// Returns the physical stick position of the given logical stick position in normalized
+    /// values, which is rotated by 90 degrees clockwise for the Joy-Con (L) and
+    /// counterclockwise for the Joy-Con (R) held sideways.
+    pub fn physical_position(
+        &self,
+        controller_type: ControllerType,
+        (x, y): (f32, f32),
+    ) -> (f32, f32) {
+        if !self.is_remapped(controller_type) {
+            return (x, y);
+        }
+
+        match controller_type {
+            ControllerType::JoyConL => (y, -x),
+            _ => (-y, x),
+        }
+    }
+
+    /// Returns the logical stick position of the given physical stick position, which reverses
+    /// `physical_position`.
+    pub fn logical_position(
+        &self,
+        controller_type: ControllerType,
+        position: StickPosition,
+    ) -> StickPosition {
+        if !self.is_remapped(controller_type) {
+            return position;
+        }
+
+        match controller_type {
+            ControllerType::JoyConL => position.rotate_counterclockwise(),
+            _ => position.rotate_clockwise(),
+        }
+    }
+
+    /// Returns the physical input of the given logical input. Logical buttons and sticks which
+    /// cannot be reached in the orientation must be released and centered, or the input is
+    /// rejected with `ErrorKind::Unavailable`.
+    pub fn physical_input(
+        &self,
+        controller_type: ControllerType,
+        input: &ControllerInput,
+    ) -> Result<ControllerInput> {
+        if !self.is_remapped(controller_type) {
+            return Ok(*input);
+        }
+
+        let mut physical = ControllerInput {
+            motion: input.motion,
+            ..ControllerInput::new()
+        };
+        for button in input.buttons.pressed() {
+            match self.physical_button(controller_type, button) {
+                Some(button) => physical.buttons.press(button),
+                None => return Err(self.unavailable(controller_type, button)),
+            }
+        }
+        for (stick, position) in [
+            (Stick::Left, input.left_stick),
+            (Stick::Right, input.right_stick),
+        ] {
+            match self.physical_stick(controller_type, stick) {
+                Some(Stick::Left) => {
+                    physical.left_stick = self.physical_position(controller_type, position)
+                }
+                Some(Stick::Right) => {
+                    physical.right_stick = self.physical_position(controller_type, position)
+                }
+                None if position == (0.0, 0.0) => {}
+                None => return Err(self.unavailable(controller_type, stick)),
+            }
+        }
+
+        Ok(physical)
+    }
+
+    /// Returns the error of the given logical input which cannot be reached in the orientation.
+    pub fn unavailable(&self, controller_type: ControllerType, input: impl Display) -> Error {
+        Error::new(
+            ErrorKind::Unavailable,
+            format!("{} held {} does not have {}", controller_type, self, input),
+        )
+    }
+
+    fn sideways_buttons(
+        &self,
+        controller_type: ControllerType,
+    ) -> Option<&'static [(Button, Button)]> {
+        if !self.is_remapped(controller_type) {
+            return None;
+        }
+
+        match controller_type {
+            ControllerType::JoyConL => Some(&SIDEWAYS_JOY_CON_L),
+            _ => Some(&SIDEWAYS_JOY_CON_R),
+        }
+    }
+}
+
+impl Display for Orientation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Orientation::Upright => write!(f, "upright"),
+            Orientation::Sideways => write!(f, "sideways"),
+        }
+    }
+}
+
+/// Mirrors a 12-bit stick value around the center.
+fn mirror(value: u16) -> u16 {
+    (STICK_CENTER * 2).saturating_sub(value).min(STICK_MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stick position in normalized values.
+    type Position = (f32, f32);
+
+    /// Logical directions of the stick, and their physical directions on the Joy-Con (L) and
+    /// Joy-Con (R) held sideways.
+    const SIDEWAYS_DIRECTIONS: [(Position, Position, Position); 4] = [
+        ((0.0, 1.0), (1.0, 0.0), (-1.0, 0.0)),
+        ((1.0, 0.0), (0.0, -1.0), (0.0, 1.0)),
+        ((0.0, -1.0), (-1.0, 0.0), (1.0, 0.0)),
+        ((-1.0, 0.0), (0.0, 1.0), (0.0, -1.0)),
+    ];
+
+    #[test]
+    fn sideways_joy_con_l_rotates_stick_clockwise() {
+        let config = StickConfig::default();
+        for (logical, physical, _) in SIDEWAYS_DIRECTIONS {
+            let (x, y) = Orientation::Sideways.physical_position(ControllerType::JoyConL, logical);
+            assert_eq!((x, y), physical, "{:?}", logical);
+
+            let position = Orientation::Sideways
+                .logical_position(ControllerType::JoyConL, config.position(x, y));
+            assert_eq!(
+                position,
+                config.position(logical.0, logical.1),
+                "{:?}",
+                logical
+            );
+        }
+
+        let mut input = ControllerInput::new();
+        input.left_stick = (1.0, 0.0);
+        let physical = Orientation::Sideways
+            .physical_input(ControllerType::JoyConL, &input)
+            .unwrap();
+        assert_eq!(physical.left_stick, (0.0, -1.0));
+        assert_eq!(physical.right_stick, (0.0, 0.0));
+    }
+
+    #[test]
+    fn sideways_joy_con_r_rotates_stick_counterclockwise() {
+        let config = StickConfig::default();
+        for (logical, _, physical) in SIDEWAYS_DIRECTIONS {
+            let (x, y) = Orientation::Sideways.physical_position(ControllerType::JoyConR, logical);
+            assert_eq!((x, y), physical, "{:?}", logical);
+
+            let position = Orientation::Sideways
+                .logical_position(ControllerType::JoyConR, config.position(x, y));
+            assert_eq!(
+                position,
+                config.position(logical.0, logical.1),
+                "{:?}",
+                logical
+            );
+        }
+
+        // The logical left stick is the physical right stick
+        let mut input = ControllerInput::new();
+        input.left_stick = (1.0, 0.0);
+        let physical = Orientation::Sideways
+            .physical_input(ControllerType::JoyConR, &input)
+            .unwrap();
+        assert_eq!(physical.left_stick, (0.0, 0.0));
+        assert_eq!(physical.right_stick, (0.0, 1.0));
+
+        input.right_stick = (0.0, 1.0);
+        assert!(matches!(
+            Orientation::Sideways.physical_input(ControllerType::JoyConR, &input),
+            Err(Error {
+                kind: ErrorKind::Unavailable,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn upright_keeps_stick() {
+        for controller_type in [ControllerType::JoyConL, ControllerType::JoyConR] {
+            for (logical, _, _) in SIDEWAYS_DIRECTIONS {
+                assert_eq!(
+                    Orientation::Upright.physical_position(controller_type, logical),
+                    logical
+                );
+            }
+        }
+        // Only single Joy-Cons are remapped
+        assert_eq!(
+            Orientation::Sideways.physical_position(ControllerType::ProController, (1.0, 0.0)),
+            (1.0, 0.0)
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Support for input macros.
+
+use super::input::{Button, Stick};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Duration a button is held in a tap.
+pub const TAP_DURATION: Duration = Duration::from_millis(100);
+
+/// Enumeration for steps of an input macro.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum MacroStep {
+    /// Represents holding the buttons and the sticks in normalized values in -1 to 1 for the
+    /// duration. The buttons are released and the sticks are centered when the step ends.
+    Hold {
+        buttons: Vec<Button>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        sticks: Vec<(Stick, f32, f32)>,
+        #[cfg_attr(feature = "serde", serde(with = "duration_ms"))]
+        duration: Duration,
+    },
+    /// Represents keeping the input state for the duration.
+    Wait {
+        #[cfg_attr(feature = "serde", serde(with = "duration_ms"))]
+        duration: Duration,
+    },
+    /// Represents releasing all buttons and centering all sticks at once.
+    ReleaseAll,
+}
+
+impl MacroStep {
+    /// Returns the duration of the step.
+    pub fn duration(&self) -> Duration {
+        match self {
+            MacroStep::Hold { duration, .. } | MacroStep::Wait { duration } => *duration,
+            MacroStep::ReleaseAll => Duration::ZERO,
+        }
+    }
+}
+
+/// Represents a timed sequence of input states.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(transparent))]
+pub struct InputMacro {
+    /// Represents the steps.
+    pub steps: Vec<MacroStep>,
+}
+
+impl InputMacro {
+    /// Creates an empty `InputMacro`.
+    pub fn new() -> Self {
+        InputMacro::default()
+    }
+
+    /// Creates an `InputMacro` which taps the given button, and waits as long before the next
+    /// step so repeated taps are told apart.
+    pub fn tap(button: Button) -> Self {
+        InputMacro::hold(button, TAP_DURATION).then(MacroStep::Wait {
+            duration: TAP_DURATION,
+        })
+    }
+
+    /// Creates an `InputMacro` which holds the given button for the duration.
+    pub fn hold(button: Button, duration: Duration) -> Self {
+        InputMacro::new().then(MacroStep::Hold {
+            buttons: vec![button],
+            sticks: vec![],
+            duration,
+        })
+    }
+
+    /// Creates an `InputMacro` which waits for the duration.
+    pub fn wait(duration: Duration) -> Self {
+        InputMacro::new().then(MacroStep::Wait { duration })
+    }
+
+    /// Creates an `InputMacro` which releases all buttons and centers all sticks.
+    pub fn release_all() -> Self {
+        InputMacro::new().then(MacroStep::ReleaseAll)
+    }
+
+    /// Appends the given step.
+    pub fn then(mut self, step: MacroStep) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    /// Appends the steps of the given macro.
+    pub fn chain(mut self, other: InputMacro) -> Self {
+        self.steps.extend(other.steps);
+        self
+    }
+
+    /// Returns the total duration of the steps, before being aligned to input reports.
+    pub fn duration(&self) -> Duration {
+        self.steps.iter().map(|step| step.duration()).sum()
+    }
+}
+
+/// Enumeration for the progress of macro playback.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum MacroEvent {
+    /// Represents the step with the index started.
+    Step { index: usize, steps: usize },
+    /// Represents all steps finished.
+    Finished,
+    /// Represents the playback stopped before finishing.
+    Stopped,
+}
+
+#[cfg(feature = "serde")]
+mod duration_ms {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_millis(u64::deserialize(deserializer)?))
+    }
+}
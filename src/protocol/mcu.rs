@@ -0,0 +1,561 @@
+//! Support for the NFC/IR MCU.
+
+use crate::{Error, ErrorKind, Result};
+use log::{debug, warn};
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+
+/// Length of the MCU data in an input report.
+pub const MCU_DATA_LEN: usize = 313;
+/// Length of the MCU configuration reply data.
+const MCU_CONFIGURATION_LEN: usize = 34;
+/// Number of input reports without NFC requests before polling times out.
+const NFC_POLLING_TIMEOUT: u32 = 300;
+/// Length of an NTAG215 dump.
+pub const NTAG215_LEN: usize = 540;
+/// Length of the tag data in the first NTAG read packet.
+const NTAG_READ_FIRST_LEN: usize = 245;
+/// Header of the first NTAG read packet, which is followed by the UID.
+const NTAG_READ_FIRST_HEADER: [u8; 15] = [
+    0x3A, 0x00, 0x07, 0x01, 0x00, 0x01, 0x31, 0x02, 0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x07,
+];
+/// Tag info of NTAG215 in the first NTAG read packet, which follows the UID.
+const NTAG215_INFO: [u8; 38] = [
+    0x00, 0x00, 0x00, 0x7D, 0xFD, 0xF0, 0x79, 0x36, 0x51, 0xAB, 0xD7, 0x66, 0xE4, 0x6F, 0x81, 0x50,
+    0xB4, 0xC6, 0xC4, 0x2C, 0x5E, 0x6C, 0xE7, 0xA7, 0x4D, 0x94, 0x88, 0xEB, 0xFD, 0x3A, 0x4E, 0x85,
+    0x38, 0xD2, 0x3A, 0x6E, 0x6A, 0xEB,
+];
+/// Header of the second NTAG read packet.
+const NTAG_READ_SECOND_HEADER: [u8; 7] = [0x3A, 0x00, 0x07, 0x02, 0x00, 0x08, 0x27];
+
+/// Represents an amiibo of an NTAG215 dump.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Amiibo {
+    data: Vec<u8>,
+}
+
+impl Amiibo {
+    /// Creates an `Amiibo` from an NTAG215 dump. Dumps with or without the trailing signature
+    /// are accepted, and only the first 540 bytes are kept.
+    pub fn new(mut data: Vec<u8>) -> Result<Self> {
+        if data.len() < NTAG215_LEN - 8 {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("invalid NTAG215 dump of {} bytes", data.len()),
+            ));
+        }
+        data.resize(NTAG215_LEN, 0x00);
+
+        Ok(Amiibo { data })
+    }
+
+    /// Loads an `Amiibo` from the NTAG215 dump in the given path.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Amiibo::new(std::fs::read(path)?)
+    }
+
+    /// Returns the 7-byte UID, which skips the check byte in the dump.
+    pub fn uid(&self) -> [u8; 7] {
+        let mut uid = [0u8; 7];
+        uid[..3].copy_from_slice(&self.data[..3]);
+        uid[3..].copy_from_slice(&self.data[4..8]);
+
+        uid
+    }
+
+    /// Returns the NTAG215 dump.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Represents the firmware version of the MCU.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct McuVersion {
+    /// Represents the major version.
+    pub major: u16,
+    /// Represents the minor version.
+    pub minor: u16,
+}
+
+impl McuVersion {
+    /// Creates a `McuVersion` with the given major and minor versions.
+    pub fn new(major: u16, minor: u16) -> Self {
+        McuVersion { major, minor }
+    }
+
+    /// Returns the version in bytes.
+    pub fn to_bytes(&self) -> [u8; 4] {
+        let mut buf = [0u8; 4];
+        buf[..2].copy_from_slice(&self.major.to_be_bytes());
+        buf[2..].copy_from_slice(&self.minor.to_be_bytes());
+
+        buf
+    }
+}
+
+impl Display for McuVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Enumeration for MCU states.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum McuState {
+    /// Represents the suspended state.
+    Suspended = 0x00,
+    /// Represents the standby state.
+    Standby = 0x01,
+    /// Represents the NFC state.
+    Nfc = 0x04,
+    /// Represents the IR state.
+    Ir = 0x05,
+}
+
+/// Enumeration for NFC states.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum NfcState {
+    /// Represents the idle state.
+    None = 0x00,
+    /// Represents the polling state.
+    Polling = 0x01,
+}
+
+/// Enumeration for MCU requests.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum McuRequest {
+    /// Represents the request of MCU status.
+    Status = 0x01,
+    /// Represents the NFC command.
+    Nfc = 0x02,
+}
+
+impl TryFrom<u8> for McuRequest {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0x01 => Ok(McuRequest::Status),
+            0x02 => Ok(McuRequest::Nfc),
+            _ => Err(Error::new(
+                ErrorKind::Protocol,
+                "unknown MCU request".into(),
+            )),
+        }
+    }
+}
+
+/// Enumeration for NFC commands.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum NfcCommand {
+    /// Represents the start of polling.
+    StartPolling = 0x01,
+    /// Represents the stop of polling.
+    StopPolling = 0x02,
+    /// Represents the request of NFC status.
+    Status = 0x04,
+    /// Represents the read of an NTAG.
+    ReadNtag = 0x06,
+}
+
+impl TryFrom<u8> for NfcCommand {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0x01 => Ok(NfcCommand::StartPolling),
+            0x02 => Ok(NfcCommand::StopPolling),
+            0x04 => Ok(NfcCommand::Status),
+            0x06 => Ok(NfcCommand::ReadNtag),
+            _ => Err(Error::new(
+                ErrorKind::Protocol,
+                "unknown NFC command".into(),
+            )),
+        }
+    }
+}
+
+/// Enumeration for pending MCU reports.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+enum Pending {
+    None,
+    Status,
+    Nfc,
+}
+
+/// Represents the NFC/IR MCU.
+///
+/// Firmware updates are not supported. The MCU always reports its firmware as the latest, and
+/// update packets are answered with the MCU status in standby, which aborts the update.
+///
+/// Only reading NTAG215 tags is supported over NFC. NTAG writes are rejected as unknown NFC
+/// commands.
+pub struct Mcu {
+    version: McuVersion,
+    update_attempted: bool,
+    state: McuState,
+    nfc_state: NfcState,
+    pending: Pending,
+    idle_reports: u32,
+    tag: Option<Amiibo>,
+    // The tag being read and the next packet, which is unaffected by changing the tag
+    read: Option<(Amiibo, u8)>,
+    tag_read: bool,
+}
+
+impl Mcu {
+    /// Creates a `Mcu`.
+    pub fn new() -> Self {
+        Mcu {
+            version: McuVersion::new(0x0008, 0x001B),
+            update_attempted: false,
+            state: McuState::Suspended,
+            nfc_state: NfcState::None,
+            pending: Pending::None,
+            idle_reports: 0,
+            tag: None,
+            read: None,
+            tag_read: false,
+        }
+    }
+
+    /// Resets the MCU. The firmware version and the tag are preserved.
+    pub fn reset(&mut self) {
+        *self = Mcu {
+            version: self.version,
+            update_attempted: self.update_attempted,
+            tag: self.tag.take(),
+            ..Mcu::new()
+        };
+    }
+
+    /// Returns the tag on the NFC point.
+    pub fn tag(&self) -> Option<&Amiibo> {
+        self.tag.as_ref()
+    }
+
+    /// Puts the given tag on the NFC point, or lifts the tag off if `None`. A read in progress
+    /// finishes with the previous tag.
+    pub fn set_tag(&mut self, tag: Option<Amiibo>) {
+        self.tag = tag;
+    }
+
+    /// Returns if a tag has been read completely since the last call.
+    pub fn take_tag_read(&mut self) -> bool {
+        std::mem::take(&mut self.tag_read)
+    }
+
+    /// Returns the firmware version.
+    pub fn version(&self) -> McuVersion {
+        self.version
+    }
+
+    /// Sets the firmware version.
+    pub fn set_version(&mut self, version: McuVersion) {
+        self.version = version;
+    }
+
+    /// Rejects the firmware update.
+    pub fn reject_update(&mut self) {
+        if !self.update_attempted {
+            warn!(
+                "Device attempted to update the MCU firmware, which is not supported and rejected"
+            );
+            self.update_attempted = true;
+        }
+        debug!("reject MCU firmware update");
+
+        self.state = McuState::Standby;
+        self.nfc_state = NfcState::None;
+        self.pending = Pending::Status;
+    }
+
+    /// Returns the MCU state.
+    pub fn state(&self) -> McuState {
+        self.state
+    }
+
+    /// Returns the NFC state.
+    pub fn nfc_state(&self) -> NfcState {
+        self.nfc_state
+    }
+
+    /// Sets the MCU state from subcommand 0x22 and returns if it is valid.
+    pub fn set_state(&mut self, state: u8) -> bool {
+        match state {
+            0x00 => self.state = McuState::Suspended,
+            0x01 => self.state = McuState::Standby,
+            // Resume for update
+            0x02 => {
+                self.reject_update();
+
+                return true;
+            }
+            _ => return false,
+        }
+        self.nfc_state = NfcState::None;
+        debug!("MCU state {:?}", self.state);
+
+        true
+    }
+
+    /// Configures the MCU from subcommand 0x21 and returns the reply data.
+    pub fn configure(&mut self, data: &[u8]) -> Vec<u8> {
+        // The MCU mode follows the command and subcommand bytes
+        match data.get(2) {
+            Some(0x01) => self.state = McuState::Standby,
+            Some(0x04) => self.state = McuState::Nfc,
+            Some(0x05) => self.state = McuState::Ir,
+            _ => {}
+        }
+        debug!("MCU state {:?}", self.state);
+
+        let mut reply = vec![0u8; MCU_CONFIGURATION_LEN];
+        reply[..3].copy_from_slice(&[0x01, 0x00, 0xFF]);
+        reply[3..7].copy_from_slice(&self.version.to_bytes());
+        reply[7] = self.state as u8;
+        reply[MCU_CONFIGURATION_LEN - 1] = crc8(&reply[1..MCU_CONFIGURATION_LEN - 1]);
+
+        reply
+    }
+
+    /// Handles a request from output report 0x11.
+    pub fn handle_request(&mut self, request: u8, data: &[u8]) -> Result<()> {
+        match McuRequest::try_from(request)? {
+            McuRequest::Status => self.pending = Pending::Status,
+            McuRequest::Nfc => {
+                let command = data
+                    .first()
+                    .ok_or_else(|| Error::new(ErrorKind::Protocol, "missing NFC command".into()))?;
+                let command = NfcCommand::try_from(*command)?;
+                debug!("NFC command {:?}", command);
+
+                self.idle_reports = 0;
+                match command {
+                    NfcCommand::StartPolling => self.nfc_state = NfcState::Polling,
+                    NfcCommand::StopPolling => self.nfc_state = NfcState::None,
+                    NfcCommand::Status => {}
+                    NfcCommand::ReadNtag => match &self.tag {
+                        Some(tag) => self.read = Some((tag.clone(), 1)),
+                        None => debug!("no tag to read"),
+                    },
+                }
+                self.pending = Pending::Nfc;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the MCU data for the next input report.
+    pub fn report(&mut self) -> [u8; MCU_DATA_LEN] {
+        let mut buf = [0u8; MCU_DATA_LEN];
+
+        // A read is sent in 2 packets in consecutive reports
+        if let Some((tag, packet)) = self.read.take() {
+            self.idle_reports = 0;
+            if packet == 1 {
+                let mut offset = 0;
+                for part in [&NTAG_READ_FIRST_HEADER[..], &tag.uid(), &NTAG215_INFO] {
+                    buf[offset..offset + part.len()].copy_from_slice(part);
+                    offset += part.len();
+                }
+                buf[offset..offset + NTAG_READ_FIRST_LEN]
+                    .copy_from_slice(&tag.data[..NTAG_READ_FIRST_LEN]);
+                self.read = Some((tag, 2));
+            } else {
+                let offset = NTAG_READ_SECOND_HEADER.len();
+                buf[..offset].copy_from_slice(&NTAG_READ_SECOND_HEADER);
+                buf[offset..offset + NTAG215_LEN - NTAG_READ_FIRST_LEN]
+                    .copy_from_slice(&tag.data[NTAG_READ_FIRST_LEN..]);
+                self.tag_read = true;
+                debug!("NTAG read");
+            }
+            buf[MCU_DATA_LEN - 1] = crc8(&buf[..MCU_DATA_LEN - 1]);
+
+            return buf;
+        }
+
+        // Polling without NFC requests times out back to standby
+        if self.nfc_state == NfcState::Polling {
+            self.idle_reports += 1;
+            if self.idle_reports > NFC_POLLING_TIMEOUT {
+                debug!("NFC polling timed out");
+                self.state = McuState::Standby;
+                self.nfc_state = NfcState::None;
+                self.pending = Pending::Status;
+            } else if self.pending == Pending::None {
+                self.pending = Pending::Nfc;
+            }
+        }
+
+        match self.pending {
+            Pending::None => buf[0] = 0xFF,
+            Pending::Status => {
+                buf[..3].copy_from_slice(&[0x01, 0x00, 0x00]);
+                buf[3..7].copy_from_slice(&self.version.to_bytes());
+                buf[7] = self.state as u8;
+            }
+            Pending::Nfc => {
+                buf[..8].copy_from_slice(&[
+                    0x2A,
+                    0x00,
+                    0x05,
+                    0x00,
+                    0x00,
+                    0x09,
+                    0x31,
+                    self.nfc_state as u8,
+                ]);
+                // A tag is detected while polling
+                if let (NfcState::Polling, Some(tag)) = (self.nfc_state, &self.tag) {
+                    buf[8..15].copy_from_slice(&[0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x07]);
+                    buf[15..22].copy_from_slice(&tag.uid());
+                }
+            }
+        }
+        self.pending = Pending::None;
+        buf[MCU_DATA_LEN - 1] = crc8(&buf[..MCU_DATA_LEN - 1]);
+
+        buf
+    }
+}
+
+impl Default for Mcu {
+    fn default() -> Self {
+        Mcu::new()
+    }
+}
+
+/// Calculates the CRC-8 used by the MCU.
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for b in data {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// NFC status packet while polling without a tag.
+    const POLLING_NO_TAG: [u8; 8] = [0x2A, 0x00, 0x05, 0x00, 0x00, 0x09, 0x31, 0x01];
+    /// NFC status packet after polling stops.
+    const NOT_POLLING: [u8; 8] = [0x2A, 0x00, 0x05, 0x00, 0x00, 0x09, 0x31, 0x00];
+
+    fn nfc(mcu: &mut Mcu, command: NfcCommand) {
+        mcu.handle_request(McuRequest::Nfc as u8, &[command as u8])
+            .unwrap();
+    }
+
+    fn assert_packet(report: &[u8; MCU_DATA_LEN], header: &[u8]) {
+        assert_eq!(&report[..header.len()], header);
+        assert!(report[header.len()..MCU_DATA_LEN - 1]
+            .iter()
+            .all(|b| *b == 0x00));
+        assert_eq!(report[MCU_DATA_LEN - 1], crc8(&report[..MCU_DATA_LEN - 1]));
+    }
+
+    #[test]
+    fn polling_without_tag() {
+        let mut mcu = Mcu::new();
+        mcu.configure(&[0x21, 0x00, 0x04]);
+        assert_eq!(mcu.state(), McuState::Nfc);
+
+        nfc(&mut mcu, NfcCommand::StartPolling);
+        assert_eq!(mcu.nfc_state(), NfcState::Polling);
+        // Polling keeps answering in every report without further requests
+        for _ in 0..10 {
+            assert_packet(&mcu.report(), &POLLING_NO_TAG);
+        }
+
+        nfc(&mut mcu, NfcCommand::StopPolling);
+        assert_eq!(mcu.nfc_state(), NfcState::None);
+        assert_packet(&mcu.report(), &NOT_POLLING);
+        assert_packet(&mcu.report(), &[0xFF]);
+        assert_eq!(mcu.state(), McuState::Nfc);
+    }
+
+    #[test]
+    fn polling_times_out_to_standby() {
+        let mut mcu = Mcu::new();
+        mcu.configure(&[0x21, 0x00, 0x04]);
+        nfc(&mut mcu, NfcCommand::StartPolling);
+        for _ in 0..NFC_POLLING_TIMEOUT {
+            assert_packet(&mcu.report(), &POLLING_NO_TAG);
+        }
+
+        let mut status = vec![0x01, 0x00, 0x00];
+        status.extend_from_slice(&mcu.version().to_bytes());
+        status.push(McuState::Standby as u8);
+        assert_packet(&mcu.report(), &status);
+        assert_eq!(mcu.state(), McuState::Standby);
+        assert_eq!(mcu.nfc_state(), NfcState::None);
+        assert_packet(&mcu.report(), &[0xFF]);
+    }
+
+    #[test]
+    fn nfc_request_extends_polling() {
+        let mut mcu = Mcu::new();
+        nfc(&mut mcu, NfcCommand::StartPolling);
+        for _ in 0..NFC_POLLING_TIMEOUT {
+            mcu.report();
+        }
+        nfc(&mut mcu, NfcCommand::Status);
+        assert_packet(&mcu.report(), &POLLING_NO_TAG);
+        assert_eq!(mcu.nfc_state(), NfcState::Polling);
+    }
+
+    #[test]
+    fn empty_requests() {
+        let mut mcu = Mcu::new();
+        assert!(mcu.handle_request(McuRequest::Nfc as u8, &[]).is_err());
+        assert!(mcu.handle_request(0x00, &[]).is_err());
+        mcu.handle_request(McuRequest::Status as u8, &[]).unwrap();
+        assert_eq!(mcu.configure(&[]).len(), MCU_CONFIGURATION_LEN);
+        assert_eq!(mcu.configure(&[0x21]).len(), MCU_CONFIGURATION_LEN);
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_requests_do_not_panic(
+            requests in prop::collection::vec(
+                (any::<u8>(), prop::collection::vec(any::<u8>(), 0..8)),
+                0..16,
+            ),
+            tag in any::<bool>(),
+        ) {
+            let mut mcu = Mcu::new();
+            if tag {
+                mcu.set_tag(Some(Amiibo::new(vec![0x04; NTAG215_LEN]).unwrap()));
+            }
+            for (request, data) in requests {
+                let _ = mcu.handle_request(request, &data);
+                let _ = mcu.configure(&data);
+                if let Some(state) = data.first() {
+                    mcu.set_state(*state);
+                }
+                let report = mcu.report();
+                prop_assert_eq!(report[MCU_DATA_LEN - 1], crc8(&report[..MCU_DATA_LEN - 1]));
+            }
+        }
+    }
+}
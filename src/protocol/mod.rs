@@ -1,9 +1,41 @@
 //! Support for Nintendo Switch controller protocol.
 
-use crate::{Error, ErrorKind, Result};
+use crate::bluetooth::Address;
+use crate::{ControllerType, Dump, Error, ErrorKind, PairedDevice, Result};
+use log::{debug, warn};
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+pub mod battery;
+pub mod event;
+pub mod imu;
+pub mod input;
+pub mod macros;
+pub mod mcu;
+pub mod recording;
+pub mod rumble;
+pub mod spi;
+pub mod state;
+pub mod tas;
+pub mod trace;
+
+use battery::BatteryLevel;
+use event::{HciState, HomeLightPattern, PairingEvent, PlayerLights, SwitchEvent};
+use imu::{ImuSensitivity, MotionSample, IMU_SAMPLES, IMU_SAMPLE_LEN};
+use input::{
+    Button, ButtonState, ControllerInput, Orientation, Stick, StickConfig, StickPosition,
+    ABSENT_STICK,
+};
+use macros::{InputMacro, MacroEvent, MacroStep};
+use mcu::{Amiibo, Mcu, McuState, McuVersion, NfcState, MCU_DATA_LEN};
+use recording::InputRecording;
+use rumble::{MotorState, RumbleEvent, RumbleFrame, DEFAULT_RUMBLE_EPSILON};
+use spi::{Colors, ReloadMode, SpiFlash, SECTOR_SIZE};
 
 /// Enumeration for direction.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 #[repr(u8)]
 pub enum Direction {
     /// Represents the input (from controller to device) direction.
@@ -26,10 +58,14 @@ impl Display for Direction {
 pub enum Type {
     /// Represents the subcommand.
     Subcommand = 0x01,
+    /// Represents the MCU firmware update packet.
+    McuFirmwareUpdate = 0x03,
     /// Represents the rumble.
     Rumble = 0x10,
     /// Represents the request of IR, NFC, or MCU data.
     RequestIrNfcMcu = 0x11,
+    /// Represents the request of MCU firmware update.
+    RequestMcuUpdate = 0x12,
 }
 
 impl TryFrom<u8> for Type {
@@ -38,8 +74,10 @@ impl TryFrom<u8> for Type {
     fn try_from(value: u8) -> Result<Self> {
         match value {
             0x01 => Ok(Type::Subcommand),
+            0x03 => Ok(Type::McuFirmwareUpdate),
             0x10 => Ok(Type::Rumble),
             0x11 => Ok(Type::RequestIrNfcMcu),
+            0x12 => Ok(Type::RequestMcuUpdate),
             _ => Err(Error::new(
                 ErrorKind::Protocol,
                 "invalid output type".into(),
@@ -48,44 +86,3442 @@ impl TryFrom<u8> for Type {
     }
 }
 
-/// Enumeration for subcommands,
-pub enum Subcommand {}
+/// Enumeration for subcommands.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Subcommand {
+    /// Represents the Bluetooth manual pairing.
+    BluetoothManualPairing = 0x01,
+    /// Represents the request of device info.
+    RequestDeviceInfo = 0x02,
+    /// Represents the setting of input report mode.
+    SetInputReportMode = 0x03,
+    /// Represents the trigger buttons elapsed time.
+    TriggerButtonsElapsedTime = 0x04,
+    /// Represents the setting of HCI state.
+    SetHciState = 0x06,
+    /// Represents the reset of pairing info.
+    ResetPairingInfo = 0x07,
+    /// Represents the setting of shipment low power state.
+    SetShipmentLowPowerState = 0x08,
+    /// Represents the SPI flash read.
+    SpiFlashRead = 0x10,
+    /// Represents the SPI flash write.
+    SpiFlashWrite = 0x11,
+    /// Represents the SPI sector erase.
+    SpiSectorErase = 0x12,
+    /// Represents the reset of NFC/IR MCU.
+    ResetNfcIrMcu = 0x20,
+    /// Represents the setting of NFC/IR MCU configuration.
+    SetNfcIrMcuConfiguration = 0x21,
+    /// Represents the setting of NFC/IR MCU state.
+    SetNfcIrMcuState = 0x22,
+    /// Represents the setting of player lights.
+    SetPlayerLights = 0x30,
+    /// Represents the request of player lights.
+    GetPlayerLights = 0x31,
+    /// Represents the setting of HOME light.
+    SetHomeLight = 0x38,
+    /// Represents the enabling of IMU.
+    EnableImu = 0x40,
+    /// Represents the setting of IMU sensitivity.
+    SetImuSensitivity = 0x41,
+    /// Represents the writing to IMU registers.
+    WriteImuRegisters = 0x42,
+    /// Represents the reading of IMU registers.
+    ReadImuRegisters = 0x43,
+    /// Represents the enabling of vibration.
+    EnableVibration = 0x48,
+    /// Represents the request of regulated voltage.
+    GetRegulatedVoltage = 0x50,
+}
+
+impl TryFrom<u8> for Subcommand {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0x01 => Ok(Subcommand::BluetoothManualPairing),
+            0x02 => Ok(Subcommand::RequestDeviceInfo),
+            0x03 => Ok(Subcommand::SetInputReportMode),
+            0x04 => Ok(Subcommand::TriggerButtonsElapsedTime),
+            0x06 => Ok(Subcommand::SetHciState),
+            0x07 => Ok(Subcommand::ResetPairingInfo),
+            0x08 => Ok(Subcommand::SetShipmentLowPowerState),
+            0x10 => Ok(Subcommand::SpiFlashRead),
+            0x11 => Ok(Subcommand::SpiFlashWrite),
+            0x12 => Ok(Subcommand::SpiSectorErase),
+            0x20 => Ok(Subcommand::ResetNfcIrMcu),
+            0x21 => Ok(Subcommand::SetNfcIrMcuConfiguration),
+            0x22 => Ok(Subcommand::SetNfcIrMcuState),
+            0x30 => Ok(Subcommand::SetPlayerLights),
+            0x31 => Ok(Subcommand::GetPlayerLights),
+            0x38 => Ok(Subcommand::SetHomeLight),
+            0x40 => Ok(Subcommand::EnableImu),
+            0x41 => Ok(Subcommand::SetImuSensitivity),
+            0x42 => Ok(Subcommand::WriteImuRegisters),
+            0x43 => Ok(Subcommand::ReadImuRegisters),
+            0x48 => Ok(Subcommand::EnableVibration),
+            0x50 => Ok(Subcommand::GetRegulatedVoltage),
+            _ => Err(Error::new(ErrorKind::Protocol, "unknown subcommand".into())),
+        }
+    }
+}
+
+/// Enumeration for input report types.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum InputType {
+    /// Represents the standard input report with subcommand reply.
+    SubcommandReply = 0x21,
+    /// Represents the standard full input report.
+    StandardFull = 0x30,
+    /// Represents the standard full input report with NFC/IR MCU data.
+    NfcIr = 0x31,
+    /// Represents the simple HID input report.
+    SimpleHid = 0x3F,
+}
+
+/// Enumeration for input report modes.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ReportMode {
+    /// Represents the low power mode, which pushes standard full input reports at about 33 Hz.
+    LowPower = 0x23,
+    /// Represents the standard full mode, which pushes standard full input reports at 60 Hz.
+    StandardFull = 0x30,
+    /// Represents the NFC/IR mode, which pushes input reports with NFC/IR MCU data at 60 Hz.
+    NfcIr = 0x31,
+    /// Represents the simple HID mode, which pushes input reports on button change.
+    SimpleHid = 0x3F,
+}
+
+impl ReportMode {
+    /// Returns the interval between input reports, or `None` if input reports are not pushed
+    /// periodically.
+    pub fn interval(&self) -> Option<Duration> {
+        match self {
+            ReportMode::LowPower => Some(Duration::from_micros(30_000)),
+            ReportMode::StandardFull | ReportMode::NfcIr => Some(Duration::from_micros(16_667)),
+            ReportMode::SimpleHid => None,
+        }
+    }
+
+    /// Returns if standard full input reports are streamed in the mode.
+    pub fn is_streaming(&self) -> bool {
+        !matches!(self, ReportMode::SimpleHid)
+    }
+
+    /// Returns the length of input reports pushed in the mode.
+    pub fn report_len(&self) -> usize {
+        match self {
+            ReportMode::LowPower | ReportMode::StandardFull => STANDARD_INPUT_LEN,
+            ReportMode::NfcIr => NFC_IR_INPUT_LEN,
+            ReportMode::SimpleHid => SIMPLE_HID_INPUT_LEN,
+        }
+    }
+}
+
+impl Display for ReportMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ReportMode::LowPower => write!(f, "Low Power"),
+            ReportMode::StandardFull => write!(f, "Standard Full"),
+            ReportMode::NfcIr => write!(f, "NFC/IR"),
+            ReportMode::SimpleHid => write!(f, "Simple HID"),
+        }
+    }
+}
+
+impl TryFrom<u8> for ReportMode {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0x23 => Ok(ReportMode::LowPower),
+            0x30 => Ok(ReportMode::StandardFull),
+            0x31 => Ok(ReportMode::NfcIr),
+            0x3F => Ok(ReportMode::SimpleHid),
+            _ => Err(Error::new(
+                ErrorKind::Protocol,
+                "unsupported input report mode".into(),
+            )),
+        }
+    }
+}
 
+/// Represents an output report (from device to controller).
 pub struct Output {
-    direction: Direction,
-    t: Type,
-    timer: u8,
-    left_rumble: u32,
-    right_rumble: u32,
-    subcommand: Option<u8>,
-    data: Option<Vec<u8>>,
+    /// Represents the direction.
+    pub direction: Direction,
+    /// Represents the type.
+    pub t: Type,
+    /// Represents the timer.
+    pub timer: u8,
+    /// Represents the raw left rumble data.
+    pub left_rumble: u32,
+    /// Represents the raw right rumble data.
+    pub right_rumble: u32,
+    /// Represents the subcommand, or the MCU subcommand in the request of IR, NFC or MCU data.
+    pub subcommand: Option<u8>,
+    /// Represents the data of the subcommand.
+    pub data: Option<Vec<u8>>,
 }
 
 impl TryFrom<&[u8]> for Output {
     type Error = Error;
 
     fn try_from(value: &[u8]) -> Result<Self> {
-        if value.len() < 12 {
+        let invalid = |message: &str, offset: usize| {
+            Error::with_dump(ErrorKind::Protocol, message.into(), value, offset)
+        };
+
+        // Direction
+        let direction = *value.first().ok_or_else(|| invalid("empty output", 0))?;
+        if direction != Direction::Output as u8 {
+            return Err(invalid("invalid output direction", 0));
+        }
+
+        // Type
+        let t = *value
+            .get(1)
+            .ok_or_else(|| invalid("invalid output length", value.len()))?;
+        let t: Type = t
+            .try_into()
+            .map_err(|e: Error| Error::with_dump(e.kind, e.message, value, 1))?;
+
+        // Timer
+        let timer = *value
+            .get(2)
+            .ok_or_else(|| invalid("invalid output length", value.len()))?;
+
+        // Rumble
+        let rumble = |start: usize| {
+            value
+                .get(start..start + 4)
+                .and_then(|b| b.try_into().ok())
+                .map(u32::from_be_bytes)
+                .ok_or_else(|| invalid("invalid output length", value.len()))
+        };
+        let left_rumble = rumble(3)?;
+        let right_rumble = rumble(7)?;
+
+        // Subcommand
+        let rest = value.get(11..).unwrap_or_default();
+        let (subcommand, data) = match t {
+            Type::Rumble => (None, None),
+            Type::McuFirmwareUpdate | Type::RequestMcuUpdate => (None, Some(rest.to_vec())),
+            Type::Subcommand | Type::RequestIrNfcMcu => match rest.split_first() {
+                Some((subcommand, data)) => (Some(*subcommand), Some(data.to_vec())),
+                None => return Err(invalid("missing subcommand", value.len())),
+            },
+        };
+
+        Ok(Output {
+            direction: Direction::Output,
+            t,
+            timer,
+            left_rumble,
+            right_rumble,
+            subcommand,
+            data,
+        })
+    }
+}
+
+/// Represents the index of a periodic input report in a connection, which starts at 0.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ReportTick(pub u64);
+
+impl Display for ReportTick {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "tick {}", self.0)
+    }
+}
+
+/// Enumeration for the targets of scheduled inputs.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ScheduleAt {
+    /// Represents the periodic input report of the tick.
+    Tick(ReportTick),
+    /// Represents the first input report at or after the instant.
+    Instant(Instant),
+}
+
+impl From<ReportTick> for ScheduleAt {
+    fn from(tick: ReportTick) -> Self {
+        ScheduleAt::Tick(tick)
+    }
+}
+
+impl From<Instant> for ScheduleAt {
+    fn from(instant: Instant) -> Self {
+        ScheduleAt::Instant(instant)
+    }
+}
+
+/// Enumeration for rates of periodic input reports.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub enum ReportRate {
+    /// Represents the rate implied by the input report mode and the firmware profile.
+    #[default]
+    Auto,
+    /// Represents 60 Hz in all streaming modes.
+    Hz60,
+    /// Represents 120 Hz in all streaming modes, which is the timing of the Pro Controller over
+    /// USB.
+    Hz120,
+}
+
+impl ReportRate {
+    /// Returns the interval between input reports, or `None` if the interval follows the input
+    /// report mode.
+    pub fn interval(&self) -> Option<Duration> {
+        match self {
+            ReportRate::Auto => None,
+            ReportRate::Hz60 => Some(Duration::from_micros(16_667)),
+            ReportRate::Hz120 => Some(Duration::from_micros(8_333)),
+        }
+    }
+}
+
+impl Display for ReportRate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ReportRate::Auto => write!(f, "Auto"),
+            ReportRate::Hz60 => write!(f, "60 Hz"),
+            ReportRate::Hz120 => write!(f, "120 Hz"),
+        }
+    }
+}
+
+impl FromStr for ReportRate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "AUTO" => Ok(ReportRate::Auto),
+            "60" => Ok(ReportRate::Hz60),
+            "120" => Ok(ReportRate::Hz120),
+            _ => Err(Error::new(ErrorKind::Other, "unknown report rate".into())),
+        }
+    }
+}
+
+/// Version of `ProtocolStateSnapshot`, which is bumped on incompatible changes.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Represents a snapshot of the per-connection state of a `Protocol`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct ProtocolStateSnapshot {
+    /// Represents the version of the snapshot.
+    pub version: u32,
+    /// Represents the firmware profile.
+    pub firmware_profile: FirmwareProfile,
+    /// Represents the input report mode.
+    pub report_mode: ReportMode,
+    /// Represents if the IMU is enabled.
+    pub imu_enabled: bool,
+    /// Represents if the vibration is enabled.
+    pub vibration_enabled: bool,
+    /// Represents the NFC/IR MCU state.
+    pub mcu_state: McuState,
+    /// Represents the NFC state.
+    pub nfc_state: NfcState,
+    /// Represents the player lights.
+    pub player_lights: u8,
+    /// Represents the battery level.
+    pub battery: BatteryLevel,
+    /// Represents if the battery is charging.
+    pub charging: bool,
+    /// Represents the timer.
+    pub timer: u8,
+    /// Represents if a tag is on the NFC point.
+    pub tag_loaded: bool,
+}
+
+/// Enumeration for controller firmware profiles.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum FirmwareProfile {
+    /// Represents the firmware 3.72.
+    Fw3_72,
+    /// Represents the firmware 3.139.
+    #[default]
+    Fw3_139,
+    /// Represents the firmwares 4.xx.
+    Fw4,
+    /// Represents the latest firmware.
+    Latest,
+}
+
+impl FirmwareProfile {
+    /// Returns the firmware version in device info.
+    pub fn version(&self) -> [u8; 2] {
+        match self {
+            FirmwareProfile::Fw3_72 => [0x03, 0x48],
+            FirmwareProfile::Fw3_139 => [0x03, 0x8B],
+            FirmwareProfile::Fw4 => [0x04, 0x06],
+            FirmwareProfile::Latest => [0x04, 0x33],
+        }
+    }
+
+    /// Returns the NFC/IR MCU firmware version.
+    pub fn mcu_version(&self) -> McuVersion {
+        match self {
+            FirmwareProfile::Fw3_72 => McuVersion::new(0x0003, 0x0005),
+            _ => McuVersion::new(0x0008, 0x001B),
+        }
+    }
+
+    /// Returns if the trigger buttons elapsed time is counted.
+    pub fn counts_trigger_buttons_elapsed_time(&self) -> bool {
+        !matches!(self, FirmwareProfile::Fw3_72)
+    }
+
+    /// Returns the interval between input reports in the given input report mode, or `None` if
+    /// input reports are not pushed periodically.
+    pub fn report_interval(&self, mode: ReportMode) -> Option<Duration> {
+        match (self, mode) {
+            // Early firmwares push standard full input reports every 15 ms
+            (FirmwareProfile::Fw3_72, ReportMode::StandardFull | ReportMode::NfcIr) => {
+                Some(Duration::from_millis(15))
+            }
+            _ => mode.interval(),
+        }
+    }
+}
+
+impl Display for FirmwareProfile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FirmwareProfile::Fw3_72 => write!(f, "3.72"),
+            FirmwareProfile::Fw3_139 => write!(f, "3.139"),
+            FirmwareProfile::Fw4 => write!(f, "4.xx"),
+            FirmwareProfile::Latest => write!(f, "Latest"),
+        }
+    }
+}
+
+impl FromStr for FirmwareProfile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "FW_3_72" => Ok(FirmwareProfile::Fw3_72),
+            "FW_3_139" => Ok(FirmwareProfile::Fw3_139),
+            "FW_4_XX" => Ok(FirmwareProfile::Fw4),
+            "LATEST" => Ok(FirmwareProfile::Latest),
+            _ => Err(Error::new(
+                ErrorKind::Protocol,
+                "unknown firmware profile".into(),
+            )),
+        }
+    }
+}
+
+/// Represents the device info replied to subcommand 0x02.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct DeviceInfo {
+    /// Represents the firmware version.
+    pub firmware_version: [u8; 2],
+    /// Represents the controller type byte.
+    pub controller_type: u8,
+    /// Represents the unknown bytes following the controller type and the address.
+    pub unknown: [u8; 2],
+    /// Represents if colors in SPI flash are used.
+    pub spi_colors: u8,
+}
+
+impl DeviceInfo {
+    /// Creates a `DeviceInfo` of first-party controllers with the given controller type.
+    pub fn new(controller_type: ControllerType) -> Self {
+        DeviceInfo {
+            firmware_version: FirmwareProfile::default().version(),
+            controller_type: controller_type.id(),
+            unknown: [0x02, 0x01],
+            spi_colors: 0x01,
+        }
+    }
+
+    /// Returns the device info in bytes with the given address.
+    pub fn to_bytes(&self, address: Address) -> [u8; 12] {
+        let mut buf = [0u8; 12];
+
+        buf[..2].copy_from_slice(&self.firmware_version);
+        buf[2] = self.controller_type;
+        buf[3] = self.unknown[0];
+        buf[4..10].copy_from_slice(&address.0);
+        buf[10] = self.unknown[1];
+        buf[11] = self.spi_colors;
+
+        buf
+    }
+}
+
+/// Default delay before replying to subcommands.
+pub const DEFAULT_REPLY_DELAY: Duration = Duration::from_millis(3);
+
+/// Represents a frame to be sent to the device.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct OutgoingFrame {
+    /// Represents the data of the frame.
+    pub data: Vec<u8>,
+    /// Represents the time after which the frame should be sent, or `None` if it should be sent
+    /// immediately.
+    pub send_after: Option<Instant>,
+}
+
+impl OutgoingFrame {
+    /// Creates an `OutgoingFrame` with the given data, which should be sent immediately.
+    pub fn new(data: Vec<u8>) -> Self {
+        OutgoingFrame {
+            data,
+            send_after: None,
+        }
+    }
+
+    /// Creates an `OutgoingFrame` with the given data, which should be sent after the given time.
+    pub fn new_after(data: Vec<u8>, send_after: Instant) -> Self {
+        OutgoingFrame {
+            data,
+            send_after: Some(send_after),
+        }
+    }
+
+    /// Returns if the frame should be sent at the given time.
+    pub fn is_due(&self, now: Instant) -> bool {
+        match self.send_after {
+            Some(send_after) => send_after <= now,
+            None => true,
+        }
+    }
+}
+
+/// Offset of the subcommand data in an output report.
+const SUBCOMMAND_DATA_OFFSET: usize = 12;
+/// Length of a standard input report.
+const STANDARD_INPUT_LEN: usize = 50;
+/// Length of an input report with NFC/IR MCU data.
+const NFC_IR_INPUT_LEN: usize = STANDARD_INPUT_LEN + MCU_DATA_LEN;
+/// Length of a simple HID input report.
+const SIMPLE_HID_INPUT_LEN: usize = 13;
+/// Maximum number of raw reports pending to send.
+const RAW_REPORTS_CAPACITY: usize = 16;
+/// Minimum turbo rate in Hz.
+const MIN_TURBO_RATE: f32 = 0.1;
+/// Maximum number of motion samples kept until reported.
+const MOTION_SAMPLES_CAPACITY: usize = 64;
+/// Maximum number of events kept until taken.
+const EVENTS_CAPACITY: usize = 64;
+/// Length of the subcommand reply data.
+const SUBCOMMAND_REPLY_DATA_LEN: usize = 35;
+
+/// Represents the statistics of a connection.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct ProtocolStats {
+    /// Represents the number of received rumble frames.
+    pub rumble_frames: u64,
+    /// Represents the number of rumble frames which are not recorded as events.
+    pub suppressed_rumble_frames: u64,
+}
+
+/// Represents a scheduled release of a button.
+struct Release {
+    button: Button,
+    at: Instant,
+    // The press must be carried by an input report before the release
+    reported: bool,
+}
+
+/// Represents the turbo of a button.
+struct Turbo {
+    button: Button,
+    rate: f32,
+    // The current phase has started if its end is set
+    phase_end: Option<Instant>,
+}
+
+/// Represents the playback of a macro.
+struct Playback {
+    steps: Vec<MacroStep>,
+    index: usize,
+    // The current step has started if its end is set
+    step_end: Option<Instant>,
+}
+
+/// Represents the state machine of the Nintendo Switch controller protocol.
+///
+/// The protocol does not perform any IO. Output reports received from the device are fed to
+/// `handle_incoming`, and the returned frames, along with the periodic input reports from
+/// `poll_tick`, are to be sent to the device by the caller.
+pub struct Protocol {
+    controller_type: ControllerType,
+    address: Address,
+    device_info: DeviceInfo,
+    firmware_profile: FirmwareProfile,
+    reply_delay: Duration,
+    grip: bool,
+    orientation: Orientation,
+    spi_flash: SpiFlash,
+    initial_spi_flash: SpiFlash,
+    spi_writes: Vec<(usize, usize)>,
+    buttons: ButtonState,
+    releases: Vec<Release>,
+    turbos: Vec<Turbo>,
+    stick_config: StickConfig,
+    left_stick: StickPosition,
+    right_stick: StickPosition,
+    timer: u8,
+    trigger_elapsed_time: [u16; 7],
+    report_mode: ReportMode,
+    report_rate: ReportRate,
+    next_report: Option<Instant>,
+    report_tick: u64,
+    tick_inputs: BTreeMap<u64, ControllerInput>,
+    timed_inputs: Vec<(Instant, ControllerInput)>,
+    recording: Option<InputRecording>,
+    input_changed: bool,
+    last_input: Option<Instant>,
+    pending_reports: VecDeque<Vec<u8>>,
+    raw_reports: VecDeque<Vec<u8>>,
+    playback: Option<Playback>,
+    imu_enabled: bool,
+    imu_sensitivity: ImuSensitivity,
+    motion: MotionSample,
+    motion_samples: VecDeque<MotionSample>,
+    battery: BatteryLevel,
+    charging: bool,
+    vibration_enabled: bool,
+    player_lights: u8,
+    home_light: Vec<u8>,
+    mcu: Mcu,
+    rumble_epsilon: f32,
+    rumble: Option<RumbleFrame>,
+    hci_state: Option<HciState>,
+    pairing_reset: bool,
+    disconnect_request: Option<Option<Duration>>,
+    handshake: Option<PairedDevice>,
+    handshake_started: bool,
+    initial_buttons: Vec<Button>,
+    initial_buttons_release: Option<Duration>,
+    events: VecDeque<SwitchEvent>,
+    stats: ProtocolStats,
+}
+
+impl Protocol {
+    /// Creates a `Protocol` with the given controller type.
+    pub fn new(controller_type: ControllerType) -> Self {
+        Protocol {
+            controller_type,
+            address: Address::any(),
+            device_info: DeviceInfo::new(controller_type),
+            firmware_profile: FirmwareProfile::default(),
+            reply_delay: DEFAULT_REPLY_DELAY,
+            grip: false,
+            orientation: Orientation::default(),
+            spi_flash: SpiFlash::new(controller_type),
+            initial_spi_flash: SpiFlash::new(controller_type),
+            spi_writes: vec![],
+            buttons: ButtonState::new(),
+            releases: vec![],
+            turbos: vec![],
+            stick_config: StickConfig::default(),
+            left_stick: StickPosition::default(),
+            right_stick: StickPosition::default(),
+            timer: 0,
+            trigger_elapsed_time: [0; 7],
+            report_mode: ReportMode::SimpleHid,
+            report_rate: ReportRate::default(),
+            next_report: None,
+            report_tick: 0,
+            tick_inputs: BTreeMap::new(),
+            timed_inputs: vec![],
+            recording: None,
+            input_changed: false,
+            last_input: None,
+            pending_reports: VecDeque::new(),
+            raw_reports: VecDeque::new(),
+            playback: None,
+            imu_enabled: false,
+            imu_sensitivity: ImuSensitivity::default(),
+            motion: MotionSample::default(),
+            motion_samples: VecDeque::new(),
+            battery: BatteryLevel::default(),
+            charging: false,
+            vibration_enabled: false,
+            player_lights: 0,
+            home_light: Vec::new(),
+            mcu: Mcu::new(),
+            rumble_epsilon: DEFAULT_RUMBLE_EPSILON,
+            rumble: None,
+            hci_state: None,
+            pairing_reset: false,
+            disconnect_request: None,
+            handshake: None,
+            handshake_started: false,
+            initial_buttons: vec![],
+            initial_buttons_release: None,
+            events: VecDeque::new(),
+            stats: ProtocolStats::default(),
+        }
+    }
+
+    /// Creates a `Protocol` with the given controller type and SPI flash.
+    pub fn with_spi_flash(controller_type: ControllerType, spi_flash: SpiFlash) -> Self {
+        let mut protocol = Protocol::new(controller_type);
+        protocol.spi_flash = spi_flash.clone();
+        protocol.initial_spi_flash = spi_flash;
+
+        protocol
+    }
+
+    /// Sets the Bluetooth address reported in device info.
+    pub fn set_address(&mut self, address: Address) {
+        self.address = address;
+    }
+
+    /// Returns the device info.
+    pub fn device_info(&self) -> DeviceInfo {
+        self.device_info
+    }
+
+    /// Sets the device info.
+    pub fn set_device_info(&mut self, device_info: DeviceInfo) {
+        self.device_info = device_info;
+    }
+
+    /// Returns the firmware profile.
+    pub fn firmware_profile(&self) -> FirmwareProfile {
+        self.firmware_profile
+    }
+
+    /// Sets the firmware profile, which also updates the firmware version in device info and the
+    /// NFC/IR MCU firmware version.
+    pub fn set_firmware_profile(&mut self, profile: FirmwareProfile) {
+        self.firmware_profile = profile;
+        self.device_info.firmware_version = profile.version();
+        self.mcu.set_version(profile.mcu_version());
+    }
+
+    /// Sets the NFC/IR MCU firmware version, which is reported as the latest version.
+    pub fn set_mcu_version(&mut self, version: McuVersion) {
+        self.mcu.set_version(version);
+    }
+
+    /// Returns the delay before replying to subcommands.
+    pub fn reply_delay(&self) -> Duration {
+        self.reply_delay
+    }
+
+    /// Sets the delay before replying to subcommands. Real controllers take a few milliseconds to
+    /// reply, and a zero delay replies immediately.
+    pub fn set_reply_delay(&mut self, delay: Duration) {
+        self.reply_delay = delay;
+    }
+
+    /// Returns if the Joy-Con is connected to the charging grip.
+    pub fn grip(&self) -> bool {
+        self.grip
+    }
+
+    /// Sets if the Joy-Con is connected to the charging grip. Only Joy-Cons can be connected to
+    /// the charging grip.
+    pub fn set_grip(&mut self, grip: bool) -> Result<()> {
+        if grip && !self.controller_type.is_joy_con() {
             return Err(Error::new(
                 ErrorKind::Protocol,
-                "invalid output length".into(),
+                format!(
+                    "{} cannot be connected to the charging grip",
+                    self.controller_type
+                ),
             ));
         }
+        self.grip = grip;
 
-        // Direction
-        if value[0] != Direction::Output as u8 {
+        Ok(())
+    }
+
+    /// Returns the orientation in which the controller is held.
+    pub fn orientation(&self) -> Orientation {
+        self.orientation
+    }
+
+    /// Sets the orientation in which the controller is held. Only single Joy-Cons can be held
+    /// sideways. The protocol keeps physical inputs, so the orientation only applies to logical
+    /// inputs mapped through it.
+    pub fn set_orientation(&mut self, orientation: Orientation) -> Result<()> {
+        if orientation == Orientation::Sideways && !self.controller_type.is_joy_con() {
             return Err(Error::new(
                 ErrorKind::Protocol,
-                "invalid output direction".into(),
+                format!("{} cannot be held sideways", self.controller_type),
             ));
         }
+        self.orientation = orientation;
 
-        // Type
-        let t: Type = value[1].try_into()?;
+        Ok(())
+    }
 
-        // Timer
-        let timer = value[2];
+    /// Resets the per-connection state, including the timer, the input report mode, the inputs
+    /// along with their releases and recording, the IMU and vibration, the rumble, the player
+    /// lights and HOME light, and the NFC/IR MCU. The configuration, including the controller
+    /// type, device info, firmware profile, charging grip, SPI flash, turbos and rumble epsilon,
+    /// is preserved, where turbos restart their phases.
+    pub fn reset(&mut self) {
+        self.buttons = ButtonState::new();
+        self.releases.clear();
+        for turbo in self.turbos.iter_mut() {
+            turbo.phase_end = None;
+        }
+        self.left_stick = StickPosition::default();
+        self.right_stick = StickPosition::default();
+        self.timer = 0;
+        self.trigger_elapsed_time = [0; 7];
+        self.report_mode = ReportMode::SimpleHid;
+        self.next_report = None;
+        self.report_tick = 0;
+        self.tick_inputs.clear();
+        self.timed_inputs.clear();
+        self.recording = None;
+        self.input_changed = false;
+        self.last_input = None;
+        self.pending_reports.clear();
+        self.raw_reports.clear();
+        self.playback = None;
+        self.imu_enabled = false;
+        self.imu_sensitivity = ImuSensitivity::default();
+        self.motion = MotionSample::default();
+        self.motion_samples.clear();
+        self.vibration_enabled = false;
+        self.player_lights = 0;
+        self.home_light.clear();
+        self.mcu.reset();
+        self.rumble = None;
+        self.hci_state = None;
+        self.pairing_reset = false;
+        self.disconnect_request = None;
+        self.handshake = None;
+        self.handshake_started = false;
+        self.events.clear();
+        self.stats = ProtocolStats::default();
+    }
+
+    /// Returns a snapshot of the per-connection state.
+    pub fn snapshot(&self) -> ProtocolStateSnapshot {
+        ProtocolStateSnapshot {
+            version: SNAPSHOT_VERSION,
+            firmware_profile: self.firmware_profile,
+            report_mode: self.report_mode,
+            imu_enabled: self.imu_enabled,
+            vibration_enabled: self.vibration_enabled,
+            mcu_state: self.mcu.state(),
+            nfc_state: self.mcu.nfc_state(),
+            player_lights: self.player_lights,
+            battery: self.battery,
+            charging: self.charging(),
+            timer: self.timer,
+            tag_loaded: self.mcu.tag().is_some(),
+        }
+    }
+
+    /// Resets the per-connection state like `reset`, and also restores the SPI flash to its
+    /// contents at construction.
+    pub fn hard_reset(&mut self) {
+        self.reset();
+        self.spi_flash = self.initial_spi_flash.clone();
+        self.spi_writes.clear();
+    }
+
+    /// Replaces the SPI flash, where later reads are served from the given one. Writes by the
+    /// device since construction, the last `hard_reset` or the last reload are either kept over
+    /// the given SPI flash or discarded, by the given mode. The given SPI flash also becomes the
+    /// one restored by `hard_reset`, and the stick configuration is not reapplied to it.
+    ///
+    /// Returns if any region which the device reads on connection and caches changed, e.g., the
+    /// colors and calibration, where the device only sees the changes after reconnecting.
+    pub fn reload_spi_flash(&mut self, spi_flash: SpiFlash, mode: ReloadMode) -> bool {
+        let mut reloaded = spi_flash.clone();
+        match mode {
+            ReloadMode::Merge => {
+                for &(start, end) in self.spi_writes.iter() {
+                    reloaded.copy_region(&self.spi_flash, start, end);
+                }
+            }
+            ReloadMode::Discard => self.spi_writes.clear(),
+        }
+        let reconnect = self.spi_flash.cached_differs(&reloaded);
+        self.spi_flash = reloaded;
+        self.initial_spi_flash = spi_flash;
+
+        reconnect
+    }
+
+    /// Returns the controller type.
+    pub fn controller_type(&self) -> ControllerType {
+        self.controller_type
+    }
+
+    /// Returns the input report mode.
+    pub fn report_mode(&self) -> ReportMode {
+        self.report_mode
+    }
+
+    /// Returns the SPI flash.
+    pub fn spi_flash(&self) -> &SpiFlash {
+        &self.spi_flash
+    }
+
+    /// Returns the colors.
+    pub fn colors(&self) -> Colors {
+        self.spi_flash.colors()
+    }
+
+    /// Sets the colors.
+    pub fn set_colors(&mut self, colors: Colors) {
+        self.spi_flash.set_colors(colors);
+    }
+
+    /// Returns the state of buttons.
+    pub fn buttons(&self) -> ButtonState {
+        self.buttons
+    }
+
+    /// Sets if the given button is pressed. Setting a button which the controller does not have
+    /// is rejected with `ErrorKind::Unavailable`.
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> Result<()> {
+        self.set_buttons(&[(button, pressed)])
+    }
+
+    /// Sets if the given buttons are pressed at once, so they are reflected in the same input
+    /// report. If any of the buttons is unavailable in the controller, none of them is set.
+    pub fn set_buttons(&mut self, buttons: &[(Button, bool)]) -> Result<()> {
+        if let Some((button, _)) = buttons
+            .iter()
+            .find(|(button, _)| !self.controller_type.has_button(*button))
+        {
+            return Err(Error::new(
+                ErrorKind::Unavailable,
+                format!("{} does not have {}", self.controller_type, button),
+            ));
+        }
+
+        let previous = self.buttons;
+        for (button, pressed) in buttons {
+            self.buttons.set(*button, *pressed);
+        }
+        self.releases
+            .retain(|release| buttons.iter().all(|(button, _)| *button != release.button));
+        if previous != self.buttons {
+            self.state_changed();
+        }
+
+        Ok(())
+    }
+
+    /// Presses the given button, which is released at the given duration after now. The button
+    /// is released only after an input report has carried the press, and pressing the button
+    /// again extends the hold instead of truncating it. Durations beyond the range of time are
+    /// rejected.
+    pub fn press_for(&mut self, button: Button, duration: Duration, now: Instant) -> Result<()> {
+        let at = now.checked_add(duration).ok_or_else(|| {
+            Error::new(
+                ErrorKind::Other,
+                format!("cannot press {} for {:?}", button, duration),
+            )
+        })?;
+        let previous = self
+            .releases
+            .iter()
+            .find(|release| release.button == button)
+            .map(|release| (release.at, release.reported));
+        self.set_button(button, true)?;
+
+        let release = match previous {
+            Some((previous_at, reported)) => Release {
+                button,
+                at: previous_at.max(at),
+                reported,
+            },
+            None => Release {
+                button,
+                at,
+                // Simple HID input reports are pushed on the press
+                reported: self.report_mode == ReportMode::SimpleHid,
+            },
+        };
+        self.releases.push(release);
+
+        Ok(())
+    }
+
+    /// Returns the turbo rate of the given button in Hz if any.
+    pub fn turbo(&self, button: Button) -> Option<f32> {
+        self.turbos
+            .iter()
+            .find(|turbo| turbo.button == button)
+            .map(|turbo| turbo.rate)
+    }
+
+    /// Sets the turbo rate of the given button in Hz, or disables the turbo if `None`. The
+    /// button is toggled by `poll_tick`, where each phase spans whole input report intervals while
+    /// streaming, so the rate is bounded by half the input report rate. Setting the turbo of a
+    /// button which the controller does not have is rejected with `ErrorKind::Unavailable`.
+    pub fn set_turbo(&mut self, button: Button, rate: Option<f32>) -> Result<()> {
+        if !self.controller_type.has_button(button) {
+            return Err(Error::new(
+                ErrorKind::Unavailable,
+                format!("{} does not have {}", self.controller_type, button),
+            ));
+        }
+        let rate = match rate {
+            Some(rate) if !(MIN_TURBO_RATE..=f32::MAX).contains(&rate) => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("invalid turbo rate {}", rate),
+                ))
+            }
+            rate => rate,
+        };
+
+        let previous = self.turbos.len();
+        self.turbos.retain(|turbo| turbo.button != button);
+        match rate {
+            Some(rate) => self.turbos.push(Turbo {
+                button,
+                rate,
+                phase_end: None,
+            }),
+            // The button is left released
+            None if previous != self.turbos.len() => self.set_button(button, false)?,
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    /// Presses the given button for one input report.
+    pub fn tap(&mut self, button: Button, now: Instant) -> Result<()> {
+        self.press_for(button, Duration::ZERO, now)
+    }
+
+    /// Returns the position of the given stick.
+    pub fn stick(&self, stick: Stick) -> StickPosition {
+        match stick {
+            Stick::Left => self.left_stick,
+            Stick::Right => self.right_stick,
+        }
+    }
+
+    /// Sets the position of the given stick. Setting a stick which the controller does not have
+    /// is rejected with `ErrorKind::Unavailable`, and its bytes in input reports always keep the
+    /// pattern of real hardware.
+    pub fn set_stick(&mut self, stick: Stick, position: StickPosition) -> Result<()> {
+        self.set_sticks(&[(stick, position)])
+    }
+
+    /// Sets the positions of the given sticks at once, so they are reflected in the same input
+    /// report. If any of the sticks is unavailable in the controller, none of them is set.
+    pub fn set_sticks(&mut self, sticks: &[(Stick, StickPosition)]) -> Result<()> {
+        if let Some((stick, _)) = sticks
+            .iter()
+            .find(|(stick, _)| !self.controller_type.has_stick(*stick))
+        {
+            return Err(Error::new(
+                ErrorKind::Unavailable,
+                format!("{} does not have {}", self.controller_type, stick),
+            ));
+        }
+
+        let previous = (self.left_stick, self.right_stick);
+        for (stick, position) in sticks {
+            match stick {
+                Stick::Left => self.left_stick = *position,
+                Stick::Right => self.right_stick = *position,
+            }
+        }
+        if previous != (self.left_stick, self.right_stick) {
+            self.state_changed();
+        }
+
+        Ok(())
+    }
+
+    /// Sets the position of the given stick from normalized values in -1 to 1, which are
+    /// converted with the stick configuration.
+    pub fn set_stick_normalized(&mut self, stick: Stick, x: f32, y: f32) -> Result<()> {
+        self.set_stick(stick, self.stick_config.position(x, y))
+    }
+
+    /// Sets the whole input state at once, so it is reflected in the same input report. Buttons
+    /// and sticks which the controller does not have must be released and centered, or the input
+    /// is rejected with `ErrorKind::Unavailable`, where none of the input is set.
+    pub fn set_input(&mut self, input: &ControllerInput) -> Result<()> {
+        let sticks = self.validate_input(input)?;
+        let previous = (self.buttons, self.left_stick, self.right_stick);
+        self.buttons = input.buttons;
+        self.releases.clear();
+        for (stick, position) in sticks {
+            match stick {
+                Stick::Left => self.left_stick = position,
+                Stick::Right => self.right_stick = position,
+            }
+        }
+        if previous != (self.buttons, self.left_stick, self.right_stick) {
+            self.state_changed();
+        }
+        if let Some(motion) = input.motion {
+            self.set_motion(motion);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the stick positions of the input to set if the controller has its buttons and
+    /// sticks.
+    fn validate_input(&self, input: &ControllerInput) -> Result<Vec<(Stick, StickPosition)>> {
+        if let Some(button) = input
+            .buttons
+            .pressed()
+            .into_iter()
+            .find(|button| !self.controller_type.has_button(*button))
+        {
+            return Err(Error::new(
+                ErrorKind::Unavailable,
+                format!("{} does not have {}", self.controller_type, button),
+            ));
+        }
+        let mut sticks = vec![];
+        for (stick, (x, y)) in [
+            (Stick::Left, input.left_stick),
+            (Stick::Right, input.right_stick),
+        ] {
+            let position = self.stick_config.position(x, y);
+            if self.controller_type.has_stick(stick) {
+                sticks.push((stick, position));
+            } else if position != self.stick_config.position(0.0, 0.0) {
+                return Err(Error::new(
+                    ErrorKind::Unavailable,
+                    format!("{} does not have {}", self.controller_type, stick),
+                ));
+            }
+        }
+
+        Ok(sticks)
+    }
+
+    /// Returns the tick of the next periodic input report.
+    pub fn current_tick(&self) -> ReportTick {
+        ReportTick(self.report_tick)
+    }
+
+    /// Returns the estimated time of the periodic input report of the given tick, or `None` if
+    /// input reports are not pushed periodically, the tick is in the past, or the time is beyond
+    /// the range of time. The estimation assumes the input report mode and the report rate are
+    /// kept, and that the next input report is due at the given time if streaming has not
+    /// started.
+    pub fn tick_time(&self, tick: ReportTick, now: Instant) -> Option<Instant> {
+        let interval = self.report_interval()?;
+        let ticks = tick.0.checked_sub(self.report_tick)?;
+        let next_report = self.next_report.unwrap_or(now);
+        let elapsed = interval.checked_mul(u32::try_from(ticks).ok()?)?;
+
+        next_report.checked_add(elapsed)
+    }
+
+    /// Returns the estimated tick of the first periodic input report at or after the given time,
+    /// or `None` if input reports are not pushed periodically or the tick is beyond the range of
+    /// ticks. The next input report is assumed due now if streaming has not started.
+    pub fn time_tick(&self, time: Instant, now: Instant) -> Option<ReportTick> {
+        let interval = self.report_interval()?;
+        let next_report = self.next_report.unwrap_or(now);
+        let ticks = match time.checked_duration_since(next_report) {
+            Some(elapsed) => (elapsed.as_secs_f64() / interval.as_secs_f64()).ceil() as u64,
+            None => 0,
+        };
+
+        self.report_tick.checked_add(ticks).map(ReportTick)
+    }
+
+    /// Schedules the input state to be set at the given target. An input scheduled at a tick is
+    /// carried by exactly the periodic input report of the tick, and an input scheduled at an
+    /// instant is carried by the first input report at or after the instant. Ticks only advance
+    /// while input reports are pushed periodically. A later input at the same target replaces
+    /// the earlier one. Targets in the past are rejected, and so are inputs which cannot be set.
+    pub fn schedule(&mut self, at: ScheduleAt, input: ControllerInput, now: Instant) -> Result<()> {
+        self.validate_input(&input)?;
+        match at {
+            ScheduleAt::Tick(tick) => {
+                if tick.0 < self.report_tick {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        format!(
+                            "{} is in the past, where the current tick is {}",
+                            tick, self.report_tick
+                        ),
+                    ));
+                }
+                self.tick_inputs.insert(tick.0, input);
+            }
+            ScheduleAt::Instant(instant) => {
+                if instant < now {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        "scheduled instant is in the past".into(),
+                    ));
+                }
+                self.timed_inputs.retain(|(at, _)| *at != instant);
+                let index = self.timed_inputs.partition_point(|(at, _)| *at < instant);
+                self.timed_inputs.insert(index, (instant, input));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the whole input state.
+    pub fn input(&self) -> ControllerInput {
+        ControllerInput {
+            buttons: self.buttons,
+            left_stick: self.stick_config.normalize(self.left_stick),
+            right_stick: self.stick_config.normalize(self.right_stick),
+            motion: Some(self.motion),
+        }
+    }
+
+    /// Starts recording the input state carried by periodic input reports, which replaces the
+    /// ongoing recording if any. The recording follows the interval between input reports at
+    /// the start.
+    pub fn start_recording(&mut self) {
+        let interval = self
+            .report_interval()
+            .or_else(|| self.report_rate.interval())
+            .unwrap_or_else(|| ReportRate::Hz60.interval().unwrap());
+        self.recording = Some(InputRecording::new(interval));
+    }
+
+    /// Stops recording and returns the recording, or `None` if not recording.
+    pub fn stop_recording(&mut self) -> Option<InputRecording> {
+        self.recording.take()
+    }
+
+    /// Returns if any input is scheduled.
+    pub fn has_scheduled(&self) -> bool {
+        !self.tick_inputs.is_empty() || !self.timed_inputs.is_empty()
+    }
+
+    /// Removes all scheduled inputs.
+    pub fn clear_schedule(&mut self) {
+        self.tick_inputs.clear();
+        self.timed_inputs.clear();
+    }
+
+    /// Sets the scheduled inputs due at the given time, and the ones of the tick if the periodic
+    /// input report of the tick is to be pushed.
+    fn apply_scheduled(&mut self, at: Instant, tick: Option<u64>) {
+        let mut inputs = vec![];
+        let due = self
+            .timed_inputs
+            .partition_point(|(instant, _)| *instant <= at);
+        inputs.extend(self.timed_inputs.drain(..due).map(|(_, input)| input));
+        if let Some(tick) = tick {
+            let later = self.tick_inputs.split_off(&(tick + 1));
+            inputs.extend(std::mem::replace(&mut self.tick_inputs, later).into_values());
+        }
+        for input in inputs {
+            if let Err(e) = self.set_input(&input) {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    /// Returns the stick configuration.
+    pub fn stick_config(&self) -> StickConfig {
+        self.stick_config
+    }
+
+    /// Sets the stick configuration, which also updates the factory stick calibration in the SPI
+    /// flash.
+    pub fn set_stick_config(&mut self, config: StickConfig) {
+        self.stick_config = config;
+        self.spi_flash.set_stick_config(config);
+        self.initial_spi_flash.set_stick_config(config);
+    }
+
+    /// Clears the pairing entries of the given host in the SPI flash. Returns if any entry is
+    /// cleared.
+    pub fn clear_pairing(&mut self, host: Address) -> bool {
+        let cleared = self.spi_flash.clear_pairing(host.0);
+        self.initial_spi_flash.clear_pairing(host.0) || cleared
+    }
+
+    /// Returns if the IMU is enabled.
+    pub fn imu_enabled(&self) -> bool {
+        self.imu_enabled
+    }
+
+    /// Returns the battery level.
+    pub fn battery(&self) -> BatteryLevel {
+        self.battery
+    }
+
+    /// Returns if the battery is charging. Joy-Cons in the charging grip are always charging.
+    pub fn charging(&self) -> bool {
+        self.charging || self.grip
+    }
+
+    /// Sets the battery level and if the battery is charging, which are reflected in the next
+    /// standard input report. Simple HID input reports do not carry the battery.
+    pub fn set_battery(&mut self, level: BatteryLevel, charging: bool) {
+        self.battery = level;
+        self.charging = charging;
+    }
+
+    /// Returns the IMU sensitivity set by the device.
+    pub fn imu_sensitivity(&self) -> ImuSensitivity {
+        self.imu_sensitivity
+    }
+
+    /// Returns the motion sample.
+    pub fn motion(&self) -> MotionSample {
+        self.motion
+    }
+
+    /// Sets the motion sample in physical units, which is reported in raw values of the IMU
+    /// sensitivity and calibration when the IMU is enabled. Samples set between input reports
+    /// are downsampled into the 3 samples of the next input report, and the last sample is
+    /// repeated if samples are set less often.
+    pub fn set_motion(&mut self, motion: MotionSample) {
+        if motion != self.motion {
+            self.input_changed = true;
+        }
+        self.motion = motion;
+        if self.motion_samples.len() >= MOTION_SAMPLES_CAPACITY {
+            self.motion_samples.pop_front();
+        }
+        self.motion_samples.push_back(motion);
+    }
+
+    /// Returns if the vibration is enabled.
+    pub fn vibration_enabled(&self) -> bool {
+        self.vibration_enabled
+    }
+
+    /// Returns the HCI state requested by the device in this connection if any.
+    pub fn hci_state(&self) -> Option<HciState> {
+        self.hci_state
+    }
+
+    /// Returns if the device reset the pairing info in this connection.
+    pub fn pairing_reset(&self) -> bool {
+        self.pairing_reset
+    }
+
+    /// Requests the connection to be dropped as if the battery is pulled, and to reconnect after
+    /// the given duration if any. The request is cleared when the connection starts over.
+    pub fn request_disconnect(&mut self, reconnect_after: Option<Duration>) {
+        self.disconnect_request = Some(reconnect_after);
+    }
+
+    /// Takes the request to drop the connection, which is the duration to reconnect after if
+    /// any.
+    pub fn take_disconnect_request(&mut self) -> Option<Option<Duration>> {
+        self.disconnect_request.take()
+    }
+
+    /// Returns the buttons held during the pairing handshake.
+    pub fn initial_buttons(&self) -> &[Button] {
+        &self.initial_buttons
+    }
+
+    /// Sets the buttons held during the pairing handshake, which are pressed from the first input
+    /// report and released once the device assigns a player, or after the given duration if
+    /// any. If any of the buttons is unavailable in the controller, none of them is set.
+    pub fn set_initial_buttons(
+        &mut self,
+        buttons: &[Button],
+        release_after: Option<Duration>,
+    ) -> Result<()> {
+        if let Some(button) = buttons
+            .iter()
+            .find(|button| !self.controller_type.has_button(**button))
+        {
+            return Err(Error::new(
+                ErrorKind::Unavailable,
+                format!("{} does not have {}", self.controller_type, button),
+            ));
+        }
+        self.initial_buttons = buttons.to_vec();
+        self.initial_buttons_release = release_after;
+
+        Ok(())
+    }
+
+    /// Starts the pairing handshake with the given device, whose progress is recorded as pairing
+    /// events until the device assigns a player, which completes the pairing. The initial
+    /// buttons are pressed at the given time.
+    pub fn start_handshake(&mut self, device: PairedDevice, now: Instant) -> Result<()> {
+        self.handshake = Some(device);
+        self.handshake_started = false;
+        match self.initial_buttons_release {
+            Some(duration) => {
+                for button in self.initial_buttons.clone() {
+                    self.press_for(button, duration, now)?;
+                }
+            }
+            None => {
+                let buttons: Vec<_> = self
+                    .initial_buttons
+                    .iter()
+                    .map(|button| (*button, true))
+                    .collect();
+                self.set_buttons(&buttons)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Releases the initial buttons at the end of the pairing handshake.
+    fn release_initial_buttons(&mut self) {
+        let buttons: Vec<_> = self
+            .initial_buttons
+            .iter()
+            .map(|button| (*button, false))
+            .collect();
+        // The buttons are validated when set
+        let _ = self.set_buttons(&buttons);
+    }
+
+    /// Returns if the pairing handshake is in progress.
+    pub fn is_handshaking(&self) -> bool {
+        self.handshake.is_some()
+    }
+
+    /// Fails the pairing handshake in progress for the given reason. Returns if any handshake is
+    /// in progress.
+    pub fn fail_handshake(&mut self, reason: String) -> bool {
+        if self.handshake.take().is_none() {
+            return false;
+        }
+        self.release_initial_buttons();
+        self.push_event(SwitchEvent::Pairing(PairingEvent::PairingFailed(reason)));
+
+        true
+    }
+
+    /// Returns if the input changed since the last `poll_tick`.
+    pub fn input_changed(&self) -> bool {
+        self.input_changed
+    }
+
+    /// Returns the time of the `poll_tick` which saw the last input change, or the first
+    /// `poll_tick` in this connection if the input never changed. Input reports repeating the
+    /// same input do not count as changes.
+    pub fn last_input(&self) -> Option<Instant> {
+        self.last_input
+    }
+
+    /// Returns the player lights.
+    pub fn player_lights(&self) -> u8 {
+        self.player_lights
+    }
+
+    /// Returns the HOME light pattern.
+    pub fn home_light(&self) -> &[u8] {
+        &self.home_light
+    }
+
+    /// Returns the threshold of changes in rumble frames.
+    pub fn rumble_epsilon(&self) -> f32 {
+        self.rumble_epsilon
+    }
+
+    /// Sets the threshold of changes in rumble frames. Rumble frames are only recorded as events
+    /// if they differ from the last recorded one beyond the threshold.
+    pub fn set_rumble_epsilon(&mut self, epsilon: f32) {
+        self.rumble_epsilon = epsilon;
+    }
+
+    /// Returns the last recorded rumble frame, or `None` if the rumble is neutral.
+    pub fn rumble(&self) -> Option<RumbleFrame> {
+        self.rumble
+    }
+
+    /// Takes the events recorded since the last call. Only the latest events are kept if they
+    /// are not taken in time.
+    pub fn take_events(&mut self) -> Vec<SwitchEvent> {
+        self.events.drain(..).collect()
+    }
+
+    /// Returns the statistics of the connection.
+    pub fn stats(&self) -> ProtocolStats {
+        self.stats
+    }
+
+    /// Resets the statistics.
+    pub fn reset_stats(&mut self) {
+        self.stats = ProtocolStats::default();
+    }
+
+    /// Returns the amiibo on the NFC point.
+    pub fn amiibo(&self) -> Option<&Amiibo> {
+        self.mcu.tag()
+    }
+
+    /// Puts the given amiibo on the NFC point, which replaces the previous one. A read in
+    /// progress finishes with the previous amiibo.
+    pub fn set_amiibo(&mut self, amiibo: Amiibo) {
+        self.mcu.set_tag(Some(amiibo));
+    }
+
+    /// Lifts the amiibo off the NFC point.
+    pub fn remove_amiibo(&mut self) {
+        self.mcu.set_tag(None);
+    }
+
+    /// Returns the NFC/IR MCU.
+    pub fn mcu(&self) -> &Mcu {
+        &self.mcu
+    }
+
+    /// Returns the interval between input reports in the current input report mode, or `None` if
+    /// input reports are not pushed periodically.
+    pub fn report_interval(&self) -> Option<Duration> {
+        let interval = self.firmware_profile.report_interval(self.report_mode)?;
+
+        Some(self.report_rate.interval().unwrap_or(interval))
+    }
+
+    /// Returns the rate of periodic input reports.
+    pub fn report_rate(&self) -> ReportRate {
+        self.report_rate
+    }
+
+    /// Sets the rate of periodic input reports at the given time. A change takes effect from the
+    /// next input report, where the cadence restarts from the report due earlier.
+    pub fn set_report_rate(&mut self, rate: ReportRate, now: Instant) {
+        self.report_rate = rate;
+        if let (Some(next_report), Some(interval)) = (self.next_report, self.report_interval()) {
+            self.next_report = Some(next_report.min(now + interval));
+        }
+    }
+
+    /// Handles incoming bytes from the device received at the given time and returns the frames
+    /// to be sent. Subcommand replies are scheduled after the reply delay.
+    pub fn handle_incoming(&mut self, bytes: &[u8], now: Instant) -> Result<Vec<OutgoingFrame>> {
+        let delay = self.reply_delay;
+
+        Ok(self
+            .handle(bytes, now)?
+            .into_iter()
+            .map(|data| match delay.is_zero() {
+                true => OutgoingFrame::new(data),
+                false => OutgoingFrame::new_after(data, now + delay),
+            })
+            .collect())
+    }
+
+    /// Plays the given macro, which replaces the playing one. Steps are advanced by `poll_tick`
+    /// and span whole input report intervals while streaming, where a hold spans at least one.
+    /// If any step holds inputs unavailable in the controller, the macro is rejected with
+    /// `ErrorKind::Unavailable`.
+    pub fn play_macro(&mut self, input_macro: InputMacro) -> Result<()> {
+        for step in input_macro.steps.iter() {
+            if let MacroStep::Hold {
+                buttons, sticks, ..
+            } = step
+            {
+                let button = buttons
+                    .iter()
+                    .find(|button| !self.controller_type.has_button(**button));
+                if let Some(button) = button {
+                    return Err(Error::new(
+                        ErrorKind::Unavailable,
+                        format!("{} does not have {}", self.controller_type, button),
+                    ));
+                }
+                let stick = sticks
+                    .iter()
+                    .find(|(stick, _, _)| !self.controller_type.has_stick(*stick));
+                if let Some((stick, _, _)) = stick {
+                    return Err(Error::new(
+                        ErrorKind::Unavailable,
+                        format!("{} does not have {}", self.controller_type, stick),
+                    ));
+                }
+            }
+        }
+
+        self.stop_macro();
+        self.playback = Some(Playback {
+            steps: input_macro.steps,
+            index: 0,
+            step_end: None,
+        });
+
+        Ok(())
+    }
+
+    /// Stops the playing macro, where inputs held by the current step are released.
+    pub fn stop_macro(&mut self) {
+        if let Some(playback) = self.playback.take() {
+            if playback.step_end.is_some() {
+                if let Some(step) = playback.steps.get(playback.index) {
+                    self.end_step(step);
+                }
+            }
+            self.push_event(SwitchEvent::Macro(MacroEvent::Stopped));
+        }
+    }
+
+    /// Returns if a macro is playing.
+    pub fn is_playing_macro(&self) -> bool {
+        self.playback.is_some()
+    }
+
+    /// Pushes a raw input report, which is sent as is before any other input report. Only the
+    /// direction byte and the length are validated, so the report may confuse the device.
+    pub fn push_raw_report(&mut self, report: &[u8]) -> Result<()> {
+        if report.first() != Some(&(Direction::Input as u8)) {
+            return Err(Error::new(
+                ErrorKind::Protocol,
+                "raw report is not an input report".into(),
+            ));
+        }
+        if report.len() < 2 || report.len() > NFC_IR_INPUT_LEN {
+            return Err(Error::new(
+                ErrorKind::Protocol,
+                format!("invalid raw report length {}", report.len()),
+            ));
+        }
+        if self.raw_reports.len() >= RAW_REPORTS_CAPACITY {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "too many raw reports pending".into(),
+            ));
+        }
+
+        debug!("raw report {}", Dump::new(report, 0));
+        self.raw_reports.push_back(report.to_vec());
+
+        Ok(())
+    }
+
+    /// Returns the input report due at the given time if any. Input reports are pushed at the
+    /// interval of the current input report mode. In simple HID mode, input reports are instead
+    /// pushed on every input state change.
+    ///
+    /// Once streaming starts, an input report is due on every tick even if the input state has
+    /// not changed, which keeps the connection alive. Each report carries the last input state
+    /// and advances the timer.
+    pub fn poll_tick(&mut self, now: Instant) -> Option<OutgoingFrame> {
+        if self.input_changed || self.last_input.is_none() {
+            self.input_changed = false;
+            self.last_input = Some(now);
+        }
+        self.advance_macro(now);
+        self.release_due(now);
+        self.advance_turbos(now);
+        if self.report_interval().is_none() {
+            // Without periodic input reports, scheduled inputs push simple HID input reports
+            self.apply_scheduled(now, None);
+        }
+        if let Some(report) = self.raw_reports.pop_front() {
+            return Some(OutgoingFrame::new(report));
+        }
+        if let Some(report) = self.pending_reports.pop_front() {
+            return Some(OutgoingFrame::new(report));
+        }
+
+        let interval = match self.report_interval() {
+            Some(interval) => interval,
+            None => {
+                self.next_report = None;
+
+                return None;
+            }
+        };
+
+        match self.next_report {
+            Some(next_report) if now < next_report => None,
+            next_report => {
+                // Keep the cadence unless the caller falls behind
+                let report_time = next_report.unwrap_or(now);
+                let mut next_report = report_time + interval;
+                if next_report <= now {
+                    next_report = now + interval;
+                }
+                self.next_report = Some(next_report);
+
+                // Scheduled inputs are carried by exactly the report of their tick
+                self.apply_scheduled(report_time, Some(self.report_tick));
+                self.report_tick += 1;
+                if self.recording.is_some() {
+                    let input = self.input();
+                    if let Some(recording) = &mut self.recording {
+                        recording.record(input);
+                    }
+                }
+
+                Some(OutgoingFrame::new(self.input_report()))
+            }
+        }
+    }
+
+    /// Returns the time when the next input report is due, or `None` if no input report is
+    /// pending and input reports are not pushed periodically. Input reports which are due
+    /// immediately are due at the given time.
+    pub fn next_tick(&self, now: Instant) -> Option<Instant> {
+        if !self.pending_reports.is_empty() || !self.raw_reports.is_empty() {
+            return Some(now);
+        }
+        let step_end = self
+            .playback
+            .as_ref()
+            .map(|playback| playback.step_end.unwrap_or(now));
+        let next_report = self
+            .report_interval()
+            .map(|_| self.next_report.unwrap_or(now));
+
+        let release = self
+            .releases
+            .iter()
+            .filter(|release| release.reported)
+            .map(|release| release.at)
+            .min();
+        let phase_end = self
+            .turbos
+            .iter()
+            .map(|turbo| turbo.phase_end.unwrap_or(now))
+            .min();
+        let scheduled = match self.report_interval() {
+            Some(_) => None,
+            None => self.timed_inputs.first().map(|(at, _)| *at),
+        };
+
+        step_end
+            .into_iter()
+            .chain(release)
+            .chain(phase_end)
+            .chain(scheduled)
+            .chain(next_report)
+            .min()
+    }
+
+    /// Handles an output report received at the given time and returns the reply if any. This is
+    /// the delay-free form of `handle_incoming` for replaying captures and tests, which ignores
+    /// the reply delay. Connections should use `handle_incoming`, which schedules subcommand
+    /// replies after the reply delay like a real controller.
+    pub fn handle_output(&mut self, frame: &[u8], now: Instant) -> Result<Option<Vec<u8>>> {
+        self.handle(frame, now)
+    }
+
+    fn handle(&mut self, frame: &[u8], now: Instant) -> Result<Option<Vec<u8>>> {
+        let output = Output::try_from(frame)?;
+
+        // Handshake
+        if self.handshake.is_some() && !self.handshake_started {
+            self.handshake_started = true;
+            self.push_event(SwitchEvent::Pairing(PairingEvent::HandshakeStarted));
+        }
+
+        // Rumble
+        if let Type::Subcommand | Type::Rumble = output.t {
+            self.handle_rumble(&output, now);
+        }
+
+        match output.t {
+            Type::Subcommand => {
+                let subcommand = output.subcommand.ok_or_else(|| {
+                    Error::with_dump(
+                        ErrorKind::Protocol,
+                        "missing subcommand".into(),
+                        frame,
+                        frame.len(),
+                    )
+                })?;
+                let data = output.data.unwrap_or_default();
+
+                Ok(Some(self.handle_subcommand(frame, subcommand, &data)?))
+            }
+            Type::RequestIrNfcMcu => {
+                let request = output.subcommand.ok_or_else(|| {
+                    Error::with_dump(
+                        ErrorKind::Protocol,
+                        "missing MCU request".into(),
+                        frame,
+                        frame.len(),
+                    )
+                })?;
+                let data = output.data.unwrap_or_default();
+                self.mcu.handle_request(request, &data).map_err(|e| {
+                    Error::with_dump(e.kind, e.message, frame, SUBCOMMAND_DATA_OFFSET - 1)
+                })?;
+
+                Ok(None)
+            }
+            Type::McuFirmwareUpdate | Type::RequestMcuUpdate => {
+                self.mcu.reject_update();
+
+                Ok(None)
+            }
+            Type::Rumble => Ok(None),
+        }
+    }
+
+    /// Returns if standard full input reports are streamed. Streaming only starts after the
+    /// device sets a streaming input report mode with subcommand 0x03.
+    pub fn is_streaming(&self) -> bool {
+        self.report_mode.is_streaming()
+    }
+
+    /// Pushes an input report of the given type immediately. Standard full input reports are
+    /// refused before streaming starts, simple HID input reports are refused after streaming
+    /// starts, and subcommand replies are only sent in response to output reports.
+    pub fn push_report(&mut self, t: InputType) -> Result<OutgoingFrame> {
+        let expected = match self.report_mode {
+            ReportMode::LowPower | ReportMode::StandardFull => InputType::StandardFull,
+            ReportMode::NfcIr => InputType::NfcIr,
+            ReportMode::SimpleHid => InputType::SimpleHid,
+        };
+        if t != expected {
+            return Err(Error::new(
+                ErrorKind::Protocol,
+                format!(
+                    "cannot push input report 0x{:02x} in input report mode {}",
+                    t as u8, self.report_mode
+                ),
+            ));
+        }
+
+        Ok(OutgoingFrame::new(self.input_report()))
+    }
+
+    /// Pushes an input report of the current input report mode immediately.
+    pub fn push_current_report(&mut self) -> OutgoingFrame {
+        OutgoingFrame::new(self.input_report())
+    }
+
+    fn state_changed(&mut self) {
+        self.input_changed = true;
+        // Simple HID input reports are event-driven
+        if self.report_mode == ReportMode::SimpleHid {
+            let report = self.simple_hid_input();
+            self.pending_reports.push_back(report);
+        }
+    }
+
+    fn input_report(&mut self) -> Vec<u8> {
+        match self.report_mode {
+            ReportMode::LowPower | ReportMode::StandardFull => {
+                self.standard_input(InputType::StandardFull, STANDARD_INPUT_LEN)
+            }
+            ReportMode::NfcIr => {
+                let mut buf = self.standard_input(InputType::NfcIr, NFC_IR_INPUT_LEN);
+                buf[STANDARD_INPUT_LEN..].copy_from_slice(&self.mcu.report());
+                if self.mcu.take_tag_read() {
+                    self.push_event(SwitchEvent::AmiiboRead);
+                }
+
+                buf
+            }
+            ReportMode::SimpleHid => self.simple_hid_input(),
+        }
+    }
+
+    fn handle_rumble(&mut self, output: &Output, now: Instant) {
+        let frame = RumbleFrame {
+            left: MotorState::decode(output.left_rumble.to_be_bytes()),
+            right: MotorState::decode(output.right_rumble.to_be_bytes()),
+            timestamp: now,
+        };
+
+        // Rumble starts idle, and returning to idle is only recorded once
+        self.stats.rumble_frames += 1;
+        let epsilon = self.rumble_epsilon;
+        let event = match &self.rumble {
+            Some(rumble) if !frame.differs(rumble, epsilon) => None,
+            Some(_) if frame.is_idle(epsilon) => Some(RumbleEvent::Stopped(now)),
+            None if frame.is_idle(epsilon) => None,
+            _ => Some(RumbleEvent::Changed(frame)),
+        };
+        match event {
+            Some(event) => {
+                self.rumble = match event {
+                    RumbleEvent::Changed(frame) => Some(frame),
+                    RumbleEvent::Stopped(_) => None,
+                };
+                self.push_event(SwitchEvent::Rumble(event));
+            }
+            None => self.stats.suppressed_rumble_frames += 1,
+        }
+    }
+
+    fn mark_reported(&mut self) {
+        for release in self.releases.iter_mut() {
+            release.reported = true;
+        }
+    }
+
+    fn release_due(&mut self, now: Instant) {
+        let due: Vec<_> = self
+            .releases
+            .iter()
+            .filter(|release| release.reported && release.at <= now)
+            .map(|release| (release.button, false))
+            .collect();
+        if !due.is_empty() {
+            // Scheduled buttons are always available
+            if let Err(e) = self.set_buttons(&due) {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    fn advance_turbos(&mut self, now: Instant) {
+        let base = self.tick_base(now);
+        let interval = self
+            .report_interval()
+            .filter(|interval| !interval.is_zero());
+        let controller_type = self.controller_type;
+
+        let previous = self.buttons;
+        let mut turbos = std::mem::take(&mut self.turbos);
+        turbos.retain(|turbo| {
+            let available = controller_type.has_button(turbo.button);
+            if !available {
+                warn!("Stop turbo of unavailable {}", turbo.button);
+            }
+
+            available
+        });
+        for turbo in turbos.iter_mut() {
+            match turbo.phase_end {
+                Some(phase_end) if now < phase_end => continue,
+                _ => {}
+            }
+
+            // Each phase is a half of the period
+            let phase = Duration::from_secs_f32(0.5 / turbo.rate);
+            let phase = match interval {
+                Some(interval) => {
+                    let ticks = (phase.as_secs_f32() / interval.as_secs_f32()).round() as u32;
+                    interval * ticks.max(1)
+                }
+                None => phase,
+            };
+            turbo.phase_end = Some(base + phase);
+            let pressed = self.buttons.is_pressed(turbo.button);
+            self.buttons.set(turbo.button, !pressed);
+        }
+        self.turbos = turbos;
+        if previous != self.buttons {
+            self.state_changed();
+        }
+    }
+
+    /// Returns the time the report due now is scheduled at, which steps align to.
+    fn tick_base(&self, now: Instant) -> Instant {
+        match self.next_report {
+            Some(next_report) if next_report <= now && self.report_interval().is_some() => {
+                next_report
+            }
+            _ => now,
+        }
+    }
+
+    fn advance_macro(&mut self, now: Instant) {
+        // Steps take effect on the report due now, and end on report boundaries
+        let base = self.tick_base(now);
+        while let Some(playback) = &mut self.playback {
+            let (index, step_end) = (playback.index, playback.step_end);
+            match step_end {
+                Some(step_end) if now < step_end => return,
+                Some(_) => {
+                    playback.index += 1;
+                    playback.step_end = None;
+                    if let Some(step) = playback.steps.get(index).cloned() {
+                        self.end_step(&step);
+                    }
+                }
+                None => {
+                    let steps = playback.steps.len();
+                    let step = match playback.steps.get(index).cloned() {
+                        Some(step) => step,
+                        None => {
+                            self.playback = None;
+                            self.push_event(SwitchEvent::Macro(MacroEvent::Finished));
+
+                            return;
+                        }
+                    };
+                    let duration = self.align_duration(&step);
+                    if let Some(playback) = &mut self.playback {
+                        playback.step_end = Some(base + duration);
+                    }
+                    self.start_step(&step);
+                    self.push_event(SwitchEvent::Macro(MacroEvent::Step { index, steps }));
+                }
+            }
+        }
+    }
+
+    fn align_duration(&self, step: &MacroStep) -> Duration {
+        let duration = step.duration();
+        match self.report_interval() {
+            Some(interval) if !interval.is_zero() => {
+                let mut ticks = duration.as_nanos().div_ceil(interval.as_nanos()) as u32;
+                if let MacroStep::Hold { .. } = step {
+                    ticks = ticks.max(1);
+                }
+
+                interval * ticks
+            }
+            _ => duration,
+        }
+    }
+
+    fn start_step(&mut self, step: &MacroStep) {
+        // Inputs are validated when the macro is played
+        let result = match step {
+            MacroStep::Hold {
+                buttons, sticks, ..
+            } => {
+                let buttons: Vec<_> = buttons.iter().map(|button| (*button, true)).collect();
+                let sticks: Vec<_> = sticks
+                    .iter()
+                    .map(|(stick, x, y)| (*stick, self.stick_config.position(*x, *y)))
+                    .collect();
+                self.set_buttons(&buttons)
+                    .and_then(|_| self.set_sticks(&sticks))
+            }
+            MacroStep::Wait { .. } => Ok(()),
+            MacroStep::ReleaseAll => {
+                let buttons: Vec<_> = self
+                    .buttons
+                    .pressed()
+                    .into_iter()
+                    .map(|button| (button, false))
+                    .collect();
+                let center = self.stick_config.position(0.0, 0.0);
+                let sticks: Vec<_> = [Stick::Left, Stick::Right]
+                    .into_iter()
+                    .filter(|stick| self.controller_type.has_stick(*stick))
+                    .map(|stick| (stick, center))
+                    .collect();
+                self.set_buttons(&buttons)
+                    .and_then(|_| self.set_sticks(&sticks))
+            }
+        };
+        if let Err(e) = result {
+            warn!("{}", e);
+        }
+    }
+
+    fn end_step(&mut self, step: &MacroStep) {
+        if let MacroStep::Hold {
+            buttons, sticks, ..
+        } = step
+        {
+            let buttons: Vec<_> = buttons.iter().map(|button| (*button, false)).collect();
+            let center = self.stick_config.position(0.0, 0.0);
+            let sticks: Vec<_> = sticks
+                .iter()
+                .map(|(stick, _, _)| (*stick, center))
+                .collect();
+            if let Err(e) = self
+                .set_buttons(&buttons)
+                .and_then(|_| self.set_sticks(&sticks))
+            {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    fn push_event(&mut self, event: SwitchEvent) {
+        if self.events.len() >= EVENTS_CAPACITY {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    fn handle_subcommand(&mut self, frame: &[u8], subcommand: u8, data: &[u8]) -> Result<Vec<u8>> {
+        let s = match Subcommand::try_from(subcommand) {
+            Ok(s) => s,
+            Err(_) => {
+                warn!("Unknown subcommand 0x{:02x}", subcommand);
+
+                return Ok(self.subcommand_reply(0x80, subcommand, &[]));
+            }
+        };
+        debug!("subcommand {:?}", s);
+
+        match s {
+            Subcommand::SetInputReportMode => {
+                let mode = first(frame, data, "missing input report mode")?;
+                self.report_mode = ReportMode::try_from(mode).map_err(|e| {
+                    Error::with_dump(e.kind, e.message, frame, SUBCOMMAND_DATA_OFFSET)
+                })?;
+                debug!("input report mode {}", self.report_mode);
+                if self.report_mode.is_streaming() {
+                    self.pending_reports.clear();
+                }
+
+                Ok(self.subcommand_reply(0x80, subcommand, &[]))
+            }
+            Subcommand::RequestDeviceInfo => {
+                let info = self.device_info.to_bytes(self.address);
+                if self.handshake.is_some() {
+                    self.push_event(SwitchEvent::Pairing(PairingEvent::DeviceInfoSent));
+                }
+
+                Ok(self.subcommand_reply(0x82, subcommand, &info))
+            }
+            Subcommand::BluetoothManualPairing => {
+                Ok(self.subcommand_reply(0x81, subcommand, &[0x03]))
+            }
+            Subcommand::SetShipmentLowPowerState => {
+                Ok(self.subcommand_reply(0x80, subcommand, &[]))
+            }
+            Subcommand::SpiFlashRead => {
+                let (addr, len) = spi_range(frame, data)?;
+                let mut reply = addr.to_le_bytes().to_vec();
+                reply.push(len);
+                reply.extend_from_slice(self.spi_flash.read(addr, len).map_err(|e| {
+                    Error::with_dump(e.kind, e.message, frame, SUBCOMMAND_DATA_OFFSET)
+                })?);
+
+                Ok(self.subcommand_reply(0x90, subcommand, &reply))
+            }
+            Subcommand::SpiFlashWrite => {
+                let (addr, len) = spi_range(frame, data)?;
+                let content = data.get(5..5 + len as usize).ok_or_else(|| {
+                    Error::with_dump(
+                        ErrorKind::Protocol,
+                        "missing SPI flash data".into(),
+                        frame,
+                        frame.len(),
+                    )
+                })?;
+                let status = match self.spi_flash.write(addr, content) {
+                    Ok(_) => {
+                        let start = addr as usize;
+                        self.spi_writes.push((start, start + content.len()));
+
+                        0x00
+                    }
+                    Err(e) => {
+                        warn!("{}", e);
+
+                        0x01
+                    }
+                };
+
+                Ok(self.subcommand_reply(0x80, subcommand, &[status]))
+            }
+            Subcommand::SpiSectorErase => {
+                let addr = match *data {
+                    [a, b, c, d, ..] => u32::from_le_bytes([a, b, c, d]),
+                    _ => {
+                        return Err(Error::with_dump(
+                            ErrorKind::Protocol,
+                            "missing SPI flash address".into(),
+                            frame,
+                            SUBCOMMAND_DATA_OFFSET,
+                        ))
+                    }
+                };
+                let status = match self.spi_flash.erase_sector(addr) {
+                    Ok(_) => {
+                        let start = addr as usize - addr as usize % SECTOR_SIZE;
+                        self.spi_writes.push((start, start + SECTOR_SIZE));
+
+                        0x00
+                    }
+                    Err(e) => {
+                        warn!("{}", e);
+
+                        0x01
+                    }
+                };
+
+                Ok(self.subcommand_reply(0x80, subcommand, &[status]))
+            }
+            Subcommand::SetPlayerLights => {
+                let lights = first(frame, data, "missing player lights")?;
+                debug!("player lights 0x{:02x}", lights);
+                if lights != self.player_lights {
+                    self.player_lights = lights;
+                    self.push_event(SwitchEvent::PlayerLights(PlayerLights::new(lights)));
+                }
+                if let Some(player) = PlayerLights::new(lights).player() {
+                    if let Some(device) = self.handshake.take() {
+                        self.release_initial_buttons();
+                        self.push_event(SwitchEvent::Pairing(PairingEvent::PlayerAssigned(player)));
+                        self.push_event(SwitchEvent::Pairing(PairingEvent::PairingComplete(
+                            device,
+                        )));
+                    }
+                }
+
+                Ok(self.subcommand_reply(0x80, subcommand, &[]))
+            }
+            Subcommand::GetPlayerLights => {
+                let lights = self.player_lights;
+
+                Ok(self.subcommand_reply(0xB0, subcommand, &[lights]))
+            }
+            Subcommand::SetHomeLight => {
+                let home_light: Vec<u8> = data.iter().take(25).copied().collect();
+                if home_light != self.home_light {
+                    self.home_light = home_light.clone();
+                    self.push_event(SwitchEvent::HomeLight(HomeLightPattern::new(home_light)));
+                }
+
+                Ok(self.subcommand_reply(0x80, subcommand, &[]))
+            }
+            Subcommand::EnableImu => {
+                self.imu_enabled = first(frame, data, "missing IMU state")? != 0x00;
+                debug!("IMU enabled {}", self.imu_enabled);
+
+                Ok(self.subcommand_reply(0x80, subcommand, &[]))
+            }
+            Subcommand::SetImuSensitivity => {
+                self.imu_sensitivity = ImuSensitivity::from_setting(data).map_err(|e| {
+                    Error::with_dump(e.kind, e.message, frame, SUBCOMMAND_DATA_OFFSET)
+                })?;
+                debug!("IMU sensitivity {}", self.imu_sensitivity);
+
+                Ok(self.subcommand_reply(0x80, subcommand, &[]))
+            }
+            Subcommand::WriteImuRegisters => {
+                let (addr, value) = match *data {
+                    [0x01, addr, value, ..] => (addr, value),
+                    _ => {
+                        return Err(Error::with_dump(
+                            ErrorKind::Protocol,
+                            "invalid IMU register write".into(),
+                            frame,
+                            SUBCOMMAND_DATA_OFFSET,
+                        ))
+                    }
+                };
+                self.imu_sensitivity.write_register(addr, value);
+                debug!(
+                    "IMU register 0x{:02x} 0x{:02x}, sensitivity {}",
+                    addr, value, self.imu_sensitivity
+                );
+
+                Ok(self.subcommand_reply(0x80, subcommand, &[]))
+            }
+            Subcommand::ReadImuRegisters => {
+                let (addr, count) = match *data {
+                    [addr, count, ..] => (addr, count),
+                    _ => {
+                        return Err(Error::with_dump(
+                            ErrorKind::Protocol,
+                            "missing IMU register range".into(),
+                            frame,
+                            SUBCOMMAND_DATA_OFFSET,
+                        ))
+                    }
+                };
+                let mut reply = vec![addr, count];
+                reply.extend(
+                    (0..count.min(0x20))
+                        .map(|i| self.imu_sensitivity.read_register(addr.wrapping_add(i))),
+                );
+
+                Ok(self.subcommand_reply(0xC0, subcommand, &reply))
+            }
+            Subcommand::EnableVibration => {
+                let enabled = first(frame, data, "missing vibration state")? != 0x00;
+                debug!("vibration enabled {}", enabled);
+                if enabled != self.vibration_enabled {
+                    self.vibration_enabled = enabled;
+                    self.push_event(SwitchEvent::VibrationToggled(enabled));
+                }
+
+                Ok(self.subcommand_reply(0x80, subcommand, &[]))
+            }
+            Subcommand::TriggerButtonsElapsedTime => {
+                let mut elapsed = [0u8; 14];
+                if self.firmware_profile.counts_trigger_buttons_elapsed_time() {
+                    for (i, time) in self.trigger_elapsed_time.iter().enumerate() {
+                        elapsed[i * 2..i * 2 + 2].copy_from_slice(&time.to_le_bytes());
+                    }
+                }
+
+                Ok(self.subcommand_reply(0x83, subcommand, &elapsed))
+            }
+            Subcommand::ResetNfcIrMcu => {
+                self.mcu.reset();
+
+                Ok(self.subcommand_reply(0x80, subcommand, &[]))
+            }
+            Subcommand::SetNfcIrMcuConfiguration => {
+                let reply = self.mcu.configure(data);
+
+                Ok(self.subcommand_reply(0xA0, subcommand, &reply))
+            }
+            Subcommand::SetNfcIrMcuState => {
+                let state = first(frame, data, "missing MCU state")?;
+                if !self.mcu.set_state(state) {
+                    warn!("Unknown MCU state 0x{:02x}", state);
+                }
+
+                Ok(self.subcommand_reply(0x80, subcommand, &[]))
+            }
+            Subcommand::SetHciState => {
+                let state = first(frame, data, "missing HCI state")?;
+                let state = HciState::try_from(state).map_err(|e| {
+                    Error::with_dump(e.kind, e.message, frame, SUBCOMMAND_DATA_OFFSET)
+                })?;
+                debug!("HCI state {}", state);
+                self.hci_state = Some(state);
+                self.push_event(SwitchEvent::HciState(state));
+
+                Ok(self.subcommand_reply(0x80, subcommand, &[]))
+            }
+            Subcommand::ResetPairingInfo => {
+                debug!("reset pairing info");
+                self.pairing_reset = true;
+                self.push_event(SwitchEvent::PairingReset);
+
+                Ok(self.subcommand_reply(0x80, subcommand, &[]))
+            }
+            Subcommand::GetRegulatedVoltage => {
+                let voltage = self.battery.regulated_voltage(self.charging());
+
+                Ok(self.subcommand_reply(0xD0, subcommand, &voltage.to_le_bytes()))
+            }
+        }
+    }
+
+    fn standard_input(&mut self, t: InputType, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        self.mark_reported();
+
+        buf[0] = Direction::Input as u8;
+        buf[1] = t as u8;
+        buf[2] = self.timer;
+        self.timer = self.timer.wrapping_add(1);
+
+        // Battery and connection info
+        buf[3] = self.battery_connection();
+
+        // Buttons
+        buf[4..7].copy_from_slice(&self.buttons.encode(self.controller_type));
+        if self.grip {
+            buf[5] |= 0x80;
+        }
+
+        // Sticks
+        for (stick, offset) in [(Stick::Left, 7), (Stick::Right, 10)] {
+            let bytes = match (self.controller_type, stick) {
+                // C buttons of the Nintendo 64 controller are reported as the right stick
+                (ControllerType::N64Controller, Stick::Right) => self.buttons.c_buttons().encode(),
+                _ if self.controller_type.has_stick(stick) => self.stick(stick).encode(),
+                _ => ABSENT_STICK,
+            };
+            buf[offset..offset + 3].copy_from_slice(&bytes);
+        }
+
+        // Vibrator input report
+        buf[13] = 0x80;
+
+        // IMU
+        if self.imu_enabled && self.controller_type.has_imu() && t != InputType::SubcommandReply {
+            let calibration = self.spi_flash.imu_calibration();
+            for (i, sample) in self.take_motion_samples().iter().enumerate() {
+                let offset = 14 + i * IMU_SAMPLE_LEN;
+                buf[offset..offset + IMU_SAMPLE_LEN]
+                    .copy_from_slice(&sample.encode(self.imu_sensitivity, &calibration));
+            }
+        }
+
+        buf
+    }
+
+    /// Returns the motion samples of an input report from the oldest, which are evenly picked
+    /// from the samples set since the last input report.
+    fn take_motion_samples(&mut self) -> [MotionSample; IMU_SAMPLES] {
+        let samples: Vec<_> = self.motion_samples.drain(..).collect();
+        let mut picked = [self.motion; IMU_SAMPLES];
+        match samples.len() {
+            0 => {}
+            n if n <= IMU_SAMPLES => picked[..n].copy_from_slice(&samples),
+            n => {
+                for (i, sample) in picked.iter_mut().enumerate() {
+                    *sample = samples[i * (n - 1) / (IMU_SAMPLES - 1)];
+                }
+            }
+        }
+
+        picked
+    }
+
+    fn simple_hid_input(&mut self) -> Vec<u8> {
+        let mut buf = vec![0u8; SIMPLE_HID_INPUT_LEN];
+        self.mark_reported();
+
+        buf[0] = Direction::Input as u8;
+        buf[1] = InputType::SimpleHid as u8;
+
+        // Buttons
+        buf[2..4].copy_from_slice(&self.buttons.to_simple_hid(self.controller_type));
+
+        // Hat and sticks
+        let mut axes = [0x8000u16; 4];
+        buf[4] = match self.controller_type {
+            // Single Joy-Cons are held sideways and report the stick as the hat
+            ControllerType::JoyConL => self.left_stick.rotate_counterclockwise().to_hat(),
+            ControllerType::JoyConR => self.right_stick.rotate_clockwise().to_hat(),
+            ControllerType::ProController => {
+                axes[..2].copy_from_slice(&self.left_stick.to_simple_hid());
+                axes[2..].copy_from_slice(&self.right_stick.to_simple_hid());
+
+                self.buttons.dpad().to_hat()
+            }
+            ControllerType::N64Controller => {
+                axes[..2].copy_from_slice(&self.left_stick.to_simple_hid());
+                axes[2..].copy_from_slice(&self.buttons.c_buttons().to_simple_hid());
+
+                self.buttons.dpad().to_hat()
+            }
+            ControllerType::NesController | ControllerType::SnesController => {
+                self.buttons.dpad().to_hat()
+            }
+        };
+        for (i, axis) in axes.iter().enumerate() {
+            buf[5 + i * 2..7 + i * 2].copy_from_slice(&axis.to_le_bytes());
+        }
+
+        buf
+    }
+
+    fn battery_connection(&self) -> u8 {
+        let battery = (self.battery as u8) << 4 | if self.charging() { 0x10 } else { 0x00 };
+
+        // Joy-Cons in the charging grip are powered and reported as the Pro Controller
+        if self.grip {
+            return battery | 0x01;
+        }
+
+        match self.controller_type {
+            ControllerType::JoyConL | ControllerType::JoyConR => battery | 0x0E,
+            ControllerType::ProController
+            | ControllerType::NesController
+            | ControllerType::SnesController
+            | ControllerType::N64Controller => battery,
+        }
+    }
+
+    fn subcommand_reply(&mut self, ack: u8, subcommand: u8, data: &[u8]) -> Vec<u8> {
+        let mut buf = self.standard_input(InputType::SubcommandReply, STANDARD_INPUT_LEN);
+
+        buf[14] = ack;
+        buf[15] = subcommand;
+        let len = data.len().min(SUBCOMMAND_REPLY_DATA_LEN);
+        buf[16..16 + len].copy_from_slice(&data[..len]);
+
+        buf
+    }
+}
+
+/// Returns the first byte of the subcommand data.
+fn first(frame: &[u8], data: &[u8], message: &str) -> Result<u8> {
+    data.first().copied().ok_or_else(|| {
+        Error::with_dump(
+            ErrorKind::Protocol,
+            message.into(),
+            frame,
+            SUBCOMMAND_DATA_OFFSET,
+        )
+    })
+}
+
+/// Returns the address and length of a SPI flash subcommand.
+fn spi_range(frame: &[u8], data: &[u8]) -> Result<(u32, u8)> {
+    match *data {
+        [a, b, c, d, len, ..] => Ok((u32::from_le_bytes([a, b, c, d]), len)),
+        _ => Err(Error::with_dump(
+            ErrorKind::Protocol,
+            "missing SPI flash range".into(),
+            frame,
+            SUBCOMMAND_DATA_OFFSET,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use input::{HAT_NEUTRAL, STICK_MAX};
+    use proptest::prelude::*;
+    use spi::{FACTORY_LEFT_STICK_CALIBRATION, FACTORY_RIGHT_STICK_CALIBRATION};
+
+    /// Neutral rumble data of a motor.
+    const NEUTRAL_RUMBLE: [u8; 4] = [0x00, 0x01, 0x40, 0x40];
+
+    /// Returns an output report carrying the given subcommand.
+    fn subcommand(id: Subcommand, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![Direction::Output as u8, Type::Subcommand as u8, 0x00];
+        frame.extend_from_slice(&NEUTRAL_RUMBLE);
+        frame.extend_from_slice(&NEUTRAL_RUMBLE);
+        frame.push(id as u8);
+        frame.extend_from_slice(data);
+        frame.resize(49, 0x00);
+
+        frame
+    }
+
+    /// Returns an output report carrying only the given rumble data.
+    fn rumble(left: [u8; 4], right: [u8; 4]) -> Vec<u8> {
+        let mut frame = vec![Direction::Output as u8, Type::Rumble as u8, 0x00];
+        frame.extend_from_slice(&left);
+        frame.extend_from_slice(&right);
+
+        frame
+    }
+
+    /// Returns a device paired with.
+    fn device() -> PairedDevice {
+        PairedDevice {
+            address: Address::new([0x98, 0xB6, 0xE9, 0x00, 0x00, 0x01]),
+            name: Some("Nintendo Switch".into()),
+            alias: None,
+            adapter: "hci0".into(),
+            paired_at: std::time::SystemTime::UNIX_EPOCH,
+            removed_previous: false,
+        }
+    }
+
+    /// Completes the pairing handshake of the given protocol, which ends streaming standard full
+    /// input reports.
+    fn pair(protocol: &mut Protocol, now: Instant) {
+        protocol.start_handshake(device(), now).unwrap();
+        for (id, data) in [
+            (Subcommand::RequestDeviceInfo, &[][..]),
+            (Subcommand::SetShipmentLowPowerState, &[0x00]),
+            (
+                Subcommand::SetInputReportMode,
+                &[ReportMode::StandardFull as u8],
+            ),
+            (Subcommand::EnableImu, &[0x01]),
+            (Subcommand::EnableVibration, &[0x01]),
+            (Subcommand::SetPlayerLights, &[0x01]),
+        ] {
+            protocol
+                .handle_incoming(&subcommand(id, data), now)
+                .unwrap();
+        }
+        assert!(!protocol.is_handshaking());
+    }
+
+    /// Returns a protocol in the given input report mode.
+    fn protocol_in(mode: ReportMode) -> Protocol {
+        let mut protocol = Protocol::new(ControllerType::ProController);
+        protocol
+            .handle_output(
+                &subcommand(Subcommand::SetInputReportMode, &[mode as u8]),
+                Instant::now(),
+            )
+            .unwrap();
+        assert_eq!(protocol.report_mode(), mode);
+
+        protocol
+    }
+
+    #[test]
+    fn report_interval_of_report_modes() {
+        let cases = [
+            (ReportMode::LowPower, Some(Duration::from_micros(30_000))),
+            (
+                ReportMode::StandardFull,
+                Some(Duration::from_micros(16_667)),
+            ),
+            (ReportMode::NfcIr, Some(Duration::from_micros(16_667))),
+            (ReportMode::SimpleHid, None),
+        ];
+        for (mode, interval) in cases {
+            assert_eq!(protocol_in(mode).report_interval(), interval, "{}", mode);
+        }
+    }
+
+    #[test]
+    fn report_interval_follows_report_rate() {
+        let mut protocol = protocol_in(ReportMode::LowPower);
+        protocol.set_report_rate(ReportRate::Hz120, Instant::now());
+        assert_eq!(
+            protocol.report_interval(),
+            Some(Duration::from_micros(8_333))
+        );
+
+        let mut protocol = protocol_in(ReportMode::SimpleHid);
+        protocol.set_report_rate(ReportRate::Hz120, Instant::now());
+        assert_eq!(protocol.report_interval(), None);
+    }
+
+    #[test]
+    fn default_device_info_bytes() {
+        let address = Address::new([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        let cases = [
+            (ControllerType::JoyConL, 0x01),
+            (ControllerType::JoyConR, 0x02),
+            (ControllerType::ProController, 0x03),
+        ];
+        for (controller_type, id) in cases {
+            assert_eq!(
+                DeviceInfo::new(controller_type).to_bytes(address),
+                [0x03, 0x8B, id, 0x02, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x01, 0x01],
+                "{}",
+                controller_type
+            );
+        }
+    }
+
+    #[test]
+    fn device_info_reply() {
+        let address = Address::new([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        let mut protocol = Protocol::new(ControllerType::ProController);
+        protocol.set_address(address);
+        let reply = protocol
+            .handle_output(
+                &subcommand(Subcommand::RequestDeviceInfo, &[]),
+                Instant::now(),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(&reply[14..16], &[0x82, 0x02]);
+        assert_eq!(
+            &reply[16..28],
+            &DeviceInfo::new(ControllerType::ProController).to_bytes(address)
+        );
+
+        // Overrides replace only the overridden bytes
+        protocol.set_device_info(DeviceInfo {
+            controller_type: 0x0B,
+            spi_colors: 0x02,
+            ..protocol.device_info()
+        });
+        let reply = protocol
+            .handle_output(
+                &subcommand(Subcommand::RequestDeviceInfo, &[]),
+                Instant::now(),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            &reply[16..28],
+            &[0x03, 0x8B, 0x0B, 0x02, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x01, 0x02]
+        );
+    }
+
+    #[test]
+    fn reset_clears_connection_state() {
+        let now = Instant::now();
+        let mut spi_flash = SpiFlash::new(ControllerType::ProController);
+        let colors = Colors {
+            body: [0x32, 0x32, 0x32],
+            buttons: [0xFF, 0xFF, 0xFF],
+            left_grip: [0x0A, 0xB9, 0xE6],
+            right_grip: [0xFF, 0x3C, 0x28],
+        };
+        spi_flash.set_colors(colors);
+        let initial_spi_flash = spi_flash.clone();
+
+        for hard in [false, true] {
+            let mut protocol =
+                Protocol::with_spi_flash(ControllerType::ProController, spi_flash.clone());
+            pair(&mut protocol, now);
+            protocol
+                .handle_incoming(
+                    &subcommand(
+                        Subcommand::SpiFlashWrite,
+                        &[0x10, 0x80, 0x00, 0x00, 0x02, 0xB2, 0xA1],
+                    ),
+                    now,
+                )
+                .unwrap();
+            let written_spi_flash = protocol.spi_flash().clone();
+            assert_ne!(written_spi_flash, initial_spi_flash);
+
+            protocol.set_button(Button::B, true).unwrap();
+            protocol
+                .press_for(Button::A, Duration::from_secs(1), now)
+                .unwrap();
+            protocol.set_turbo(Button::X, Some(10.0)).unwrap();
+            protocol
+                .set_sticks(&[
+                    (Stick::Left, StickPosition::new(0x000, 0xFFF)),
+                    (Stick::Right, StickPosition::new(0xFFF, 0x000)),
+                ])
+                .unwrap();
+            protocol.start_recording();
+            protocol.set_motion(MotionSample::new([0.0, 0.0, 1.0], [100.0, 0.0, 0.0]));
+            protocol.set_rumble_epsilon(0.5);
+            protocol
+                .schedule(ReportTick(10).into(), ControllerInput::new(), now)
+                .unwrap();
+            protocol.poll_tick(now).unwrap();
+
+            match hard {
+                false => protocol.reset(),
+                true => protocol.hard_reset(),
+            }
+
+            assert_eq!(protocol.buttons(), ButtonState::new());
+            assert_eq!(protocol.stick(Stick::Left), StickPosition::default());
+            assert_eq!(protocol.stick(Stick::Right), StickPosition::default());
+            assert!(protocol.stop_recording().is_none());
+            assert_eq!(protocol.motion(), MotionSample::default());
+            assert_eq!(protocol.report_mode(), ReportMode::SimpleHid);
+            assert_eq!(protocol.current_tick(), ReportTick(0));
+            assert!(!protocol.has_scheduled());
+            assert!(!protocol.imu_enabled());
+            assert!(!protocol.vibration_enabled());
+            assert_eq!(protocol.player_lights(), 0);
+            assert!(!protocol.is_handshaking());
+            assert!(protocol.take_events().is_empty());
+
+            // The configuration is kept, where the turbo restarts and the release is gone
+            assert_eq!(protocol.turbo(Button::X), Some(10.0));
+            assert_eq!(protocol.rumble_epsilon(), 0.5);
+            protocol.poll_tick(now + Duration::from_secs(2));
+            assert_eq!(protocol.buttons().pressed(), vec![Button::X]);
+
+            let expected = match hard {
+                false => &written_spi_flash,
+                true => &initial_spi_flash,
+            };
+            assert_eq!(protocol.spi_flash(), expected);
+            assert_eq!(protocol.colors(), colors);
+            assert_eq!(protocol.controller_type(), ControllerType::ProController);
+        }
+    }
+
+    #[test]
+    fn tick_estimation_follows_given_time() {
+        let now = Instant::now();
+        let interval = Duration::from_micros(16_667);
+        let mut protocol = protocol_in(ReportMode::StandardFull);
+
+        // Before streaming starts, the next input report is due at the given time
+        for base in [now, now + Duration::from_secs(5)] {
+            assert_eq!(
+                protocol.tick_time(ReportTick(60), base),
+                Some(base + interval * 60)
+            );
+            assert_eq!(
+                protocol.time_tick(base + Duration::from_secs(1), base),
+                Some(ReportTick(60))
+            );
+            assert_eq!(protocol.next_tick(base), Some(base));
+        }
+
+        // After streaming starts, the cadence ignores the given time
+        protocol.poll_tick(now).unwrap();
+        let later = now + Duration::from_secs(5);
+        assert_eq!(
+            protocol.tick_time(ReportTick(1), later),
+            Some(now + interval)
+        );
+        assert_eq!(protocol.tick_time(ReportTick(0), later), None);
+        assert_eq!(protocol.time_tick(now, later), Some(ReportTick(1)));
+        assert_eq!(protocol.next_tick(later), Some(now + interval));
+    }
+
+    #[test]
+    fn report_rate_change_follows_given_time() {
+        let now = Instant::now();
+        let mut protocol = protocol_in(ReportMode::StandardFull);
+        protocol.poll_tick(now).unwrap();
+
+        protocol.set_report_rate(ReportRate::Hz120, now);
+        let next = now + Duration::from_micros(8_333);
+        assert_eq!(protocol.next_tick(now), Some(next));
+        assert_eq!(protocol.poll_tick(next - Duration::from_micros(1)), None);
+        assert!(protocol.poll_tick(next).is_some());
+        assert_eq!(
+            protocol.next_tick(next),
+            Some(next + Duration::from_micros(8_333))
+        );
+    }
+
+    #[test]
+    fn pending_reports_are_due_at_given_time() {
+        let now = Instant::now();
+        let mut protocol = Protocol::new(ControllerType::ProController);
+        assert_eq!(protocol.next_tick(now), None);
+
+        protocol.set_button(Button::A, true).unwrap();
+        assert_eq!(protocol.next_tick(now), Some(now));
+        assert!(protocol.poll_tick(now).is_some());
+        assert_eq!(protocol.next_tick(now), None);
+    }
+
+    #[test]
+    fn output_is_handled_at_given_time() {
+        let now = Instant::now();
+        let mut protocol = Protocol::new(ControllerType::ProController);
+        let received = now + Duration::from_secs(1);
+        let reply = protocol
+            .handle_output(&rumble([0x28, 0xC8, 0x81, 0x71], NEUTRAL_RUMBLE), received)
+            .unwrap();
+        assert_eq!(reply, None);
+
+        match protocol.take_events().as_slice() {
+            [SwitchEvent::Rumble(RumbleEvent::Changed(frame))] => {
+                assert_eq!(frame.timestamp, received)
+            }
+            events => panic!("unexpected events {:?}", events),
+        }
+    }
+
+    #[test]
+    fn absent_stick_bytes() {
+        let position = StickPosition::new(0x123, 0xABC);
+        let cases = [
+            (ControllerType::JoyConL, Some(Stick::Left)),
+            (ControllerType::JoyConR, Some(Stick::Right)),
+            (ControllerType::ProController, None),
+        ];
+        for (controller_type, single) in cases {
+            let mut protocol = Protocol::new(controller_type);
+            for stick in [Stick::Left, Stick::Right] {
+                let result = protocol.set_stick(stick, position);
+                match single {
+                    Some(single) if single != stick => {
+                        assert!(matches!(
+                            result,
+                            Err(Error {
+                                kind: ErrorKind::Unavailable,
+                                ..
+                            })
+                        ))
+                    }
+                    _ => result.unwrap(),
+                }
+            }
+            protocol
+                .handle_output(
+                    &subcommand(Subcommand::SetInputReportMode, &[0x30]),
+                    Instant::now(),
+                )
+                .unwrap();
+
+            let report = protocol.push_report(InputType::StandardFull).unwrap().data;
+            for (stick, bytes) in [
+                (Stick::Left, &report[7..10]),
+                (Stick::Right, &report[10..13]),
+            ] {
+                let expected = match single {
+                    Some(single) if single != stick => ABSENT_STICK,
+                    _ => position.encode(),
+                };
+                assert_eq!(bytes, expected, "{} {}", controller_type, stick);
+            }
+        }
+    }
+
+    #[test]
+    fn simple_hid_stick_of_single_joy_cons() {
+        let up = StickPosition::new(0x800, 0xFFF);
+        let cases = [
+            (ControllerType::JoyConL, Stick::Left, 0x06),
+            (ControllerType::JoyConR, Stick::Right, 0x02),
+        ];
+        for (controller_type, stick, hat) in cases {
+            let mut protocol = Protocol::new(controller_type);
+            let report = protocol.push_current_report().data;
+            assert_eq!(report[4], HAT_NEUTRAL, "{}", controller_type);
+
+            protocol.set_stick(stick, up).unwrap();
+            let report = protocol.poll_tick(Instant::now()).unwrap().data;
+            assert_eq!(report.len(), SIMPLE_HID_INPUT_LEN);
+            assert_eq!(report[1], InputType::SimpleHid as u8);
+            assert_eq!(report[4], hat, "{}", controller_type);
+            // Single Joy-Cons report the stick only as the hat
+            assert!(report[5..]
+                .chunks(2)
+                .all(|axis| axis == 0x8000u16.to_le_bytes()));
+        }
+    }
+
+    #[test]
+    fn streaming_waits_for_report_mode() {
+        let now = Instant::now();
+        let mut protocol = Protocol::new(ControllerType::ProController);
+        protocol.start_handshake(device(), now).unwrap();
+
+        // The handshake arrives out of order, where the input report mode is set last
+        for (i, (id, data)) in [
+            (Subcommand::SetPlayerLights, &[0x01][..]),
+            (Subcommand::EnableImu, &[0x01]),
+            (Subcommand::SpiFlashRead, &[0x00, 0x60, 0x00, 0x00, 0x10]),
+            (Subcommand::RequestDeviceInfo, &[]),
+            (Subcommand::EnableVibration, &[0x01]),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let at = now + Duration::from_millis(100) * i as u32;
+            let frames = protocol.handle_incoming(&subcommand(id, data), at).unwrap();
+            assert_eq!(frames.len(), 1);
+            assert_eq!(frames[0].data[1], InputType::SubcommandReply as u8);
+            assert_eq!(protocol.poll_tick(at), None, "{:?}", id);
+            assert_eq!(protocol.next_tick(at), None, "{:?}", id);
+            assert!(!protocol.is_streaming());
+            assert!(protocol.push_report(InputType::StandardFull).is_err());
+        }
+
+        let at = now + Duration::from_secs(1);
+        protocol
+            .handle_incoming(&subcommand(Subcommand::SetInputReportMode, &[0x30]), at)
+            .unwrap();
+        assert!(protocol.is_streaming());
+        let report = protocol.poll_tick(at).unwrap();
+        assert_eq!(report.data[1], InputType::StandardFull as u8);
+        assert!(protocol.push_report(InputType::SimpleHid).is_err());
+
+        // Streaming stops when the device returns to simple HID mode
+        protocol
+            .handle_incoming(&subcommand(Subcommand::SetInputReportMode, &[0x3F]), at)
+            .unwrap();
+        assert_eq!(protocol.poll_tick(at + Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn subcommand_replies_are_delayed() {
+        let now = Instant::now();
+        let mut protocol = Protocol::new(ControllerType::ProController);
+        assert_eq!(protocol.reply_delay(), DEFAULT_REPLY_DELAY);
+
+        let mut frames = vec![];
+        for (i, id) in [
+            Subcommand::RequestDeviceInfo,
+            Subcommand::SetShipmentLowPowerState,
+            Subcommand::GetPlayerLights,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let at = now + Duration::from_millis(1) * i as u32;
+            frames.extend(
+                protocol
+                    .handle_incoming(&subcommand(id, &[0x00]), at)
+                    .unwrap(),
+            );
+        }
+        assert!(protocol
+            .handle_incoming(&rumble(NEUTRAL_RUMBLE, NEUTRAL_RUMBLE), now)
+            .unwrap()
+            .is_empty());
+
+        let replies: Vec<_> = frames
+            .iter()
+            .map(|frame| (frame.data[14], frame.data[15], frame.send_after))
+            .collect();
+        assert_eq!(
+            replies,
+            [
+                (0x82, 0x02, Some(now + Duration::from_millis(3))),
+                (0x80, 0x08, Some(now + Duration::from_millis(4))),
+                (0xB0, 0x31, Some(now + Duration::from_millis(5))),
+            ]
+        );
+        assert!(!frames[0].is_due(now + Duration::from_millis(2)));
+        assert!(frames[0].is_due(now + Duration::from_millis(3)));
+        // Replies carry the timer in the order they are built
+        assert_eq!(frames[0].data[2].wrapping_add(1), frames[1].data[2]);
+        assert_eq!(frames[1].data[2].wrapping_add(1), frames[2].data[2]);
+
+        protocol.set_reply_delay(Duration::ZERO);
+        let frames = protocol
+            .handle_incoming(&subcommand(Subcommand::RequestDeviceInfo, &[]), now)
+            .unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].send_after, None);
+        assert!(frames[0].is_due(now));
+    }
+
+    #[test]
+    fn rumble_frames_are_deduplicated() {
+        let now = Instant::now();
+        let mut protocol = Protocol::new(ControllerType::ProController);
+        let rumbling = [0x00, 0xC9, 0x40, 0x72];
+        let frames = [
+            // Neutral frames before any rumble are not recorded
+            rumble(NEUTRAL_RUMBLE, NEUTRAL_RUMBLE),
+            rumble(rumbling, NEUTRAL_RUMBLE),
+            rumble(rumbling, NEUTRAL_RUMBLE),
+            rumble(rumbling, NEUTRAL_RUMBLE),
+            rumble(NEUTRAL_RUMBLE, NEUTRAL_RUMBLE),
+            rumble(NEUTRAL_RUMBLE, NEUTRAL_RUMBLE),
+            // Changes of frequencies of idle motors are not recorded either
+            rumble([0xFC, 0x01, 0x7F, 0x40], NEUTRAL_RUMBLE),
+        ];
+        for (i, frame) in frames.iter().enumerate() {
+            let at = now + Duration::from_millis(15) * i as u32;
+            protocol.handle_output(frame, at).unwrap();
+        }
+
+        let events = protocol.take_events();
+        assert_eq!(events.len(), 2);
+        match &events[0] {
+            SwitchEvent::Rumble(RumbleEvent::Changed(frame)) => {
+                assert_eq!(frame.left, MotorState::decode(rumbling));
+                assert_eq!(frame.right, MotorState::default());
+                assert_eq!(frame.timestamp, now + Duration::from_millis(15));
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert_eq!(
+            events[1],
+            SwitchEvent::Rumble(RumbleEvent::Stopped(now + Duration::from_millis(60)))
+        );
+        assert_eq!(
+            protocol.stats(),
+            ProtocolStats {
+                rumble_frames: 7,
+                suppressed_rumble_frames: 5,
+            }
+        );
+        assert_eq!(protocol.rumble(), None);
+    }
+
+    #[test]
+    fn rumble_epsilon_suppresses_small_changes() {
+        let now = Instant::now();
+        let mut protocol = Protocol::new(ControllerType::ProController);
+        protocol.set_rumble_epsilon(0.1);
+        protocol
+            .handle_output(&rumble([0x00, 0x41, 0x40, 0x40], NEUTRAL_RUMBLE), now)
+            .unwrap();
+        protocol
+            .handle_output(&rumble([0x00, 0x45, 0x40, 0x40], NEUTRAL_RUMBLE), now)
+            .unwrap();
+        assert_eq!(protocol.take_events().len(), 1);
+
+        protocol
+            .handle_output(&rumble([0x00, 0x81, 0x40, 0x40], NEUTRAL_RUMBLE), now)
+            .unwrap();
+        assert_eq!(protocol.take_events().len(), 1);
+    }
+
+    /// Returns valid output reports of every type and subcommand.
+    fn valid_outputs() -> Vec<Vec<u8>> {
+        let mut outputs: Vec<_> = [
+            (Subcommand::BluetoothManualPairing, &[0x01][..]),
+            (Subcommand::RequestDeviceInfo, &[]),
+            (Subcommand::SetInputReportMode, &[0x30]),
+            (Subcommand::TriggerButtonsElapsedTime, &[]),
+            (Subcommand::SetHciState, &[0x04]),
+            (Subcommand::ResetPairingInfo, &[]),
+            (Subcommand::SetShipmentLowPowerState, &[0x00]),
+            (Subcommand::SpiFlashRead, &[0x00, 0x60, 0x00, 0x00, 0x10]),
+            (
+                Subcommand::SpiFlashWrite,
+                &[0x10, 0x80, 0x00, 0x00, 0x02, 0xB2, 0xA1],
+            ),
+            (Subcommand::SpiSectorErase, &[0x00, 0x80, 0x00, 0x00]),
+            (Subcommand::ResetNfcIrMcu, &[]),
+            (Subcommand::SetNfcIrMcuConfiguration, &[0x21, 0x00, 0x04]),
+            (Subcommand::SetNfcIrMcuState, &[0x01]),
+            (Subcommand::SetPlayerLights, &[0x01]),
+            (Subcommand::GetPlayerLights, &[]),
+            (Subcommand::SetHomeLight, &[0x1F, 0xF0, 0xFF]),
+            (Subcommand::EnableImu, &[0x01]),
+            (Subcommand::SetImuSensitivity, &[0x03, 0x00, 0x01, 0x01]),
+            (Subcommand::WriteImuRegisters, &[0x01, 0x11, 0x0C]),
+            (Subcommand::ReadImuRegisters, &[0x10, 0x02]),
+            (Subcommand::EnableVibration, &[0x01]),
+            (Subcommand::GetRegulatedVoltage, &[]),
+        ]
+        .into_iter()
+        .map(|(id, data)| subcommand(id, data))
+        .collect();
+        outputs.push(rumble([0x00, 0xC9, 0x40, 0x72], NEUTRAL_RUMBLE));
+        for (t, data) in [
+            (Type::RequestIrNfcMcu, &[0x01][..]),
+            (Type::RequestIrNfcMcu, &[0x02, 0x01]),
+            (Type::RequestIrNfcMcu, &[0x02, 0x06]),
+            (Type::McuFirmwareUpdate, &[0x00]),
+            (Type::RequestMcuUpdate, &[]),
+        ] {
+            let mut frame = rumble(NEUTRAL_RUMBLE, NEUTRAL_RUMBLE);
+            frame[1] = t as u8;
+            frame.extend_from_slice(data);
+            frame.resize(49, 0x00);
+            outputs.push(frame);
+        }
+
+        outputs
+    }
+
+    #[test]
+    fn valid_outputs_are_accepted() {
+        for output in valid_outputs() {
+            let mut protocol = Protocol::new(ControllerType::ProController);
+            protocol.handle_output(&output, Instant::now()).unwrap();
+        }
+    }
+
+    #[test]
+    fn empty_and_short_outputs_are_rejected() {
+        let mut protocol = Protocol::new(ControllerType::ProController);
+        let outputs: [&[u8]; 4] = [&[], &[0xA2], &[0xA1], &[0x00]];
+        for output in outputs {
+            assert!(matches!(
+                Output::try_from(output),
+                Err(Error {
+                    kind: ErrorKind::Protocol,
+                    ..
+                })
+            ));
+            assert!(protocol.handle_output(output, Instant::now()).is_err());
+        }
+
+        // A subcommand output without the subcommand
+        let mut output = rumble(NEUTRAL_RUMBLE, NEUTRAL_RUMBLE);
+        output[1] = Type::Subcommand as u8;
+        assert!(Output::try_from(output.as_slice()).is_err());
+    }
+
+    /// Returns a strategy of valid output reports mutated by overwriting, truncating and
+    /// extending.
+    fn mutated_output() -> impl Strategy<Value = Vec<u8>> {
+        let outputs = valid_outputs();
+        (
+            0..outputs.len(),
+            prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 0..8),
+            prop::option::of(any::<prop::sample::Index>()),
+            prop::collection::vec(any::<u8>(), 0..4),
+        )
+            .prop_map(move |(i, overwrites, truncate, extend)| {
+                let mut output = outputs[i].clone();
+                for (index, value) in overwrites {
+                    let index = index.index(output.len());
+                    output[index] = value;
+                }
+                if let Some(truncate) = truncate {
+                    output.truncate(truncate.index(output.len() + 1));
+                }
+                output.extend(extend);
+
+                output
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_outputs_do_not_panic(output in prop::collection::vec(any::<u8>(), 0..64)) {
+            let _ = Output::try_from(output.as_slice());
+            let mut protocol = Protocol::new(ControllerType::ProController);
+            let _ = protocol.handle_output(&output, Instant::now());
+        }
+
+        #[test]
+        fn mutated_outputs_do_not_panic(outputs in prop::collection::vec(mutated_output(), 1..8)) {
+            let now = Instant::now();
+            let mut protocol = Protocol::new(ControllerType::ProController);
+            for (i, output) in outputs.iter().enumerate() {
+                if let Ok(parsed) = Output::try_from(output.as_slice()) {
+                    prop_assert_eq!(parsed.direction, Direction::Output);
+                }
+                let at = now + Duration::from_millis(15) * i as u32;
+                let _ = protocol.handle_incoming(output, at);
+                while protocol.poll_tick(at).is_some() {}
+            }
+        }
+
+        #[test]
+        fn mutated_sub_parsers_do_not_panic(data in prop::collection::vec(any::<u8>(), 0..16)) {
+            let _ = ImuSensitivity::from_setting(&data);
+            let _ = spi_range(&data, &data);
+            let _ = first(&data, &data, "missing data");
+            if let Some(value) = data.first() {
+                let _ = HciState::try_from(*value);
+                let _ = ReportMode::try_from(*value);
+                let _ = Subcommand::try_from(*value);
+            }
+        }
+    }
+
+    #[test]
+    fn keep_alive_reports_at_60_hz() {
+        let start = Instant::now();
+        let end = start + Duration::from_secs(10);
+        let mut protocol = protocol_in(ReportMode::StandardFull);
+        protocol.set_button(Button::A, true).unwrap();
+
+        // Sleep until each tick like the run loop, without any input change
+        let mut reports = vec![];
+        let mut now = start;
+        while let Some(next) = protocol.next_tick(now) {
+            if next > end {
+                break;
+            }
+            now = now.max(next);
+            while let Some(frame) = protocol.poll_tick(now) {
+                reports.push(frame.data);
+            }
+        }
+
+        assert!((599..=601).contains(&reports.len()), "{}", reports.len());
+        for (i, report) in reports.iter().enumerate() {
+            assert_eq!(report[1], InputType::StandardFull as u8);
+            assert_eq!(report[2], (i as u8).wrapping_add(reports[0][2]));
+            assert_eq!(report[3..13], reports[0][3..13]);
+        }
+    }
+
+    #[test]
+    fn keep_alive_reports_with_coarse_polling() {
+        let start = Instant::now();
+        let mut protocol = protocol_in(ReportMode::StandardFull);
+
+        // The cadence is kept when the caller polls every 1 ms instead of sleeping until ticks
+        let mut reports = 0;
+        for ms in 0..10_000 {
+            let now = start + Duration::from_millis(ms);
+            while protocol.poll_tick(now).is_some() {
+                reports += 1;
+            }
+        }
+        assert!((599..=601).contains(&reports), "{}", reports);
+        assert_eq!(protocol.current_tick(), ReportTick(reports));
+    }
+
+    #[test]
+    fn motion_follows_imu_sensitivity() {
+        let now = Instant::now();
+        let mut protocol = protocol_in(ReportMode::StandardFull);
+        protocol
+            .handle_output(&subcommand(Subcommand::EnableImu, &[0x01]), now)
+            .unwrap();
+
+        let mut gyro = vec![];
+        for (i, setting) in [0x00, 0x03].into_iter().enumerate() {
+            let at = now + Duration::from_millis(20) * i as u32;
+            let reply = protocol
+                .handle_output(
+                    &subcommand(Subcommand::SetImuSensitivity, &[setting, 0x00, 0x01, 0x01]),
+                    at,
+                )
+                .unwrap()
+                .unwrap();
+            assert_eq!(&reply[14..16], &[0x80, 0x41]);
+            protocol.set_motion(MotionSample::default().rotating(100.0, 0.0, 0.0));
+            let report = protocol.poll_tick(at).unwrap().data;
+            // The X axis of the gyroscope in the first sample
+            gyro.push(i16::from_le_bytes([report[20], report[21]]));
+        }
+
+        assert_eq!(gyro, [11_429, 1_429]);
+        let ratio = gyro[0] as f32 / gyro[1] as f32;
+        assert!((ratio - 8.0).abs() < 0.01, "{}", ratio);
+    }
+
+    /// Returns the 6 12-bit stick calibration values read from the SPI flash like the device.
+    fn read_stick_calibration(protocol: &mut Protocol, addr: usize) -> [u16; 6] {
+        let mut data = (addr as u32).to_le_bytes().to_vec();
+        data.push(9);
+        let reply = protocol
+            .handle_output(&subcommand(Subcommand::SpiFlashRead, &data), Instant::now())
+            .unwrap()
+            .unwrap();
+        let bytes = &reply[21..30];
+
+        let mut values = [0u16; 6];
+        for (i, value) in values.iter_mut().enumerate() {
+            let b = &bytes[i / 2 * 3..i / 2 * 3 + 3];
+            *value = match i % 2 {
+                0 => b[0] as u16 | ((b[1] as u16 & 0x0F) << 8),
+                _ => (b[1] >> 4) as u16 | ((b[2] as u16) << 4),
+            };
+        }
+
+        values
+    }
+
+    #[test]
+    fn normalized_extremes_land_at_calibration() {
+        for config in [
+            StickConfig::default(),
+            StickConfig::new(0x100, 0x500, 0x7F0),
+        ] {
+            let mut protocol = Protocol::new(ControllerType::ProController);
+            protocol.set_stick_config(config);
+
+            // The left stick is calibrated as the maximums above, the center and the minimums
+            // below, and the right stick as the center, the minimums below and the maximums above
+            let left = read_stick_calibration(&mut protocol, FACTORY_LEFT_STICK_CALIBRATION);
+            let right = read_stick_calibration(&mut protocol, FACTORY_RIGHT_STICK_CALIBRATION);
+            for (stick, max, center, min) in [
+                (
+                    Stick::Left,
+                    [left[0], left[1]],
+                    [left[2], left[3]],
+                    [left[4], left[5]],
+                ),
+                (
+                    Stick::Right,
+                    [right[4], right[5]],
+                    [right[0], right[1]],
+                    [right[2], right[3]],
+                ),
+            ] {
+                protocol.set_stick_normalized(stick, 1.0, 1.0).unwrap();
+                assert_eq!(
+                    protocol.stick(stick),
+                    StickPosition::new(center[0] + max[0], center[1] + max[1])
+                );
+                protocol.set_stick_normalized(stick, -1.0, -1.0).unwrap();
+                assert_eq!(
+                    protocol.stick(stick),
+                    StickPosition::new(center[0] - min[0], center[1] - min[1])
+                );
+                // Values beyond the extremes are clamped at the calibration
+                protocol.set_stick_normalized(stick, 2.0, -2.0).unwrap();
+                assert_eq!(
+                    protocol.stick(stick),
+                    StickPosition::new(center[0] + max[0], center[1] - min[1])
+                );
+                protocol.set_stick_normalized(stick, 0.0, 0.0).unwrap();
+                assert_eq!(
+                    protocol.stick(stick),
+                    StickPosition::new(center[0], center[1])
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stick_config_is_clamped_to_12_bits() {
+        let config = StickConfig::new(0x100, 0xA00, 0xC00);
+        assert_eq!(config.range, STICK_MAX - 0xC00);
+        assert_eq!(config.to_raw(1.0), STICK_MAX);
+        assert_eq!(config.to_raw(-1.0), 0xC00 - config.range);
+        assert_eq!(StickConfig::new(0x900, 0x400, 0x800).deadzone, 0x400);
+    }
+
+    #[test]
+    fn neutral_rumble_bursts_are_suppressed() {
+        let now = Instant::now();
+        let interval = Duration::from_micros(16_667);
+        for rumbling in [false, true] {
+            let mut protocol = Protocol::new(ControllerType::ProController);
+            if rumbling {
+                protocol
+                    .handle_output(&rumble([0x00, 0xC9, 0x40, 0x72], NEUTRAL_RUMBLE), now)
+                    .unwrap();
+                protocol.take_events();
+                protocol.reset_stats();
+            }
+
+            // 10 seconds of neutral rumble at 60 Hz
+            for i in 1..=600 {
+                protocol
+                    .handle_output(&rumble(NEUTRAL_RUMBLE, NEUTRAL_RUMBLE), now + interval * i)
+                    .unwrap();
+            }
+
+            let events = protocol.take_events();
+            match rumbling {
+                false => assert_eq!(events, vec![]),
+                true => assert_eq!(
+                    events,
+                    vec![SwitchEvent::Rumble(RumbleEvent::Stopped(now + interval))]
+                ),
+            }
+            assert_eq!(
+                protocol.stats(),
+                ProtocolStats {
+                    rumble_frames: 600,
+                    suppressed_rumble_frames: 600 - events.len() as u64,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn past_tick_is_rejected() {
+        let now = Instant::now();
+        let mut protocol = protocol_in(ReportMode::StandardFull);
+        let interval = protocol.report_interval().unwrap();
+        for i in 0..3 {
+            assert!(protocol.poll_tick(now + interval * i).is_some());
+        }
+        assert_eq!(protocol.current_tick(), ReportTick(3));
+
+        assert!(protocol
+            .schedule(ReportTick(2).into(), ControllerInput::new(), now)
+            .is_err());
+        assert!(!protocol.has_scheduled());
+        assert!(protocol
+            .schedule(ReportTick(3).into(), ControllerInput::new(), now)
+            .is_ok());
+        assert_eq!(protocol.tick_time(ReportTick(2), now), None);
+    }
+
+    #[test]
+    fn scheduled_input_lands_in_targeted_report() {
+        let now = Instant::now();
+        let mut protocol = protocol_in(ReportMode::StandardFull);
+        let interval = protocol.report_interval().unwrap();
+        let mut pressed = ControllerInput::new();
+        pressed.buttons.press(Button::A);
+        protocol
+            .schedule(ReportTick(3).into(), pressed, now)
+            .unwrap();
+        protocol
+            .schedule(ReportTick(4).into(), ControllerInput::new(), now)
+            .unwrap();
+
+        for i in 0..6 {
+            let report = protocol.poll_tick(now + interval * i).unwrap();
+            let expected = match i {
+                3 => pressed.buttons,
+                _ => ButtonState::new(),
+            };
+            assert_eq!(
+                report.data[4..7],
+                expected.encode(ControllerType::ProController),
+                "report {}",
+                i
+            );
+        }
+        assert!(!protocol.has_scheduled());
+    }
+
+    #[test]
+    fn out_of_range_times_are_rejected() {
+        let now = Instant::now();
+        let mut protocol = protocol_in(ReportMode::StandardFull);
+        assert_eq!(protocol.tick_time(ReportTick(u64::MAX), now), None);
+        assert_eq!(
+            protocol.tick_time(ReportTick(u32::MAX as u64 + 1), now),
+            None
+        );
+
+        assert!(protocol.press_for(Button::A, Duration::MAX, now).is_err());
+        assert!(!protocol.buttons().is_pressed(Button::A));
+    }
 
-        todo!();
+    #[test]
+    fn unsupported_report_mode_is_rejected() {
+        let mut protocol = Protocol::new(ControllerType::ProController);
+        assert!(protocol
+            .handle_output(
+                &subcommand(Subcommand::SetInputReportMode, &[0x24]),
+                Instant::now()
+            )
+            .is_err());
+        assert_eq!(protocol.report_mode(), ReportMode::SimpleHid);
     }
 }
@@ -0,0 +1,71 @@
+//! Support for input recordings.
+
+use super::input::ControllerInput;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Represents an input state set at a report tick of a recording.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RecordedInput {
+    /// Represents the report tick from the start of the recording.
+    pub tick: u64,
+    /// Represents the input state.
+    pub input: ControllerInput,
+}
+
+/// Represents a timeline of input states carried by periodic input reports.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct InputRecording {
+    /// Represents the interval between input reports when recorded.
+    #[cfg_attr(feature = "serde", serde(with = "duration_us"))]
+    pub interval: Duration,
+    /// Represents the number of report ticks recorded.
+    pub ticks: u64,
+    /// Represents the changes of the input state, where the first one is at tick 0.
+    pub inputs: Vec<RecordedInput>,
+}
+
+impl InputRecording {
+    /// Creates an empty `InputRecording` with the given interval between input reports.
+    pub fn new(interval: Duration) -> Self {
+        InputRecording {
+            interval,
+            ticks: 0,
+            inputs: vec![],
+        }
+    }
+
+    /// Records the input state carried by the next report tick, which is kept only if it
+    /// changes.
+    pub fn record(&mut self, input: ControllerInput) {
+        if self.inputs.last().map(|recorded| recorded.input) != Some(input) {
+            self.inputs.push(RecordedInput {
+                tick: self.ticks,
+                input,
+            });
+        }
+        self.ticks += 1;
+    }
+
+    /// Returns the duration of the recording.
+    pub fn duration(&self) -> Duration {
+        self.interval.mul_f64(self.ticks as f64)
+    }
+}
+
+#[cfg(feature = "serde")]
+mod duration_us {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_micros() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_micros(u64::deserialize(deserializer)?))
+    }
+}
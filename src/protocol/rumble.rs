@@ -0,0 +1,184 @@
+//! Support for HD rumble.
+
+use std::time::Instant;
+
+/// Default threshold of changes in rumble frames.
+pub const DEFAULT_RUMBLE_EPSILON: f32 = 0.01;
+
+/// Represents the state of a motor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotorState {
+    /// Represents the frequency of the high band in Hz.
+    pub high_frequency: f32,
+    /// Represents the amplitude of the high band in 0 to 1.
+    pub high_amplitude: f32,
+    /// Represents the frequency of the low band in Hz.
+    pub low_frequency: f32,
+    /// Represents the amplitude of the low band in 0 to 1.
+    pub low_amplitude: f32,
+}
+
+impl MotorState {
+    /// Decodes the 4 bytes of rumble data of a motor.
+    pub fn decode(data: [u8; 4]) -> Self {
+        // High band
+        let hf = data[0] as u16 | ((data[1] as u16 & 0x01) << 8);
+        let high_frequency = decode_frequency(hf / 4 + 0x60);
+        let high_amplitude = decode_amplitude((data[1] & 0xFE) / 2);
+
+        // Low band
+        let lf = (data[2] & 0x7F) as u16;
+        let low_frequency = decode_frequency(lf + 0x40);
+        let low_amplitude =
+            decode_amplitude((data[3].saturating_sub(0x40) & 0x3F) * 2 + (data[2] >> 7));
+
+        MotorState {
+            high_frequency,
+            high_amplitude,
+            low_frequency,
+            low_amplitude,
+        }
+    }
+
+    /// Returns if the motor is idle with zero amplitudes.
+    pub fn is_idle(&self, epsilon: f32) -> bool {
+        self.high_amplitude <= epsilon && self.low_amplitude <= epsilon
+    }
+
+    /// Returns if the motor state differs from the other one beyond the given threshold.
+    /// Frequencies are compared relatively, and are ignored if both motors are idle.
+    pub fn differs(&self, other: &MotorState, epsilon: f32) -> bool {
+        if self.is_idle(epsilon) && other.is_idle(epsilon) {
+            return false;
+        }
+
+        let frequency = |a: f32, b: f32| (a - b).abs() > epsilon * a.max(b);
+
+        (self.high_amplitude - other.high_amplitude).abs() > epsilon
+            || (self.low_amplitude - other.low_amplitude).abs() > epsilon
+            || frequency(self.high_frequency, other.high_frequency)
+            || frequency(self.low_frequency, other.low_frequency)
+    }
+}
+
+impl Default for MotorState {
+    fn default() -> Self {
+        MotorState::decode([0x00, 0x01, 0x40, 0x40])
+    }
+}
+
+/// Represents a frame of rumble.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RumbleFrame {
+    /// Represents the state of the left motor.
+    pub left: MotorState,
+    /// Represents the state of the right motor.
+    pub right: MotorState,
+    /// Represents the time when the frame is received.
+    pub timestamp: Instant,
+}
+
+impl RumbleFrame {
+    /// Returns if the frame differs from the other one beyond the given threshold.
+    pub fn differs(&self, other: &RumbleFrame, epsilon: f32) -> bool {
+        self.left.differs(&other.left, epsilon) || self.right.differs(&other.right, epsilon)
+    }
+
+    /// Returns if both motors are idle.
+    pub fn is_idle(&self, epsilon: f32) -> bool {
+        self.left.is_idle(epsilon) && self.right.is_idle(epsilon)
+    }
+}
+
+/// Enumeration for rumble events.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RumbleEvent {
+    /// Represents the rumble changed to the frame.
+    Changed(RumbleFrame),
+    /// Represents the rumble returned to neutral at the time.
+    Stopped(Instant),
+}
+
+/// Decodes the encoded frequency into Hz.
+fn decode_frequency(encoded: u16) -> f32 {
+    10.0 * 2f32.powf(encoded as f32 / 32.0)
+}
+
+/// Decodes the encoded amplitude into 0 to 1.
+fn decode_amplitude(encoded: u8) -> f32 {
+    let encoded = encoded.min(0x64) as f32;
+    let amplitude = if encoded >= 32.0 {
+        2f32.powf(encoded / 32.0) / 8.7
+    } else if encoded >= 16.0 {
+        2f32.powf(encoded / 16.0) / 17.0
+    } else {
+        encoded * (2.0 / 17.0) / 16.0
+    };
+
+    amplitude.min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() <= expected.abs() * 0.005 + 0.001,
+            "{} is not close to {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn decode_frequencies() {
+        // Frequencies of the encoding table at the default and the extremes of both bands
+        let cases = [
+            ([0x00, 0x01, 0x40, 0x40], 320.0, 160.0),
+            ([0x00, 0x00, 0x01, 0x40], 80.0, 40.87),
+            ([0xFC, 0x01, 0x7F, 0x40], 1252.6, 626.61),
+        ];
+        for (data, high_frequency, low_frequency) in cases {
+            let state = MotorState::decode(data);
+            assert_close(state.high_frequency, high_frequency);
+            assert_close(state.low_frequency, low_frequency);
+            assert!(state.is_idle(0.0));
+        }
+    }
+
+    #[test]
+    fn decode_amplitudes() {
+        // Amplitudes of the encoding table, where the high band is encoded in the second byte,
+        // and the low band in the fourth byte and the top bit of the third byte
+        let cases = [
+            (0x00, 0x00, 0x40, 0.0),
+            (0x10, 0x20, 0x48, 0.117_647),
+            (0x20, 0x40, 0x50, 0.229_885),
+            (0x40, 0x80, 0x60, 0.459_770),
+            (0x64, 0xC8, 0x72, 1.0),
+        ];
+        for (encoded, high, low, amplitude) in cases {
+            let state = MotorState::decode([0x00, high | 0x01, 0x40, low]);
+            assert_close(state.high_amplitude, amplitude);
+            assert_close(state.low_amplitude, amplitude);
+
+            // Odd amplitudes carry the lowest bit in the third byte
+            let state = MotorState::decode([0x00, 0x01, 0xC0, low]);
+            assert_close(state.low_amplitude, decode_amplitude(encoded + 1));
+        }
+        assert_eq!(decode_amplitude(0xFF), 1.0);
+    }
+
+    #[test]
+    fn idle_motors_do_not_differ() {
+        let neutral = MotorState::default();
+        let other_frequency = MotorState::decode([0xFC, 0x01, 0x7F, 0x40]);
+        assert!(!neutral.differs(&other_frequency, DEFAULT_RUMBLE_EPSILON));
+
+        let rumbling = MotorState::decode([0x00, 0xC9, 0x40, 0x72]);
+        assert!(rumbling.differs(&neutral, DEFAULT_RUMBLE_EPSILON));
+        assert!(!rumbling.differs(&rumbling, DEFAULT_RUMBLE_EPSILON));
+        assert!(!rumbling.is_idle(DEFAULT_RUMBLE_EPSILON));
+    }
+}
@@ -0,0 +1,317 @@
+//! Support for the SPI flash.
+
+use super::imu::{ImuCalibration, IMU_CALIBRATION_LEN};
+use super::input::StickConfig;
+use crate::{ControllerType, Error, ErrorKind, Result};
+
+/// Size of the SPI flash.
+pub const SPI_FLASH_SIZE: usize = 0x80000;
+/// Size of a sector in the SPI flash.
+pub const SECTOR_SIZE: usize = 0x1000;
+/// Maximum length of a SPI flash read or write.
+pub const SPI_FLASH_MAX_LEN: usize = 0x1D;
+
+/// Address of the pairing info.
+pub const PAIRING_INFO: usize = 0x2000;
+/// Length of a host entry in the pairing info.
+const PAIRING_ENTRY_LEN: usize = 0x26;
+/// Number of host entries in the pairing info.
+const PAIRING_ENTRIES: usize = 2;
+/// Offset of the host Bluetooth address in a pairing entry.
+const PAIRING_HOST_OFFSET: usize = 0x04;
+/// Address of the serial number.
+pub const SERIAL_NUMBER: usize = 0x6000;
+/// Address of the device type.
+pub const DEVICE_TYPE: usize = 0x6012;
+/// Address of the flag indicating if colors exist.
+pub const COLOR_INFO: usize = 0x601B;
+/// Address of the factory IMU calibration.
+pub const FACTORY_IMU_CALIBRATION: usize = 0x6020;
+/// Address of the factory left stick calibration.
+pub const FACTORY_LEFT_STICK_CALIBRATION: usize = 0x603D;
+/// Address of the factory right stick calibration.
+pub const FACTORY_RIGHT_STICK_CALIBRATION: usize = 0x6046;
+/// Address of the colors.
+pub const COLORS: usize = 0x6050;
+/// Address of the IMU horizontal offsets.
+pub const IMU_HORIZONTAL_OFFSETS: usize = 0x6080;
+/// Address of the left stick parameters.
+pub const LEFT_STICK_PARAMETERS: usize = 0x6086;
+/// Address of the right stick parameters.
+pub const RIGHT_STICK_PARAMETERS: usize = 0x6098;
+/// Address of the user calibration.
+pub const USER_CALIBRATION: usize = 0x8010;
+/// Address and length of regions which the device reads on connection and caches, which are the
+/// factory configuration and calibration, and the user calibration.
+const CACHED_REGIONS: [(usize, usize); 2] = [(SERIAL_NUMBER, 0xB0), (USER_CALIBRATION, 0x30)];
+
+/// Enumeration for how writes by the device are handled when the SPI flash is reloaded.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ReloadMode {
+    /// Represents writes by the device are kept over the reloaded contents, e.g., the pairing
+    /// info and user calibration.
+    Merge,
+    /// Represents writes by the device are discarded.
+    Discard,
+}
+
+/// Represents the colors of a controller.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Colors {
+    /// Represents the body color.
+    pub body: [u8; 3],
+    /// Represents the buttons color.
+    pub buttons: [u8; 3],
+    /// Represents the left grip color.
+    pub left_grip: [u8; 3],
+    /// Represents the right grip color.
+    pub right_grip: [u8; 3],
+}
+
+impl Colors {
+    /// Creates a `Colors` of the given controller type.
+    pub fn new(controller_type: ControllerType) -> Self {
+        match controller_type {
+            ControllerType::JoyConL => Colors {
+                body: [0x0A, 0xB9, 0xE6],
+                buttons: [0x00, 0x1E, 0x1E],
+                left_grip: [0x0A, 0xB9, 0xE6],
+                right_grip: [0x0A, 0xB9, 0xE6],
+            },
+            ControllerType::JoyConR => Colors {
+                body: [0xFF, 0x3C, 0x28],
+                buttons: [0x1E, 0x0A, 0x0A],
+                left_grip: [0xFF, 0x3C, 0x28],
+                right_grip: [0xFF, 0x3C, 0x28],
+            },
+            ControllerType::ProController => Colors {
+                body: [0x32, 0x32, 0x32],
+                buttons: [0xFF, 0xFF, 0xFF],
+                left_grip: [0x32, 0x32, 0x32],
+                right_grip: [0x32, 0x32, 0x32],
+            },
+            ControllerType::NesController => Colors {
+                body: [0xC8, 0xC8, 0xC8],
+                buttons: [0x32, 0x32, 0x32],
+                left_grip: [0xC8, 0xC8, 0xC8],
+                right_grip: [0xC8, 0xC8, 0xC8],
+            },
+            ControllerType::SnesController => Colors {
+                body: [0xB4, 0xB4, 0xBE],
+                buttons: [0x5A, 0x4B, 0x8C],
+                left_grip: [0xB4, 0xB4, 0xBE],
+                right_grip: [0xB4, 0xB4, 0xBE],
+            },
+            ControllerType::N64Controller => Colors {
+                body: [0x3C, 0x3C, 0x3C],
+                buttons: [0xF0, 0xC8, 0x00],
+                left_grip: [0x3C, 0x3C, 0x3C],
+                right_grip: [0x3C, 0x3C, 0x3C],
+            },
+        }
+    }
+}
+
+/// Represents the SPI flash of a controller.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct SpiFlash {
+    data: Vec<u8>,
+}
+
+impl SpiFlash {
+    /// Creates a `SpiFlash` with the default factory configuration of the given controller type.
+    pub fn new(controller_type: ControllerType) -> Self {
+        let mut flash = SpiFlash {
+            data: vec![0xFF; SPI_FLASH_SIZE],
+        };
+
+        flash.data[DEVICE_TYPE] = controller_type.id();
+        flash.data[COLOR_INFO] = 0x01;
+
+        // Factory IMU calibration with zero origins and default sensitivities
+        let mut imu = [0u8; 24];
+        for i in 0..3 {
+            imu[6 + i * 2..8 + i * 2].copy_from_slice(&0x4000i16.to_le_bytes());
+            imu[18 + i * 2..20 + i * 2].copy_from_slice(&0x343Bi16.to_le_bytes());
+        }
+        flash.data[FACTORY_IMU_CALIBRATION..FACTORY_IMU_CALIBRATION + 24].copy_from_slice(&imu);
+
+        flash.set_colors(Colors::new(controller_type));
+
+        flash.data[IMU_HORIZONTAL_OFFSETS..IMU_HORIZONTAL_OFFSETS + 6]
+            .copy_from_slice(&[0x50, 0xFD, 0x00, 0x00, 0xC6, 0x0F]);
+        let parameters = [
+            0x0F, 0x30, 0x61, 0x96, 0x30, 0xF3, 0xD4, 0x14, 0x54, 0x41, 0x15, 0x54, 0xC7, 0x79,
+            0x9C, 0x33, 0x36, 0x63,
+        ];
+        flash.data[LEFT_STICK_PARAMETERS..LEFT_STICK_PARAMETERS + 18].copy_from_slice(&parameters);
+        flash.data[RIGHT_STICK_PARAMETERS..RIGHT_STICK_PARAMETERS + 18]
+            .copy_from_slice(&parameters);
+
+        // Factory stick calibration
+        flash.set_stick_config(StickConfig::default());
+
+        flash
+    }
+
+    /// Creates a `SpiFlash` from an image of the whole SPI flash.
+    pub fn from_image(data: Vec<u8>) -> Result<Self> {
+        if data.len() != SPI_FLASH_SIZE {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "invalid SPI flash image of {} bytes, expected {} bytes",
+                    data.len(),
+                    SPI_FLASH_SIZE
+                ),
+            ));
+        }
+
+        Ok(SpiFlash { data })
+    }
+
+    /// Reads data from the given address.
+    pub fn read(&self, addr: u32, len: u8) -> Result<&[u8]> {
+        let (start, end) = range(addr, len as usize)?;
+
+        Ok(&self.data[start..end])
+    }
+
+    /// Writes data to the given address.
+    pub fn write(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        let (start, end) = range(addr, data.len())?;
+        self.data[start..end].copy_from_slice(data);
+
+        Ok(())
+    }
+
+    /// Erases the sector containing the given address.
+    pub fn erase_sector(&mut self, addr: u32) -> Result<()> {
+        let (start, _) = range(addr, 1)?;
+        let start = start - start % SECTOR_SIZE;
+        self.data[start..start + SECTOR_SIZE].fill(0xFF);
+
+        Ok(())
+    }
+
+    /// Copies the given region from the other SPI flash.
+    pub fn copy_region(&mut self, other: &SpiFlash, start: usize, end: usize) {
+        self.data[start..end].copy_from_slice(&other.data[start..end]);
+    }
+
+    /// Returns if any region which the device caches differs from the other SPI flash.
+    pub fn cached_differs(&self, other: &SpiFlash) -> bool {
+        CACHED_REGIONS
+            .iter()
+            .any(|&(addr, len)| self.data[addr..addr + len] != other.data[addr..addr + len])
+    }
+
+    /// Returns the colors.
+    pub fn colors(&self) -> Colors {
+        let color = |i: usize| {
+            let mut color = [0u8; 3];
+            color.copy_from_slice(&self.data[COLORS + i * 3..COLORS + i * 3 + 3]);
+            color
+        };
+
+        Colors {
+            body: color(0),
+            buttons: color(1),
+            left_grip: color(2),
+            right_grip: color(3),
+        }
+    }
+
+    /// Sets the colors.
+    pub fn set_colors(&mut self, colors: Colors) {
+        for (i, color) in [
+            colors.body,
+            colors.buttons,
+            colors.left_grip,
+            colors.right_grip,
+        ]
+        .iter()
+        .enumerate()
+        {
+            self.data[COLORS + i * 3..COLORS + i * 3 + 3].copy_from_slice(color);
+        }
+    }
+
+    /// Returns the factory IMU calibration.
+    pub fn imu_calibration(&self) -> ImuCalibration {
+        let mut data = [0u8; IMU_CALIBRATION_LEN];
+        data.copy_from_slice(
+            &self.data[FACTORY_IMU_CALIBRATION..FACTORY_IMU_CALIBRATION + IMU_CALIBRATION_LEN],
+        );
+
+        ImuCalibration::from_bytes(&data)
+    }
+
+    /// Returns the pairing info.
+    pub fn pairing_info(&self) -> &[u8] {
+        &self.data[PAIRING_INFO..PAIRING_INFO + PAIRING_ENTRY_LEN * PAIRING_ENTRIES]
+    }
+
+    /// Clears the pairing entries of the given host Bluetooth address. Returns if any entry is
+    /// cleared.
+    pub fn clear_pairing(&mut self, host: [u8; 6]) -> bool {
+        let mut cleared = false;
+        for i in 0..PAIRING_ENTRIES {
+            let entry = PAIRING_INFO + i * PAIRING_ENTRY_LEN;
+            let addr = entry + PAIRING_HOST_OFFSET;
+            if self.data[addr..addr + 6] == host {
+                self.data[entry..entry + PAIRING_ENTRY_LEN].fill(0xFF);
+                cleared = true;
+            }
+        }
+
+        cleared
+    }
+
+    /// Sets the factory stick calibration and the deadzone in stick parameters of both sticks
+    /// from the given stick configuration.
+    pub fn set_stick_config(&mut self, config: StickConfig) {
+        let (range, center) = (config.range, config.center);
+        self.data[FACTORY_LEFT_STICK_CALIBRATION..FACTORY_LEFT_STICK_CALIBRATION + 9]
+            .copy_from_slice(&pack_stick_calibration([
+                range, range, center, center, range, range,
+            ]));
+        self.data[FACTORY_RIGHT_STICK_CALIBRATION..FACTORY_RIGHT_STICK_CALIBRATION + 9]
+            .copy_from_slice(&pack_stick_calibration([
+                center, center, range, range, range, range,
+            ]));
+
+        // The deadzone shares 3 bytes with the range ratio
+        for addr in [LEFT_STICK_PARAMETERS, RIGHT_STICK_PARAMETERS] {
+            let bytes = &mut self.data[addr + 3..addr + 6];
+            let ratio = (bytes[1] >> 4) as u16 | ((bytes[2] as u16) << 4);
+            bytes[0] = (config.deadzone & 0xFF) as u8;
+            bytes[1] = ((config.deadzone >> 8) & 0x0F) as u8 | ((ratio & 0x0F) << 4) as u8;
+            bytes[2] = (ratio >> 4) as u8;
+        }
+    }
+}
+
+fn range(addr: u32, len: usize) -> Result<(usize, usize)> {
+    let start = addr as usize;
+    match start.checked_add(len) {
+        Some(end) if end <= SPI_FLASH_SIZE && len <= SPI_FLASH_MAX_LEN => Ok((start, end)),
+        _ => Err(Error::new(
+            ErrorKind::Protocol,
+            format!("invalid SPI flash range 0x{:x} of {} bytes", addr, len),
+        )),
+    }
+}
+
+/// Packs 6 12-bit stick calibration values into 9 bytes.
+pub fn pack_stick_calibration(values: [u16; 6]) -> [u8; 9] {
+    let mut buf = [0u8; 9];
+    for i in 0..3 {
+        let (a, b) = (values[i * 2], values[i * 2 + 1]);
+        buf[i * 3] = (a & 0xFF) as u8;
+        buf[i * 3 + 1] = ((a >> 8) & 0x0F) as u8 | ((b & 0x0F) << 4) as u8;
+        buf[i * 3 + 2] = (b >> 4) as u8;
+    }
+
+    buf
+}
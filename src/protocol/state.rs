@@ -0,0 +1,180 @@
+//! Support for declarative controller states.
+
+use super::imu::MotionSample;
+use super::input::{Button, ButtonState, ControllerInput, Orientation, Stick};
+use crate::{ControllerType, Error, ErrorKind, Result};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+
+/// Represents an error of a field in a `ControllerState`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct FieldError {
+    /// Represents the path of the field, like `buttons[1]`.
+    pub field: String,
+    /// Represents the message.
+    pub message: String,
+}
+
+impl Display for FieldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// Represents a whole input state of a controller in a declarative document, which is applied
+/// at once.
+///
+/// In JSON, a state looks like
+///
+/// ```json
+/// {
+///     "buttons": ["a", "zl"],
+///     "left_stick": [0.0, 1.0],
+///     "motion": { "accel": [0.0, 0.0, 1.0], "gyro": [0.0, 0.0, 0.0] }
+/// }
+/// ```
+///
+/// where buttons are named as `Button::name`, sticks are in normalized values in -1 to 1, and
+/// every field is optional. Absent buttons are released, absent sticks are centered and an
+/// absent motion keeps the current one.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct ControllerState {
+    /// Represents the names of pressed buttons.
+    pub buttons: Vec<String>,
+    /// Represents the left stick position, or `None` if centered.
+    pub left_stick: Option<(f32, f32)>,
+    /// Represents the right stick position, or `None` if centered.
+    pub right_stick: Option<(f32, f32)>,
+    /// Represents the motion sample, or `None` to keep the current one.
+    pub motion: Option<MotionSample>,
+}
+
+impl ControllerState {
+    /// Creates a `ControllerState` with all buttons released and all sticks centered.
+    pub fn new() -> Self {
+        ControllerState::default()
+    }
+
+    /// Parses a `ControllerState` from JSON.
+    #[cfg(feature = "json")]
+    pub fn from_json(s: &str) -> Result<Self> {
+        serde_json::from_str(s).map_err(|e| Error::new(ErrorKind::Other, e.to_string()))
+    }
+
+    /// Serializes the `ControllerState` to JSON.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(|e| Error::new(ErrorKind::Other, e.to_string()))
+    }
+
+    /// Validates the state against the given controller type held in the given orientation, and
+    /// returns the errors of every invalid field, which are unknown buttons, buttons and sticks
+    /// the controller does not have, and stick values out of -1 to 1.
+    pub fn validate(
+        &self,
+        controller_type: ControllerType,
+        orientation: Orientation,
+    ) -> Vec<FieldError> {
+        let mut errors = vec![];
+        for (i, name) in self.buttons.iter().enumerate() {
+            let message = match name.parse::<Button>() {
+                Ok(button)
+                    if orientation
+                        .physical_button(controller_type, button)
+                        .is_some_and(|button| controller_type.has_button(button)) =>
+                {
+                    continue
+                }
+                Ok(button) if orientation.is_remapped(controller_type) => {
+                    orientation.unavailable(controller_type, button).message
+                }
+                Ok(button) => format!("{} does not have {}", controller_type, button),
+                Err(e) => e.message,
+            };
+            errors.push(FieldError {
+                field: format!("buttons[{}]", i),
+                message,
+            });
+        }
+        for (field, stick, position) in [
+            ("left_stick", Stick::Left, self.left_stick),
+            ("right_stick", Stick::Right, self.right_stick),
+        ] {
+            let (x, y) = match position {
+                Some(position) => position,
+                None => continue,
+            };
+            let message = match orientation.physical_stick(controller_type, stick) {
+                None => orientation.unavailable(controller_type, stick).message,
+                Some(physical) if !controller_type.has_stick(physical) => {
+                    format!("{} does not have {}", controller_type, stick)
+                }
+                _ if !(-1.0..=1.0).contains(&x) || !(-1.0..=1.0).contains(&y) => {
+                    format!("stick position ({}, {}) is out of -1 to 1", x, y)
+                }
+                _ => continue,
+            };
+            errors.push(FieldError {
+                field: field.into(),
+                message,
+            });
+        }
+
+        errors
+    }
+
+    /// Converts the state to the logical input of the given controller type held in the given
+    /// orientation. Errors of every invalid field are joined into one error.
+    pub fn to_input(
+        &self,
+        controller_type: ControllerType,
+        orientation: Orientation,
+    ) -> Result<ControllerInput> {
+        let errors = self.validate(controller_type, orientation);
+        if !errors.is_empty() {
+            let message = errors
+                .iter()
+                .map(|error| error.to_string())
+                .collect::<Vec<_>>()
+                .join("; ");
+
+            return Err(Error::new(ErrorKind::Unavailable, message));
+        }
+
+        let mut buttons = ButtonState::new();
+        for name in self.buttons.iter() {
+            buttons.press(name.parse()?);
+        }
+
+        Ok(ControllerInput {
+            buttons,
+            left_stick: self.left_stick.unwrap_or_default(),
+            right_stick: self.right_stick.unwrap_or_default(),
+            motion: self.motion,
+        })
+    }
+}
+
+impl From<ControllerInput> for ControllerState {
+    fn from(input: ControllerInput) -> Self {
+        let stick = |position: (f32, f32)| match position {
+            (x, y) if x == 0.0 && y == 0.0 => None,
+            position => Some(position),
+        };
+
+        ControllerState {
+            buttons: input
+                .buttons
+                .pressed()
+                .iter()
+                .map(|button| button.name().into())
+                .collect(),
+            left_stick: stick(input.left_stick),
+            right_stick: stick(input.right_stick),
+            motion: input.motion,
+        }
+    }
+}
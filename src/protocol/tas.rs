@@ -0,0 +1,257 @@
+//! Support for nx-TAS scripts.
+
+use super::input::{Button, ButtonState, ControllerInput};
+use crate::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Maximum of stick values in nx-TAS scripts.
+const TAS_STICK_MAX: f32 = 32767.0;
+
+/// Represents the input of a frame in an nx-TAS script.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TasFrame {
+    /// Represents the frame number.
+    pub frame: u64,
+    /// Represents the input.
+    pub input: ControllerInput,
+}
+
+/// Represents an nx-TAS script, where each line is of `FRAME KEYS LX;LY RX;RY`.
+///
+/// Keys are separated by `;`, or `NONE` if no key is pressed. Stick values are in -32768 to
+/// 32767. Frames absent from the script have no input. Blank lines and comments starting with
+/// `//` or `#` are ignored.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TasScript {
+    frames: Vec<TasFrame>,
+}
+
+impl TasScript {
+    /// Parses an nx-TAS script.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut frames: Vec<TasFrame> = vec![];
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("//") || line.starts_with('#') {
+                continue;
+            }
+
+            let frame = parse_line(line).map_err(|message| {
+                Error::new(ErrorKind::Other, format!("line {}: {}", i + 1, message))
+            })?;
+            if let Some(last) = frames.last() {
+                if frame.frame <= last.frame {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        format!(
+                            "line {}: frame {} does not follow frame {}",
+                            i + 1,
+                            frame.frame,
+                            last.frame
+                        ),
+                    ));
+                }
+            }
+            frames.push(frame);
+        }
+
+        Ok(TasScript { frames })
+    }
+
+    /// Loads an nx-TAS script from the given path.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        TasScript::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Returns the frames in the script.
+    pub fn frames(&self) -> &[TasFrame] {
+        &self.frames
+    }
+
+    /// Returns the number of frames, which ends at the last frame in the script.
+    pub fn len(&self) -> u64 {
+        self.frames.last().map_or(0, |frame| frame.frame + 1)
+    }
+
+    /// Returns if the script has no frame.
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Returns the input of the given frame.
+    pub fn input(&self, frame: u64) -> ControllerInput {
+        match self.frames.binary_search_by_key(&frame, |f| f.frame) {
+            Ok(i) => self.frames[i].input,
+            Err(_) => ControllerInput::new(),
+        }
+    }
+}
+
+impl FromStr for TasScript {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        TasScript::parse(s)
+    }
+}
+
+fn parse_line(line: &str) -> std::result::Result<TasFrame, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 4 {
+        return Err(format!(
+            "expected 4 fields of FRAME KEYS LX;LY RX;RY, found {}",
+            fields.len()
+        ));
+    }
+
+    let frame = fields[0]
+        .parse()
+        .map_err(|_| format!("invalid frame number {}", fields[0]))?;
+    let mut buttons = ButtonState::new();
+    if fields[1] != "NONE" {
+        for key in fields[1].split(';').filter(|key| !key.is_empty()) {
+            buttons.press(parse_key(key)?);
+        }
+    }
+
+    Ok(TasFrame {
+        frame,
+        input: ControllerInput {
+            buttons,
+            left_stick: parse_stick(fields[2])?,
+            right_stick: parse_stick(fields[3])?,
+            motion: None,
+        },
+    })
+}
+
+fn parse_key(key: &str) -> std::result::Result<Button, String> {
+    match key {
+        "KEY_A" => Ok(Button::A),
+        "KEY_B" => Ok(Button::B),
+        "KEY_X" => Ok(Button::X),
+        "KEY_Y" => Ok(Button::Y),
+        "KEY_L" => Ok(Button::L),
+        "KEY_R" => Ok(Button::R),
+        "KEY_ZL" => Ok(Button::Zl),
+        "KEY_ZR" => Ok(Button::Zr),
+        "KEY_PLUS" => Ok(Button::Plus),
+        "KEY_MINUS" => Ok(Button::Minus),
+        "KEY_DUP" => Ok(Button::Up),
+        "KEY_DDOWN" => Ok(Button::Down),
+        "KEY_DLEFT" => Ok(Button::Left),
+        "KEY_DRIGHT" => Ok(Button::Right),
+        "KEY_LSTICK" => Ok(Button::LeftStick),
+        "KEY_RSTICK" => Ok(Button::RightStick),
+        "KEY_HOME" => Ok(Button::Home),
+        "KEY_CAPTURE" => Ok(Button::Capture),
+        "KEY_SL" => Ok(Button::Sl),
+        "KEY_SR" => Ok(Button::Sr),
+        _ => Err(format!(
+            "unknown key {}, expected KEY_A, KEY_B, KEY_X, KEY_Y, KEY_L, KEY_R, KEY_ZL, KEY_ZR, \
+             KEY_PLUS, KEY_MINUS, KEY_DUP, KEY_DDOWN, KEY_DLEFT, KEY_DRIGHT, KEY_LSTICK, \
+             KEY_RSTICK, KEY_HOME, KEY_CAPTURE, KEY_SL or KEY_SR",
+            key
+        )),
+    }
+}
+
+fn parse_stick(s: &str) -> std::result::Result<(f32, f32), String> {
+    let axis = |value: &str| {
+        value
+            .parse::<i16>()
+            .map(|value| (value as f32 / TAS_STICK_MAX).max(-1.0))
+            .map_err(|_| format!("invalid stick value {}, expected -32768 to 32767", value))
+    };
+
+    match s.split_once(';') {
+        Some((x, y)) => Ok((axis(x)?, axis(y)?)),
+        None => Err(format!("invalid stick {}, expected X;Y", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Direction, Protocol, ReportMode, ReportTick, Subcommand, Type};
+    use crate::ControllerType;
+    use std::time::Instant;
+
+    /// Sample nx-TAS script.
+    const NX_TAS_SCRIPT: &str = include_str!("../../tests/fixtures/nx_tas_script.txt");
+
+    /// Returns a protocol streaming standard full input reports.
+    fn streaming_protocol() -> Protocol {
+        let mut frame = vec![Direction::Output as u8, Type::Subcommand as u8, 0x00];
+        frame.extend_from_slice(&[0x00, 0x01, 0x40, 0x40, 0x00, 0x01, 0x40, 0x40]);
+        frame.push(Subcommand::SetInputReportMode as u8);
+        frame.push(ReportMode::StandardFull as u8);
+        frame.resize(49, 0x00);
+        let mut protocol = Protocol::new(ControllerType::ProController);
+        protocol.handle_output(&frame, Instant::now()).unwrap();
+
+        protocol
+    }
+
+    #[test]
+    fn parse_sample_script() {
+        let script = TasScript::parse(NX_TAS_SCRIPT).unwrap();
+        assert_eq!(script.frames().len(), 6);
+        assert_eq!(script.len(), 8);
+
+        let input = script.input(3);
+        assert_eq!(input.buttons.pressed(), vec![Button::Zr]);
+        assert_eq!(input.left_stick, (1.0, 1.0));
+        assert_eq!(input.right_stick, (0.0, -1.0));
+        // Frames absent from the script have no input
+        assert_eq!(script.input(4), ControllerInput::new());
+        assert_eq!(script.input(8), ControllerInput::new());
+    }
+
+    #[test]
+    fn sample_script_round_trips_to_reports() {
+        let now = Instant::now();
+        let script = TasScript::parse(NX_TAS_SCRIPT).unwrap();
+        let mut protocol = streaming_protocol();
+        let interval = protocol.report_interval().unwrap();
+        for frame in 0..=script.len() {
+            protocol
+                .schedule(ReportTick(frame).into(), script.input(frame), now)
+                .unwrap();
+        }
+
+        let config = protocol.stick_config();
+        for frame in 0..=script.len() {
+            let report = protocol.poll_tick(now + interval * frame as u32).unwrap();
+            let input = script.input(frame);
+            let (lx, ly) = input.left_stick;
+            let (rx, ry) = input.right_stick;
+            assert_eq!(
+                report.data[4..7],
+                input.buttons.encode(ControllerType::ProController),
+                "frame {}",
+                frame
+            );
+            assert_eq!(
+                report.data[7..10],
+                config.position(lx, ly).encode(),
+                "frame {}",
+                frame
+            );
+            assert_eq!(
+                report.data[10..13],
+                config.position(rx, ry).encode(),
+                "frame {}",
+                frame
+            );
+        }
+        assert!(!protocol.has_scheduled());
+    }
+
+    #[test]
+    fn unknown_key_is_reported_with_line() {
+        let e = TasScript::parse("0 NONE 0;0 0;0\n\n2 KEY_Q 0;0 0;0").unwrap_err();
+        assert!(e.message.starts_with("line 3: unknown key KEY_Q"), "{}", e);
+    }
+}
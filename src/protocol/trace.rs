@@ -0,0 +1,304 @@
+//! Support for replaying captured sessions.
+//!
+//! A capture is a text of hex lines, one frame per line, in the order they were seen on the
+//! interrupt channel. Lines starting with `#` and empty lines are ignored. The direction of each
+//! frame is determined by its first byte.
+
+use super::spi::SERIAL_NUMBER;
+use super::{Direction, InputType, Protocol, Subcommand};
+use crate::{Error, ErrorKind, Result};
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::time::Instant;
+
+/// Offset of the timer in an input report.
+const TIMER_OFFSET: usize = 2;
+/// Offset of the ACK in a subcommand reply.
+const REPLY_ACK_OFFSET: usize = 14;
+/// Offset of the subcommand in a subcommand reply.
+const REPLY_SUBCOMMAND_OFFSET: usize = 15;
+/// Offset of the data in a subcommand reply.
+const REPLY_DATA_OFFSET: usize = 16;
+/// Range of the address in the device info.
+const DEVICE_INFO_ADDRESS: Range<usize> = 4..10;
+/// Length of the address and the length preceding the data of a SPI flash read.
+const SPI_READ_HEADER_LEN: usize = 5;
+/// Length of the serial number.
+const SERIAL_NUMBER_LEN: usize = 0x10;
+
+/// Represents a frame in a capture.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct TraceFrame {
+    /// Represents the line number in the capture.
+    pub line: usize,
+    /// Represents the frame.
+    pub data: Vec<u8>,
+}
+
+impl TraceFrame {
+    /// Returns if the frame is sent from the device to the controller.
+    pub fn is_output(&self) -> bool {
+        self.data.first() == Some(&(Direction::Output as u8))
+    }
+
+    /// Returns if the frame is a subcommand reply from the controller.
+    pub fn is_subcommand_reply(&self) -> bool {
+        self.data.first() == Some(&(Direction::Input as u8))
+            && self.data.get(1) == Some(&(InputType::SubcommandReply as u8))
+    }
+}
+
+/// Represents a difference between a captured reply and the generated one.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Mismatch {
+    /// Represents the line number of the captured reply.
+    pub line: usize,
+    /// Represents the offset of the differing byte.
+    pub offset: usize,
+    /// Represents the captured byte, or `None` if the captured reply is shorter.
+    pub expected: Option<u8>,
+    /// Represents the generated byte, or `None` if no reply or a shorter one is generated.
+    pub actual: Option<u8>,
+}
+
+/// Parses a capture of hex lines.
+pub fn parse(capture: &str) -> Result<Vec<TraceFrame>> {
+    let mut frames = vec![];
+    for (i, line) in capture.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let digits: Vec<char> = line
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != ':')
+            .collect();
+        if !digits.len().is_multiple_of(2) {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("odd number of hex digits in line {}", i + 1),
+            ));
+        }
+        let data = digits
+            .chunks(2)
+            .map(|pair| {
+                u8::from_str_radix(&pair.iter().collect::<String>(), 16).map_err(|_| {
+                    Error::new(
+                        ErrorKind::Other,
+                        format!("invalid hex digits in line {}", i + 1),
+                    )
+                })
+            })
+            .collect::<Result<Vec<u8>>>()?;
+
+        frames.push(TraceFrame { line: i + 1, data });
+    }
+
+    Ok(frames)
+}
+
+/// Enumeration for fields of subcommand replies which legitimately differ between a real
+/// controller and the protocol, which are excluded from comparisons.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum AllowedField {
+    /// Represents the timer, which depends on when the connection starts.
+    Timer,
+    /// Represents the battery, the inputs and the vibrator byte, which depend on the physical
+    /// state of the controller.
+    Inputs,
+    /// Represents the Bluetooth address in the device info, which is unique per controller.
+    Address,
+    /// Represents the serial number in SPI flash reads, which is unique per controller.
+    SerialNumber,
+}
+
+impl AllowedField {
+    /// Returns if the byte at the given offset of the subcommand reply belongs to the field.
+    pub fn contains(&self, reply: &[u8], offset: usize) -> bool {
+        let data = offset.checked_sub(REPLY_DATA_OFFSET);
+        match self {
+            AllowedField::Timer => offset == TIMER_OFFSET,
+            AllowedField::Inputs => (TIMER_OFFSET + 1..REPLY_ACK_OFFSET).contains(&offset),
+            AllowedField::Address => {
+                reply.get(REPLY_SUBCOMMAND_OFFSET) == Some(&(Subcommand::RequestDeviceInfo as u8))
+                    && data.is_some_and(|data| DEVICE_INFO_ADDRESS.contains(&data))
+            }
+            AllowedField::SerialNumber => {
+                if reply.get(REPLY_SUBCOMMAND_OFFSET) != Some(&(Subcommand::SpiFlashRead as u8)) {
+                    return false;
+                }
+                let addr = reply
+                    .get(REPLY_DATA_OFFSET..REPLY_DATA_OFFSET + 4)
+                    .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize);
+                match (addr, data) {
+                    (Some(addr), Some(data)) if data >= SPI_READ_HEADER_LEN => (SERIAL_NUMBER
+                        ..SERIAL_NUMBER + SERIAL_NUMBER_LEN)
+                        .contains(&(addr + data - SPI_READ_HEADER_LEN)),
+                    _ => false,
+                }
+            }
+        }
+    }
+}
+
+/// Fields excluded from comparisons. Everything else in subcommand replies, including the ACK,
+/// the subcommand, the device info and the SPI flash contents, must match byte for byte.
+pub const ALLOWLIST: [AllowedField; 4] = [
+    AllowedField::Timer,
+    AllowedField::Inputs,
+    AllowedField::Address,
+    AllowedField::SerialNumber,
+];
+
+/// Replays the output reports of a capture received at the given time through
+/// `Protocol::handle_output`, and compares the subcommand replies with the captured ones. Fields
+/// in `ALLOWLIST` are not compared.
+pub fn replay(
+    protocol: &mut Protocol,
+    frames: &[TraceFrame],
+    now: Instant,
+) -> Result<Vec<Mismatch>> {
+    let mut mismatches = vec![];
+    let mut replies = VecDeque::new();
+    for frame in frames {
+        if frame.is_output() {
+            replies.extend(protocol.handle_output(&frame.data, now)?);
+        } else if frame.is_subcommand_reply() {
+            let actual = replies.pop_front().unwrap_or_default();
+            mismatches.extend(compare(frame, &actual));
+        }
+    }
+
+    Ok(mismatches)
+}
+
+fn compare(expected: &TraceFrame, actual: &[u8]) -> Vec<Mismatch> {
+    let len = expected.data.len().max(actual.len());
+
+    (0..len)
+        .filter(|&offset| {
+            !ALLOWLIST
+                .iter()
+                .any(|field| field.contains(&expected.data, offset))
+        })
+        .map(|offset| Mismatch {
+            line: expected.line,
+            offset,
+            expected: expected.data.get(offset).copied(),
+            actual: actual.get(offset).copied(),
+        })
+        .filter(|mismatch| mismatch.expected != mismatch.actual)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ControllerType;
+
+    /// Capture of a pairing handshake with a Pro Controller.
+    const PRO_CONTROLLER_PAIRING: &str =
+        include_str!("../../tests/fixtures/pro_controller_pairing.txt");
+
+    fn replay_pairing(frames: &[TraceFrame]) -> Vec<Mismatch> {
+        let mut protocol = Protocol::new(ControllerType::ProController);
+
+        replay(&mut protocol, frames, Instant::now()).unwrap()
+    }
+
+    #[test]
+    fn pro_controller_pairing_matches() {
+        let frames = parse(PRO_CONTROLLER_PAIRING).unwrap();
+        assert_eq!(frames.iter().filter(|frame| frame.is_output()).count(), 18);
+        assert_eq!(
+            frames
+                .iter()
+                .filter(|frame| frame.is_subcommand_reply())
+                .count(),
+            18
+        );
+        assert_eq!(replay_pairing(&frames), vec![]);
+    }
+
+    #[test]
+    fn allowed_fields_differ() {
+        let frames = parse(PRO_CONTROLLER_PAIRING).unwrap();
+        let device_info = frames
+            .iter()
+            .find(|frame| frame.is_subcommand_reply() && frame.data[15] == 0x02)
+            .unwrap();
+        let serial_number = frames
+            .iter()
+            .find(|frame| frame.is_subcommand_reply() && frame.data[15] == 0x10)
+            .unwrap();
+        for offset in [2, 3, 7, 13] {
+            assert!(ALLOWLIST
+                .iter()
+                .any(|field| field.contains(&device_info.data, offset)));
+        }
+        for offset in 20..26 {
+            assert!(AllowedField::Address.contains(&device_info.data, offset));
+            assert!(!AllowedField::Address.contains(&serial_number.data, offset));
+        }
+        for offset in 21..37 {
+            assert!(AllowedField::SerialNumber.contains(&serial_number.data, offset));
+            assert!(!AllowedField::SerialNumber.contains(&device_info.data, offset));
+        }
+        for offset in [0, 1, 14, 15, 16, 19, 20, 37] {
+            assert!(
+                !ALLOWLIST
+                    .iter()
+                    .any(|field| field.contains(&serial_number.data, offset)),
+                "{}",
+                offset
+            );
+        }
+    }
+
+    #[test]
+    fn deterministic_fields_are_compared() {
+        let mut frames = parse(PRO_CONTROLLER_PAIRING).unwrap();
+        let replies: Vec<_> = frames
+            .iter()
+            .enumerate()
+            .filter(|(_, frame)| frame.is_subcommand_reply())
+            .map(|(i, _)| i)
+            .collect();
+        // ACK of the device info, firmware version, and colors in a SPI flash read
+        frames[replies[0]].data[14] = 0x80;
+        frames[replies[0]].data[16] = 0x04;
+        frames[replies[3]].data[22] = 0x00;
+        let line = frames[replies[3]].line;
+
+        let mismatches = replay_pairing(&frames);
+        assert_eq!(
+            mismatches
+                .iter()
+                .map(|mismatch| (mismatch.line, mismatch.offset))
+                .collect::<Vec<_>>(),
+            [
+                (frames[replies[0]].line, 14),
+                (frames[replies[0]].line, 16),
+                (line, 22)
+            ]
+        );
+        assert_eq!(mismatches[0].expected, Some(0x80));
+        assert_eq!(mismatches[0].actual, Some(0x82));
+    }
+
+    #[test]
+    fn missing_replies_are_reported() {
+        let frames = parse("a1 21 00 90 00 00 00 00 00 00 00 00 00 00 80 08").unwrap();
+        let mismatches = replay_pairing(&frames);
+        assert_eq!(mismatches.len(), 4);
+        assert!(mismatches.iter().all(|mismatch| mismatch.actual.is_none()));
+    }
+
+    #[test]
+    fn invalid_captures_are_rejected() {
+        assert!(parse("# comment\n\na2 01:00").is_ok());
+        assert!(parse("a2 0").is_err());
+        assert!(parse("a2 0g").is_err());
+    }
+}
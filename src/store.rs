@@ -0,0 +1,250 @@
+//! Support for persisting pairing information.
+
+use crate::bluetooth::Address;
+use crate::{ControllerType, Error, ErrorKind, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Represents a pairing of a controller with a Nintendo Switch.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct PairingRecord {
+    /// Represents the address of the Nintendo Switch.
+    #[serde(with = "address")]
+    pub switch_addr: Address,
+    /// Represents the address of the adapter.
+    #[serde(with = "address")]
+    pub adapter_addr: Address,
+    /// Represents the controller type.
+    pub controller_type: ControllerType,
+    /// Represents the pairing info in the SPI flash.
+    pub spi_pairing: Vec<u8>,
+    /// Represents the time when the controller paired.
+    pub paired_at: SystemTime,
+    /// Represents the time when the controller last connected.
+    pub connected_at: SystemTime,
+}
+
+/// Represents a store of pairing records in a JSON file.
+///
+/// The store is locked by an advisory lock on a lock file next to it while opened, so another
+/// process opening the same store fails instead of overwriting the records. The lock is released
+/// by the OS when the process exits, even if it crashes, so a lock file left behind never locks
+/// the store.
+#[derive(Debug)]
+pub struct PairingStore {
+    path: PathBuf,
+    // The lock is held while the file is open
+    _lock: File,
+    records: Vec<PairingRecord>,
+}
+
+impl PairingStore {
+    /// Opens the store in the given path, which is created on the first save. Records which
+    /// cannot be parsed are logged and dropped, and a corrupted file is treated as empty.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+
+        let lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(|e| {
+                Error::new(
+                    ErrorKind::Io(e),
+                    format!("cannot lock pairing store {}", path.display()),
+                )
+            })?;
+        if let Err(e) = lock.try_lock() {
+            let (e, message) = match e {
+                TryLockError::WouldBlock => (
+                    io::Error::from(io::ErrorKind::WouldBlock),
+                    format!(
+                        "pairing store {} is locked by another process",
+                        path.display()
+                    ),
+                ),
+                TryLockError::Error(e) => {
+                    (e, format!("cannot lock pairing store {}", path.display()))
+                }
+            };
+
+            return Err(Error::new(ErrorKind::Io(e), message));
+        }
+        let mut store = PairingStore {
+            path,
+            _lock: lock,
+            records: vec![],
+        };
+
+        let data = match fs::read(&store.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(store),
+            Err(e) => return Err(e.into()),
+        };
+        match serde_json::from_slice::<Vec<serde_json::Value>>(&data) {
+            Ok(values) => {
+                for value in values {
+                    match serde_json::from_value(value) {
+                        Ok(record) => store.records.push(record),
+                        Err(e) => warn!("Drop invalid pairing record: {}", e),
+                    }
+                }
+            }
+            Err(e) => warn!(
+                "Ignore corrupted pairing store {}: {}",
+                store.path.display(),
+                e
+            ),
+        }
+
+        Ok(store)
+    }
+
+    /// Returns the path of the store.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the records.
+    pub fn records(&self) -> &[PairingRecord] {
+        &self.records
+    }
+
+    /// Returns the most recently connected record.
+    pub fn latest(&self) -> Option<&PairingRecord> {
+        self.records.iter().max_by_key(|record| record.connected_at)
+    }
+
+    /// Returns the most recently connected record of the given adapter.
+    pub fn latest_of(&self, adapter_addr: Address) -> Option<&PairingRecord> {
+        self.records
+            .iter()
+            .filter(|record| record.adapter_addr == adapter_addr)
+            .max_by_key(|record| record.connected_at)
+    }
+
+    /// Returns the record of the given Nintendo Switch.
+    pub fn find(&self, switch_addr: Address) -> Option<&PairingRecord> {
+        self.records
+            .iter()
+            .filter(|record| record.switch_addr == switch_addr)
+            .max_by_key(|record| record.connected_at)
+    }
+
+    /// Inserts the record, which replaces the record of the same Nintendo Switch and adapter, and
+    /// saves the store.
+    pub fn insert(&mut self, record: PairingRecord) -> Result<()> {
+        self.records.retain(|r| {
+            r.switch_addr != record.switch_addr || r.adapter_addr != record.adapter_addr
+        });
+        self.records.push(record);
+
+        self.save()
+    }
+
+    /// Updates the time when the controller last connected to the given Nintendo Switch with the
+    /// given adapter, and saves the store. Returns if the record exists.
+    pub fn touch(&mut self, switch_addr: Address, adapter_addr: Address) -> Result<bool> {
+        let record = self.records.iter_mut().find(|record| {
+            record.switch_addr == switch_addr && record.adapter_addr == adapter_addr
+        });
+        match record {
+            Some(record) => record.connected_at = SystemTime::now(),
+            None => return Ok(false),
+        }
+        self.save()?;
+
+        Ok(true)
+    }
+
+    /// Removes the records of the given Nintendo Switch, and saves the store. Returns if any
+    /// record is removed.
+    pub fn remove(&mut self, switch_addr: Address) -> Result<bool> {
+        let len = self.records.len();
+        self.records
+            .retain(|record| record.switch_addr != switch_addr);
+        if self.records.len() == len {
+            return Ok(false);
+        }
+        self.save()?;
+
+        Ok(true)
+    }
+
+    fn save(&self) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&self.records)
+            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+
+        // Write to a temporary file first, so the store is never left half written
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        fs::write(&tmp_path, data)?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        Ok(())
+    }
+}
+
+mod address {
+    use crate::bluetooth::Address;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(address: &Address, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(address)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Address, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the path of a store in a new directory of the given name.
+    fn store_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("playwith-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir.join("pairings.json")
+    }
+
+    #[test]
+    fn open_store_is_locked() {
+        let path = store_path("locked");
+        let store = PairingStore::open(&path).unwrap();
+        let e = PairingStore::open(&path).unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::Io(_)));
+        assert!(e.message.contains("locked by another process"), "{}", e);
+
+        drop(store);
+        assert!(PairingStore::open(&path).is_ok());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn stale_lock_file_does_not_lock() {
+        // A lock file is left behind by a process which crashed
+        let path = store_path("stale");
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        fs::write(&lock_path, b"").unwrap();
+
+        assert!(PairingStore::open(&path).is_ok());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}